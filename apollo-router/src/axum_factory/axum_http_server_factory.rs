@@ -1,8 +1,10 @@
 //! Axum http server factory. Axum provides routing capability on top of Hyper HTTP.
+use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use axum::extract::Extension;
+use axum::extract::WebSocketUpgrade;
 use axum::http::StatusCode;
 use axum::middleware;
 use axum::response::*;
@@ -33,8 +35,11 @@ use super::listeners::ensure_endpoints_consistency;
 use super::listeners::ensure_listenaddrs_consistency;
 use super::listeners::extra_endpoints;
 use super::listeners::ListenersAndRouters;
+use super::tls::TlsAcceptor;
 use super::utils::decompress_request_body;
 use super::utils::PropagatingMakeSpan;
+use super::websocket::accepts_graphql_transport_ws;
+use super::websocket::handle_graphql_ws;
 use super::ListenAddrAndRouter;
 use crate::axum_factory::listeners::get_extra_listeners;
 use crate::axum_factory::listeners::serve_router_on_listen_addr;
@@ -45,6 +50,7 @@ use crate::http_server_factory::HttpServerHandle;
 use crate::http_server_factory::Listener;
 use crate::plugins::traffic_shaping::Elapsed;
 use crate::plugins::traffic_shaping::RateLimited;
+use crate::protocols::websocket::WebSocketProtocol;
 use crate::router::ApolloRouterError;
 use crate::router_factory::Endpoint;
 use crate::router_factory::RouterFactory;
@@ -63,7 +69,6 @@ impl AxumHttpServerFactory {
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "UPPERCASE")]
-#[allow(dead_code)]
 enum HealthStatus {
     Up,
     Down,
@@ -72,6 +77,10 @@ enum HealthStatus {
 #[derive(Debug, Serialize)]
 struct Health {
     status: HealthStatus,
+    /// State of the connections to Redis, per cache. The router stays up if Redis is down, the
+    /// caches only use their in memory tier
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    redis: BTreeMap<String, HealthStatus>,
 }
 
 pub(crate) fn make_axum_router<RF>(
@@ -85,6 +94,7 @@ where
     ensure_listenaddrs_consistency(configuration, &endpoints)?;
 
     if configuration.health_check.enabled {
+        let caches = service_factory.caches();
         tracing::info!(
             "Health check endpoint exposed at {}/health",
             configuration.health_check.listen
@@ -96,6 +106,18 @@ where
                 service_fn(move |req: router::Request| {
                     let health = Health {
                         status: HealthStatus::Up,
+                        redis: caches
+                            .redis_connection_states()
+                            .into_iter()
+                            .map(|(kind, connected)| {
+                                let status = if connected {
+                                    HealthStatus::Up
+                                } else {
+                                    HealthStatus::Down
+                                };
+                                (kind, status)
+                            })
+                            .collect(),
                     };
                     tracing::trace!(?health, request = ?req.router_request, "health check");
                     async move {
@@ -198,14 +220,25 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 .local_addr()
                 .map_err(ApolloRouterError::ServerCreationError)?;
 
+            let tls = configuration
+                .tls
+                .supergraph
+                .as_ref()
+                .map(TlsAcceptor::new)
+                .transpose()
+                .map_err(ApolloRouterError::ServerCreationError)?;
+            let scheme = if tls.is_some() { "https://" } else { "" };
+
             let (main_server, main_shutdown_sender) = serve_router_on_listen_addr(
                 main_listener,
                 actual_main_listen_address.clone(),
                 all_routers.main.1,
+                tls,
             );
 
             tracing::info!(
-                "GraphQL endpoint exposed at {}{} 🚀",
+                "GraphQL endpoint exposed at {}{}{} 🚀",
+                scheme,
                 actual_main_listen_address,
                 configuration.supergraph.path
             );
@@ -235,8 +268,12 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 listeners_and_routers
                     .into_iter()
                     .map(|((listen_addr, listener), router)| {
-                        let (server, shutdown_sender) =
-                            serve_router_on_listen_addr(listener, listen_addr.clone(), router);
+                        let (server, shutdown_sender) = serve_router_on_listen_addr(
+                            listener,
+                            listen_addr.clone(),
+                            router,
+                            None,
+                        );
                         (
                             server.map(|listener| (listen_addr, listener)),
                             shutdown_sender,
@@ -308,11 +345,15 @@ pub(super) fn main_router<RF>(configuration: &Configuration) -> axum::Router
 where
     RF: RouterFactory,
 {
+    // client-facing WebSockets are only served when subscriptions are enabled
+    let websocket_enabled = configuration.subscription_enabled();
     let mut router = Router::new().route(
         &configuration.supergraph.sanitized_path(),
         get({
-            move |Extension(service): Extension<RF>, request: Request<Body>| {
-                handle_graphql(service.create().boxed(), request)
+            move |Extension(service): Extension<RF>,
+                  websocket: Option<WebSocketUpgrade>,
+                  request: Request<Body>| {
+                handle_get(service, websocket.filter(|_| websocket_enabled), request)
             }
         })
         .post({
@@ -326,8 +367,10 @@ where
         router = router.route(
             "/",
             get({
-                move |Extension(service): Extension<RF>, request: Request<Body>| {
-                    handle_graphql(service.create().boxed(), request)
+                move |Extension(service): Extension<RF>,
+                      websocket: Option<WebSocketUpgrade>,
+                      request: Request<Body>| {
+                    handle_get(service, websocket.filter(|_| websocket_enabled), request)
                 }
             })
             .post({
@@ -341,6 +384,35 @@ where
     router
 }
 
+async fn handle_get<RF>(
+    service_factory: RF,
+    websocket: Option<WebSocketUpgrade>,
+    http_request: Request<Body>,
+) -> Response
+where
+    RF: RouterFactory,
+{
+    match websocket {
+        Some(websocket) => {
+            let (parts, _body) = http_request.into_parts();
+            if !accepts_graphql_transport_ws(&parts.headers) {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "the only supported WebSocket subprotocol is graphql-transport-ws",
+                )
+                    .into_response();
+            }
+
+            websocket
+                .protocols([WebSocketProtocol::GraphqlTransportWs.subprotocol()])
+                .on_upgrade(move |socket| handle_graphql_ws(service_factory, parts, socket))
+        }
+        None => handle_graphql(service_factory.create().boxed(), http_request)
+            .await
+            .into_response(),
+    }
+}
+
 async fn handle_graphql(
     service: router::BoxService,
     http_request: Request<Body>,
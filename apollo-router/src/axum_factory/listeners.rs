@@ -6,7 +6,9 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
+use axum::extract::ConnectInfo;
 use axum::response::*;
+use axum::Extension;
 use axum::Router;
 use futures::channel::oneshot;
 use futures::prelude::*;
@@ -16,7 +18,10 @@ use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
 use tokio::sync::Notify;
+use tower::Layer;
 
+use super::tls::MaybeTlsStream;
+use super::tls::TlsAcceptor;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
 use crate::http_server_factory::Listener;
@@ -188,6 +193,7 @@ pub(super) fn serve_router_on_listen_addr(
     mut listener: Listener,
     address: ListenAddr,
     router: axum::Router,
+    tls: Option<TlsAcceptor>,
 ) -> (impl Future<Output = Listener>, oneshot::Sender<()>) {
     let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
     // this server reproduces most of hyper::server::Server's behaviour
@@ -225,17 +231,35 @@ pub(super) fn serve_router_on_listen_addr(
                             );
 
                             let address = address.clone();
+                            let tls = tls.clone();
                             tokio::task::spawn(async move {
                                 match res {
-                                    NetworkStream::Tcp(stream) => {
+                                    NetworkStream::Tcp(stream, peer_addr) => {
                                         stream
                                             .set_nodelay(true)
                                             .expect(
                                                 "this should not fail unless the socket is invalid",
                                             );
+                                            // lets the router services know the client address
+                                            let app = Extension(ConnectInfo(peer_addr)).layer(app);
+                                            let (stream, http2) = match &tls {
+                                                Some(tls) => match tls.accept(stream).await {
+                                                    Ok(res) => res,
+                                                    Err(e) => {
+                                                        tracing::debug!("TLS handshake failed: {}", e);
+                                                        tracing::info!(
+                                                            counter.apollo_router_session_count_total = -1,
+                                                            listener = &address
+                                                        );
+                                                        return;
+                                                    }
+                                                },
+                                                None => (MaybeTlsStream::Plain(stream), false),
+                                            };
                                             let connection = Http::new()
                                             .http1_keep_alive(true)
                                             .http1_header_read_timeout(Duration::from_secs(10))
+                                            .http2_only(http2)
                                             .serve_connection(stream, app);
 
                                         tokio::pin!(connection);
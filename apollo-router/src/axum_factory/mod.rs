@@ -3,7 +3,9 @@ mod axum_http_server_factory;
 mod listeners;
 #[cfg(test)]
 pub(crate) mod tests;
+mod tls;
 pub(crate) mod utils;
+mod websocket;
 
 pub(crate) use axum_http_server_factory::make_axum_router;
 pub(crate) use axum_http_server_factory::AxumHttpServerFactory;
@@ -15,6 +15,7 @@ use futures::future::BoxFuture;
 use futures::stream;
 use futures::stream::poll_fn;
 use futures::Future;
+use futures::SinkExt;
 use futures::StreamExt;
 use http::header::ACCEPT_ENCODING;
 use http::header::CONTENT_ENCODING;
@@ -962,6 +963,8 @@ async fn response_failure() -> Result<(), ApolloRouterError> {
         let example_response = crate::error::FetchError::SubrequestHttpError {
             service: "Mock service".to_string(),
             reason: "Mock error".to_string(),
+            status_code: None,
+            retry_after: None,
         }
         .to_response();
 
@@ -997,6 +1000,8 @@ async fn response_failure() -> Result<(), ApolloRouterError> {
         crate::error::FetchError::SubrequestHttpError {
             service: "Mock service".to_string(),
             reason: "Mock error".to_string(),
+            status_code: None,
+            retry_after: None,
         }
         .to_response()
     );
@@ -1148,7 +1153,7 @@ async fn it_sends_bad_accept_header() -> Result<(), ApolloRouterError> {
     );
     assert_eq!(
         response.text().await.unwrap(),
-        r#"{"message":"'accept' header can't be different from \\\"*/*\\\", \"application/json\", \"application/graphql-response+json\" or \"multipart/mixed;boundary=\\\"graphql\\\";deferSpec=20220824\"","extensions":{"code":"INVALID_ACCEPT_HEADER"}}"#
+        r#"{"message":"'accept' header can't be different from \\\"*/*\\\", \"application/json\", \"application/graphql-response+json\", \"multipart/mixed;boundary=\\\"graphql\\\";deferSpec=20220824\" or \"multipart/mixed;boundary=\\\"graphql\\\";subscriptionSpec=1.0\"","extensions":{"code":"INVALID_ACCEPT_HEADER"}}"#
     );
 
     server.shutdown().await
@@ -1725,6 +1730,112 @@ async fn multipart_response_shape_with_one_chunk() -> Result<(), ApolloRouterErr
     server.shutdown().await
 }
 
+#[test(tokio::test)]
+async fn it_executes_operations_over_websocket() -> Result<(), ApolloRouterError> {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let router_service = router_service::from_supergraph_mock_callback(move |req| {
+        assert_eq!(
+            req.supergraph_request.body().query.as_deref(),
+            Some("query { test }")
+        );
+        Ok(SupergraphResponse::new_from_graphql_response(
+            graphql::Response::builder()
+                .data(json!({"test": "hello"}))
+                .build(),
+            req.context,
+        ))
+    })
+    .await;
+    let conf = Configuration::from_str("subscription:\n  enabled: true").unwrap();
+    let (server, _client) =
+        init_with_config(router_service, Arc::new(conf), MultiMap::new()).await?;
+    let url =
+        format!("{}/", server.graphql_listen_address().as_ref().unwrap()).replacen("http", "ws", 1);
+
+    let mut request = url.into_client_request().unwrap();
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static("graphql-transport-ws"),
+    );
+    let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers().get(header::SEC_WEBSOCKET_PROTOCOL),
+        Some(&HeaderValue::from_static("graphql-transport-ws"))
+    );
+
+    socket
+        .send(Message::Text(
+            json!({"type": "connection_init"}).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_websocket_message(&mut socket).await,
+        json!({"type": "connection_ack"})
+    );
+
+    socket
+        .send(Message::Text(json!({"type": "ping"}).to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_websocket_message(&mut socket).await,
+        json!({"type": "pong"})
+    );
+
+    socket
+        .send(Message::Text(
+            json!({
+                "type": "subscribe",
+                "id": "1",
+                "payload": { "query": "query { test }" }
+            })
+            .to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_websocket_message(&mut socket).await,
+        json!({
+            "type": "next",
+            "id": "1",
+            "payload": { "data": { "test": "hello" } }
+        })
+    );
+    assert_eq!(
+        next_websocket_message(&mut socket).await,
+        json!({"type": "complete", "id": "1"})
+    );
+
+    // a second `connection_init` closes the connection
+    socket
+        .send(Message::Text(
+            json!({"type": "connection_init"}).to_string(),
+        ))
+        .await
+        .unwrap();
+    match socket.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 4429),
+        message => panic!("unexpected message: {message:?}"),
+    }
+
+    server.shutdown().await
+}
+
+async fn next_websocket_message<S>(
+    socket: &mut tokio_tungstenite::WebSocketStream<S>,
+) -> serde_json::Value
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    match socket.next().await.unwrap().unwrap() {
+        tokio_tungstenite::tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        message => panic!("unexpected message: {message:?}"),
+    }
+}
+
 #[tokio::test]
 async fn it_supports_server_restart() {
     let configuration = Arc::new(
@@ -2296,6 +2407,7 @@ async fn test_supergraph_timeout() {
 
     let service = RouterCreator::new(Arc::new(supergraph_creator), &conf)
         .await
+        .unwrap()
         .make();
 
     // keep the server handle around otherwise it will immediately shutdown
@@ -0,0 +1,272 @@
+//! TLS termination of the supergraph listener
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use futures::prelude::*;
+use rustls::ServerConfig;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+
+use crate::configuration::TlsSupergraph;
+use crate::files;
+use crate::router_factory::load_certs;
+use crate::router_factory::load_key;
+
+/// Protocols advertised with ALPN, by order of preference
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TLS connections with the current certificates, which are reloaded when their files change
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<ArcSwap<ServerConfig>>,
+    _reloader: Option<Arc<Reloader>>,
+}
+
+/// Stops watching the certificate files when dropped
+struct Reloader(JoinHandle<()>);
+
+impl Drop for Reloader {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl TlsAcceptor {
+    pub(crate) fn new(conf: &TlsSupergraph) -> io::Result<Self> {
+        let config = Arc::new(ArcSwap::from(load_server_config(conf)?));
+
+        let reloader = conf.hot_reload.then(|| {
+            let conf = conf.clone();
+            let config = config.clone();
+            // the first event of each watch is sent when the watch starts
+            let changes = stream::select(
+                files::watch(&conf.certificate_chain).skip(1),
+                files::watch(&conf.key).skip(1),
+            );
+            let task = changes.for_each(move |_| {
+                reload(&conf, &config);
+                future::ready(())
+            });
+            Arc::new(Reloader(tokio::task::spawn(task)))
+        });
+
+        Ok(Self {
+            config,
+            _reloader: reloader,
+        })
+    }
+
+    /// Performs the TLS handshake, and returns whether HTTP/2 was negotiated
+    pub(crate) async fn accept(&self, stream: TcpStream) -> io::Result<(MaybeTlsStream, bool)> {
+        let acceptor = tokio_rustls::TlsAcceptor::from(self.config.load_full());
+        let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2".as_slice());
+        Ok((MaybeTlsStream::Tls(Box::new(stream)), http2))
+    }
+}
+
+fn reload(conf: &TlsSupergraph, config: &ArcSwap<ServerConfig>) {
+    match load_server_config(conf) {
+        Ok(server_config) => {
+            tracing::info!("reloaded the TLS certificates of the supergraph listener");
+            config.store(server_config);
+        }
+        Err(e) => {
+            tracing::error!(
+                "could not reload the TLS certificates of the supergraph listener, keeping the previous ones: {}",
+                e
+            );
+        }
+    }
+}
+
+fn load_server_config(conf: &TlsSupergraph) -> io::Result<Arc<ServerConfig>> {
+    let certificate_chain = load_certs(&std::fs::read_to_string(&conf.certificate_chain)?)?;
+    if certificate_chain.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "no certificate found in {}",
+                conf.certificate_chain.display()
+            ),
+        ));
+    }
+    let key = load_key(&std::fs::read_to_string(&conf.key)?)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificate_chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// A TCP connection, over TLS or in plaintext
+pub(crate) enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.is_write_vectored(),
+            MaybeTlsStream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rustls::ClientConfig;
+    use rustls::RootCertStore;
+    use rustls::ServerName;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::files::tests::create_temp_file;
+    use crate::files::tests::write_and_flush;
+
+    fn testdata() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testdata")
+    }
+
+    fn conf() -> TlsSupergraph {
+        TlsSupergraph {
+            certificate_chain: testdata().join("server.crt"),
+            key: testdata().join("server.key"),
+            hot_reload: false,
+        }
+    }
+
+    #[test]
+    fn it_rejects_files_without_a_key() {
+        let mut conf = conf();
+        conf.key = conf.certificate_chain.clone();
+        assert!(load_server_config(&conf).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_negotiates_http2() {
+        let acceptor = TlsAcceptor::new(&conf()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.unwrap().1
+        });
+
+        // the server certificate is signed by this certificate authority
+        let mut roots = RootCertStore::empty();
+        for certificate in
+            load_certs(&std::fs::read_to_string(testdata().join("ca.crt")).unwrap()).unwrap()
+        {
+            roots.add(&certificate).unwrap();
+        }
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let _stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert!(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_reloads_the_certificates() {
+        let certificate_chain = std::fs::read_to_string(conf().certificate_chain).unwrap();
+        let key = std::fs::read_to_string(conf().key).unwrap();
+        let (certificate_chain_path, mut certificate_chain_file) = create_temp_file();
+        let (key_path, mut key_file) = create_temp_file();
+        write_and_flush(&mut certificate_chain_file, &certificate_chain).await;
+        write_and_flush(&mut key_file, &key).await;
+
+        let acceptor = TlsAcceptor::new(&TlsSupergraph {
+            certificate_chain: certificate_chain_path,
+            key: key_path,
+            hot_reload: true,
+        })
+        .unwrap();
+        let initial = acceptor.config.load_full();
+
+        // invalid files are not loaded
+        write_and_flush(&mut key_file, "not a key").await;
+        assert!(Arc::ptr_eq(&initial, &acceptor.config.load_full()));
+
+        write_and_flush(&mut key_file, &key).await;
+        assert!(!Arc::ptr_eq(&initial, &acceptor.config.load_full()));
+    }
+}
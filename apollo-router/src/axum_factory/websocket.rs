@@ -0,0 +1,342 @@
+//! GraphQL over WebSocket server, implementing the `graphql-transport-ws` protocol.
+//!
+//! Each operation started by the client is sent through the router service pipeline,
+//! as if it was a POST request on the GraphQL endpoint, so plugins see all operations.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::extract::ws::CloseFrame;
+use axum::extract::ws::Message;
+use axum::extract::ws::WebSocket;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use futures::StreamExt;
+use http::header::ACCEPT;
+use http::header::CONNECTION;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::header::SEC_WEBSOCKET_EXTENSIONS;
+use http::header::SEC_WEBSOCKET_KEY;
+use http::header::SEC_WEBSOCKET_PROTOCOL;
+use http::header::SEC_WEBSOCKET_VERSION;
+use http::header::UPGRADE;
+use http::request::Parts;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::Method;
+use mime::APPLICATION_JSON;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+use tower::ServiceExt;
+use tracing::Instrument;
+
+use crate::graphql;
+use crate::protocols::websocket::ClientMessage;
+use crate::protocols::websocket::ServerError;
+use crate::protocols::websocket::ServerMessage;
+use crate::protocols::websocket::WebSocketProtocol;
+use crate::protocols::websocket::WebSocketRequest;
+use crate::router_factory::RouterFactory;
+use crate::services::router;
+use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
+
+/// Delay after which the connection is closed if the client did not send `connection_init`
+const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const EXECUTION_ERROR_CODE: &str = "WEBSOCKET_EXECUTION_ERROR";
+
+// close codes defined by the graphql-transport-ws protocol
+const INVALID_MESSAGE: u16 = 4400;
+const UNAUTHORIZED: u16 = 4401;
+const CONNECTION_INIT_TIMED_OUT: u16 = 4408;
+const SUBSCRIBER_ALREADY_EXISTS: u16 = 4409;
+const TOO_MANY_INITIALISATION_REQUESTS: u16 = 4429;
+
+impl From<ServerMessage> for Message {
+    fn from(message: ServerMessage) -> Self {
+        Message::Text(
+            serde_json::to_string(&message).expect("server messages are always serializable"),
+        )
+    }
+}
+
+/// Returns true if the client asked for the `graphql-transport-ws` subprotocol
+pub(super) fn accepts_graphql_transport_ws(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == WebSocketProtocol::GraphqlTransportWs.subprotocol())
+}
+
+/// Runs a `graphql-transport-ws` session on an upgraded connection.
+///
+/// `parts` are the parts of the upgrade request: its headers are copied to each operation's request.
+pub(super) async fn handle_graphql_ws<RF>(service_factory: RF, parts: Parts, socket: WebSocket)
+where
+    RF: RouterFactory,
+{
+    let (mut sink, mut stream) = socket.split();
+
+    // operations run concurrently, so all outgoing messages go through this channel
+    let (mut sender, mut receiver) = mpsc::channel::<Message>(16);
+    let writer = tokio::task::spawn(async move {
+        while let Some(message) = receiver.next().await {
+            let is_close = matches!(message, Message::Close(_));
+            if sink.send(message).await.is_err() || is_close {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let init_deadline = tokio::time::Instant::now() + CONNECTION_INIT_TIMEOUT;
+    let mut acknowledged = false;
+    let mut operations: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        let message = if acknowledged {
+            stream.next().await
+        } else {
+            match tokio::time::timeout_at(init_deadline, stream.next()).await {
+                Ok(message) => message,
+                Err(_) => {
+                    close(
+                        &mut sender,
+                        CONNECTION_INIT_TIMED_OUT,
+                        "Connection initialisation timeout",
+                    )
+                    .await;
+                    break;
+                }
+            }
+        };
+
+        let message = match message {
+            Some(Ok(Message::Text(text))) => serde_json::from_str::<ClientMessage>(&text),
+            Some(Ok(Message::Binary(bytes))) => serde_json::from_slice::<ClientMessage>(&bytes),
+            // ping frames are answered by axum
+            Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+        };
+
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::debug!("invalid graphql-transport-ws message: {err}");
+                close(&mut sender, INVALID_MESSAGE, "Invalid message received").await;
+                break;
+            }
+        };
+
+        let sent = match message {
+            ClientMessage::ConnectionInit { .. } if acknowledged => {
+                close(
+                    &mut sender,
+                    TOO_MANY_INITIALISATION_REQUESTS,
+                    "Too many initialisation requests",
+                )
+                .await;
+                break;
+            }
+            ClientMessage::ConnectionInit { .. } => {
+                acknowledged = true;
+                sender
+                    .send(ServerMessage::ConnectionAck { payload: None }.into())
+                    .await
+            }
+            ClientMessage::Subscribe { .. } if !acknowledged => {
+                close(&mut sender, UNAUTHORIZED, "Unauthorized").await;
+                break;
+            }
+            ClientMessage::Subscribe { id, payload } => {
+                operations.retain(|_, operation| !operation.is_finished());
+                if operations.contains_key(&id) {
+                    close(
+                        &mut sender,
+                        SUBSCRIBER_ALREADY_EXISTS,
+                        &format!("Subscriber for {id} already exists"),
+                    )
+                    .await;
+                    break;
+                }
+
+                let operation = tokio::task::spawn(
+                    execute(
+                        service_factory.create().boxed(),
+                        router_request(&parts, &payload),
+                        id.clone(),
+                        sender.clone(),
+                    )
+                    .in_current_span(),
+                );
+                operations.insert(id, operation);
+                Ok(())
+            }
+            ClientMessage::Complete { id } => {
+                if let Some(operation) = operations.remove(&id) {
+                    operation.abort();
+                }
+                Ok(())
+            }
+            ClientMessage::Ping { .. } => {
+                sender
+                    .send(ServerMessage::Pong { payload: None }.into())
+                    .await
+            }
+            ClientMessage::Pong { .. } => Ok(()),
+            // messages from the legacy graphql-ws protocol
+            ClientMessage::Start { .. }
+            | ClientMessage::Stop { .. }
+            | ClientMessage::ConnectionTerminate => {
+                close(&mut sender, INVALID_MESSAGE, "Invalid message received").await;
+                break;
+            }
+        };
+
+        if sent.is_err() {
+            // the writer stopped, the connection is gone
+            break;
+        }
+    }
+
+    for (_, operation) in operations {
+        operation.abort();
+    }
+    drop(sender);
+    let _ = writer.await;
+}
+
+async fn close(sender: &mut mpsc::Sender<Message>, code: u16, reason: &str) {
+    let _ = sender
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: Cow::Owned(reason.to_string()),
+        })))
+        .await;
+}
+
+/// Creates the request for an operation, from the upgrade request and the `subscribe` payload
+fn router_request(parts: &Parts, payload: &graphql::Request) -> Result<router::Request, BoxError> {
+    let mut request = http::Request::builder()
+        .method(Method::POST)
+        .uri(parts.uri.clone())
+        .body(router::Body::from(serde_json::to_vec(payload)?))?;
+    request.extensions_mut().insert(WebSocketRequest);
+
+    let headers = request.headers_mut();
+    for (name, value) in parts.headers.iter() {
+        if ![
+            CONNECTION,
+            UPGRADE,
+            CONTENT_LENGTH,
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+            SEC_WEBSOCKET_PROTOCOL,
+            SEC_WEBSOCKET_EXTENSIONS,
+        ]
+        .contains(name)
+        {
+            headers.append(name, value.clone());
+        }
+    }
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(APPLICATION_JSON.essence_str()),
+    );
+    // subscription events and deferred responses are sent as separate `next` messages
+    headers.insert(
+        ACCEPT,
+        HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE),
+    );
+
+    Ok(request.into())
+}
+
+/// Executes an operation and sends its results as `next` messages, followed by `complete`.
+///
+/// If the operation could not be executed, a single `error` message is sent instead.
+async fn execute(
+    service: router::BoxService,
+    request: Result<router::Request, BoxError>,
+    id: String,
+    mut sender: mpsc::Sender<Message>,
+) {
+    let response = match request {
+        Ok(request) => service.oneshot(request).await,
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            let error = graphql::Error::builder()
+                .message(format!("router service call failed: {err}"))
+                .extension_code(EXECUTION_ERROR_CODE)
+                .build();
+            let _ = sender
+                .send(
+                    ServerMessage::Error {
+                        id,
+                        payload: ServerError::Errors(vec![error]),
+                    }
+                    .into(),
+                )
+                .await;
+            return;
+        }
+    };
+
+    let is_success = response.response.status().is_success();
+    let mut responses = response.into_graphql_response_stream().await;
+
+    // the request was rejected before execution, like with validation errors
+    if !is_success {
+        let errors = match responses.next().await {
+            Some(Ok(response)) => response.errors,
+            _ => Vec::new(),
+        };
+        let _ = sender
+            .send(
+                ServerMessage::Error {
+                    id,
+                    payload: ServerError::Errors(errors),
+                }
+                .into(),
+            )
+            .await;
+        return;
+    }
+
+    while let Some(response) = responses.next().await {
+        let payload = match response {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::error!("cannot deserialize the router response: {err}");
+                break;
+            }
+        };
+
+        // the end of a subscription is marked by an empty response
+        if payload.data.is_none()
+            && payload.errors.is_empty()
+            && payload.incremental.is_empty()
+            && payload.extensions.is_empty()
+        {
+            continue;
+        }
+
+        let message = ServerMessage::Next {
+            id: id.clone(),
+            payload,
+        };
+        if sender.send(message.into()).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = sender.send(ServerMessage::Complete { id }.into()).await;
+}
@@ -0,0 +1,125 @@
+//! Invalidation of the entries of the caches, across their in memory and Redis tiers.
+
+use serde::Serialize;
+
+/// Entries to remove from a cache
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Invalidation {
+    /// The entry with this key
+    Key(String),
+    /// The entries with a key starting with this prefix
+    Prefix(String),
+    /// All the entries
+    All,
+}
+
+impl Invalidation {
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        match self {
+            Invalidation::Key(k) => key == k,
+            Invalidation::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Invalidation::All => true,
+        }
+    }
+}
+
+/// Number of entries removed from a cache
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Invalidated {
+    /// Name of the cache
+    pub(crate) kind: String,
+    /// Entries removed from the in memory cache
+    pub(crate) memory: usize,
+    /// Entries removed from Redis, if the cache uses it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) redis: Option<usize>,
+    /// Why the entries could not be removed from Redis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// A cache whose entries can be invalidated
+#[async_trait::async_trait]
+pub(crate) trait Invalidate: Send + Sync {
+    /// Name of the cache, e.g. `query planner`
+    fn kind(&self) -> &str;
+
+    /// Number of entries in the in memory cache
+    async fn len(&self) -> usize;
+
+    fn has_redis(&self) -> bool;
+
+    async fn invalidate(&self, invalidation: &Invalidation) -> Invalidated;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::cache::registry::CacheRegistry;
+    use crate::cache::storage::CacheStorage;
+
+    #[test]
+    fn it_matches_keys() {
+        let key = Invalidation::Key("plan:a".to_string());
+        assert!(key.matches("plan:a"));
+        assert!(!key.matches("plan:ab"));
+
+        let prefix = Invalidation::Prefix("plan:".to_string());
+        assert!(prefix.matches("plan:a"));
+        assert!(!prefix.matches("apq:plan:a"));
+
+        assert!(Invalidation::All.matches("apq:a"));
+    }
+
+    #[tokio::test]
+    async fn it_invalidates_in_memory_entries() {
+        let registry = CacheRegistry::default();
+        let storage: CacheStorage<String, String> = registry
+            .clone()
+            .scope(CacheStorage::new(
+                NonZeroUsize::new(10).unwrap(),
+                None,
+                "invalidation test",
+            ))
+            .await;
+        // caches created outside of a router are not registered
+        let _other: CacheStorage<String, String> =
+            CacheStorage::new(NonZeroUsize::new(10).unwrap(), None, "other").await;
+        for key in ["plan:a", "plan:b", "apq:a"] {
+            storage.insert(key.to_string(), "value".to_string()).await;
+        }
+        let caches = registry.caches();
+        assert_eq!(caches.len(), 1);
+        let cache = caches.into_iter().next().unwrap();
+        assert_eq!(cache.kind(), "invalidation test");
+        assert_eq!(cache.len().await, 3);
+
+        let invalidated = cache
+            .invalidate(&Invalidation::Prefix("plan:".to_string()))
+            .await;
+        assert_eq!(invalidated.memory, 2);
+        assert_eq!(
+            storage.get(&"apq:a".to_string()).await,
+            Some("value".to_string())
+        );
+
+        let invalidated = cache
+            .invalidate(&Invalidation::Key("apq:a".to_string()))
+            .await;
+        assert_eq!(invalidated.memory, 1);
+        assert_eq!(cache.len().await, 0);
+
+        storage
+            .insert("apq:b".to_string(), "value".to_string())
+            .await;
+        let invalidated = cache.invalidate(&Invalidation::All).await;
+        assert_eq!(invalidated.memory, 1);
+        assert_eq!(invalidated.redis, None);
+
+        drop(cache);
+        drop(storage);
+        assert!(registry.caches().is_empty());
+    }
+}
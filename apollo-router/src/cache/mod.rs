@@ -9,8 +9,11 @@ use tokio::sync::Mutex;
 use self::storage::CacheStorage;
 use self::storage::KeyType;
 use self::storage::ValueType;
+use crate::configuration::RedisCache;
 
+pub(crate) mod invalidation;
 pub(crate) mod redis;
+pub(crate) mod registry;
 pub(crate) mod storage;
 
 type WaitMap<K, V> = Arc<Mutex<HashMap<K, broadcast::Sender<V>>>>;
@@ -33,12 +36,12 @@ where
 {
     pub(crate) async fn with_capacity(
         capacity: NonZeroUsize,
-        redis_urls: Option<Vec<String>>,
+        redis: Option<&RedisCache>,
         caller: &str,
     ) -> Self {
         Self {
             wait_map: Arc::new(Mutex::new(HashMap::new())),
-            storage: CacheStorage::new(capacity, redis_urls, caller).await,
+            storage: CacheStorage::new(capacity, redis, caller).await,
         }
     }
 
@@ -46,12 +49,7 @@ where
         config: &crate::configuration::Cache,
         caller: &str,
     ) -> Self {
-        Self::with_capacity(
-            config.in_memory.limit,
-            config.redis.as_ref().map(|c| c.urls.clone()),
-            caller,
-        )
-        .await
+        Self::with_capacity(config.in_memory.limit, config.redis.as_ref(), caller).await
     }
 
     pub(crate) async fn get(&self, key: &K) -> Entry<K, V> {
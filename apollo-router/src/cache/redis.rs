@@ -1,20 +1,42 @@
 // This entire file is license key functionality
 
 use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
 use std::time::Duration;
 
+use rand::Rng;
+use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
+use redis::ConnectionAddr;
+use redis::ConnectionInfo;
 use redis::FromRedisValue;
+use redis::IntoConnectionInfo;
 use redis::RedisResult;
 use redis::RedisWrite;
 use redis::ToRedisArgs;
 use redis_cluster_async::Client;
 use redis_cluster_async::Connection;
-use tokio::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tower::BoxError;
 
+use super::registry::CacheRegistry;
 use super::KeyType;
 use super::ValueType;
+use crate::configuration::RedisCache;
+use crate::router_factory::create_certificate_store;
+use crate::services::subgraph_service::generate_tls_client_config;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub(crate) struct RedisKey<K>(pub(crate) K)
@@ -26,15 +48,35 @@ pub(crate) struct RedisValue<V>(pub(crate) V)
 where
     V: ValueType;
 
+/// A multiplexed connection, shared by concurrent commands
+#[derive(Clone)]
 enum RedisConnection {
-    Single(redis::aio::Connection),
+    Single(MultiplexedConnection),
     Cluster(Connection),
 }
 
 #[derive(Clone)]
 pub(crate) struct RedisCacheStorage {
-    inner: Arc<Mutex<RedisConnection>>,
+    inner: Arc<Pool>,
     ttl: Option<Duration>,
+    namespace: Option<Arc<String>>,
+    /// Prefix of the keys of the cache: its namespace, if any, and its kind, so that the caches
+    /// sharing a namespace can be invalidated separately
+    prefix: Arc<String>,
+    timeout: Duration,
+}
+
+/// Connections to Redis, re-established in the background when they are lost
+pub(crate) struct Pool {
+    /// Name of the cache using the connections
+    kind: String,
+    connection_infos: Vec<ConnectionInfo>,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+    connect_timeout: Duration,
+    connections: Vec<Mutex<Option<RedisConnection>>>,
+    /// Index of the next connection to use
+    next: AtomicUsize,
+    reconnecting: AtomicBool,
 }
 
 fn get_type_of<T>(_: &T) -> &'static str {
@@ -111,25 +153,121 @@ where
     }
 }
 
+/// Runs a command on the next connection of the pool, failing after the command timeout.
+///
+/// The connection is re-established in the background if the command fails with an I/O error.
+macro_rules! with_connection {
+    ($storage:expr, $connection:ident => $command:expr) => {
+        match $storage.inner.connection() {
+            None => Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "not connected to Redis",
+            ))),
+            Some((index, connection)) => {
+                let result = tokio::time::timeout($storage.timeout, async {
+                    match connection {
+                        RedisConnection::Single(mut $connection) => {
+                            let $connection = &mut $connection;
+                            $command.await
+                        }
+                        RedisConnection::Cluster(mut $connection) => {
+                            let $connection = &mut $connection;
+                            $command.await
+                        }
+                    }
+                })
+                .await
+                .unwrap_or_else(|_| {
+                    Err(redis::RedisError::from((
+                        redis::ErrorKind::ClientError,
+                        "Redis command timed out",
+                    )))
+                });
+                if let Err(e) = &result {
+                    if e.is_io_error() {
+                        $storage.inner.disconnect(index, e);
+                    }
+                }
+                result
+            }
+        }
+    };
+}
+
 impl RedisCacheStorage {
-    pub(crate) async fn new(
-        mut urls: Vec<String>,
-        ttl: Option<Duration>,
-    ) -> Result<Self, redis::RedisError> {
-        let connection = if urls.len() == 1 {
-            let client = redis::Client::open(urls.pop().expect("urls contains only one url; qed"))?;
-            let connection = client.get_async_connection().await?;
-            RedisConnection::Single(connection)
-        } else {
-            let client = Client::open(urls)?;
-            let connection = client.get_connection().await?;
-            RedisConnection::Cluster(connection)
+    /// Connects to Redis.
+    ///
+    /// Fails if the configuration is invalid. If Redis cannot be reached, the connections are
+    /// established in the background, and the commands fail in the meantime.
+    pub(crate) async fn new(config: &RedisCache, kind: &str) -> Result<Self, BoxError> {
+        let tls_config = match &config.tls {
+            Some(tls) => {
+                let store = tls
+                    .certificate_authorities
+                    .as_deref()
+                    .map(create_certificate_store)
+                    .transpose()?;
+                Some(Arc::new(generate_tls_client_config(store, None)?))
+            }
+            None => None,
         };
+        if tls_config.is_some() && config.urls.len() > 1 {
+            return Err("the TLS configuration is only supported with a single Redis URL".into());
+        }
+
+        let connection_infos = config
+            .urls
+            .iter()
+            .map(|url| {
+                let mut info = url.as_str().into_connection_info()?;
+                if config.username.is_some() {
+                    info.redis.username = config.username.clone();
+                }
+                if config.password.is_some() {
+                    info.redis.password = config.password.clone();
+                }
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>, redis::RedisError>>()?;
+
+        let pool_size = config.pool_size.map(NonZeroUsize::get).unwrap_or(1);
+        let pool = Arc::new(Pool {
+            kind: kind.to_string(),
+            connection_infos,
+            tls_config,
+            connect_timeout: config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            connections: (0..pool_size).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            reconnecting: AtomicBool::new(true),
+        });
+        // reported by the health check of the router
+        if let Some(registry) = CacheRegistry::current() {
+            registry.register_pool(Arc::downgrade(&pool));
+        }
 
-        tracing::trace!("redis connection established");
+        if let Err(e) = pool.connect_missing().await {
+            tracing::error!(
+                "could not connect to Redis for {} caching, retrying in the background: {}",
+                kind,
+                e
+            );
+            tokio::spawn(reconnect(Arc::downgrade(&pool)));
+        } else {
+            pool.reconnecting.store(false, Ordering::SeqCst);
+            tracing::trace!("redis connection established");
+        }
+
+        let kind = kind.to_ascii_lowercase().replace(' ', "_");
+        let prefix = match &config.namespace {
+            Some(namespace) => format!("{namespace}:{kind}:"),
+            None => format!("{kind}:"),
+        };
         Ok(Self {
-            inner: Arc::new(Mutex::new(connection)),
-            ttl,
+            inner: pool,
+            ttl: config.ttl,
+            namespace: config.namespace.clone().map(Arc::new),
+            prefix: Arc::new(prefix),
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
         })
     }
 
@@ -138,67 +276,56 @@ impl RedisCacheStorage {
         self.ttl = ttl;
     }
 
+    /// The key in Redis, prefixed with the namespace and the kind of the cache
+    fn make_key<K: KeyType>(&self, key: &RedisKey<K>) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    /// The pattern matching the keys of the cache starting with `prefix`
+    fn prefix_pattern(&self, prefix: &str) -> String {
+        format!(
+            "{}*",
+            escape_glob(&self.make_key(&RedisKey(prefix.to_string())))
+        )
+    }
+
     pub(crate) async fn get<K: KeyType, V: ValueType>(
         &self,
         key: RedisKey<K>,
     ) -> Option<RedisValue<V>> {
         tracing::trace!("getting from redis: {:?}", key);
-        let mut guard = self.inner.lock().await;
-
-        match &mut *guard {
-            RedisConnection::Single(conn) => conn.get(key).await.ok(),
-            RedisConnection::Cluster(conn) => conn.get(key).await.ok(),
-        }
+        let key = self.make_key(&key);
+        with_connection!(self, conn => conn.get::<_, RedisValue<V>>(&key))
+            .map_err(|e| {
+                tracing::debug!("get error: {}", e);
+                e
+            })
+            .ok()
     }
 
-    #[allow(dead_code)]
     pub(crate) async fn get_multiple<K: KeyType, V: ValueType>(
         &self,
         keys: Vec<RedisKey<K>>,
     ) -> Option<Vec<Option<RedisValue<V>>>> {
         tracing::trace!("getting multiple values from redis: {:?}", keys);
-        let mut guard = self.inner.lock().await;
-
-        let res = if keys.len() == 1 {
-            let res = match &mut *guard {
-                RedisConnection::Single(conn) => conn
-                    .get(keys.first().unwrap())
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("mget error: {}", e);
-                        e
-                    })
-                    .ok(),
-                RedisConnection::Cluster(conn) => conn
-                    .get(keys.first().unwrap())
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("mget error: {}", e);
-                        e
-                    })
-                    .ok(),
-            };
+        let redis_keys: Vec<String> = keys.iter().map(|key| self.make_key(key)).collect();
+
+        let res = if redis_keys.len() == 1 {
+            let res = with_connection!(self, conn => conn.get::<_, RedisValue<V>>(&redis_keys[0]))
+                .map_err(|e| {
+                    tracing::error!("mget error: {}", e);
+                    e
+                })
+                .ok();
 
             Some(vec![res])
         } else {
-            match &mut *guard {
-                RedisConnection::Single(conn) => conn
-                    .get::<Vec<RedisKey<K>>, Vec<Option<RedisValue<V>>>>(keys.clone())
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("mget error: {}", e);
-                        e
-                    })
-                    .ok(),
-                RedisConnection::Cluster(conn) => conn
-                    .get::<Vec<RedisKey<K>>, Vec<Option<RedisValue<V>>>>(keys.clone())
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("mget error: {}", e);
-                        e
-                    })
-                    .ok(),
-            }
+            with_connection!(self, conn => conn.get::<_, Vec<Option<RedisValue<V>>>>(&redis_keys))
+                .map_err(|e| {
+                    tracing::error!("mget error: {}", e);
+                    e
+                })
+                .ok()
         };
         tracing::trace!("result for '{:?}': {:?}", keys, res);
 
@@ -210,65 +337,533 @@ impl RedisCacheStorage {
         key: RedisKey<K>,
         value: RedisValue<V>,
     ) {
-        tracing::trace!("inserting into redis: {:?}, {:?}", key, value);
-        let mut guard = self.inner.lock().await;
-        let r = match &mut *guard {
-            RedisConnection::Single(conn) => {
-                conn.set::<RedisKey<K>, RedisValue<V>, redis::Value>(key, value)
-                    .await
+        match self.ttl {
+            Some(ttl) => self.insert_with_ttl(key, value, ttl).await,
+            None => {
+                tracing::trace!("inserting into redis: {:?}, {:?}", key, value);
+                let key = self.make_key(&key);
+                let r =
+                    with_connection!(self, conn => conn.set::<_, _, redis::Value>(&key, &value));
+                tracing::trace!("insert result {:?}", r);
             }
-            RedisConnection::Cluster(conn) => {
-                conn.set::<RedisKey<K>, RedisValue<V>, redis::Value>(key, value)
-                    .await
-            }
-        };
+        }
+    }
+
+    pub(crate) async fn insert_with_ttl<K: KeyType, V: ValueType>(
+        &self,
+        key: RedisKey<K>,
+        value: RedisValue<V>,
+        ttl: Duration,
+    ) {
+        tracing::trace!(
+            "inserting into redis: {:?}, {:?}, ttl: {:?}",
+            key,
+            value,
+            ttl
+        );
+        let expiration = self.expiration(ttl);
+        let key = self.make_key(&key);
+        let r = with_connection!(
+            self,
+            conn => conn.set_ex::<_, _, redis::Value>(&key, &value, expiration)
+        );
+        tracing::trace!("insert result {:?}", r);
+    }
+
+    /// Expiration in seconds of an entry inserted with a `ttl`: the TTL of the configuration is
+    /// the maximum lifetime of the entries
+    fn expiration(&self, ttl: Duration) -> usize {
+        let ttl = self.ttl.map(|max| max.min(ttl)).unwrap_or(ttl);
+        ttl.as_secs().max(1) as usize
+    }
+
+    /// Inserts entries expiring after `ttl`, capped by the TTL of the configuration
+    pub(crate) async fn insert_multiple_with_ttl<K: KeyType, V: ValueType>(
+        &self,
+        data: &[(RedisKey<K>, RedisValue<V>)],
+        ttl: Duration,
+    ) {
+        tracing::trace!("inserting into redis: {:#?}, ttl: {:?}", data, ttl);
+        let expiration = self.expiration(ttl);
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        for (key, value) in data {
+            pipeline.set_ex(self.make_key(key), value, expiration);
+        }
+
+        let r = with_connection!(self, conn => pipeline.query_async::<_, redis::Value>(conn));
         tracing::trace!("insert result {:?}", r);
     }
 
+    /// Increments a counter that expires `ttl` after its creation.
+    ///
+    /// Returns the new value of the counter, and the time left before it expires.
+    pub(crate) async fn increment(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<(u64, Duration), redis::RedisError> {
+        tracing::trace!("incrementing in redis: {:?}", key);
+        let key = self.make_key(&RedisKey(key.to_string()));
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg(0)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .arg("NX")
+            .ignore()
+            .incr(&key, 1u64)
+            .cmd("PTTL")
+            .arg(&key);
+
+        let (count, time_left): (u64, i64) =
+            with_connection!(self, conn => pipeline.query_async::<_, (u64, i64)>(conn))?;
+        Ok((count, Duration::from_millis(time_left.max(0) as u64)))
+    }
+
     #[allow(dead_code)]
     pub(crate) async fn insert_multiple<K: KeyType, V: ValueType>(
         &self,
         data: &[(RedisKey<K>, RedisValue<V>)],
     ) {
         tracing::trace!("inserting into redis: {:#?}", data);
+        let data: Vec<(String, &RedisValue<V>)> = data
+            .iter()
+            .map(|(key, value)| (self.make_key(key), value))
+            .collect();
 
-        if let Some(ttl) = self.ttl.as_ref() {
+        let r = if let Some(ttl) = self.ttl.as_ref() {
             let expiration: usize = ttl.as_secs().try_into().unwrap();
             let mut pipeline = redis::pipe();
             pipeline.atomic();
 
-            for (key, value) in data {
+            for (key, value) in &data {
                 pipeline.set_ex(key, value, expiration);
             }
 
-            let mut guard = self.inner.lock().await;
+            with_connection!(self, conn => pipeline.query_async::<_, redis::Value>(conn))
+        } else {
+            with_connection!(self, conn => conn.set_multiple::<_, _, redis::Value>(&data))
+        };
+        tracing::trace!("insert result {:?}", r);
+    }
 
-            let r = match &mut *guard {
-                RedisConnection::Single(conn) => {
-                    pipeline
-                        .query_async::<redis::aio::Connection, redis::Value>(conn)
-                        .await
-                }
-                RedisConnection::Cluster(conn) => {
-                    pipeline.query_async::<Connection, redis::Value>(conn).await
-                }
-            };
+    /// Deletes entries, returning the number of keys that were removed
+    pub(crate) async fn delete(&self, keys: &[&str]) -> RedisResult<usize> {
+        tracing::trace!("deleting from redis: {:?}", keys);
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| self.make_key(&RedisKey(key.to_string())))
+            .collect();
+        with_connection!(self, conn => conn.del::<_, usize>(&keys))
+    }
 
-            tracing::trace!("insert result {:?}", r);
-        } else {
-            let mut guard = self.inner.lock().await;
+    /// Deletes the entries of the cache with a key starting with `prefix`, returning the number of
+    /// keys that were removed.
+    ///
+    /// Without a namespace, all the entries of the cache cannot be removed, as other applications
+    /// may use keys with the same prefix.
+    pub(crate) async fn delete_prefix(&self, prefix: &str) -> RedisResult<usize> {
+        if self.inner.connection_infos.len() > 1 {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "invalidation by prefix is not supported with a Redis cluster",
+            )));
+        }
+        if self.namespace.is_none() && prefix.is_empty() {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "a namespace must be configured to invalidate all the entries in Redis",
+            )));
+        }
 
-            let r = match &mut *guard {
-                RedisConnection::Single(conn) => {
-                    conn.set_multiple::<RedisKey<K>, RedisValue<V>, redis::Value>(data)
-                        .await
-                }
-                RedisConnection::Cluster(conn) => {
-                    conn.set_multiple::<RedisKey<K>, RedisValue<V>, redis::Value>(data)
-                        .await
+        let pattern = self.prefix_pattern(prefix);
+        tracing::trace!("deleting from redis keys matching: {:?}", pattern);
+        let mut cursor = 0u64;
+        let mut deleted = 0;
+        loop {
+            let mut scan = redis::cmd("SCAN");
+            scan.arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(1000);
+            let (next, keys): (u64, Vec<String>) =
+                with_connection!(self, conn => scan.query_async::<_, (u64, Vec<String>)>(conn))?;
+            if !keys.is_empty() {
+                deleted += with_connection!(self, conn => conn.del::<_, usize>(&keys))?;
+            }
+            if next == 0 {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+}
+
+/// Escapes the special characters of a Redis glob pattern
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Pool {
+    /// Name of the cache using the connections
+    pub(crate) fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connections
+            .iter()
+            .any(|connection| connection.lock().expect("lock poisoned").is_some())
+    }
+
+    /// The next established connection of the pool, and its index
+    fn connection(&self) -> Option<(usize, RedisConnection)> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.connections.len()).find_map(|offset| {
+            let index = (start + offset) % self.connections.len();
+            self.connections[index]
+                .lock()
+                .expect("lock poisoned")
+                .clone()
+                .map(|connection| (index, connection))
+        })
+    }
+
+    /// Drops a broken connection, and re-establishes it in the background
+    fn disconnect(self: &Arc<Self>, index: usize, error: &redis::RedisError) {
+        let was_connected = self.connections[index]
+            .lock()
+            .expect("lock poisoned")
+            .take()
+            .is_some();
+        if was_connected {
+            tracing::error!(
+                "lost the connection to Redis for {} caching: {}",
+                self.kind,
+                error
+            );
+            tracing::info!(
+                counter.apollo_router_cache_redis_connections = -1i64,
+                kind = %self.kind,
+            );
+            tracing::info!(
+                monotonic_counter.apollo_router_cache_redis_connection_lost_count = 1u64,
+                kind = %self.kind,
+            );
+        }
+        if !self.reconnecting.swap(true, Ordering::SeqCst) {
+            tokio::spawn(reconnect(Arc::downgrade(self)));
+        }
+    }
+
+    /// Establishes the missing connections of the pool
+    async fn connect_missing(&self) -> Result<(), BoxError> {
+        for slot in &self.connections {
+            if slot.lock().expect("lock poisoned").is_some() {
+                continue;
+            }
+            let connection = tokio::time::timeout(
+                self.connect_timeout,
+                connect(self.connection_infos.clone(), self.tls_config.clone()),
+            )
+            .await
+            .map_err(|_| "timed out connecting to Redis")??;
+            *slot.lock().expect("lock poisoned") = Some(connection);
+            tracing::info!(
+                counter.apollo_router_cache_redis_connections = 1i64,
+                kind = %self.kind,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Re-establishes the connections of a pool, with an exponential backoff between attempts.
+///
+/// Stops when the pool is dropped, e.g. after a configuration reload.
+async fn reconnect(pool: Weak<Pool>) {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+    loop {
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        match pool.connect_missing().await {
+            Ok(()) => {
+                tracing::info!("reconnected to Redis for {} caching", pool.kind);
+                pool.reconnecting.store(false, Ordering::SeqCst);
+                // a connection may have been lost since it was established
+                if pool
+                    .connections
+                    .iter()
+                    .all(|connection| connection.lock().expect("lock poisoned").is_some())
+                    || pool.reconnecting.swap(true, Ordering::SeqCst)
+                {
+                    return;
                 }
-            };
-            tracing::trace!("insert result {:?}", r);
+                backoff = MIN_RECONNECT_BACKOFF;
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "could not reconnect to Redis for {} caching: {}",
+                    pool.kind,
+                    e
+                );
+                drop(pool);
+                // the delay is between half and all of the backoff
+                let half = backoff / 2;
+                let delay = half + half.mul_f64(rand::thread_rng().gen::<f64>());
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Opens a connection to a single Redis instance, or to a Redis cluster if there are several
+/// URLs
+async fn connect(
+    mut connection_infos: Vec<ConnectionInfo>,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+) -> Result<RedisConnection, BoxError> {
+    if connection_infos.len() != 1 {
+        let client = Client::open(connection_infos)?;
+        return Ok(RedisConnection::Cluster(client.get_connection().await?));
+    }
+
+    let info = connection_infos
+        .pop()
+        .expect("connection_infos contains only one element; qed");
+    match (tls_config, &info.addr) {
+        (
+            Some(tls_config),
+            ConnectionAddr::Tcp(host, port) | ConnectionAddr::TcpTls { host, port, .. },
+        ) => {
+            let stream = TcpStream::connect((host.as_str(), *port)).await?;
+            let server_name = rustls::ServerName::try_from(host.as_str())?;
+            let stream = TlsConnector::from(tls_config)
+                .connect(server_name, stream)
+                .await?;
+            let (connection, driver) = MultiplexedConnection::new(&info.redis, stream).await?;
+            tokio::spawn(driver);
+            Ok(RedisConnection::Single(connection))
+        }
+        (Some(_), _) => Err("TLS is only supported for TCP connections to Redis".into()),
+        (None, _) => {
+            let client = redis::Client::open(info)?;
+            Ok(RedisConnection::Single(
+                client.get_multiplexed_tokio_connection().await?,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn unreachable_storage(config: serde_json::Value) -> RedisCacheStorage {
+        let mut config: RedisCache = serde_json::from_value(config).unwrap();
+        // nothing listens on this port
+        config.urls = vec!["redis://127.0.0.1:1".to_string()];
+        config.connect_timeout = Some(Duration::from_millis(100));
+        RedisCacheStorage::new(&config, "unreachable")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_prefixes_the_keys_with_the_namespace_and_the_kind() {
+        let storage = unreachable_storage(serde_json::json!({ "urls": [] })).await;
+        assert_eq!(
+            storage.make_key(&RedisKey("plan:a".to_string())),
+            "unreachable:plan:a"
+        );
+
+        let storage =
+            unreachable_storage(serde_json::json!({ "urls": [], "namespace": "router" })).await;
+        assert_eq!(
+            storage.make_key(&RedisKey("plan:a".to_string())),
+            "router:unreachable:plan:a"
+        );
+    }
+
+    #[test]
+    fn it_escapes_glob_patterns() {
+        assert_eq!(escape_glob("plan:a"), "plan:a");
+        assert_eq!(escape_glob(r"a*b?c[d]e\f"), r"a\*b\?c\[d\]e\\f");
+    }
+
+    #[tokio::test]
+    async fn it_only_deletes_the_keys_of_the_cache() {
+        let config = serde_json::json!({ "urls": [], "namespace": "router" });
+        let mut config: RedisCache = serde_json::from_value(config).unwrap();
+        config.urls = vec!["redis://127.0.0.1:1".to_string()];
+        config.connect_timeout = Some(Duration::from_millis(100));
+        let storage = RedisCacheStorage::new(&config, "query planner")
+            .await
+            .unwrap();
+
+        // the caches sharing the namespace are not invalidated with all the entries
+        assert_eq!(storage.prefix_pattern(""), "router:query_planner:*");
+        assert_eq!(
+            storage.prefix_pattern("plan:[1]"),
+            r"router:query_planner:plan:\[1\]*"
+        );
+
+        // Redis is not reachable
+        assert!(storage.delete(&["plan:a"]).await.is_err());
+        assert!(storage.delete_prefix("plan:").await.is_err());
+
+        // all the entries cannot be deleted without a namespace
+        config.namespace = None;
+        let storage = RedisCacheStorage::new(&config, "query planner")
+            .await
+            .unwrap();
+        assert!(storage
+            .delete_prefix("")
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("a namespace must be configured"));
+    }
+
+    #[tokio::test]
+    async fn it_caps_the_ttl_of_the_entries() {
+        let storage = unreachable_storage(serde_json::json!({ "urls": [] })).await;
+        assert_eq!(storage.expiration(Duration::from_secs(120)), 120);
+        // entries expire after at least one second
+        assert_eq!(storage.expiration(Duration::from_millis(10)), 1);
+
+        let storage = unreachable_storage(serde_json::json!({ "urls": [], "ttl": "60s" })).await;
+        assert_eq!(storage.expiration(Duration::from_secs(120)), 60);
+        assert_eq!(storage.expiration(Duration::from_secs(30)), 30);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_support_tls_with_several_urls() {
+        let config: RedisCache = serde_json::from_value(serde_json::json!({
+            "urls": ["redis://127.0.0.1:7000", "redis://127.0.0.1:7001"],
+            "tls": {}
+        }))
+        .unwrap();
+        let error = RedisCacheStorage::new(&config, "cluster")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "the TLS configuration is only supported with a single Redis URL"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_fast_while_redis_is_unreachable() {
+        let config: RedisCache = serde_json::from_value(serde_json::json!({
+            // nothing listens on this port
+            "urls": ["redis://127.0.0.1:1"],
+            "connect_timeout": "100ms",
+            "pool_size": 2
+        }))
+        .unwrap();
+        let registry = CacheRegistry::default();
+        let storage = registry
+            .clone()
+            .scope(RedisCacheStorage::new(&config, "unreachable"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.redis_connection_states(),
+            vec![("unreachable".to_string(), false)]
+        );
+
+        let start = Instant::now();
+        let value: Option<RedisValue<String>> = storage.get(RedisKey("key".to_string())).await;
+        assert!(value.is_none());
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+    }
+
+    /// Accepts connections until the task is aborted. Connecting to Redis without a password or a
+    /// database does not send any command, so this is enough to stand in for Redis
+    fn serve(listener: TcpListener) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        })
+    }
+
+    async fn wait_for_connection_state(
+        registry: &CacheRegistry,
+        storage: &RedisCacheStorage,
+        connected: bool,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            // a lost connection is noticed by the next command
+            let _: Option<RedisValue<String>> = storage.get(RedisKey("key".to_string())).await;
+            if registry.redis_connection_states() == vec![("reconnection".to_string(), connected)] {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the connection state did not become {connected}"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
     }
+
+    #[tokio::test]
+    async fn it_reconnects_with_backoff_when_redis_comes_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config: RedisCache = serde_json::from_value(serde_json::json!({
+            "urls": [format!("redis://{address}")],
+            "connect_timeout": "100ms",
+            "timeout": "100ms"
+        }))
+        .unwrap();
+        let registry = CacheRegistry::default();
+        let storage = registry
+            .clone()
+            .scope(RedisCacheStorage::new(&config, "reconnection"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.redis_connection_states(),
+            vec![("reconnection".to_string(), false)]
+        );
+
+        // Redis starts after the router
+        let server = serve(TcpListener::bind(address).await.unwrap());
+        wait_for_connection_state(&registry, &storage, true).await;
+
+        // Redis goes down, closing the connections
+        server.abort();
+        let _ = server.await;
+        wait_for_connection_state(&registry, &storage, false).await;
+
+        // and comes back
+        let _server = serve(TcpListener::bind(address).await.unwrap());
+        wait_for_connection_state(&registry, &storage, true).await;
+    }
 }
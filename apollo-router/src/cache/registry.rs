@@ -0,0 +1,66 @@
+//! The caches and Redis connections of a router.
+//!
+//! They are registered while the router is created, within [`CacheRegistry::scope`], and the
+//! registry is kept by the router factory, so that the health check and the cache administration
+//! endpoint of a router only see its own caches.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+use super::invalidation::Invalidate;
+use super::redis::Pool;
+
+tokio::task_local! {
+    /// The registry of the router being created
+    static CURRENT: CacheRegistry;
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct CacheRegistry {
+    caches: Arc<Mutex<Vec<Weak<dyn Invalidate>>>>,
+    pools: Arc<Mutex<Vec<Weak<Pool>>>>,
+}
+
+impl CacheRegistry {
+    /// Runs the creation of a router, registering its caches in this registry
+    pub(crate) async fn scope<F: Future>(self, creation: F) -> F::Output {
+        CURRENT.scope(self, creation).await
+    }
+
+    /// The registry of the router being created, if any
+    pub(crate) fn current() -> Option<CacheRegistry> {
+        CURRENT.try_with(CacheRegistry::clone).ok()
+    }
+
+    pub(crate) fn register_cache(&self, cache: Weak<dyn Invalidate>) {
+        let mut caches = self.caches.lock().expect("lock poisoned");
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(cache);
+    }
+
+    /// The caches that are still in use
+    pub(crate) fn caches(&self) -> Vec<Arc<dyn Invalidate>> {
+        let mut caches = self.caches.lock().expect("lock poisoned");
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    pub(crate) fn register_pool(&self, pool: Weak<Pool>) {
+        let mut pools = self.pools.lock().expect("lock poisoned");
+        pools.retain(|pool| pool.strong_count() > 0);
+        pools.push(pool);
+    }
+
+    /// Whether each cache using Redis is connected to it
+    pub(crate) fn redis_connection_states(&self) -> Vec<(String, bool)> {
+        let mut pools = self.pools.lock().expect("lock poisoned");
+        pools.retain(|pool| pool.strong_count() > 0);
+        pools
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|pool| (pool.kind().to_string(), pool.is_connected()))
+            .collect()
+    }
+}
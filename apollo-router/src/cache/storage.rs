@@ -5,6 +5,8 @@ use std::fmt::{self};
 use std::hash::Hash;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
 
 use lru::LruCache;
 use serde::de::DeserializeOwned;
@@ -12,7 +14,12 @@ use serde::Serialize;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
 
+use super::invalidation::Invalidate;
+use super::invalidation::Invalidated;
+use super::invalidation::Invalidation;
 use super::redis::*;
+use super::registry::CacheRegistry;
+use crate::configuration::RedisCache;
 
 pub(crate) trait KeyType:
     Clone + fmt::Debug + fmt::Display + Hash + Eq + Send + Sync
@@ -47,8 +54,13 @@ where
 // a suitable implementation.
 #[derive(Clone)]
 pub(crate) struct CacheStorage<K: KeyType, V: ValueType> {
+    inner: Arc<Tiers<K, V>>,
+}
+
+/// The in memory and Redis tiers of a cache
+struct Tiers<K: KeyType, V: ValueType> {
     caller: String,
-    inner: Arc<Mutex<LruCache<K, V>>>,
+    memory: Mutex<LruCache<K, V>>,
     redis: Option<RedisCacheStorage>,
 }
 
@@ -59,17 +71,17 @@ where
 {
     pub(crate) async fn new(
         max_capacity: NonZeroUsize,
-        _redis_urls: Option<Vec<String>>,
+        redis: Option<&RedisCache>,
         caller: &str,
     ) -> Self {
-        Self {
+        let tiers = Arc::new(Tiers {
             caller: caller.to_string(),
-            inner: Arc::new(Mutex::new(LruCache::new(max_capacity))),
-            redis: if let Some(urls) = _redis_urls {
-                match RedisCacheStorage::new(urls, None).await {
+            memory: Mutex::new(LruCache::new(max_capacity)),
+            redis: if let Some(config) = redis {
+                match RedisCacheStorage::new(config, caller).await {
                     Err(e) => {
                         tracing::error!(
-                            "could not open connection to Redis for {} caching: {:?}",
+                            "invalid Redis configuration for {} caching, only the in memory cache will be used: {:?}",
                             caller,
                             e
                         );
@@ -80,55 +92,64 @@ where
             } else {
                 None
             },
+        });
+        // listed and invalidated by the cache administration endpoint of the router
+        if let Some(registry) = CacheRegistry::current() {
+            registry.register_cache(Arc::downgrade(&tiers) as Weak<dyn Invalidate>);
         }
+        Self { inner: tiers }
     }
 
     pub(crate) async fn get(&self, key: &K) -> Option<V> {
-        let mut guard = self.inner.lock().await;
+        let mut guard = self.inner.memory.lock().await;
         let instant_memory = Instant::now();
-        match guard.get(key) {
+        match guard.get(key).cloned() {
             Some(v) => {
                 tracing::info!(
                     monotonic_counter.apollo_router_cache_hit_count = 1u64,
-                    kind = %self.caller,
+                    kind = %self.inner.caller,
                     storage = &tracing::field::display(CacheStorageName::Memory),
                 );
                 let duration = instant_memory.elapsed().as_secs_f64();
                 tracing::info!(
                     histogram.apollo_router_cache_hit_time = duration,
-                    kind = %self.caller,
+                    kind = %self.inner.caller,
                     storage = &tracing::field::display(CacheStorageName::Memory),
                 );
-                Some(v.clone())
+                Some(v)
             }
             None => {
                 let duration = instant_memory.elapsed().as_secs_f64();
                 tracing::info!(
                     histogram.apollo_router_cache_miss_time = duration,
-                    kind = %self.caller,
+                    kind = %self.inner.caller,
                     storage = &tracing::field::display(CacheStorageName::Memory),
                 );
                 tracing::info!(
                     monotonic_counter.apollo_router_cache_miss_count = 1u64,
-                    kind = %self.caller,
+                    kind = %self.inner.caller,
                     storage = &tracing::field::display(CacheStorageName::Memory),
                 );
 
+                // other lookups can go on while waiting for Redis
+                drop(guard);
+
                 let instant_redis = Instant::now();
-                if let Some(redis) = self.redis.as_ref() {
+                if let Some(redis) = self.inner.redis.as_ref() {
                     let inner_key = RedisKey(key.clone());
+                    // if Redis is not available, the lookup is a miss
                     match redis.get::<K, V>(inner_key).await {
                         Some(v) => {
-                            guard.put(key.clone(), v.0.clone());
+                            self.inner.memory.lock().await.put(key.clone(), v.0.clone());
                             tracing::info!(
                                 monotonic_counter.apollo_router_cache_hit_count = 1u64,
-                                kind = %self.caller,
+                                kind = %self.inner.caller,
                                 storage = &tracing::field::display(CacheStorageName::Redis),
                             );
                             let duration = instant_redis.elapsed().as_secs_f64();
                             tracing::info!(
                                 histogram.apollo_router_cache_hit_time = duration,
-                                kind = %self.caller,
+                                kind = %self.inner.caller,
                                 storage = &tracing::field::display(CacheStorageName::Redis),
                             );
                             Some(v.0)
@@ -136,13 +157,13 @@ where
                         None => {
                             tracing::info!(
                                 monotonic_counter.apollo_router_cache_miss_count = 1u64,
-                                kind = %self.caller,
+                                kind = %self.inner.caller,
                                 storage = &tracing::field::display(CacheStorageName::Redis),
                             );
                             let duration = instant_redis.elapsed().as_secs_f64();
                             tracing::info!(
                                 histogram.apollo_router_cache_miss_time = duration,
-                                kind = %self.caller,
+                                kind = %self.inner.caller,
                                 storage = &tracing::field::display(CacheStorageName::Redis),
                             );
                             None
@@ -155,18 +176,112 @@ where
         }
     }
 
+    /// Looks for several values at once: the values missing from memory are requested from Redis
+    /// in a single round trip
+    pub(crate) async fn get_multiple(&self, keys: &[K]) -> Vec<Option<V>> {
+        let mut values: Vec<Option<V>> = {
+            let mut guard = self.inner.memory.lock().await;
+            keys.iter().map(|key| guard.get(key).cloned()).collect()
+        };
+        let missing: Vec<usize> = values
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| value.is_none().then_some(index))
+            .collect();
+        self.record_lookups(
+            CacheStorageName::Memory,
+            keys.len() - missing.len(),
+            missing.len(),
+        );
+
+        let redis = match self.inner.redis.as_ref() {
+            Some(redis) if !missing.is_empty() => redis,
+            _ => return values,
+        };
+        let redis_keys = missing
+            .iter()
+            .map(|index| RedisKey(keys[*index].clone()))
+            .collect();
+        // if Redis is not available, the lookups are misses
+        let found = redis
+            .get_multiple::<K, V>(redis_keys)
+            .await
+            .unwrap_or_default();
+        let mut hits = 0;
+        let mut memory = self.inner.memory.lock().await;
+        for (index, value) in missing.iter().zip(found) {
+            if let Some(RedisValue(value)) = value {
+                memory.put(keys[*index].clone(), value.clone());
+                values[*index] = Some(value);
+                hits += 1;
+            }
+        }
+        self.record_lookups(CacheStorageName::Redis, hits, missing.len() - hits);
+        values
+    }
+
+    fn record_lookups(&self, storage: CacheStorageName, hits: usize, misses: usize) {
+        if hits > 0 {
+            tracing::info!(
+                monotonic_counter.apollo_router_cache_hit_count = hits as u64,
+                kind = %self.inner.caller,
+                storage = &tracing::field::display(&storage),
+            );
+        }
+        if misses > 0 {
+            tracing::info!(
+                monotonic_counter.apollo_router_cache_miss_count = misses as u64,
+                kind = %self.inner.caller,
+                storage = &tracing::field::display(&storage),
+            );
+        }
+    }
+
     pub(crate) async fn insert(&self, key: K, value: V) {
-        if let Some(redis) = self.redis.as_ref() {
+        if let Some(redis) = self.inner.redis.as_ref() {
             redis
                 .insert(RedisKey(key.clone()), RedisValue(value.clone()))
                 .await;
         }
 
-        self.inner.lock().await.put(key, value);
+        self.inner.memory.lock().await.put(key, value);
+    }
+
+    /// Inserts a value that expires from Redis after `ttl`.
+    ///
+    /// The in memory cache does not expire entries, so values must carry their own expiration.
+    pub(crate) async fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        if let Some(redis) = self.inner.redis.as_ref() {
+            redis
+                .insert_with_ttl(RedisKey(key.clone()), RedisValue(value.clone()), ttl)
+                .await;
+        }
+
+        self.inner.memory.lock().await.put(key, value);
+    }
+
+    /// Inserts values that expire from Redis after `ttl`, in a single round trip
+    pub(crate) async fn insert_multiple_with_ttl(&self, values: Vec<(K, V)>, ttl: Duration) {
+        if values.is_empty() {
+            return;
+        }
+        if let Some(redis) = self.inner.redis.as_ref() {
+            let data: Vec<_> = values
+                .iter()
+                .map(|(key, value)| (RedisKey(key.clone()), RedisValue(value.clone())))
+                .collect();
+            redis.insert_multiple_with_ttl(&data, ttl).await;
+        }
+
+        let mut memory = self.inner.memory.lock().await;
+        for (key, value) in values {
+            memory.put(key, value);
+        }
     }
 
     pub(crate) async fn in_memory_keys(&self) -> Vec<K> {
         self.inner
+            .memory
             .lock()
             .await
             .iter()
@@ -176,7 +291,68 @@ where
 
     #[cfg(test)]
     pub(crate) async fn len(&self) -> usize {
-        self.inner.lock().await.len()
+        self.inner.memory.lock().await.len()
+    }
+}
+
+#[async_trait::async_trait]
+impl<K, V> Invalidate for Tiers<K, V>
+where
+    K: KeyType,
+    V: ValueType,
+{
+    fn kind(&self) -> &str {
+        &self.caller
+    }
+
+    async fn len(&self) -> usize {
+        self.memory.lock().await.len()
+    }
+
+    fn has_redis(&self) -> bool {
+        self.redis.is_some()
+    }
+
+    async fn invalidate(&self, invalidation: &Invalidation) -> Invalidated {
+        let mut invalidated = Invalidated {
+            kind: self.caller.clone(),
+            ..Default::default()
+        };
+
+        {
+            let mut memory = self.memory.lock().await;
+            match invalidation {
+                Invalidation::All => {
+                    invalidated.memory = memory.len();
+                    memory.clear();
+                }
+                _ => {
+                    let keys: Vec<K> = memory
+                        .iter()
+                        .map(|(key, _)| key)
+                        .filter(|key| invalidation.matches(&key.to_string()))
+                        .cloned()
+                        .collect();
+                    for key in &keys {
+                        memory.pop(key);
+                    }
+                    invalidated.memory = keys.len();
+                }
+            }
+        }
+
+        if let Some(redis) = &self.redis {
+            let result = match invalidation {
+                Invalidation::Key(key) => redis.delete(&[key.as_str()]).await,
+                Invalidation::Prefix(prefix) => redis.delete_prefix(prefix).await,
+                Invalidation::All => redis.delete_prefix("").await,
+            };
+            match result {
+                Ok(count) => invalidated.redis = Some(count),
+                Err(e) => invalidated.error = Some(e.to_string()),
+            }
+        }
+        invalidated
     }
 }
 
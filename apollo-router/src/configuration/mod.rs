@@ -14,7 +14,9 @@ use std::fmt;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use derivative::Derivative;
 use displaydoc::Display;
@@ -40,6 +42,7 @@ pub(crate) use self::schema::generate_upgrade;
 use crate::cache::DEFAULT_CACHE_CAPACITY;
 use crate::configuration::schema::Mode;
 use crate::plugin::plugins;
+use crate::plugins::subscription::APOLLO_SUBSCRIPTION_PLUGIN_NAME;
 
 static SUPERGRAPH_ENDPOINT_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"(?P<first_path>.*/)(?P<sub_path>.+)\*$")
@@ -77,6 +80,9 @@ pub enum ConfigurationError {
 
     /// could not load certificate authorities: {error}
     CertificateAuthorities { error: String },
+
+    /// could not load client certificate: {error}
+    ClientCertificate { error: String },
 }
 
 /// The configuration for the router.
@@ -118,6 +124,22 @@ pub struct Configuration {
     #[serde(default)]
     pub(crate) tls: Tls,
 
+    /// Persisted queries and safelisting configuration
+    #[serde(default)]
+    pub(crate) persisted_queries: PersistedQueries,
+
+    /// Limits on the shape of operations
+    #[serde(default)]
+    pub(crate) limits: OperationLimits,
+
+    /// Batching of operations in HTTP requests
+    #[serde(default)]
+    pub(crate) batching: Batching,
+
+    /// Authorization directives configuration
+    #[serde(default)]
+    pub(crate) authorization: Authorization,
+
     /// Plugin configuration
     #[serde(default)]
     plugins: UserPlugins,
@@ -148,6 +170,10 @@ impl<'de> serde::Deserialize<'de> for Configuration {
             #[serde(flatten)]
             apollo_plugins: ApolloPlugins,
             tls: Tls,
+            persisted_queries: PersistedQueries,
+            limits: OperationLimits,
+            batching: Batching,
+            authorization: Authorization,
         }
         let ad_hoc: AdHocConfiguration = serde::Deserialize::deserialize(deserializer)?;
 
@@ -161,6 +187,10 @@ impl<'de> serde::Deserialize<'de> for Configuration {
             .plugins(ad_hoc.plugins.plugins.unwrap_or_default())
             .apollo_plugins(ad_hoc.apollo_plugins.plugins)
             .tls(ad_hoc.tls)
+            .persisted_queries(ad_hoc.persisted_queries)
+            .limits(ad_hoc.limits)
+            .batching(ad_hoc.batching)
+            .authorization(ad_hoc.authorization)
             .build()
             .map_err(|e| serde::de::Error::custom(e.to_string()))
     }
@@ -192,6 +222,10 @@ impl Configuration {
         plugins: Map<String, Value>,
         apollo_plugins: Map<String, Value>,
         tls: Option<Tls>,
+        persisted_queries: Option<PersistedQueries>,
+        limits: Option<OperationLimits>,
+        batching: Option<Batching>,
+        authorization: Option<Authorization>,
     ) -> Result<Self, ConfigurationError> {
         let conf = Self {
             validated_yaml: Default::default(),
@@ -208,6 +242,10 @@ impl Configuration {
                 plugins: apollo_plugins,
             },
             tls: tls.unwrap_or_default(),
+            persisted_queries: persisted_queries.unwrap_or_default(),
+            limits: limits.unwrap_or_default(),
+            batching: batching.unwrap_or_default(),
+            authorization: authorization.unwrap_or_default(),
         };
 
         conf.validate()
@@ -246,6 +284,20 @@ impl Configuration {
             Err("incompatible telemetry configuration. Telemetry cannot be reloaded and its configuration must stay the same for the entire life of the process")
         }
     }
+
+    /// Whether subscriptions are enabled through the `subscription` plugin
+    pub(crate) fn subscription_enabled(&self) -> bool {
+        self.apollo_plugins
+            .plugins
+            .get(APOLLO_SUBSCRIPTION_PLUGIN_NAME)
+            .map(|config| {
+                config
+                    .get("enabled")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true)
+            })
+            .unwrap_or(false)
+    }
 }
 
 impl Default for Configuration {
@@ -269,6 +321,10 @@ impl Configuration {
         plugins: Map<String, Value>,
         apollo_plugins: Map<String, Value>,
         tls: Option<Tls>,
+        persisted_queries: Option<PersistedQueries>,
+        limits: Option<OperationLimits>,
+        batching: Option<Batching>,
+        authorization: Option<Authorization>,
     ) -> Result<Self, ConfigurationError> {
         let configuration = Self {
             validated_yaml: Default::default(),
@@ -285,6 +341,10 @@ impl Configuration {
                 plugins: apollo_plugins,
             },
             tls: tls.unwrap_or_default(),
+            persisted_queries: persisted_queries.unwrap_or_default(),
+            limits: limits.unwrap_or_default(),
+            batching: batching.unwrap_or_default(),
+            authorization: authorization.unwrap_or_default(),
         };
 
         configuration.validate()
@@ -293,6 +353,20 @@ impl Configuration {
 
 impl Configuration {
     pub(crate) fn validate(self) -> Result<Self, ConfigurationError> {
+        // Persisted queries are resolved from a manifest
+        if self.persisted_queries.enabled && self.persisted_queries.manifest.is_none() {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "persisted queries require a manifest",
+                error: "set 'persisted_queries.manifest' to the path of the manifest file"
+                    .to_string(),
+            });
+        }
+        if self.limits.cost.max_cost.is_some() && !self.limits.cost.enabled {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "a maximum operation cost requires cost estimation",
+                error: "set 'limits.cost.enabled' to true".to_string(),
+            });
+        }
         // Sandbox and Homepage cannot be both enabled
         if self.sandbox.enabled && self.homepage.enabled {
             return Err(ConfigurationError::InvalidConfiguration {
@@ -582,6 +656,111 @@ impl Default for Apq {
     }
 }
 
+/// Persisted queries configuration
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct PersistedQueries {
+    /// Activates persisted queries (disabled by default)
+    pub(crate) enabled: bool,
+    /// Path to the persisted query manifest, a JSON file mapping operation IDs to operation bodies
+    pub(crate) manifest: Option<PathBuf>,
+    /// Reloads the manifest when the file changes
+    pub(crate) hot_reload: bool,
+    /// Logs the operations that are not in the manifest
+    pub(crate) log_unknown: bool,
+    /// Safelisting configuration
+    pub(crate) safelist: Safelist,
+}
+
+/// Safelisting configuration
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct Safelist {
+    /// Only allows the operations that are in the manifest (disabled by default)
+    pub(crate) enabled: bool,
+}
+
+/// Batching configuration
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct Batching {
+    /// Accepts HTTP requests containing a JSON array of operations (disabled by default)
+    pub(crate) enabled: bool,
+    /// Maximum number of operations in a batch, unlimited if not set
+    pub(crate) max_size: Option<usize>,
+}
+
+/// Authorization of the fields and types of the supergraph, from the `@authenticated` and
+/// `@requiresScopes` directives
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct Authorization {
+    /// Removes the fields and types the request is not authorized to access from operations (disabled by default)
+    pub(crate) enabled: bool,
+    /// Rejects operations requesting unauthorized fields or types, instead of removing them
+    pub(crate) reject_unauthorized: bool,
+}
+
+/// Limits on the shape of operations, evaluated after fragment expansion.
+///
+/// Limits are disabled if they are not set.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct OperationLimits {
+    /// Maximum depth of nested fields, root fields having a depth of 1
+    pub(crate) max_depth: Option<u32>,
+    /// Maximum number of fields in an operation
+    pub(crate) max_height: Option<u32>,
+    /// Maximum number of aliased fields in an operation
+    pub(crate) max_aliases: Option<u32>,
+    /// Maximum number of root fields in an operation
+    pub(crate) max_root_fields: Option<u32>,
+    /// Only logs a warning for the operations exceeding a limit, instead of rejecting them
+    pub(crate) warn_only: bool,
+    /// Static cost of operations
+    pub(crate) cost: OperationCost,
+}
+
+/// Static cost estimation of operations.
+///
+/// The estimated cost is stored in the request context, where it can be used by
+/// the rate limits of `traffic_shaping`.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct OperationCost {
+    /// Estimates the cost of operations (disabled by default)
+    pub(crate) enabled: bool,
+    /// Maximum estimated cost of an operation
+    pub(crate) max_cost: Option<u64>,
+    /// Weight of the fields that have no configured weight (defaults to 1)
+    pub(crate) default_field_weight: u64,
+    /// Weights of specific fields, keyed by `Type.field`
+    pub(crate) field_weights: HashMap<String, u64>,
+    /// Size of the list fields without a `first` or `limit` argument (defaults to 10)
+    pub(crate) default_list_size: u64,
+    /// Cost of each subgraph fetch of the query plan (defaults to 1)
+    pub(crate) fetch_cost: u64,
+}
+
+impl Default for OperationCost {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_cost: None,
+            default_field_weight: 1,
+            field_weights: HashMap::new(),
+            default_list_size: 10,
+            fetch_cost: 1,
+        }
+    }
+}
+
 /// Query planning cache configuration
 #[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -621,12 +800,57 @@ impl Default for InMemoryCache {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 /// Redis cache configuration
 pub(crate) struct RedisCache {
     /// List of URLs to the Redis cluster
     pub(crate) urls: Vec<String>,
+
+    /// Redis username, if it is not provided in the URLs
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+
+    /// Redis password, if it is not provided in the URLs
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+
+    /// TTL of the entries, by default they do not expire
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "Option<String>", default)]
+    pub(crate) ttl: Option<Duration>,
+
+    /// Prefix of the keys, to share a Redis instance between several routers or caches
+    #[serde(default)]
+    pub(crate) namespace: Option<String>,
+
+    /// TLS configuration of the connections. The connections use TLS with the system
+    /// certificate authorities if this is present, whatever the scheme of the URLs
+    #[serde(default)]
+    pub(crate) tls: Option<TlsRedis>,
+
+    /// Timeout when establishing the connections (default: 5s)
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "Option<String>", default)]
+    pub(crate) connect_timeout: Option<Duration>,
+
+    /// Timeout of the Redis commands (default: 500ms)
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "Option<String>", default)]
+    pub(crate) timeout: Option<Duration>,
+
+    /// Number of connections to Redis (default: 1)
+    #[serde(default)]
+    pub(crate) pool_size: Option<NonZeroUsize>,
+}
+
+/// TLS configuration of the connections to Redis
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct TlsRedis {
+    /// list of certificate authorities in PEM format
+    pub(crate) certificate_authorities: Option<String>,
 }
 
 /// TLS related configuration options.
@@ -634,9 +858,28 @@ pub(crate) struct RedisCache {
 #[serde(deny_unknown_fields)]
 #[serde(default)]
 pub(crate) struct Tls {
+    /// TLS server configuration of the supergraph listener. The listener serves plaintext HTTP if it is not set
+    pub(crate) supergraph: Option<TlsSupergraph>,
     pub(crate) subgraph: TlsSubgraphWrapper,
 }
 
+/// Configuration options pertaining to the supergraph server component.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsSupergraph {
+    /// path to the certificate chain in PEM format, starting with the server certificate
+    pub(crate) certificate_chain: PathBuf,
+    /// path to the private key in PEM format
+    pub(crate) key: PathBuf,
+    /// reloads the certificate chain and the key when their files change
+    #[serde(default = "default_tls_hot_reload")]
+    pub(crate) hot_reload: bool,
+}
+
+fn default_tls_hot_reload() -> bool {
+    true
+}
+
 /// Configuration options pertaining to the subgraph server component.
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -669,14 +912,20 @@ impl Default for TlsSubgraphWrapper {
 pub(crate) struct TlsSubgraph {
     /// list of certificate authorities in PEM format
     pub(crate) certificate_authorities: Option<String>,
+    /// client certificate authentication
+    pub(crate) client_authentication: Option<TlsClientAuth>,
 }
 
 #[buildstructor::buildstructor]
 impl TlsSubgraph {
     #[builder]
-    pub(crate) fn new(certificate_authorities: Option<String>) -> Self {
+    pub(crate) fn new(
+        certificate_authorities: Option<String>,
+        client_authentication: Option<TlsClientAuth>,
+    ) -> Self {
         Self {
             certificate_authorities,
+            client_authentication,
         }
     }
 }
@@ -687,6 +936,16 @@ impl Default for TlsSubgraph {
     }
 }
 
+/// TLS client authentication
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsClientAuth {
+    /// list of certificates in PEM format, starting with the client certificate
+    pub(crate) certificate_chain: String,
+    /// key in PEM format
+    pub(crate) key: String,
+}
+
 /// Configuration options pertaining to the sandbox page.
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -135,6 +135,39 @@ fn cors_defaults() {
     );
 }
 
+#[test]
+fn redis_cache_configuration() {
+    let redis: RedisCache = serde_yaml::from_str(
+        r#"
+urls: ["redis://127.0.0.1:6379"]
+ttl: 10m
+connect_timeout: 1s
+timeout: 50ms
+pool_size: 4
+tls: {}
+"#,
+    )
+    .unwrap();
+    assert_eq!(redis.ttl, Some(Duration::from_secs(600)));
+    assert_eq!(redis.connect_timeout, Some(Duration::from_secs(1)));
+    assert_eq!(redis.timeout, Some(Duration::from_millis(50)));
+    assert_eq!(redis.pool_size, NonZeroUsize::new(4));
+    assert_eq!(redis.tls, Some(TlsRedis::default()));
+
+    let redis: RedisCache = serde_yaml::from_str(r#"urls: ["redis://127.0.0.1:6379"]"#).unwrap();
+    assert_eq!(redis.ttl, None);
+    assert_eq!(redis.pool_size, None);
+
+    assert!(
+        serde_yaml::from_str::<RedisCache>("urls: [\"redis://127.0.0.1:6379\"]\npool_size: 0")
+            .is_err()
+    );
+    assert!(
+        serde_yaml::from_str::<RedisCache>("urls: [\"redis://127.0.0.1:6379\"]\ntimeout: 50")
+            .is_err()
+    );
+}
+
 #[test]
 fn bad_graphql_path_configuration_without_slash() {
     let error = Configuration::fake_builder()
@@ -170,6 +170,40 @@ impl Context {
         self.entries.iter_mut()
     }
 
+    /// Inserts all the entries of `other`, overwriting the entries with the same keys.
+    ///
+    /// Entries that are not present in `other` are kept.
+    pub(crate) fn extend(&self, other: &Context) {
+        for entry in other.entries.iter() {
+            self.entries
+                .insert(entry.key().clone(), entry.value().clone());
+        }
+    }
+
+    /// Returns a copy of the context whose entries are not shared with this one.
+    pub(crate) fn snapshot(&self) -> Context {
+        Context {
+            entries: Arc::new(self.entries.as_ref().clone()),
+            created_at: self.created_at,
+            busy_timer: self.busy_timer.clone(),
+        }
+    }
+
+    /// Applies the context returned by a co-processor: its entries are inserted, overwriting the
+    /// entries with the same keys, and the entries of `sent` that it does not contain are removed.
+    ///
+    /// Entries that were not sent, like the ones added in the meantime, are kept.
+    pub(crate) fn write_back(&self, sent: Option<&Context>, returned: &Context) {
+        if let Some(sent) = sent {
+            for entry in sent.entries.iter() {
+                if !returned.entries.contains_key(entry.key()) {
+                    self.entries.remove(entry.key());
+                }
+            }
+        }
+        self.extend(returned);
+    }
+
     /// Notify the busy timer that we're waiting on a network request
     pub(crate) async fn enter_active_request(&self) {
         self.busy_timer.lock().await.increment_active_requests()
@@ -312,4 +346,28 @@ mod test {
         assert_eq!(c.get("one").unwrap(), Some(2));
         assert_eq!(c.get("two").unwrap(), Some(3));
     }
+
+    #[test]
+    fn it_writes_back_the_returned_context() {
+        let c = Context::new();
+        assert!(c.insert("kept", 1).is_ok());
+        assert!(c.insert("changed", 1).is_ok());
+        assert!(c.insert("removed", 1).is_ok());
+        let sent = c.snapshot();
+        assert!(c.insert("added_meanwhile", 1).is_ok());
+
+        let returned = Context::new();
+        assert!(returned.insert("kept", 1).is_ok());
+        assert!(returned.insert("changed", 2).is_ok());
+        assert!(returned.insert("added", 1).is_ok());
+        c.write_back(Some(&sent), &returned);
+
+        assert_eq!(c.get("kept").unwrap(), Some(1));
+        assert_eq!(c.get("changed").unwrap(), Some(2));
+        assert_eq!(c.get("added").unwrap(), Some(1));
+        assert_eq!(c.get("added_meanwhile").unwrap(), Some(1));
+        assert!(!c.contains_key("removed"));
+        // the snapshot does not share the entries of the context
+        assert!(!sent.contains_key("added_meanwhile"));
+    }
 }
@@ -71,6 +71,25 @@ pub(crate) enum FetchError {
 
         /// The reason the fetch failed.
         reason: String,
+
+        /// The HTTP status code of the subgraph response, when it caused the failure.
+        #[serde(skip)]
+        status_code: Option<http::StatusCode>,
+
+        /// The delay requested by the subgraph with the Retry-After header of its response.
+        #[serde(skip)]
+        retry_after: Option<std::time::Duration>,
+    },
+
+    /// Websocket fetch failed from '{service}': {reason}
+    ///
+    /// note that this relates to a transport error and not a GraphQL error
+    SubrequestWsError {
+        /// The service failed.
+        service: String,
+
+        /// The reason the fetch failed.
+        reason: String,
     },
 
     /// subquery requires field '{field}' but it was not found in the current response
@@ -107,6 +126,7 @@ impl FetchError {
                 FetchError::SubrequestMalformedResponse { service, .. }
                 | FetchError::SubrequestUnexpectedPatchResponse { service }
                 | FetchError::SubrequestHttpError { service, .. }
+                | FetchError::SubrequestWsError { service, .. }
                 | FetchError::CompressionError { service, .. } => {
                     extensions
                         .entry("service")
@@ -153,6 +173,7 @@ impl ErrorExtension for FetchError {
                 "SUBREQUEST_UNEXPECTED_PATCH_RESPONSE"
             }
             FetchError::SubrequestHttpError { .. } => "SUBREQUEST_HTTP_ERROR",
+            FetchError::SubrequestWsError { .. } => "SUBREQUEST_WEBSOCKET_ERROR",
             FetchError::ExecutionFieldNotFound { .. } => "EXECUTION_FIELD_NOT_FOUND",
             FetchError::ExecutionPathNotFound { .. } => "EXECUTION_PATH_NOT_FOUND",
             FetchError::CompressionError { .. } => "COMPRESSION_ERROR",
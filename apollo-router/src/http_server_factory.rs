@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
@@ -151,7 +152,7 @@ pub(crate) enum Listener {
 }
 
 pub(crate) enum NetworkStream {
-    Tcp(tokio::net::TcpStream),
+    Tcp(tokio::net::TcpStream, SocketAddr),
     #[cfg(unix)]
     Unix(tokio::net::UnixStream),
 }
@@ -176,7 +177,7 @@ impl Listener {
             Listener::Tcp(listener) => listener
                 .accept()
                 .await
-                .map(|(stream, _)| NetworkStream::Tcp(stream)),
+                .map(|(stream, addr)| NetworkStream::Tcp(stream, addr)),
             #[cfg(unix)]
             Listener::Unix(listener) => listener
                 .accept()
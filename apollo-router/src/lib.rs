@@ -61,6 +61,7 @@ mod introspection;
 pub mod layers;
 mod orbiter;
 mod plugins;
+mod protocols;
 mod query_planner;
 mod request;
 mod response;
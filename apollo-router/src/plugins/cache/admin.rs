@@ -0,0 +1,321 @@
+//! Administration endpoint of the caches.
+//!
+//! Lists the caches of the router (query plans, APQ, introspection, responses...) with their size,
+//! and invalidates their entries by key, by prefix or all of them, in memory and in Redis, so that
+//! query plans can be flushed without restarting the router.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use http::header::AUTHORIZATION;
+use http::header::CONTENT_TYPE;
+use http::Method;
+use http::StatusCode;
+use multimap::MultiMap;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::service_fn;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::cache::invalidation::Invalidation;
+use crate::cache::registry::CacheRegistry;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::router_factory::Endpoint;
+use crate::services::router;
+use crate::Context;
+use crate::ListenAddr;
+
+register_plugin!("apollo", "cache_admin", CacheAdmin);
+
+/// Configuration of the cache administration endpoint
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Expose the cache administration endpoint
+    #[serde(default)]
+    enabled: bool,
+    /// The listen address of the endpoint
+    #[serde(default = "default_listen")]
+    listen: ListenAddr,
+    /// The path of the endpoint
+    #[serde(default = "default_path")]
+    path: String,
+    /// Token expected in the `Authorization: Bearer <token>` header of the requests
+    token: Option<String>,
+}
+
+fn default_listen() -> ListenAddr {
+    SocketAddr::from_str("127.0.0.1:8088").unwrap().into()
+}
+
+fn default_path() -> String {
+    "/cache".to_string()
+}
+
+struct CacheAdmin {
+    config: Config,
+    /// Digest of the token, compared to the digest of the token of the requests so that the
+    /// comparison does not reveal the token
+    token_digest: Arc<Vec<u8>>,
+    /// The caches of the router the plugin is created with
+    caches: CacheRegistry,
+}
+
+/// Body of the invalidation requests
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct InvalidationRequest {
+    /// Only invalidate the caches of this kind, e.g. `query planner`
+    cache: Option<String>,
+    key: Option<String>,
+    prefix: Option<String>,
+    all: bool,
+}
+
+impl InvalidationRequest {
+    fn invalidation(self) -> Result<(Option<String>, Invalidation), &'static str> {
+        let invalidation = match (self.key, self.prefix, self.all) {
+            (Some(key), None, false) => Invalidation::Key(key),
+            (None, Some(prefix), false) => Invalidation::Prefix(prefix),
+            (None, None, true) => Invalidation::All,
+            _ => return Err("exactly one of `key`, `prefix` or `all` must be set"),
+        };
+        Ok((self.cache, invalidation))
+    }
+}
+
+/// A cache, as listed by the endpoint
+#[derive(Debug, Serialize)]
+struct CacheSize {
+    kind: String,
+    /// Number of entries in memory
+    size: usize,
+    redis: bool,
+}
+
+#[async_trait::async_trait]
+impl Plugin for CacheAdmin {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        let token_digest = match (&config.token, config.enabled) {
+            (Some(token), _) if !token.is_empty() => Sha256::digest(token.as_bytes()).to_vec(),
+            (_, true) => {
+                return Err(
+                    "a token is required to expose the cache administration endpoint".into(),
+                )
+            }
+            (_, false) => Vec::new(),
+        };
+        Ok(Self {
+            config,
+            token_digest: Arc::new(token_digest),
+            caches: CacheRegistry::current().unwrap_or_default(),
+        })
+    }
+
+    fn web_endpoints(&self) -> MultiMap<ListenAddr, Endpoint> {
+        let mut endpoints = MultiMap::new();
+        if self.config.enabled {
+            tracing::info!(
+                "cache administration endpoint exposed at {}{}",
+                self.config.listen,
+                self.config.path
+            );
+            let token_digest = self.token_digest.clone();
+            let caches = self.caches.clone();
+            endpoints.insert(
+                self.config.listen.clone(),
+                Endpoint::from_router_service(
+                    self.config.path.clone(),
+                    service_fn(move |request: router::Request| {
+                        handle(request, token_digest.clone(), caches.clone())
+                    })
+                    .boxed(),
+                ),
+            );
+        }
+        endpoints
+    }
+}
+
+async fn handle(
+    request: router::Request,
+    token_digest: Arc<Vec<u8>>,
+    caches: CacheRegistry,
+) -> Result<router::Response, BoxError> {
+    let router::Request {
+        router_request,
+        context,
+    } = request;
+
+    let authorized = router_request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Sha256::digest(token.trim().as_bytes()).as_slice() == token_digest.as_slice())
+        .unwrap_or(false);
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid token", context);
+    }
+
+    match *router_request.method() {
+        Method::GET => {
+            let mut sizes = Vec::new();
+            for cache in caches.caches() {
+                sizes.push(CacheSize {
+                    kind: cache.kind().to_string(),
+                    size: cache.len().await,
+                    redis: cache.has_redis(),
+                });
+            }
+            json_response(StatusCode::OK, &sizes, context)
+        }
+        Method::POST => {
+            let body = hyper::body::to_bytes(router_request.into_body()).await?;
+            let (kind, invalidation) = match serde_json::from_slice::<InvalidationRequest>(&body)
+                .map_err(|e| e.to_string())
+                .and_then(|request| request.invalidation().map_err(str::to_string))
+            {
+                Ok(invalidation) => invalidation,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e, context),
+            };
+
+            let mut invalidated = Vec::new();
+            for cache in caches.caches() {
+                if kind.as_deref().map_or(true, |kind| kind == cache.kind()) {
+                    invalidated.push(cache.invalidate(&invalidation).await);
+                }
+            }
+            tracing::info!(
+                "invalidated cache entries: {}",
+                serde_json::to_string(&invalidated)?
+            );
+            json_response(StatusCode::OK, &invalidated, context)
+        }
+        _ => error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET and POST are supported",
+            context,
+        ),
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    message: &str,
+    context: Context,
+) -> Result<router::Response, BoxError> {
+    json_response(status, &serde_json::json!({ "error": message }), context)
+}
+
+fn json_response<T: Serialize>(
+    status: StatusCode,
+    body: &T,
+    context: Context,
+) -> Result<router::Response, BoxError> {
+    Ok(router::Response {
+        response: http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body::<hyper::Body>(serde_json::to_vec(body)?.into())?,
+        context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::cache::storage::CacheStorage;
+
+    fn request(method: Method, token: &str, body: &str) -> router::Request {
+        http::Request::builder()
+            .method(method)
+            .uri("http://127.0.0.1:8088/cache")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(hyper::Body::from(body.to_string()))
+            .unwrap()
+            .into()
+    }
+
+    async fn call(
+        request: router::Request,
+        caches: &CacheRegistry,
+    ) -> (StatusCode, serde_json::Value) {
+        let token_digest = Arc::new(Sha256::digest(b"secret").to_vec());
+        let response = handle(request, token_digest, caches.clone())
+            .await
+            .unwrap()
+            .response;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn it_invalidates_cache_entries() {
+        let caches = CacheRegistry::default();
+        let storage: CacheStorage<String, String> = caches
+            .clone()
+            .scope(CacheStorage::new(
+                NonZeroUsize::new(10).unwrap(),
+                None,
+                "admin test",
+            ))
+            .await;
+        for key in ["plan:a", "plan:b", "other"] {
+            storage.insert(key.to_string(), "value".to_string()).await;
+        }
+
+        let (status, _) = call(request(Method::GET, "wrong", ""), &caches).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call(request(Method::GET, "secret", ""), &caches).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!({"kind": "admin test", "size": 3, "redis": false})));
+
+        let (status, _) = call(
+            request(
+                Method::POST,
+                "secret",
+                r#"{"cache": "admin test", "key": "other", "all": true}"#,
+            ),
+            &caches,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = call(
+            request(
+                Method::POST,
+                "secret",
+                r#"{"cache": "admin test", "prefix": "plan:"}"#,
+            ),
+            &caches,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!([{"kind": "admin test", "memory": 2}])
+        );
+        assert_eq!(storage.len().await, 1);
+
+        let (status, _) = call(request(Method::DELETE, "secret", ""), &caches).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
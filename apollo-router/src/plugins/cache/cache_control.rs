@@ -0,0 +1,160 @@
+//! Parsing and merging of the `Cache-Control` header.
+
+use http::header::AGE;
+use http::header::CACHE_CONTROL;
+use http::HeaderMap;
+use http::HeaderValue;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::services::subgraph;
+use crate::Context;
+
+/// Context entry holding the merged directives of the subgraph responses of a client request
+pub(crate) const CACHE_CONTROL_CONTEXT_KEY: &str = "apollo_cache::cache_control";
+
+/// The caching directives of a response, as far as a shared cache is concerned
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CacheControl {
+    /// Freshness lifetime in seconds, from `s-maxage` or `max-age`
+    pub(crate) max_age: Option<u64>,
+    /// The response can only be cached for a specific user
+    pub(crate) private: bool,
+    /// The response cannot be cached
+    pub(crate) no_store: bool,
+}
+
+impl CacheControl {
+    /// Parses the `Cache-Control` header, the `Age` header being removed from the lifetime.
+    ///
+    /// Returns `None` if there is no `Cache-Control` header.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut values = headers.get_all(CACHE_CONTROL).iter().peekable();
+        values.peek()?;
+
+        let mut result = CacheControl::default();
+        let mut max_age = None;
+        let mut s_max_age = None;
+        for directive in values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "max-age" => max_age = argument.and_then(|a| a.parse::<u64>().ok()),
+                "s-maxage" => s_max_age = argument.and_then(|a| a.parse::<u64>().ok()),
+                "private" => result.private = true,
+                // responses to revalidate on each use cannot be served from the cache
+                "no-store" | "no-cache" => result.no_store = true,
+                _ => {}
+            }
+        }
+
+        let age = headers
+            .get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        // a shared cache uses `s-maxage` over `max-age`
+        result.max_age = s_max_age
+            .or(max_age)
+            .map(|max_age| max_age.saturating_sub(age));
+        Some(result)
+    }
+
+    pub(crate) fn no_store() -> Self {
+        CacheControl {
+            no_store: true,
+            ..Default::default()
+        }
+    }
+
+    /// The directives of a subgraph response, as part of a client response: failed responses and
+    /// responses without a lifetime cannot be cached
+    pub(crate) fn from_subgraph_response(response: &subgraph::Response) -> Self {
+        if !response.response.status().is_success() || !response.response.body().errors.is_empty() {
+            return Self::no_store();
+        }
+        CacheControl::from_headers(response.response.headers())
+            .filter(|cache_control| cache_control.max_age.is_some())
+            .unwrap_or_else(Self::no_store)
+    }
+
+    /// Merges the directives with the ones of the other subgraph responses of the request
+    pub(crate) fn record(&self, context: &Context) {
+        let result = context.upsert(
+            CACHE_CONTROL_CONTEXT_KEY,
+            |current: Option<CacheControl>| match current {
+                Some(current) => Some(current.merge(self)),
+                None => Some(self.clone()),
+            },
+        );
+        if let Err(error) = result {
+            tracing::error!("could not record the Cache-Control of a subgraph response: {error}");
+        }
+    }
+
+    /// Combines the directives of two parts of a response: the lowest lifetime applies, and the
+    /// response is private or not cacheable if any of the parts is
+    pub(crate) fn merge(&self, other: &CacheControl) -> CacheControl {
+        CacheControl {
+            max_age: match (self.max_age, other.max_age) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            private: self.private || other.private,
+            no_store: self.no_store || other.no_store,
+        }
+    }
+
+    pub(crate) fn to_header_value(&self) -> HeaderValue {
+        if self.no_store {
+            return HeaderValue::from_static("no-store");
+        }
+        let mut directives = Vec::new();
+        if let Some(max_age) = self.max_age {
+            directives.push(format!("max-age={max_age}"));
+        }
+        directives.push(if self.private { "private" } else { "public" }.to_string());
+        HeaderValue::from_str(&directives.join(", "))
+            .expect("the directives only contain ASCII characters; qed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cache_control: &str, age: Option<&str>) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+        if let Some(age) = age {
+            headers.insert(AGE, HeaderValue::from_str(age).unwrap());
+        }
+        CacheControl::from_headers(&headers).unwrap()
+    }
+
+    #[test]
+    fn it_parses_cache_control() {
+        assert_eq!(CacheControl::from_headers(&HeaderMap::new()), None);
+
+        let cache_control = parse("max-age=60, s-maxage=\"30\", private", Some("10"));
+        assert_eq!(cache_control.max_age, Some(20));
+        assert!(cache_control.private);
+
+        assert!(parse("no-cache, max-age=60", None).no_store);
+        assert_eq!(parse("public", None).max_age, None);
+    }
+
+    #[test]
+    fn it_merges_cache_control() {
+        let merged = parse("max-age=60", None).merge(&parse("max-age=30, private", None));
+        assert_eq!(merged.to_header_value(), "max-age=30, private");
+
+        let merged = merged.merge(&parse("no-store", None));
+        assert_eq!(merged.to_header_value(), "no-store");
+    }
+}
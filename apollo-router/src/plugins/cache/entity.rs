@@ -0,0 +1,941 @@
+//! Subgraph response cache.
+//!
+//! Responses to root field queries are cached whole, and responses to `_entities` queries are
+//! cached per entity representation, so that the following requests only fetch the missing
+//! entities from the subgraph. The lifetime of the entries comes from the `Cache-Control` header
+//! of subgraph responses, with a default lifetime per subgraph.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::header::CACHE_CONTROL;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json_bytes::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use super::cache_control::CacheControl;
+use super::now;
+use crate::cache::storage::CacheStorage;
+use crate::configuration::Cache;
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::json_ext::PathElement;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::Context;
+
+register_plugin!("apollo", "entity_cache", EntityCache);
+
+const ENTITY_CACHE_KIND: &str = "entity";
+const REPRESENTATIONS: &str = "representations";
+const ENTITIES: &str = "_entities";
+
+/// Configuration of the subgraph response cache
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct Config {
+    /// Enable the subgraph response cache
+    enabled: bool,
+    /// Storage of the cached responses
+    cache: Cache,
+    /// Applied on all subgraphs
+    all: SubgraphConfig,
+    /// Applied on specific subgraphs, overriding the `all` options
+    subgraphs: HashMap<String, SubgraphConfig>,
+}
+
+/// Subgraph response cache options
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct SubgraphConfig {
+    /// Cache the responses of the subgraph (default: true)
+    enabled: Option<bool>,
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[schemars(with = "Option<String>")]
+    /// Lifetime of the responses without a `max-age` in their `Cache-Control` header. Those
+    /// responses are not cached if it is not set
+    ttl: Option<Duration>,
+    /// Name of the context entry identifying the user, e.g. set by the authentication plugin.
+    /// Private responses are cached per user, and only if it is set
+    private_id: Option<String>,
+}
+
+impl SubgraphConfig {
+    fn merge(&self, all: &SubgraphConfig) -> SubgraphConfig {
+        SubgraphConfig {
+            enabled: self.enabled.or(all.enabled),
+            ttl: self.ttl.or(all.ttl),
+            private_id: self.private_id.clone().or_else(|| all.private_id.clone()),
+        }
+    }
+}
+
+/// A cached root field response or entity
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Unix timestamp in seconds after which the entry is stale
+    expires_at: u64,
+    /// Whether the entry is specific to a user
+    private: bool,
+    data: Value,
+}
+
+impl CacheEntry {
+    fn new(data: Value, ttl: Duration, private: bool) -> Self {
+        Self {
+            expires_at: now() + ttl.as_secs(),
+            private,
+            data,
+        }
+    }
+
+    fn cache_control(&self, now: u64) -> CacheControl {
+        CacheControl {
+            max_age: Some(self.expires_at.saturating_sub(now)),
+            private: self.private,
+            no_store: false,
+        }
+    }
+}
+
+struct EntityCache {
+    config: Config,
+    storage: CacheStorage<String, CacheEntry>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for EntityCache {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let storage = CacheStorage::new(
+            init.config.cache.in_memory.limit,
+            init.config.cache.redis.as_ref(),
+            ENTITY_CACHE_KIND,
+        )
+        .await;
+        Ok(Self {
+            config: init.config,
+            storage,
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if !self.config.enabled {
+            return service;
+        }
+        let config = match self.config.subgraphs.get(name) {
+            Some(config) => config.merge(&self.config.all),
+            None => self.config.all.clone(),
+        };
+        if !config.enabled.unwrap_or(true) {
+            return service;
+        }
+
+        CacheService {
+            cache: Arc::new(SubgraphCache {
+                name: name.to_string(),
+                config,
+                storage: self.storage.clone(),
+            }),
+            inner: ServiceBuilder::new().buffered().service(service),
+        }
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+struct CacheService<S> {
+    cache: Arc<SubgraphCache>,
+    inner: S,
+}
+
+/// The cache of a subgraph, kept apart from the inner service so that it can be shared by the
+/// response futures
+struct SubgraphCache {
+    name: String,
+    config: SubgraphConfig,
+    storage: CacheStorage<String, CacheEntry>,
+}
+
+impl<S> Service<subgraph::Request> for CacheService<S>
+where
+    S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is checked if the response is not in the cache
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: subgraph::Request) -> Self::Future {
+        let cache = self.cache.clone();
+        let inner = self.inner.clone();
+        async move {
+            if request.operation_kind != OperationKind::Query
+                || request.subscription_stream.is_some()
+            {
+                return inner.oneshot(request).await;
+            }
+
+            let private_id = cache.private_id(&request.context);
+            let representations = match request
+                .subgraph_request
+                .body()
+                .variables
+                .get(REPRESENTATIONS)
+            {
+                Some(Value::Array(representations)) => Some(representations.clone()),
+                _ => None,
+            };
+            let response = match representations {
+                Some(representations) => {
+                    cache
+                        .call_entities(inner, request, representations, private_id)
+                        .await?
+                }
+                None => cache.call_root(inner, request, private_id).await?,
+            };
+            // responses served from the cache do not go through the services of other plugins
+            CacheControl::from_subgraph_response(&response).record(&response.context);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+impl SubgraphCache {
+    async fn call_root<S>(
+        &self,
+        inner: S,
+        request: subgraph::Request,
+        private_id: Option<String>,
+    ) -> Result<subgraph::Response, BoxError>
+    where
+        S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>,
+    {
+        let key = format!(
+            "subgraph:{}:query:{}",
+            self.name,
+            hash_request(request.subgraph_request.body())
+        );
+
+        let cached = self
+            .lookup(&[key.clone()], private_id.as_deref())
+            .await
+            .pop()
+            .flatten();
+        if let Some(entry) = cached {
+            let cache_control = entry.cache_control(now());
+            let mut response = subgraph::Response::builder()
+                .data(entry.data)
+                .context(request.context)
+                .build();
+            response
+                .response
+                .headers_mut()
+                .insert(CACHE_CONTROL, cache_control.to_header_value());
+            return Ok(response);
+        }
+
+        let response = inner.oneshot(request).await?;
+        if let Some((ttl, private)) = self.cache_policy(&response, private_id.as_deref()) {
+            if let Some(data) = response.response.body().data.clone() {
+                self.insert(vec![(key, data)], private_id.as_deref(), ttl, private)
+                    .await;
+            }
+        }
+        Ok(response)
+    }
+
+    async fn call_entities<S>(
+        &self,
+        inner: S,
+        mut request: subgraph::Request,
+        representations: Vec<Value>,
+        private_id: Option<String>,
+    ) -> Result<subgraph::Response, BoxError>
+    where
+        S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>,
+    {
+        let request_hash = hash_request(request.subgraph_request.body());
+        let keys: Vec<String> = representations
+            .iter()
+            .map(|representation| entity_key(&self.name, &request_hash, representation))
+            .collect();
+
+        let cached = self.lookup(&keys, private_id.as_deref()).await;
+        let missing: Vec<usize> = cached
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.is_none().then_some(index))
+            .collect();
+
+        let now = now();
+        let cached_control = cached
+            .iter()
+            .flatten()
+            .map(|entry| entry.cache_control(now))
+            .reduce(|a, b| a.merge(&b));
+
+        if missing.is_empty() {
+            let entities = cached
+                .into_iter()
+                .flatten()
+                .map(|entry| entry.data)
+                .collect();
+            let mut response = subgraph::Response::builder()
+                .data(entities_data(entities))
+                .context(request.context)
+                .build();
+            if let Some(cache_control) = cached_control {
+                response
+                    .response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, cache_control.to_header_value());
+            }
+            return Ok(response);
+        }
+
+        // only the missing entities are requested from the subgraph
+        if missing.len() < representations.len() {
+            let missing_representations = missing
+                .iter()
+                .map(|index| representations[*index].clone())
+                .collect();
+            request
+                .subgraph_request
+                .body_mut()
+                .variables
+                .insert(REPRESENTATIONS, Value::Array(missing_representations));
+        }
+
+        let mut response = inner.oneshot(request).await?;
+        let policy = self.cache_policy(&response, private_id.as_deref());
+        let fetched = response
+            .response
+            .body()
+            .data
+            .as_ref()
+            .and_then(|data| data.as_object())
+            .and_then(|data| data.get(ENTITIES))
+            .and_then(|entities| entities.as_array())
+            .filter(|entities| entities.len() == missing.len())
+            .cloned();
+
+        if let (Some((ttl, private)), Some(fetched)) = (policy, fetched.as_ref()) {
+            let entities = missing
+                .iter()
+                .zip(fetched)
+                .filter(|(_, entity)| !entity.is_null())
+                .map(|(index, entity)| (keys[*index].clone(), entity.clone()))
+                .collect();
+            self.insert(entities, private_id.as_deref(), ttl, private)
+                .await;
+        }
+
+        let cached_control = match cached_control {
+            Some(cached_control) => cached_control,
+            // nothing came from the cache: the subgraph response is unchanged
+            None => return Ok(response),
+        };
+
+        // the cached and fetched entities are put back in the order of the original request
+        let mut fetched = fetched
+            .unwrap_or_else(|| vec![Value::Null; missing.len()])
+            .into_iter();
+        let entities = cached
+            .into_iter()
+            .map(|entry| match entry {
+                Some(entry) => entry.data,
+                None => fetched.next().unwrap_or_default(),
+            })
+            .collect();
+        let body = response.response.body_mut();
+        body.data = Some(entities_data(entities));
+        for error in body.errors.iter_mut() {
+            if let Some(Path(elements)) = error.path.as_mut() {
+                if let [PathElement::Key(key), PathElement::Index(index), ..] =
+                    elements.as_mut_slice()
+                {
+                    if key == ENTITIES {
+                        if let Some(original_index) = missing.get(*index) {
+                            *index = *original_index;
+                        }
+                    }
+                }
+            }
+        }
+
+        // the response can be cached as long as all of its parts
+        let fetched_control = match policy {
+            Some((ttl, private)) => CacheControl {
+                max_age: Some(ttl.as_secs()),
+                private,
+                no_store: false,
+            },
+            None => CacheControl::no_store(),
+        };
+        response.response.headers_mut().insert(
+            CACHE_CONTROL,
+            cached_control.merge(&fetched_control).to_header_value(),
+        );
+        Ok(response)
+    }
+
+    /// Hash of the user identifier, if private responses can be cached
+    fn private_id(&self, context: &Context) -> Option<String> {
+        let key = self.config.private_id.as_deref()?;
+        let value: serde_json::Value = context.get(key).ok().flatten()?;
+        let mut digest = Sha256::new();
+        digest.update(value.to_string().as_bytes());
+        Some(hex::encode(digest.finalize()))
+    }
+
+    /// Looks for public entries, then for the missing entries among those private to the user.
+    ///
+    /// The lookups are batched, so that they take at most two round trips to Redis whatever the
+    /// number of keys.
+    async fn lookup(&self, keys: &[String], private_id: Option<&str>) -> Vec<Option<CacheEntry>> {
+        let now = now();
+        let mut entries: Vec<Option<CacheEntry>> = self
+            .storage
+            .get_multiple(keys)
+            .await
+            .into_iter()
+            .map(|entry| entry.filter(|entry| entry.expires_at > now))
+            .collect();
+
+        let private_id = match private_id {
+            Some(private_id) => private_id,
+            None => return entries,
+        };
+        let missing: Vec<usize> = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.is_none().then_some(index))
+            .collect();
+        if missing.is_empty() {
+            return entries;
+        }
+        let private_keys: Vec<String> = missing
+            .iter()
+            .map(|index| private_key(&keys[*index], private_id))
+            .collect();
+        let private_entries = self.storage.get_multiple(&private_keys).await;
+        for (index, entry) in missing.into_iter().zip(private_entries) {
+            entries[index] = entry.filter(|entry| entry.expires_at > now);
+        }
+        entries
+    }
+
+    /// Stores the data of a response, or the entities of an `_entities` response, at once
+    async fn insert(
+        &self,
+        entries: Vec<(String, Value)>,
+        private_id: Option<&str>,
+        ttl: Duration,
+        private: bool,
+    ) {
+        let entries = entries
+            .into_iter()
+            .map(|(key, data)| {
+                let key = match private_id {
+                    Some(private_id) if private => private_key(&key, private_id),
+                    _ => key,
+                };
+                (key, CacheEntry::new(data, ttl, private))
+            })
+            .collect();
+        self.storage.insert_multiple_with_ttl(entries, ttl).await;
+    }
+
+    /// The lifetime of the response and whether it is private, if it can be cached
+    fn cache_policy(
+        &self,
+        response: &subgraph::Response,
+        private_id: Option<&str>,
+    ) -> Option<(Duration, bool)> {
+        if !response.response.status().is_success() || !response.response.body().errors.is_empty() {
+            return None;
+        }
+        let cache_control =
+            CacheControl::from_headers(response.response.headers()).unwrap_or_default();
+        if cache_control.no_store || (cache_control.private && private_id.is_none()) {
+            return None;
+        }
+        let ttl = match cache_control.max_age {
+            Some(max_age) => Duration::from_secs(max_age),
+            None => self.config.ttl?,
+        };
+        (ttl.as_secs() > 0).then_some((ttl, cache_control.private))
+    }
+}
+
+/// Hash of the parts of the request that select the data, except the entity representations
+fn hash_request(body: &graphql::Request) -> String {
+    let mut digest = Sha256::new();
+    digest.update(body.query.as_deref().unwrap_or_default().as_bytes());
+    digest.update([0u8]);
+    digest.update(
+        body.operation_name
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    digest.update([0u8]);
+    let mut variables = body.variables.clone();
+    variables.remove(REPRESENTATIONS);
+    digest.update(serde_json::to_vec(&variables).unwrap_or_default());
+    hex::encode(digest.finalize())
+}
+
+fn entity_key(subgraph: &str, request_hash: &str, representation: &Value) -> String {
+    let typename = representation
+        .as_object()
+        .and_then(|representation| representation.get("__typename"))
+        .and_then(|typename| typename.as_str())
+        .unwrap_or_default();
+    let mut digest = Sha256::new();
+    digest.update(request_hash.as_bytes());
+    digest.update(serde_json::to_vec(representation).unwrap_or_default());
+    format!(
+        "subgraph:{}:entity:{}:{}",
+        subgraph,
+        typename,
+        hex::encode(digest.finalize())
+    )
+}
+
+fn private_key(key: &str, private_id: &str) -> String {
+    format!("{key}:private:{private_id}")
+}
+
+fn entities_data(entities: Vec<Value>) -> Value {
+    let mut data = Object::new();
+    data.insert(ENTITIES, Value::Array(entities));
+    Value::Object(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use http::HeaderValue;
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::DynPlugin;
+
+    async fn entity_cache(config: serde_json::Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .find(|factory| factory.name == "apollo.entity_cache")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .expect("Plugin not created")
+    }
+
+    fn entities_request(ids: &[&str]) -> subgraph::Request {
+        let representations = ids
+            .iter()
+            .map(|id| json!({"__typename": "Product", "upc": id}))
+            .collect::<Vec<_>>();
+        subgraph::Request::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(
+                        graphql::Request::fake_builder()
+                            .query("query($representations:[_Any!]!){_entities(representations:$representations){...on Product{name}}}")
+                            .variable(REPRESENTATIONS, Value::Array(representations))
+                            .build(),
+                    )
+                    .unwrap(),
+            )
+            .build()
+    }
+
+    fn root_request(context: Context) -> subgraph::Request {
+        subgraph::Request::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(graphql::Request::fake_builder().query("{me{name}}").build())
+                    .unwrap(),
+            )
+            .context(context)
+            .build()
+    }
+
+    fn user_context(user: &str) -> Context {
+        let context = Context::new();
+        context.insert("user", user.to_string()).unwrap();
+        context
+    }
+
+    /// A subgraph answering root field queries with the `Cache-Control` header, counting its calls
+    fn root_subgraph(calls: Arc<AtomicUsize>, cache_control: &'static str) -> subgraph::BoxService {
+        tower::service_fn(move |request: subgraph::Request| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let mut response = subgraph::Response::fake_builder()
+                    .data(json!({"me": {"name": "Ada"}}))
+                    .context(request.context)
+                    .build();
+                response
+                    .response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+                Ok::<_, BoxError>(response)
+            }
+        })
+        .boxed()
+    }
+
+    fn cache_control(response: &subgraph::Response) -> &str {
+        response
+            .response
+            .headers()
+            .get(CACHE_CONTROL)
+            .unwrap()
+            .to_str()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_caches_root_fields() {
+        let plugin = entity_cache(serde_json::json!({ "enabled": true })).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut service =
+            plugin.subgraph_service("accounts", root_subgraph(calls.clone(), "max-age=60"));
+
+        for _ in 0..2 {
+            let response = service
+                .ready()
+                .await
+                .unwrap()
+                .call(root_request(Context::new()))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.response.body().data,
+                Some(json!({"me": {"name": "Ada"}}))
+            );
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // the cached response keeps the remaining lifetime of the entry
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(root_request(Context::new()))
+            .await
+            .unwrap();
+        assert!(cache_control(&response).starts_with("max-age="));
+        assert!(cache_control(&response).ends_with("public"));
+    }
+
+    #[tokio::test]
+    async fn it_caches_private_responses_per_user() {
+        let plugin = entity_cache(serde_json::json!({
+            "enabled": true,
+            "all": {
+                "private_id": "user"
+            }
+        }))
+        .await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut service = plugin.subgraph_service(
+            "accounts",
+            root_subgraph(calls.clone(), "max-age=60, private"),
+        );
+
+        for user in ["alice", "alice", "bob"] {
+            service
+                .ready()
+                .await
+                .unwrap()
+                .call(root_request(user_context(user)))
+                .await
+                .unwrap();
+        }
+        // the response of alice is not served to bob
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(root_request(user_context("bob")))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(cache_control(&response).ends_with("private"));
+
+        // without a user, private responses are not cached
+        for _ in 0..2 {
+            service
+                .ready()
+                .await
+                .unwrap()
+                .call(root_request(Context::new()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_cache_uncacheable_responses() {
+        // private responses are not cached without a `private_id`
+        for cache_control in ["no-store", "max-age=60, no-cache", "max-age=60, private"] {
+            let plugin = entity_cache(serde_json::json!({
+                "enabled": true,
+                "all": {
+                    "ttl": "60s"
+                }
+            }))
+            .await;
+            let calls = Arc::new(AtomicUsize::new(0));
+            let mut service =
+                plugin.subgraph_service("accounts", root_subgraph(calls.clone(), cache_control));
+
+            for _ in 0..2 {
+                service
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(root_request(user_context("alice")))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(calls.load(Ordering::SeqCst), 2, "{cache_control}");
+        }
+    }
+
+    #[tokio::test]
+    async fn it_ignores_expired_entries() {
+        let cache = SubgraphCache {
+            name: "accounts".to_string(),
+            config: SubgraphConfig::default(),
+            storage: CacheStorage::new(NonZeroUsize::new(10).unwrap(), None, "expiration test")
+                .await,
+        };
+        let entry = |expires_at| CacheEntry {
+            expires_at,
+            private: true,
+            data: json!({"name": "Ada"}),
+        };
+        let keys = ["expired".to_string(), "fresh".to_string()];
+        cache
+            .storage
+            .insert(private_key("expired", "alice"), entry(now() - 1))
+            .await;
+        cache
+            .storage
+            .insert(private_key("fresh", "alice"), entry(now() + 60))
+            .await;
+
+        let entries = cache.lookup(&keys, Some("alice")).await;
+        assert!(entries[0].is_none());
+        assert_eq!(entries[1].as_ref().unwrap().data, json!({"name": "Ada"}));
+
+        // the entries private to alice are not found for other users
+        assert!(cache
+            .lookup(&keys, Some("bob"))
+            .await
+            .iter()
+            .all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn it_remaps_the_error_paths_of_fetched_entities() {
+        let plugin = entity_cache(serde_json::json!({
+            "enabled": true,
+            "all": {
+                "ttl": "60s"
+            }
+        }))
+        .await;
+
+        // the product 2 cannot be resolved
+        let subgraph = tower::service_fn(|request: subgraph::Request| {
+            let mut errors = Vec::new();
+            let entities = request
+                .subgraph_request
+                .body()
+                .variables
+                .get(REPRESENTATIONS)
+                .and_then(|representations| representations.as_array())
+                .unwrap()
+                .iter()
+                .enumerate()
+                .map(|(index, representation)| {
+                    let upc = representation.as_object().unwrap().get("upc").unwrap();
+                    if upc.as_str() == Some("2") {
+                        errors.push(
+                            graphql::Error::builder()
+                                .message("product not found")
+                                .path(Path::from(format!("_entities/{index}/name")))
+                                .extension_code("NOT_FOUND")
+                                .build(),
+                        );
+                        Value::Null
+                    } else {
+                        json!({ "name": format!("product {}", upc.as_str().unwrap()) })
+                    }
+                })
+                .collect::<Vec<_>>();
+            async move {
+                Ok::<_, BoxError>(
+                    subgraph::Response::fake_builder()
+                        .data(json!({ "_entities": entities }))
+                        .errors(errors)
+                        .build(),
+                )
+            }
+        })
+        .boxed();
+        let mut service = plugin.subgraph_service("products", subgraph);
+
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(entities_request(&["1"]))
+            .await
+            .unwrap();
+
+        // only the product 2 is fetched, at the index 0 of the subgraph request
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(entities_request(&["1", "2"]))
+            .await
+            .unwrap();
+        let body = response.response.body();
+        assert_eq!(
+            body.data,
+            Some(json!({"_entities": [{"name": "product 1"}, null]}))
+        );
+        assert_eq!(body.errors[0].path, Some(Path::from("_entities/1/name")));
+        assert_eq!(cache_control(&response), "no-store");
+    }
+
+    #[tokio::test]
+    async fn it_only_fetches_missing_entities() {
+        let plugin = entity_cache(serde_json::json!({
+            "enabled": true,
+            "subgraphs": {
+                "products": {
+                    "ttl": "60s"
+                }
+            }
+        }))
+        .await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let subgraph = {
+            let calls = calls.clone();
+            tower::service_fn(move |request: subgraph::Request| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let entities = request
+                    .subgraph_request
+                    .body()
+                    .variables
+                    .get(REPRESENTATIONS)
+                    .and_then(|representations| representations.as_array())
+                    .unwrap()
+                    .iter()
+                    .map(|representation| {
+                        let upc = representation.as_object().unwrap().get("upc").unwrap();
+                        json!({ "name": format!("product {}", upc.as_str().unwrap()) })
+                    })
+                    .collect::<Vec<_>>();
+                async move {
+                    Ok::<_, BoxError>(
+                        subgraph::Response::fake_builder()
+                            .data(json!({ "_entities": entities }))
+                            .build(),
+                    )
+                }
+            })
+            .boxed()
+        };
+        let mut service = plugin.subgraph_service("products", subgraph);
+
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(entities_request(&["1", "2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({"_entities": [{"name": "product 1"}, {"name": "product 2"}]}))
+        );
+
+        // only the entity 3 is requested from the subgraph
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(entities_request(&["3", "2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({"_entities": [{"name": "product 3"}, {"name": "product 2"}]}))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // everything comes from the cache
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(entities_request(&["1", "3"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({"_entities": [{"name": "product 1"}, {"name": "product 3"}]}))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(response
+            .response
+            .headers()
+            .get(CACHE_CONTROL)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with("public"));
+    }
+}
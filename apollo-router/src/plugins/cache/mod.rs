@@ -0,0 +1,17 @@
+//! Caching of subgraph and client responses, and administration of the caches.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub(crate) mod admin;
+pub(crate) mod cache_control;
+pub(crate) mod entity;
+pub(crate) mod response;
+
+/// Current Unix timestamp in seconds, used for the expiration of cache entries
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,599 @@
+//! `Cache-Control` of client responses and whole response cache.
+//!
+//! The `Cache-Control` headers of all the subgraph responses used by a client request are merged
+//! into the `Cache-Control` header of the client response, so that CDNs and browsers can cache it.
+//! Cacheable responses can also be cached by the router itself, keyed on the operation, its
+//! variables, a selection of request headers and what the request can access with its JWT claims.
+
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::ready;
+use futures::stream::once;
+use futures::FutureExt;
+use futures::StreamExt;
+use http::header::CACHE_CONTROL;
+use http::header::VARY;
+use http::HeaderName;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use super::cache_control::CacheControl;
+use super::cache_control::CACHE_CONTROL_CONTEXT_KEY;
+use super::now;
+use crate::cache::storage::CacheStorage;
+use crate::configuration::Cache;
+use crate::graphql;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::layers::content_negociation::IS_SUBSCRIPTION_CONTEXT_KEY;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::spec::authorization::RequestAccess;
+
+register_plugin!("apollo", "response_cache", ResponseCache);
+
+const RESPONSE_CACHE_KIND: &str = "response";
+
+/// Configuration of the client response caching
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct Config {
+    /// Set the `Cache-Control` header of client responses from the `Cache-Control` headers of
+    /// the subgraph responses
+    enabled: bool,
+    /// Cache whole client responses in the router
+    full_response: FullResponseConfig,
+}
+
+/// Whole client response cache
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct FullResponseConfig {
+    /// Enable the whole client response cache
+    enabled: bool,
+    /// Storage of the cached responses
+    cache: Cache,
+    /// Names of the request headers added to the cache key, and to the `Vary` header of
+    /// responses
+    headers: Vec<String>,
+}
+
+/// A cached client response
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedResponse {
+    /// Unix timestamp in seconds after which the response is stale
+    expires_at: u64,
+    response: graphql::Response,
+}
+
+struct ResponseCache {
+    enabled: bool,
+    full_response: Option<Arc<FullResponseCache>>,
+}
+
+struct FullResponseCache {
+    storage: CacheStorage<String, CachedResponse>,
+    headers: Vec<HeaderName>,
+    vary: HeaderValue,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseCache {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        let full_response = if config.enabled && config.full_response.enabled {
+            let headers = config
+                .full_response
+                .headers
+                .iter()
+                .map(|name| HeaderName::try_from(name.as_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let vary = std::iter::once("origin")
+                .chain(headers.iter().map(HeaderName::as_str))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let storage = CacheStorage::new(
+                config.full_response.cache.in_memory.limit,
+                config.full_response.cache.redis.as_ref(),
+                RESPONSE_CACHE_KIND,
+            )
+            .await;
+            Some(Arc::new(FullResponseCache {
+                storage,
+                headers,
+                vary: HeaderValue::from_str(&vary)?,
+            }))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            enabled: config.enabled,
+            full_response,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if !self.enabled {
+            return service;
+        }
+        let lookup_cache = self.full_response.clone();
+        let insert_cache = self.full_response.clone();
+
+        ServiceBuilder::new()
+            .checkpoint_async(move |request: supergraph::Request| {
+                let cache = lookup_cache.clone();
+                async move {
+                    let cached = match &cache {
+                        Some(cache) => cache.lookup(&request).await,
+                        None => None,
+                    };
+                    Ok(match cached {
+                        Some(response) => ControlFlow::Break(response),
+                        None => ControlFlow::Continue(request),
+                    })
+                }
+                .boxed()
+            })
+            .buffered()
+            .map_future_with_request_data(
+                move |request: &supergraph::Request| RequestData {
+                    authenticated: RequestAccess::from_context(&request.context).is_authenticated(),
+                    is_subscription: request
+                        .context
+                        .get(IS_SUBSCRIPTION_CONTEXT_KEY)
+                        .unwrap_or_default()
+                        .unwrap_or_default(),
+                    cache: insert_cache
+                        .clone()
+                        .map(|cache| (cache.key(request), cache)),
+                },
+                |data: RequestData, response_future| async move {
+                    let response: Result<supergraph::Response, BoxError> = response_future.await;
+                    set_cache_control(data, response?).await
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn subgraph_service(&self, _name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if !self.enabled {
+            return service;
+        }
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                |request: &subgraph::Request| request.operation_kind,
+                |operation_kind: OperationKind, response_future| async move {
+                    let response: subgraph::Response = response_future.await?;
+                    let cache_control = if operation_kind == OperationKind::Query {
+                        CacheControl::from_subgraph_response(&response)
+                    } else {
+                        CacheControl::no_store()
+                    };
+                    cache_control.record(&response.context);
+                    Ok::<_, BoxError>(response)
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+/// What is known of the client request when its response is processed
+struct RequestData {
+    /// Whether the request has JWT claims, which can give access to more fields
+    authenticated: bool,
+    is_subscription: bool,
+    /// Key of the response in the whole response cache, if enabled
+    cache: Option<(String, Arc<FullResponseCache>)>,
+}
+
+/// Sets the `Cache-Control` header of the client response, and stores it in the whole response
+/// cache if it can be
+async fn set_cache_control(
+    data: RequestData,
+    response: supergraph::Response,
+) -> Result<supergraph::Response, BoxError> {
+    let (mut parts, stream) = response.response.into_parts();
+    let (first, rest) = stream.into_future().await;
+    let first = match first {
+        Some(first) => first,
+        None => {
+            return Ok(supergraph::Response {
+                response: http::Response::from_parts(parts, rest.boxed()),
+                context: response.context,
+            })
+        }
+    };
+
+    // deferred parts and subscription events are not known yet
+    let cache_control = if data.is_subscription
+        || first.has_next.unwrap_or(false)
+        || !first.errors.is_empty()
+        || !parts.status.is_success()
+    {
+        Some(CacheControl::no_store())
+    } else {
+        // there is none if no subgraph was called, e.g. for introspection queries
+        response
+            .context
+            .get::<_, CacheControl>(CACHE_CONTROL_CONTEXT_KEY)
+            .unwrap_or_default()
+    };
+
+    if let Some(cache_control) = &cache_control {
+        if !parts.headers.contains_key(CACHE_CONTROL) {
+            parts.headers.insert(
+                CACHE_CONTROL,
+                client_cache_control(cache_control, data.authenticated).to_header_value(),
+            );
+        }
+    }
+    // the key of the responses to authenticated requests depends on their access, so that they
+    // can be shared by the router, but not by CDNs
+    if let Some((key, cache)) = data.cache {
+        parts.headers.insert(VARY, cache.vary.clone());
+        if let Some(ttl) = cache_control.as_ref().and_then(shared_lifetime) {
+            cache.insert(key, &first, ttl).await;
+        }
+    }
+
+    Ok(supergraph::Response {
+        response: http::Response::from_parts(parts, once(ready(first)).chain(rest).boxed()),
+        context: response.context,
+    })
+}
+
+/// The directives sent to the client: the responses to authenticated requests may contain fields
+/// that other users cannot access, so shared caches must not store them
+fn client_cache_control(cache_control: &CacheControl, authenticated: bool) -> CacheControl {
+    CacheControl {
+        private: cache_control.private || authenticated,
+        ..cache_control.clone()
+    }
+}
+
+/// The lifetime of a response in a cache shared by all users, if it can be stored there
+fn shared_lifetime(cache_control: &CacheControl) -> Option<Duration> {
+    if cache_control.no_store || cache_control.private {
+        return None;
+    }
+    cache_control
+        .max_age
+        .filter(|max_age| *max_age > 0)
+        .map(Duration::from_secs)
+}
+
+impl FullResponseCache {
+    /// Hash of the operation, its variables, the selected request headers and the access of the
+    /// request, as the fields it cannot access are removed from the operation
+    fn key(&self, request: &supergraph::Request) -> String {
+        let body = request.supergraph_request.body();
+        let mut digest = Sha256::new();
+        digest.update(body.query.as_deref().unwrap_or_default().as_bytes());
+        digest.update([0u8]);
+        digest.update(
+            body.operation_name
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        digest.update([0u8]);
+        digest.update(serde_json::to_vec(&body.variables).unwrap_or_default());
+        for name in &self.headers {
+            digest.update([0u8]);
+            digest.update(name.as_str().as_bytes());
+            for value in request.supergraph_request.headers().get_all(name) {
+                digest.update([0u8]);
+                digest.update(value.as_bytes());
+            }
+        }
+        digest.update([0u8]);
+        digest.update(
+            RequestAccess::from_context(&request.context)
+                .cache_key()
+                .as_bytes(),
+        );
+        format!("response:{}", hex::encode(digest.finalize()))
+    }
+
+    async fn lookup(&self, request: &supergraph::Request) -> Option<supergraph::Response> {
+        let now = now();
+        let cached = self
+            .storage
+            .get(&self.key(request))
+            .await
+            .filter(|cached| cached.expires_at > now)?;
+        let cache_control = client_cache_control(
+            &CacheControl {
+                max_age: Some(cached.expires_at - now),
+                ..Default::default()
+            },
+            RequestAccess::from_context(&request.context).is_authenticated(),
+        );
+
+        let mut response = supergraph::Response::new_from_graphql_response(
+            cached.response,
+            request.context.clone(),
+        );
+        let headers = response.response.headers_mut();
+        headers.insert(CACHE_CONTROL, cache_control.to_header_value());
+        headers.insert(VARY, self.vary.clone());
+        Some(response)
+    }
+
+    async fn insert(&self, key: String, response: &graphql::Response, ttl: Duration) {
+        let cached = CachedResponse {
+            expires_at: now() + ttl.as_secs(),
+            response: response.clone(),
+        };
+        self.storage.insert_with_ttl(key, cached, ttl).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use serde_json_bytes::json;
+    use tower::Service;
+
+    use super::*;
+    use crate::plugin::DynPlugin;
+    use crate::Context;
+
+    async fn response_cache(config: serde_json::Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .find(|factory| factory.name == "apollo.response_cache")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .expect("Plugin not created")
+    }
+
+    fn subgraph(
+        plugin: &dyn DynPlugin,
+        name: &str,
+        cache_control: &'static str,
+    ) -> subgraph::BoxService {
+        plugin.subgraph_service(
+            name,
+            tower::service_fn(move |request: subgraph::Request| async move {
+                let mut response = subgraph::Response::fake_builder()
+                    .data(json!({ "field": "value" }))
+                    .context(request.context)
+                    .build();
+                response
+                    .response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+                Ok::<_, BoxError>(response)
+            })
+            .boxed(),
+        )
+    }
+
+    #[tokio::test]
+    async fn it_merges_subgraph_cache_control_and_caches_responses() {
+        let plugin = response_cache(serde_json::json!({
+            "enabled": true,
+            "full_response": {
+                "enabled": true,
+                "headers": ["accept-language"]
+            }
+        }))
+        .await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let products = subgraph(plugin.as_ref(), "products", "max-age=60, public");
+        let reviews = subgraph(plugin.as_ref(), "reviews", "s-maxage=30");
+        let products = ServiceBuilder::new().buffered().service(products);
+        let reviews = ServiceBuilder::new().buffered().service(reviews);
+        let supergraph = {
+            let calls = calls.clone();
+            tower::service_fn(move |request: supergraph::Request| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let products = products.clone();
+                let reviews = reviews.clone();
+                async move {
+                    for subgraph in [products, reviews] {
+                        subgraph
+                            .oneshot(
+                                subgraph::Request::fake_builder()
+                                    .context(request.context.clone())
+                                    .build(),
+                            )
+                            .await?;
+                    }
+                    supergraph::Response::fake_builder()
+                        .data(json!({ "field": "value" }))
+                        .context(request.context)
+                        .build()
+                }
+            })
+            .boxed()
+        };
+        let mut service = plugin.supergraph_service(supergraph);
+
+        let request = || {
+            supergraph::Request::fake_builder()
+                .query("{ field }")
+                .header("accept-language", "en")
+                .build()
+                .unwrap()
+        };
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.headers().get(CACHE_CONTROL).unwrap(),
+            "max-age=30, public"
+        );
+        assert_eq!(
+            response.response.headers().get(VARY).unwrap(),
+            "origin, accept-language"
+        );
+
+        let mut response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            response.next_response().await.unwrap().data,
+            Some(json!({ "field": "value" }))
+        );
+
+        // the selected headers are part of the cache key
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(
+                supergraph::Request::fake_builder()
+                    .query("{ field }")
+                    .header("accept-language", "fr")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_keys_the_responses_on_the_access_of_the_request() {
+        let plugin = response_cache(serde_json::json!({
+            "enabled": true,
+            "full_response": {
+                "enabled": true
+            }
+        }))
+        .await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let products = ServiceBuilder::new().buffered().service(subgraph(
+            plugin.as_ref(),
+            "products",
+            "max-age=60",
+        ));
+        let supergraph = {
+            let calls = calls.clone();
+            tower::service_fn(move |request: supergraph::Request| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let products = products.clone();
+                async move {
+                    products
+                        .oneshot(
+                            subgraph::Request::fake_builder()
+                                .context(request.context.clone())
+                                .build(),
+                        )
+                        .await?;
+                    supergraph::Response::fake_builder()
+                        .data(json!({ "field": "value" }))
+                        .context(request.context)
+                        .build()
+                }
+            })
+            .boxed()
+        };
+        let mut service = plugin.supergraph_service(supergraph);
+
+        let request = |claims: Option<serde_json::Value>| {
+            let context = Context::new();
+            if let Some(claims) = claims {
+                context
+                    .insert(
+                        crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS,
+                        claims,
+                    )
+                    .unwrap();
+            }
+            supergraph::Request::fake_builder()
+                .query("{ field }")
+                .context(context)
+                .build()
+                .unwrap()
+        };
+        let admin = || Some(serde_json::json!({ "sub": "alice", "scope": "read admin" }));
+
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(None))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.headers().get(CACHE_CONTROL).unwrap(),
+            "max-age=60, public"
+        );
+
+        // the response to an anonymous request is not served to authenticated requests
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(admin()))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            response.response.headers().get(CACHE_CONTROL).unwrap(),
+            "max-age=60, private"
+        );
+
+        // requests with the same scopes share the cached response, which CDNs cannot store
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(Some(
+                serde_json::json!({ "sub": "bob", "scope": "admin read" }),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(response
+            .response
+            .headers()
+            .get(CACHE_CONTROL)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with("private"));
+
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(Some(serde_json::json!({ "sub": "carol" }))))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
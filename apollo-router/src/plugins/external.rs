@@ -9,6 +9,9 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::Bytes;
+use futures::future::ready;
+use futures::stream::once;
+use futures::StreamExt;
 use http::header::HeaderName;
 use http::HeaderMap;
 use http::HeaderValue;
@@ -29,18 +32,26 @@ use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
+use crate::services::execution;
 use crate::services::external::Control;
 use crate::services::external::Externalizable;
 use crate::services::external::PipelineStep;
 use crate::services::router;
+use crate::services::subgraph;
+use crate::services::supergraph;
 use crate::Context;
 
 pub(crate) const EXTERNAL_SPAN_NAME: &str = "external_plugin";
 
+const BREAK_DEFAULT_MESSAGE: &str = "request interrupted by the co-processor";
+
 #[derive(Debug)]
 struct ExternalPlugin {
     configuration: Conf,
     sdl: Arc<String>,
+    /// The client calling the co-processor at the supergraph, execution and subgraph stages,
+    /// the licensed one being used if it is not set
+    http_client: Option<reqwest::Client>,
 }
 
 /// What information is passed to a request/response stage
@@ -57,9 +68,10 @@ struct BaseConf {
     sdl: bool,
 }
 
+/// What information is passed at the request and response steps of a stage
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
 #[serde(default)]
-struct RouterStage {
+struct Stage {
     /// The request configuration
     request: Option<BaseConf>,
     /// The response configuration
@@ -70,8 +82,14 @@ struct RouterStage {
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
 #[serde(default)]
 struct Stages {
-    /// The router stage
-    router: Option<RouterStage>,
+    /// The router stage: HTTP request and response
+    router: Option<Stage>,
+    /// The supergraph stage: parsed GraphQL request and first GraphQL response
+    supergraph: Option<Stage>,
+    /// The execution stage: GraphQL request after query planning and first GraphQL response
+    execution: Option<Stage>,
+    /// The subgraph stage: requests and responses of each subgraph, sent with the subgraph name
+    subgraph: Option<Stage>,
 }
 
 /// Configures the externalization plugin
@@ -96,6 +114,7 @@ impl Plugin for ExternalPlugin {
         Ok(ExternalPlugin {
             configuration: init.config,
             sdl: init.supergraph_sdl,
+            http_client: None,
         })
     }
 
@@ -147,6 +166,7 @@ impl Plugin for ExternalPlugin {
                         request.context.enter_active_request().await;
 
                         // Second, call our co-processor and get a reply.
+                        let sent_context = context.clone();
                         let res = call_external(
                             proto_url,
                             timeout,
@@ -160,42 +180,22 @@ impl Plugin for ExternalPlugin {
 
                         request.context.leave_active_request().await;
 
-                        let co_processor_output = res?;
+                        let mut co_processor_output = res?;
 
                         tracing::debug!(?co_processor_output, "co-processor returned");
 
+                        if let Some(context) = co_processor_output.context.take() {
+                            request.context.write_back(sent_context.as_ref(), &context);
+                        }
+
                         // Thirdly, we need to interpret the control flow which may have been
                         // updated by our co-processor and decide if we should proceed or stop.
 
                         if matches!(co_processor_output.control, Control::Break(_)) {
-                            // Ensure the code is a valid http status code
-                            let code = co_processor_output.control.get_http_status()?;
-
-                            let res = if !code.is_success() {
-                                router::Response::error_builder()
-                                    .errors(vec![Error {
-                                        message: co_processor_output
-                                            .body
-                                            .unwrap_or(serde_json::Value::Null)
-                                            .to_string(),
-                                        ..Default::default()
-                                    }])
-                                    .status_code(code)
-                                    .context(request.context)
-                                    .build()?
-                            } else {
-                                router::Response::builder()
-                                    .data(
-                                        co_processor_output
-                                            .body
-                                            .unwrap_or(serde_json::Value::Null)
-                                            .to_string(),
-                                    )
-                                    .status_code(code)
-                                    .context(request.context)
-                                    .build()?
-                            };
-                            return Ok(ControlFlow::Break(res));
+                            return Ok(ControlFlow::Break(router_break_response(
+                                co_processor_output,
+                                request.context,
+                            )?));
                         }
 
                         // Finally, process our reply and act on the contents. Our processing logic is
@@ -209,10 +209,6 @@ impl Plugin for ExternalPlugin {
 
                         request.router_request = http::Request::from_parts(parts, new_body);
 
-                        if let Some(context) = co_processor_output.context {
-                            request.context = context;
-                        }
-
                         if let Some(headers) = co_processor_output.headers {
                             *request.router_request.headers_mut() =
                                 internalize_header_map(headers)?;
@@ -266,6 +262,7 @@ impl Plugin for ExternalPlugin {
                     )?;
 
                     // Second, call our co-processor and get a reply.
+                    let sent_context = context.clone();
                     let co_processor_output = call_external(
                         proto_url,
                         timeout,
@@ -292,7 +289,7 @@ impl Plugin for ExternalPlugin {
                     response.response = http::Response::from_parts(parts, new_body);
 
                     if let Some(context) = co_processor_output.context {
-                        response.context = context;
+                        response.context.write_back(sent_context.as_ref(), &context);
                     }
 
                     if let Some(headers) = co_processor_output.headers {
@@ -324,6 +321,534 @@ impl Plugin for ExternalPlugin {
             .service(service)
             .boxed()
     }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let stage = self.stage(|stages| stages.supergraph.as_ref());
+
+        let request_layer = stage.request.map(|request_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            AsyncCheckpointLayer::new(move |mut request: supergraph::Request| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let request_config = request_config.clone();
+                async move {
+                    request.context.enter_active_request().await;
+                    let res = call_stage(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::SupergraphRequest,
+                        &request_config,
+                        request.supergraph_request.headers(),
+                        request.supergraph_request.body(),
+                        &request.context,
+                        &sdl,
+                        None,
+                    )
+                    .await;
+                    request.context.leave_active_request().await;
+                    let co_processor_output = res?;
+
+                    if matches!(co_processor_output.control, Control::Break(_)) {
+                        return Ok(ControlFlow::Break(supergraph_break_response(
+                            co_processor_output,
+                            request.context,
+                        )?));
+                    }
+
+                    let (mut parts, mut body) = request.supergraph_request.into_parts();
+                    internalize(co_processor_output, &mut parts.headers, &mut body)?;
+                    request.supergraph_request = http::Request::from_parts(parts, body);
+
+                    Ok(ControlFlow::Continue(request))
+                }
+            })
+        });
+
+        let response_layer = stage.response.map(|response_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            MapFutureLayer::new(move |fut| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let response_config = response_config.clone();
+                async move {
+                    let response: supergraph::Response = fut.await?;
+                    process_first_response(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::SupergraphResponse,
+                        &response_config,
+                        &sdl,
+                        response,
+                    )
+                    .await
+                }
+            })
+        });
+
+        if request_layer.is_none() && response_layer.is_none() {
+            return service;
+        }
+
+        ServiceBuilder::new()
+            .instrument(|_request: &supergraph::Request| {
+                tracing::info_span!(
+                    EXTERNAL_SPAN_NAME,
+                    "external service" = stringify!(supergraph::Request),
+                    "otel.kind" = "INTERNAL"
+                )
+            })
+            .option_layer(request_layer)
+            .option_layer(response_layer)
+            .buffered()
+            .service(service)
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        let stage = self.stage(|stages| stages.execution.as_ref());
+
+        let request_layer = stage.request.map(|request_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            AsyncCheckpointLayer::new(move |mut request: execution::Request| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let request_config = request_config.clone();
+                async move {
+                    request.context.enter_active_request().await;
+                    let res = call_stage(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::ExecutionRequest,
+                        &request_config,
+                        request.supergraph_request.headers(),
+                        request.supergraph_request.body(),
+                        &request.context,
+                        &sdl,
+                        None,
+                    )
+                    .await;
+                    request.context.leave_active_request().await;
+                    let co_processor_output = res?;
+
+                    if matches!(co_processor_output.control, Control::Break(_)) {
+                        return Ok(ControlFlow::Break(supergraph_break_response(
+                            co_processor_output,
+                            request.context,
+                        )?));
+                    }
+
+                    let (mut parts, mut body) = request.supergraph_request.into_parts();
+                    internalize(co_processor_output, &mut parts.headers, &mut body)?;
+                    request.supergraph_request = http::Request::from_parts(parts, body);
+
+                    Ok(ControlFlow::Continue(request))
+                }
+            })
+        });
+
+        let response_layer = stage.response.map(|response_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            MapFutureLayer::new(move |fut| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let response_config = response_config.clone();
+                async move {
+                    let response: execution::Response = fut.await?;
+                    process_first_response(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::ExecutionResponse,
+                        &response_config,
+                        &sdl,
+                        response,
+                    )
+                    .await
+                }
+            })
+        });
+
+        if request_layer.is_none() && response_layer.is_none() {
+            return service;
+        }
+
+        ServiceBuilder::new()
+            .instrument(|_request: &execution::Request| {
+                tracing::info_span!(
+                    EXTERNAL_SPAN_NAME,
+                    "external service" = stringify!(execution::Request),
+                    "otel.kind" = "INTERNAL"
+                )
+            })
+            .option_layer(request_layer)
+            .option_layer(response_layer)
+            .buffered()
+            .service(service)
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let stage = self.stage(|stages| stages.subgraph.as_ref());
+
+        let request_layer = stage.request.map(|request_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            let service_name = name.to_string();
+            AsyncCheckpointLayer::new(move |mut request: subgraph::Request| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let request_config = request_config.clone();
+                let service_name = service_name.clone();
+                async move {
+                    request.context.enter_active_request().await;
+                    let res = call_stage(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::SubgraphRequest,
+                        &request_config,
+                        request.subgraph_request.headers(),
+                        request.subgraph_request.body(),
+                        &request.context,
+                        &sdl,
+                        Some(service_name),
+                    )
+                    .await;
+                    request.context.leave_active_request().await;
+                    let co_processor_output = res?;
+
+                    if matches!(co_processor_output.control, Control::Break(_)) {
+                        return Ok(ControlFlow::Break(subgraph_break_response(
+                            co_processor_output,
+                            request.context,
+                        )?));
+                    }
+
+                    let (mut parts, mut body) = request.subgraph_request.into_parts();
+                    internalize(co_processor_output, &mut parts.headers, &mut body)?;
+                    request.subgraph_request = http::Request::from_parts(parts, body);
+
+                    Ok(ControlFlow::Continue(request))
+                }
+            })
+        });
+
+        let response_layer = stage.response.map(|response_config| {
+            let configuration = self.configuration.clone();
+            let sdl = self.sdl.clone();
+            let http_client = self.http_client.clone();
+            let service_name = name.to_string();
+            MapFutureLayer::new(move |fut| {
+                let configuration = configuration.clone();
+                let sdl = sdl.clone();
+                let http_client = http_client.clone();
+                let response_config = response_config.clone();
+                let service_name = service_name.clone();
+                async move {
+                    let response: subgraph::Response = fut.await?;
+                    let subgraph::Response { response, context } = response;
+                    let (mut parts, mut body) = response.into_parts();
+
+                    let co_processor_output = call_stage(
+                        &configuration,
+                        http_client.as_ref(),
+                        PipelineStep::SubgraphResponse,
+                        &response_config,
+                        &parts.headers,
+                        &body,
+                        &context,
+                        &sdl,
+                        Some(service_name),
+                    )
+                    .await?;
+                    internalize(co_processor_output, &mut parts.headers, &mut body)?;
+
+                    Ok::<subgraph::Response, BoxError>(subgraph::Response::new_from_response(
+                        http::Response::from_parts(parts, body),
+                        context,
+                    ))
+                }
+            })
+        });
+
+        if request_layer.is_none() && response_layer.is_none() {
+            return service;
+        }
+
+        ServiceBuilder::new()
+            .instrument(|_request: &subgraph::Request| {
+                tracing::info_span!(
+                    EXTERNAL_SPAN_NAME,
+                    "external service" = stringify!(subgraph::Request),
+                    "otel.kind" = "INTERNAL"
+                )
+            })
+            .option_layer(request_layer)
+            .option_layer(response_layer)
+            .buffered()
+            .service(service)
+            .boxed()
+    }
+}
+
+impl ExternalPlugin {
+    /// Returns the configuration of a stage, empty if it is not configured
+    fn stage(&self, select: impl Fn(&Stages) -> Option<&Stage>) -> Stage {
+        self.configuration
+            .stages
+            .as_ref()
+            .and_then(select)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Sends the parts of a request or response selected by `config` to the co-processor,
+/// and returns its reply, after writing back the context it sent back.
+#[allow(clippy::too_many_arguments)]
+async fn call_stage<T>(
+    configuration: &Conf,
+    http_client: Option<&reqwest::Client>,
+    stage: PipelineStep,
+    config: &BaseConf,
+    headers: &HeaderMap<HeaderValue>,
+    body: &T,
+    context: &Context,
+    sdl: &Arc<String>,
+    service_name: Option<String>,
+) -> Result<Externalizable<serde_json::Value>, BoxError>
+where
+    T: Serialize,
+{
+    let sent_context = config.context.then(|| context.snapshot());
+    let output = Externalizable::new(
+        stage,
+        config
+            .headers
+            .then(|| externalize_header_map(headers))
+            .transpose()?,
+        config
+            .body
+            .then(|| serde_json::to_value(body))
+            .transpose()?,
+        sent_context.clone(),
+        config.sdl.then(|| sdl.to_string()),
+        service_name,
+    );
+    tracing::debug!(?output, "externalized output");
+
+    let mut co_processor_output = match http_client {
+        Some(http_client) => {
+            output
+                .call_with_client(
+                    http_client.clone(),
+                    &configuration.url,
+                    configuration.timeout,
+                )
+                .await?
+        }
+        None => {
+            output
+                .call(&configuration.url, configuration.timeout)
+                .await?
+        }
+    };
+    tracing::debug!(?co_processor_output, "co-processor returned");
+
+    if let Some(returned_context) = co_processor_output.context.take() {
+        context.write_back(sent_context.as_ref(), &returned_context);
+    }
+
+    Ok(co_processor_output)
+}
+
+/// Replaces the headers and body of a request or response with the ones that the co-processor
+/// sent back.
+fn internalize<T>(
+    co_processor_output: Externalizable<serde_json::Value>,
+    headers: &mut HeaderMap<HeaderValue>,
+    body: &mut T,
+) -> Result<(), BoxError>
+where
+    T: DeserializeOwned,
+{
+    if let Some(new_body) = co_processor_output.body {
+        *body = serde_json::from_value(new_body)?;
+    }
+
+    if let Some(new_headers) = co_processor_output.headers {
+        *headers = internalize_header_map(new_headers)?;
+    }
+
+    Ok(())
+}
+
+/// Sends the first GraphQL response of a supergraph or execution response to the co-processor.
+///
+/// The following responses, like deferred ones, are passed through unchanged.
+async fn process_first_response(
+    configuration: &Conf,
+    http_client: Option<&reqwest::Client>,
+    stage: PipelineStep,
+    config: &BaseConf,
+    sdl: &Arc<String>,
+    response: supergraph::Response,
+) -> Result<supergraph::Response, BoxError> {
+    let supergraph::Response { response, context } = response;
+    let (mut parts, mut stream) = response.into_parts();
+
+    let mut first = match stream.next().await {
+        Some(first) => first,
+        None => {
+            return Ok(supergraph::Response::new_from_response(
+                http::Response::from_parts(parts, stream),
+                context,
+            ))
+        }
+    };
+
+    let co_processor_output = call_stage(
+        configuration,
+        http_client,
+        stage,
+        config,
+        &parts.headers,
+        &first,
+        &context,
+        sdl,
+        None,
+    )
+    .await?;
+    internalize(co_processor_output, &mut parts.headers, &mut first)?;
+
+    Ok(supergraph::Response::new_from_response(
+        http::Response::from_parts(parts, once(ready(first)).chain(stream).boxed()),
+        context,
+    ))
+}
+
+/// Creates the response returned to the client when the co-processor breaks at the router stage
+fn router_break_response(
+    co_processor_output: Externalizable<serde_json::Value>,
+    context: Context,
+) -> Result<router::Response, BoxError> {
+    // Ensure the code is a valid http status code
+    let code = co_processor_output.control.get_http_status()?;
+
+    if !code.is_success() {
+        router::Response::error_builder()
+            .errors(break_errors(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build()
+    } else {
+        router::Response::builder()
+            .data(break_data(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build()
+    }
+}
+
+/// Creates the response returned to the client when the co-processor breaks
+/// at the supergraph or execution stage
+fn supergraph_break_response(
+    co_processor_output: Externalizable<serde_json::Value>,
+    context: Context,
+) -> Result<supergraph::Response, BoxError> {
+    // Ensure the code is a valid http status code
+    let code = co_processor_output.control.get_http_status()?;
+
+    if !code.is_success() {
+        supergraph::Response::error_builder()
+            .errors(break_errors(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build()
+    } else {
+        supergraph::Response::builder()
+            .data(break_data(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build()
+    }
+}
+
+/// Creates the response returned to the execution service when the co-processor breaks
+/// at the subgraph stage
+fn subgraph_break_response(
+    co_processor_output: Externalizable<serde_json::Value>,
+    context: Context,
+) -> Result<subgraph::Response, BoxError> {
+    // Ensure the code is a valid http status code
+    let code = co_processor_output.control.get_http_status()?;
+
+    if !code.is_success() {
+        subgraph::Response::error_builder()
+            .errors(break_errors(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build()
+    } else {
+        Ok(subgraph::Response::builder()
+            .data(break_data(co_processor_output.body)?)
+            .status_code(code)
+            .context(context)
+            .build())
+    }
+}
+
+/// Extracts the errors of a break response from the co-processor body.
+///
+/// The body can be a GraphQL response containing an `errors` array, or any other JSON value,
+/// which is then used as the message of a single error.
+fn break_errors(body: Option<serde_json::Value>) -> Result<Vec<Error>, BoxError> {
+    let errors = match body {
+        Some(serde_json::Value::Object(mut body)) if body.contains_key("errors") => {
+            serde_json::from_value(body.remove("errors").unwrap_or_default())?
+        }
+        Some(serde_json::Value::String(message)) => vec![Error {
+            message,
+            ..Default::default()
+        }],
+        Some(body) => vec![Error {
+            message: body.to_string(),
+            ..Default::default()
+        }],
+        None => vec![Error {
+            message: BREAK_DEFAULT_MESSAGE.to_string(),
+            ..Default::default()
+        }],
+    };
+
+    Ok(errors)
+}
+
+/// Extracts the data of a successful break response from the co-processor body.
+///
+/// If the body is a GraphQL response, its `data` field is used, otherwise the whole body is.
+fn break_data(body: Option<serde_json::Value>) -> Result<serde_json_bytes::Value, BoxError> {
+    let data = match body {
+        Some(serde_json::Value::Object(mut body)) if body.contains_key("data") => {
+            body.remove("data").unwrap_or_default()
+        }
+        Some(body) => body,
+        None => serde_json::Value::Null,
+    };
+
+    Ok(serde_json_bytes::to_value(data)?)
 }
 
 type ExternalParams<'a> = (
@@ -356,7 +881,7 @@ fn prepare_external_params<'a>(
         }
     }
     if config.context {
-        context_opt = Some(context.clone());
+        context_opt = Some(context.snapshot());
     }
     if config.sdl {
         sdl_opt = Some(sdl);
@@ -380,7 +905,7 @@ where
     if let Some(hdrs) = headers {
         converted_headers = Some(externalize_header_map(hdrs)?);
     };
-    let output = Externalizable::new(stage, converted_headers, payload, context, sdl);
+    let output = Externalizable::new(stage, converted_headers, payload, context, sdl, None);
     tracing::debug!(?output, "externalized output");
     output.call(&url, timeout).await
 }
@@ -430,6 +955,10 @@ mod tests {
     use mime::TEXT_HTML;
 
     use super::*;
+    use crate::graphql;
+    use crate::plugin::test::MockExecutionService;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::test::MockSupergraphService;
 
     #[tokio::test]
     async fn load_plugin() {
@@ -451,6 +980,450 @@ mod tests {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn load_plugin_with_all_stages() {
+        let config = serde_json::json!({
+            "plugins": {
+                "experimental.external": {
+                    "url": "http://127.0.0.1:8081",
+                    "stages": {
+                        "router": {
+                            "request": { "headers": true }
+                        },
+                        "supergraph": {
+                            "request": { "headers": true, "body": true },
+                            "response": { "context": true }
+                        },
+                        "execution": {
+                            "request": { "sdl": true }
+                        },
+                        "subgraph": {
+                            "request": { "headers": true, "body": true },
+                            "response": { "body": true }
+                        }
+                    }
+                }
+            }
+        });
+        let _test_harness = crate::TestHarness::builder()
+            .configuration_json(config)
+            .unwrap()
+            .build_router()
+            .await
+            .unwrap();
+    }
+
+    /// Starts a stand-in co-processor, which replies with the output of `reply`
+    async fn coprocessor<F>(reply: F) -> String
+    where
+        F: Fn(Externalizable<serde_json::Value>) -> Externalizable<serde_json::Value>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(
+                move |axum::Json(input): axum::Json<Externalizable<serde_json::Value>>| {
+                    let output = reply(input);
+                    async move { axum::Json(output) }
+                },
+            ),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::task::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{address}/")
+    }
+
+    async fn call_coprocessor(
+        url: &str,
+        stage: PipelineStep,
+        body: serde_json::Value,
+        context: Option<Context>,
+    ) -> Externalizable<serde_json::Value> {
+        Externalizable::new(stage, None, Some(body), context, None, None)
+            .call_with_client(reqwest::Client::new(), url, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_breaks_with_the_coprocessor_errors() {
+        let url = coprocessor(|mut output| {
+            output.control = Control::Break(401);
+            output.body = Some(serde_json::json!({
+                "errors": [{
+                    "message": "unauthorized",
+                    "extensions": { "code": "UNAUTHENTICATED" }
+                }]
+            }));
+            output
+        })
+        .await;
+
+        let output = call_coprocessor(
+            &url,
+            PipelineStep::SupergraphRequest,
+            serde_json::json!({ "query": "{ me { name } }" }),
+            None,
+        )
+        .await;
+        let mut response = supergraph_break_response(output, Context::new()).unwrap();
+        assert_eq!(response.response.status(), 401);
+        let response = response.next_response().await.unwrap();
+        assert_eq!(response.data, None);
+        assert_eq!(
+            response.errors,
+            vec![Error::builder()
+                .message("unauthorized")
+                .extension_code("UNAUTHENTICATED")
+                .build()]
+        );
+
+        let output = call_coprocessor(
+            &url,
+            PipelineStep::RouterRequest,
+            serde_json::json!({ "query": "{ me { name } }" }),
+            None,
+        )
+        .await;
+        let response = router_break_response(output, Context::new()).unwrap();
+        assert_eq!(response.response.status(), 401);
+        let body = hyper::body::to_bytes(response.response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "errors": [{
+                    "message": "unauthorized",
+                    "extensions": { "code": "UNAUTHENTICATED" }
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn it_breaks_with_a_message() {
+        let url = coprocessor(|mut output| {
+            output.control = Control::Break(403);
+            output.body = Some(serde_json::json!("forbidden"));
+            output
+        })
+        .await;
+
+        let output = call_coprocessor(
+            &url,
+            PipelineStep::SubgraphRequest,
+            serde_json::json!({ "query": "{ me { name } }" }),
+            None,
+        )
+        .await;
+        let response = subgraph_break_response(output, Context::new()).unwrap();
+        assert_eq!(response.response.status(), 403);
+        assert_eq!(
+            response.response.body().errors,
+            vec![Error {
+                message: "forbidden".to_string(),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_writes_back_the_coprocessor_context() {
+        let url = coprocessor(|mut output| {
+            let sent = output.context.take().unwrap();
+            let context = Context::new();
+            for entry in sent.iter().filter(|entry| entry.key() != "removed") {
+                context.insert_json_value(entry.key().clone(), entry.value().clone());
+            }
+            context.insert("changed", "updated".to_string()).unwrap();
+            context.insert("added", true).unwrap();
+            output.context = Some(context);
+            output
+        })
+        .await;
+
+        let context = Context::new();
+        context.insert("kept", 1).unwrap();
+        context.insert("changed", "original".to_string()).unwrap();
+        context.insert("removed", 1).unwrap();
+        // a clone shares its entries, like the context held by the rest of the pipeline
+        let pipeline_context = context.clone();
+
+        let configuration = Conf {
+            url,
+            ..Default::default()
+        };
+        let config = BaseConf {
+            context: true,
+            body: true,
+            ..Default::default()
+        };
+        let output = call_stage(
+            &configuration,
+            Some(&reqwest::Client::new()),
+            PipelineStep::SupergraphResponse,
+            &config,
+            &HeaderMap::new(),
+            &serde_json::json!({ "data": { "me": { "name": "Ada" } } }),
+            &context,
+            &Arc::new(String::new()),
+            None,
+        )
+        .await
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        let mut body = graphql::Response::default();
+        internalize(output, &mut headers, &mut body).unwrap();
+
+        assert_eq!(
+            body.data,
+            Some(serde_json_bytes::json!({ "me": { "name": "Ada" } }))
+        );
+        assert_eq!(pipeline_context.get::<_, u32>("kept").unwrap(), Some(1));
+        assert_eq!(
+            pipeline_context.get::<_, String>("changed").unwrap(),
+            Some("updated".to_string())
+        );
+        assert_eq!(
+            pipeline_context.get::<_, bool>("added").unwrap(),
+            Some(true)
+        );
+        // the co-processor dropped the entry
+        assert!(!pipeline_context.contains_key("removed"));
+    }
+
+    /// Builds the plugin, sending the headers and body of the requests and responses of `stage`
+    async fn external_plugin(url: &str, stage: &str) -> ExternalPlugin {
+        let config = serde_json::json!({
+            "url": url,
+            "stages": {
+                stage: {
+                    "request": { "headers": true, "body": true },
+                    "response": { "headers": true, "body": true }
+                }
+            }
+        });
+        let mut plugin =
+            ExternalPlugin::new(PluginInit::try_new(config, Default::default()).unwrap())
+                .await
+                .unwrap();
+        // the licensed client is not available in tests
+        plugin.http_client = Some(reqwest::Client::new());
+        plugin
+    }
+
+    /// Co-processor replacing the query of requests and the data of responses, and adding a
+    /// header naming the stage and the subgraph
+    fn rewrite(mut output: Externalizable<serde_json::Value>) -> Externalizable<serde_json::Value> {
+        let mut headers = output.headers.take().unwrap_or_default();
+        headers.insert("x-stage".to_string(), vec![output.stage.clone()]);
+        if let Some(service_name) = &output.service_name {
+            headers.insert("x-service-name".to_string(), vec![service_name.clone()]);
+        }
+        output.headers = Some(headers);
+        let body = output.body.as_mut().unwrap();
+        if output.stage.ends_with("Request") {
+            body["query"] = serde_json::json!("{ rewritten }");
+        } else {
+            body["data"] = serde_json::json!({ "rewritten": true });
+        }
+        output
+    }
+
+    /// Co-processor breaking at every stage
+    fn forbid(mut output: Externalizable<serde_json::Value>) -> Externalizable<serde_json::Value> {
+        output.control = Control::Break(403);
+        output.body = Some(serde_json::json!({ "errors": [{ "message": "forbidden" }] }));
+        output
+    }
+
+    fn graphql_request(query: &str) -> http::Request<graphql::Request> {
+        http::Request::builder()
+            .body(graphql::Request::builder().query(query).build())
+            .unwrap()
+    }
+
+    fn forbidden() -> Vec<Error> {
+        vec![Error {
+            message: "forbidden".to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[tokio::test]
+    async fn it_rewrites_supergraph_requests_and_responses() {
+        let url = coprocessor(rewrite).await;
+        let plugin = external_plugin(&url, "supergraph").await;
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .withf(|request| {
+                request.supergraph_request.body().query.as_deref() == Some("{ rewritten }")
+                    && request.supergraph_request.headers()["x-stage"] == "SupergraphRequest"
+            })
+            .returning(|request| {
+                Ok(supergraph::Response::fake_builder()
+                    .data(serde_json_bytes::json!({ "original": true }))
+                    .context(request.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let request = supergraph::Request::fake_builder()
+            .query("{ original }")
+            .build()
+            .unwrap();
+        let mut response = plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.response.headers()["x-stage"], "SupergraphResponse");
+        assert_eq!(
+            response.next_response().await.unwrap().data,
+            Some(serde_json_bytes::json!({ "rewritten": true }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_breaks_at_the_supergraph_stage() {
+        let url = coprocessor(forbid).await;
+        let plugin = external_plugin(&url, "supergraph").await;
+
+        // the mock panics if it is called
+        let request = supergraph::Request::fake_builder()
+            .query("{ original }")
+            .build()
+            .unwrap();
+        let mut response = plugin
+            .supergraph_service(MockSupergraphService::new().boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), 403);
+        assert_eq!(response.next_response().await.unwrap().errors, forbidden());
+    }
+
+    #[tokio::test]
+    async fn it_rewrites_execution_requests_and_responses() {
+        let url = coprocessor(rewrite).await;
+        let plugin = external_plugin(&url, "execution").await;
+
+        let mut mock_service = MockExecutionService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .withf(|request| {
+                request.supergraph_request.body().query.as_deref() == Some("{ rewritten }")
+                    && request.supergraph_request.headers()["x-stage"] == "ExecutionRequest"
+            })
+            .returning(|request| {
+                Ok(execution::Response::fake_builder()
+                    .data(serde_json_bytes::json!({ "original": true }))
+                    .context(request.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let request = execution::Request::fake_builder()
+            .supergraph_request(graphql_request("{ original }"))
+            .build();
+        let mut response = plugin
+            .execution_service(mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.response.headers()["x-stage"], "ExecutionResponse");
+        assert_eq!(
+            response.next_response().await.unwrap().data,
+            Some(serde_json_bytes::json!({ "rewritten": true }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_breaks_at_the_execution_stage() {
+        let url = coprocessor(forbid).await;
+        let plugin = external_plugin(&url, "execution").await;
+
+        let request = execution::Request::fake_builder()
+            .supergraph_request(graphql_request("{ original }"))
+            .build();
+        let mut response = plugin
+            .execution_service(MockExecutionService::new().boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), 403);
+        assert_eq!(response.next_response().await.unwrap().errors, forbidden());
+    }
+
+    #[tokio::test]
+    async fn it_rewrites_subgraph_requests_and_responses() {
+        let url = coprocessor(rewrite).await;
+        let plugin = external_plugin(&url, "subgraph").await;
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .withf(|request| {
+                let headers = request.subgraph_request.headers();
+                request.subgraph_request.body().query.as_deref() == Some("{ rewritten }")
+                    && headers["x-stage"] == "SubgraphRequest"
+                    && headers["x-service-name"] == "products"
+            })
+            .returning(|request| {
+                Ok(subgraph::Response::fake_builder()
+                    .data(serde_json_bytes::json!({ "original": true }))
+                    .context(request.context)
+                    .build())
+            });
+
+        let request = subgraph::Request::fake_builder()
+            .subgraph_request(graphql_request("{ original }"))
+            .build();
+        let response = plugin
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let headers = response.response.headers();
+        assert_eq!(headers["x-stage"], "SubgraphResponse");
+        assert_eq!(headers["x-service-name"], "products");
+        assert_eq!(
+            response.response.body().data,
+            Some(serde_json_bytes::json!({ "rewritten": true }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_breaks_at_the_subgraph_stage() {
+        let url = coprocessor(forbid).await;
+        let plugin = external_plugin(&url, "subgraph").await;
+
+        let request = subgraph::Request::fake_builder()
+            .subgraph_request(graphql_request("{ original }"))
+            .build();
+        let response = plugin
+            .subgraph_service("products", MockSubgraphService::new().boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), 403);
+        assert_eq!(response.response.body().errors, forbidden());
+    }
+
     #[test]
     fn it_externalizes_headers() {
         // Build our expected HashMap
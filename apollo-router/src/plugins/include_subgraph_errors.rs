@@ -65,6 +65,8 @@ impl Plugin for IncludeSubgraphErrors {
                     _error = Box::new(crate::error::FetchError::SubrequestHttpError {
                         service: "redacted".to_string(),
                         reason: "redacted".to_string(),
+                        status_code: None,
+                        retry_after: None,
                     });
                     _error
                 })
@@ -209,6 +211,7 @@ mod test {
             &Configuration::default(),
         )
         .await
+        .expect("should create the router")
         .make()
         .boxed()
     }
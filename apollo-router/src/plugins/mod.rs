@@ -20,7 +20,8 @@ macro_rules! schemar_fn {
     };
 }
 
-mod authentication;
+pub(crate) mod authentication;
+pub(crate) mod cache;
 pub(crate) mod csrf;
 mod expose_query_plan;
 mod external;
@@ -29,5 +30,6 @@ mod headers;
 mod include_subgraph_errors;
 pub(crate) mod override_url;
 pub(crate) mod rhai;
+pub(crate) mod subscription;
 pub(crate) mod telemetry;
 pub(crate) mod traffic_shaping;
@@ -0,0 +1,148 @@
+//! Configuration for GraphQL subscriptions.
+
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
+
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::protocols::websocket::WebSocketProtocol;
+use crate::register_plugin;
+
+pub(crate) const APOLLO_SUBSCRIPTION_PLUGIN: &str = "apollo.subscription";
+pub(crate) const APOLLO_SUBSCRIPTION_PLUGIN_NAME: &str = "subscription";
+
+#[derive(Debug, Clone)]
+pub(crate) struct Subscription {
+    pub(crate) config: SubscriptionConfig,
+}
+
+/// Subscriptions configuration
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SubscriptionConfig {
+    /// Enable subscription
+    pub(crate) enabled: bool,
+    /// Select a subscription mode (passthrough)
+    pub(crate) mode: SubscriptionModeConfig,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: Default::default(),
+        }
+    }
+}
+
+/// Subscription mode configuration
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SubscriptionModeConfig {
+    /// Enable passthrough mode for subgraph(s): the router opens a WebSocket connection to the subgraph and forwards its events
+    pub(crate) passthrough: SubgraphPassthroughMode,
+}
+
+/// Passthrough mode configuration, for all subgraphs or per subgraph
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SubgraphPassthroughMode {
+    /// Configuration for all subgraphs
+    pub(crate) all: Option<WebSocketConfiguration>,
+    /// Configuration for specific subgraphs, overriding `all`
+    pub(crate) subgraphs: HashMap<String, WebSocketConfiguration>,
+}
+
+impl SubgraphPassthroughMode {
+    /// Returns the WebSocket configuration to use for this subgraph, if any
+    pub(crate) fn subgraph(&self, subgraph_name: &str) -> Option<WebSocketConfiguration> {
+        self.subgraphs
+            .get(subgraph_name)
+            .or(self.all.as_ref())
+            .cloned()
+    }
+}
+
+/// WebSocket configuration for a subgraph
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct WebSocketConfiguration {
+    /// Path on which WebSockets are served by the subgraph (default: the path of the subgraph URL)
+    pub(crate) path: Option<String>,
+    /// Which WebSocket GraphQL protocol to use for this subgraph (possible values: 'graphql_ws' | 'graphql_transport_ws', default: 'graphql_ws')
+    pub(crate) protocol: WebSocketProtocol,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Subscription {
+    type Config = SubscriptionConfig;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(Subscription {
+            config: init.config,
+        })
+    }
+}
+
+register_plugin!("apollo", "subscription", Subscription);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::plugin::DynPlugin;
+
+    #[tokio::test]
+    async fn it_selects_the_subgraph_configuration() {
+        let dyn_plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .find(|factory| factory.name == APOLLO_SUBSCRIPTION_PLUGIN)
+            .expect("Plugin not found")
+            .create_instance(
+                &Value::from_str(
+                    r#"{
+                "mode": {
+                    "passthrough": {
+                        "all": {
+                            "path": "/ws"
+                        },
+                        "subgraphs": {
+                            "reviews": {
+                                "path": "/subscriptions",
+                                "protocol": "graphql_transport_ws"
+                            }
+                        }
+                    }
+                }
+            }"#,
+                )
+                .unwrap(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let subscription = dyn_plugin.as_any().downcast_ref::<Subscription>().unwrap();
+
+        assert!(subscription.config.enabled);
+        assert_eq!(
+            subscription.config.mode.passthrough.subgraph("reviews"),
+            Some(WebSocketConfiguration {
+                path: Some("/subscriptions".to_string()),
+                protocol: WebSocketProtocol::GraphqlTransportWs,
+            })
+        );
+        assert_eq!(
+            subscription.config.mode.passthrough.subgraph("accounts"),
+            Some(WebSocketConfiguration {
+                path: Some("/ws".to_string()),
+                protocol: WebSocketProtocol::GraphqlWs,
+            })
+        );
+    }
+}
@@ -1682,6 +1682,8 @@ mod tests {
                 Err(Box::new(FetchError::SubrequestHttpError {
                     service: String::from("my_subgraph_name_error"),
                     reason: String::from("cannot contact the subgraph"),
+                    status_code: None,
+                    retry_after: None,
                 }))
             });
 
@@ -0,0 +1,465 @@
+//! Circuit breaker for subgraphs.
+//!
+//! The circuit is closed while the subgraph is healthy. It opens when the ratio of failed requests
+//! in a rolling window, or the number of consecutive failures, reaches its threshold: requests
+//! then fail fast without being sent. After a while, the circuit is half open and lets trial
+//! requests through, closing the circuit if they succeed or opening it again if one fails.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::Merge;
+use super::RateLimited;
+use crate::graphql;
+use crate::services::subgraph;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_FAILURE_RATIO: f64 = 0.5;
+const DEFAULT_MIN_REQUESTS: u32 = 20;
+const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(5);
+const DEFAULT_HALF_OPEN_REQUESTS: u32 = 1;
+
+/// Number of buckets of the rolling window
+const WINDOW_BUCKETS: u32 = 10;
+
+/// Circuit breaker configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CircuitBreakerConfig {
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Rolling window in which failed requests are counted (default: 10s)
+    window: Option<Duration>,
+    /// Opens the circuit when the ratio of failed requests in the window reaches this value,
+    /// between 0 and 1 (default: 0.5)
+    failure_ratio: Option<f64>,
+    /// Minimum number of requests in the window before the failure ratio is evaluated (default: 20)
+    min_requests: Option<u32>,
+    /// Opens the circuit after this number of consecutive failed requests (disabled by default)
+    consecutive_failures: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long the circuit stays open before letting trial requests through (default: 5s)
+    open_duration: Option<Duration>,
+    /// Number of successful trial requests closing the circuit when it is half open (default: 1)
+    half_open_requests: Option<u32>,
+}
+
+impl Merge for CircuitBreakerConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => CircuitBreakerConfig {
+                window: self.window.or(fallback.window),
+                failure_ratio: self.failure_ratio.or(fallback.failure_ratio),
+                min_requests: self.min_requests.or(fallback.min_requests),
+                consecutive_failures: self.consecutive_failures.or(fallback.consecutive_failures),
+                open_duration: self.open_duration.or(fallback.open_duration),
+                half_open_requests: self.half_open_requests.or(fallback.half_open_requests),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, successes: u32 },
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    successes: u32,
+    failures: u32,
+}
+
+struct Inner {
+    state: State,
+    /// counts of the rolling window, the last bucket being the current one
+    buckets: VecDeque<Bucket>,
+    current_bucket_start: Instant,
+    consecutive_failures: u32,
+}
+
+/// The circuit breaker of a subgraph, shared by its services
+pub(crate) struct CircuitBreaker {
+    subgraph: String,
+    window: Duration,
+    failure_ratio: f64,
+    min_requests: u32,
+    consecutive_failures: Option<u32>,
+    open_duration: Duration,
+    half_open_requests: u32,
+    inner: Mutex<Inner>,
+}
+
+/// Whether a request was let through while the circuit is closed or as a trial request
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Permit {
+    Closed,
+    Trial,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(subgraph: &str, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            subgraph: subgraph.to_string(),
+            window: config.window.unwrap_or(DEFAULT_WINDOW),
+            failure_ratio: config.failure_ratio.unwrap_or(DEFAULT_FAILURE_RATIO),
+            min_requests: config.min_requests.unwrap_or(DEFAULT_MIN_REQUESTS),
+            consecutive_failures: config.consecutive_failures,
+            open_duration: config.open_duration.unwrap_or(DEFAULT_OPEN_DURATION),
+            half_open_requests: config
+                .half_open_requests
+                .unwrap_or(DEFAULT_HALF_OPEN_REQUESTS)
+                .max(1),
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                buckets: VecDeque::from([Bucket::default()]),
+                current_bucket_start: Instant::now(),
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Lets a request through, unless the circuit is open
+    fn acquire(&self) -> Option<Permit> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        match inner.state {
+            State::Closed => Some(Permit::Closed),
+            State::Open { until } => {
+                if Instant::now() < until {
+                    return None;
+                }
+                self.transition(
+                    &mut inner,
+                    State::HalfOpen {
+                        in_flight: 1,
+                        successes: 0,
+                    },
+                );
+                Some(Permit::Trial)
+            }
+            State::HalfOpen {
+                in_flight,
+                successes,
+            } => {
+                if in_flight + successes >= self.half_open_requests {
+                    return None;
+                }
+                inner.state = State::HalfOpen {
+                    in_flight: in_flight + 1,
+                    successes,
+                };
+                Some(Permit::Trial)
+            }
+        }
+    }
+
+    /// Records the outcome of a request, `None` if it was cancelled
+    fn record(&self, permit: Permit, success: Option<bool>) {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        match (inner.state, permit) {
+            (State::Closed, Permit::Closed) => {
+                let success = match success {
+                    Some(success) => success,
+                    None => return,
+                };
+                self.rotate_buckets(&mut inner);
+                let bucket = inner.buckets.back_mut().expect("there is always a bucket");
+                if success {
+                    bucket.successes += 1;
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                bucket.failures += 1;
+                inner.consecutive_failures += 1;
+
+                let (successes, failures) =
+                    inner
+                        .buckets
+                        .iter()
+                        .fold((0u32, 0u32), |(successes, failures), bucket| {
+                            (successes + bucket.successes, failures + bucket.failures)
+                        });
+                let requests = successes + failures;
+                let ratio_exceeded = requests >= self.min_requests
+                    && f64::from(failures) >= self.failure_ratio * f64::from(requests);
+                let consecutive_exceeded = self
+                    .consecutive_failures
+                    .map_or(false, |max| inner.consecutive_failures >= max);
+                if ratio_exceeded || consecutive_exceeded {
+                    self.open(&mut inner);
+                }
+            }
+            (
+                State::HalfOpen {
+                    in_flight,
+                    successes,
+                },
+                Permit::Trial,
+            ) => match success {
+                Some(true) if successes + 1 >= self.half_open_requests => {
+                    self.transition(&mut inner, State::Closed);
+                }
+                Some(true) => {
+                    inner.state = State::HalfOpen {
+                        in_flight: in_flight.saturating_sub(1),
+                        successes: successes + 1,
+                    };
+                }
+                Some(false) => self.open(&mut inner),
+                None => {
+                    inner.state = State::HalfOpen {
+                        in_flight: in_flight.saturating_sub(1),
+                        successes,
+                    };
+                }
+            },
+            // the state changed while the request was in flight
+            _ => {}
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        let until = Instant::now() + self.open_duration;
+        self.transition(inner, State::Open { until });
+    }
+
+    fn transition(&self, inner: &mut Inner, state: State) {
+        if state == State::Closed {
+            inner.buckets = VecDeque::from([Bucket::default()]);
+            inner.current_bucket_start = Instant::now();
+            inner.consecutive_failures = 0;
+        }
+        let previous = std::mem::replace(&mut inner.state, state);
+        if previous.name() != state.name() {
+            tracing::info!(
+                monotonic_counter.apollo_router_circuit_breaker_transitions_count = 1u64,
+                subgraph = %self.subgraph,
+                state = state.name(),
+            );
+            tracing::debug!(
+                subgraph = %self.subgraph,
+                "circuit breaker state changed from {} to {}",
+                previous.name(),
+                state.name()
+            );
+        }
+    }
+
+    fn rotate_buckets(&self, inner: &mut Inner) {
+        let bucket_duration = self.window / WINDOW_BUCKETS;
+        let now = Instant::now();
+        let elapsed = now.duration_since(inner.current_bucket_start);
+        if elapsed < bucket_duration {
+            return;
+        }
+        let new_buckets = (elapsed.as_nanos() / bucket_duration.as_nanos().max(1))
+            .min(u128::from(WINDOW_BUCKETS)) as u32;
+        for _ in 0..new_buckets {
+            inner.buckets.push_back(Bucket::default());
+        }
+        while inner.buckets.len() > WINDOW_BUCKETS as usize {
+            inner.buckets.pop_front();
+        }
+        inner.current_bucket_start = now;
+    }
+}
+
+/// Records the outcome of a request when dropped, as cancelled if it was not recorded before
+struct PermitGuard {
+    breaker: Arc<CircuitBreaker>,
+    permit: Permit,
+    success: Option<bool>,
+}
+
+impl Drop for PermitGuard {
+    fn drop(&mut self) {
+        self.breaker.record(self.permit, self.success);
+    }
+}
+
+/// Whether a subgraph request failed, for the circuit breaker
+fn is_failure(result: &Result<subgraph::Response, BoxError>) -> bool {
+    match result {
+        Ok(response) => response.response.status().is_server_error(),
+        // requests rejected by the router's own rate limit do not reach the subgraph
+        Err(error) => !error.is::<RateLimited>(),
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerLayer {
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        Self { breaker }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerService<S> {
+    inner: S,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl<S> Service<subgraph::Request> for CircuitBreakerService<S>
+where
+    S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: subgraph::Request) -> Self::Future {
+        let breaker = match &self.breaker {
+            Some(breaker) => breaker.clone(),
+            None => return self.inner.call(req).boxed(),
+        };
+
+        let permit = match breaker.acquire() {
+            Some(permit) => permit,
+            None => {
+                tracing::info!(
+                    monotonic_counter.apollo_router_circuit_breaker_rejected_count = 1u64,
+                    subgraph = %breaker.subgraph,
+                );
+                let error = graphql::Error::builder()
+                    .message(format!(
+                        "circuit breaker open for subgraph '{}'",
+                        breaker.subgraph
+                    ))
+                    .extension_code("SUBREQUEST_CIRCUIT_OPEN")
+                    .extension("service", breaker.subgraph.clone())
+                    .build();
+                let response = subgraph::Response::error_builder()
+                    .error(error)
+                    .status_code(StatusCode::SERVICE_UNAVAILABLE)
+                    .context(req.context)
+                    .build();
+                return async move { response }.boxed();
+            }
+        };
+
+        let mut guard = PermitGuard {
+            breaker,
+            permit,
+            success: None,
+        };
+        let future = self.inner.call(req);
+        async move {
+            let result = future.await;
+            guard.success = Some(!is_failure(&result));
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(config: serde_json::Value) -> CircuitBreaker {
+        CircuitBreaker::new("products", &serde_json::from_value(config).unwrap())
+    }
+
+    fn call(breaker: &CircuitBreaker, success: bool) -> bool {
+        match breaker.acquire() {
+            Some(permit) => {
+                breaker.record(permit, Some(success));
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn it_opens_on_failure_ratio() {
+        let breaker = breaker(serde_json::json!({
+            "failure_ratio": 0.5,
+            "min_requests": 4,
+            "open_duration": "1h"
+        }));
+        assert!(call(&breaker, true));
+        assert!(call(&breaker, false));
+        assert!(call(&breaker, true));
+        // 2 failures out of 4 requests
+        assert!(call(&breaker, false));
+        assert!(!call(&breaker, true));
+        assert_eq!(breaker.inner.lock().unwrap().state.name(), "open");
+    }
+
+    #[test]
+    fn it_opens_on_consecutive_failures_and_closes_after_trial_requests() {
+        let breaker = breaker(serde_json::json!({
+            "consecutive_failures": 2,
+            "open_duration": "0s",
+            "half_open_requests": 2
+        }));
+        assert!(call(&breaker, false));
+        assert!(call(&breaker, true));
+        assert!(call(&breaker, false));
+        assert!(call(&breaker, false));
+        assert_eq!(breaker.inner.lock().unwrap().state.name(), "open");
+
+        // the open duration elapsed: trial requests are let through
+        let permit = breaker.acquire().unwrap();
+        assert_eq!(permit, Permit::Trial);
+        let second = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        breaker.record(permit, Some(true));
+        assert_eq!(breaker.inner.lock().unwrap().state.name(), "half_open");
+        breaker.record(second, Some(true));
+        assert_eq!(breaker.inner.lock().unwrap().state.name(), "closed");
+
+        // a failed trial request opens the circuit again
+        assert!(call(&breaker, false));
+        assert!(call(&breaker, false));
+        assert!(call(&breaker, false));
+        assert_eq!(breaker.inner.lock().unwrap().state.name(), "open");
+    }
+}
@@ -0,0 +1,510 @@
+//! Concurrency limit with load shedding.
+//!
+//! Requests beyond the limit wait in a bounded queue for a slot, and are rejected with a 503 status
+//! when the queue is full or when they waited for too long. In adaptive mode, the limit follows the
+//! observed latency: it grows while the latency stays close to its long term average, and shrinks
+//! when requests slow down.
+
+use std::num::NonZeroU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::services::subgraph;
+use crate::services::supergraph;
+
+const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_MIN_LIMIT: u32 = 1;
+const DEFAULT_MAX_LIMIT: u32 = 1000;
+const DEFAULT_SMOOTHING: f64 = 0.2;
+const DEFAULT_TOLERANCE: f64 = 1.5;
+
+/// Number of samples over which the long term latency is averaged
+const LONG_TERM_SAMPLES: f64 = 600.0;
+
+/// Concurrency limit configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConcurrencyLimitConf {
+    /// Maximum number of requests processed concurrently. In adaptive mode, this is the initial limit
+    max_concurrent_requests: NonZeroU32,
+    /// Maximum number of requests waiting for a slot, further requests being rejected (default: 0)
+    #[serde(default)]
+    max_queued_requests: u32,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long a request can wait for a slot before being rejected (default: 1s)
+    queue_timeout: Option<Duration>,
+    /// Adjusts the limit from the observed latency
+    adaptive: Option<AdaptiveConf>,
+}
+
+/// Adaptive concurrency limit configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AdaptiveConf {
+    /// Lowest value of the limit (default: 1)
+    min_limit: Option<u32>,
+    /// Highest value of the limit (default: 1000)
+    max_limit: Option<u32>,
+    /// How fast the limit changes, between 0 and 1 (default: 0.2)
+    smoothing: Option<f64>,
+    /// How much the latency can increase relative to its long term average before the limit
+    /// decreases (default: 1.5)
+    tolerance: Option<f64>,
+}
+
+/// Why a request was rejected
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rejection {
+    QueueFull,
+    QueueTimeout,
+}
+
+impl Rejection {
+    fn name(&self) -> &'static str {
+        match self {
+            Rejection::QueueFull => "queue_full",
+            Rejection::QueueTimeout => "queue_timeout",
+        }
+    }
+}
+
+/// Limit adjusted with the gradient of the latency
+struct Gradient {
+    limit: f64,
+    long_rtt: Option<f64>,
+    min_limit: f64,
+    max_limit: f64,
+    smoothing: f64,
+    tolerance: f64,
+}
+
+impl Gradient {
+    /// Updates the limit from the latency of a request, returns the new limit
+    fn update(&mut self, rtt: f64, in_flight: usize) -> f64 {
+        let long_rtt = match self.long_rtt {
+            Some(long_rtt) => long_rtt + (rtt - long_rtt) / LONG_TERM_SAMPLES,
+            None => rtt,
+        };
+        self.long_rtt = Some(long_rtt);
+
+        // the latency says nothing about the limit when most of the slots are unused
+        if (in_flight as f64) < self.limit / 2.0 || rtt <= 0.0 {
+            return self.limit;
+        }
+
+        let gradient = (self.tolerance * long_rtt / rtt).clamp(0.5, 1.0);
+        let new_limit = self.limit * gradient + self.limit.sqrt();
+        self.limit = (self.limit * (1.0 - self.smoothing) + new_limit * self.smoothing)
+            .clamp(self.min_limit, self.max_limit);
+        self.limit
+    }
+}
+
+/// Concurrency limit of the router or of a subgraph, shared by its services
+pub(crate) struct ConcurrencyLimiter {
+    subgraph: Option<String>,
+    semaphore: Arc<Semaphore>,
+    limit: AtomicUsize,
+    /// Slots to remove as they are released, after the limit decreased
+    excess: AtomicUsize,
+    queued: AtomicUsize,
+    max_queued: usize,
+    queue_timeout: Duration,
+    adaptive: Option<Mutex<Gradient>>,
+}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(subgraph: Option<&str>, config: &ConcurrencyLimitConf) -> Self {
+        let limit = config.max_concurrent_requests.get();
+        let adaptive = config.adaptive.as_ref().map(|adaptive| {
+            let min_limit = adaptive.min_limit.unwrap_or(DEFAULT_MIN_LIMIT).max(1);
+            Mutex::new(Gradient {
+                limit: f64::from(limit),
+                long_rtt: None,
+                min_limit: f64::from(min_limit),
+                max_limit: f64::from(
+                    adaptive
+                        .max_limit
+                        .unwrap_or(DEFAULT_MAX_LIMIT)
+                        .max(min_limit),
+                ),
+                smoothing: adaptive
+                    .smoothing
+                    .unwrap_or(DEFAULT_SMOOTHING)
+                    .clamp(0.0, 1.0),
+                tolerance: adaptive.tolerance.unwrap_or(DEFAULT_TOLERANCE).max(1.0),
+            })
+        });
+        Self {
+            subgraph: subgraph.map(str::to_string),
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            limit: AtomicUsize::new(limit as usize),
+            excess: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            max_queued: config.max_queued_requests as usize,
+            queue_timeout: config.queue_timeout.unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+            adaptive,
+        }
+    }
+
+    /// Waits for a slot, unless the queue is full
+    async fn acquire(self: &Arc<Self>) -> Result<Permit, Rejection> {
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+                    self.queued.fetch_sub(1, Ordering::SeqCst);
+                    return Err(Rejection::QueueFull);
+                }
+                let _queued = QueuedGuard(&self.queued);
+                match tokio::time::timeout(
+                    self.queue_timeout,
+                    self.semaphore.clone().acquire_owned(),
+                )
+                .await
+                {
+                    Ok(Ok(permit)) => permit,
+                    // the semaphore is never closed
+                    Ok(Err(_)) | Err(_) => return Err(Rejection::QueueTimeout),
+                }
+            }
+        };
+        Ok(Permit {
+            limiter: self.clone(),
+            permit: Some(permit),
+            start: Instant::now(),
+            sampled: false,
+        })
+    }
+
+    fn in_flight(&self) -> usize {
+        self.limit
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.semaphore.available_permits())
+    }
+
+    fn record_latency(&self, rtt: Duration) {
+        let adaptive = match &self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+        let in_flight = self.in_flight();
+        let new_limit = adaptive
+            .lock()
+            .expect("lock poisoned")
+            .update(rtt.as_secs_f64(), in_flight) as usize;
+        let old_limit = self.limit.swap(new_limit, Ordering::SeqCst);
+
+        if new_limit > old_limit {
+            // cancel slot removals first
+            let added = new_limit - old_limit;
+            let excess = self
+                .excess
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |excess| {
+                    Some(excess - excess.min(added))
+                })
+                .unwrap_or_default();
+            self.semaphore.add_permits(added - excess.min(added));
+        } else if new_limit < old_limit {
+            self.excess
+                .fetch_add(old_limit - new_limit, Ordering::SeqCst);
+        }
+        if new_limit != old_limit {
+            tracing::debug!(
+                subgraph = self.subgraph.as_deref().unwrap_or_default(),
+                "concurrency limit changed from {} to {}",
+                old_limit,
+                new_limit
+            );
+        }
+    }
+
+    fn rejected(&self, rejection: Rejection) {
+        match &self.subgraph {
+            Some(subgraph) => tracing::info!(
+                monotonic_counter.apollo_router_shed_requests_count = 1u64,
+                subgraph = %subgraph,
+                reason = rejection.name(),
+            ),
+            None => tracing::info!(
+                monotonic_counter.apollo_router_shed_requests_count = 1u64,
+                reason = rejection.name(),
+            ),
+        }
+    }
+}
+
+/// Leaves the queue when dropped, even if the request was cancelled while waiting
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A slot of the concurrency limit, released when dropped
+pub(crate) struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+    permit: Option<OwnedSemaphorePermit>,
+    start: Instant,
+    sampled: bool,
+}
+
+impl Permit {
+    /// Records the latency of the request, only successful requests being representative
+    fn sample(&mut self, success: bool) {
+        if success && !self.sampled {
+            self.sampled = true;
+            self.limiter.record_latency(self.start.elapsed());
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let removed = self
+            .limiter
+            .excess
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |excess| {
+                excess.checked_sub(1)
+            })
+            .is_ok();
+        if let Some(permit) = self.permit.take() {
+            if removed {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Requests that can be rejected by the concurrency limit
+pub(crate) trait LoadShed: Sized {
+    type Response;
+
+    /// The response sent when the request is rejected
+    fn overloaded(self, limiter: &ConcurrencyLimiter) -> Result<Self::Response, BoxError>;
+
+    /// Whether the request succeeded
+    fn is_success(response: &Self::Response) -> bool;
+
+    /// Keeps the slot until the response is entirely sent
+    fn release(response: Self::Response, permit: Permit) -> Self::Response;
+}
+
+impl LoadShed for supergraph::Request {
+    type Response = supergraph::Response;
+
+    fn overloaded(self, _limiter: &ConcurrencyLimiter) -> Result<Self::Response, BoxError> {
+        let error = graphql::Error::builder()
+            .message("the router is overloaded, try again later")
+            .extension_code("REQUEST_OVERLOADED")
+            .build();
+        supergraph::Response::builder()
+            .error(error)
+            .status_code(StatusCode::SERVICE_UNAVAILABLE)
+            .context(self.context)
+            .build()
+    }
+
+    fn is_success(response: &Self::Response) -> bool {
+        !response.response.status().is_server_error()
+    }
+
+    fn release(response: Self::Response, permit: Permit) -> Self::Response {
+        // deferred responses and subscriptions are still being processed
+        response.map(move |stream| {
+            stream
+                .map(move |response| {
+                    let _permit = &permit;
+                    response
+                })
+                .boxed()
+        })
+    }
+}
+
+impl LoadShed for subgraph::Request {
+    type Response = subgraph::Response;
+
+    fn overloaded(self, limiter: &ConcurrencyLimiter) -> Result<Self::Response, BoxError> {
+        let subgraph = limiter.subgraph.clone().unwrap_or_default();
+        let error = graphql::Error::builder()
+            .message(format!(
+                "too many concurrent requests to subgraph '{}'",
+                subgraph
+            ))
+            .extension_code("SUBREQUEST_OVERLOADED")
+            .extension("service", subgraph)
+            .build();
+        subgraph::Response::error_builder()
+            .error(error)
+            .status_code(StatusCode::SERVICE_UNAVAILABLE)
+            .context(self.context)
+            .build()
+    }
+
+    fn is_success(response: &Self::Response) -> bool {
+        !response.response.status().is_server_error()
+    }
+
+    fn release(response: Self::Response, _permit: Permit) -> Self::Response {
+        response
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimitLayer {
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+}
+
+impl ConcurrencyLimitLayer {
+    pub(crate) fn new(limiter: Option<Arc<ConcurrencyLimiter>>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimit<S> {
+    inner: S,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+}
+
+impl<S, Request> Service<Request> for ConcurrencyLimit<S>
+where
+    Request: LoadShed + Send + 'static,
+    Request::Response: Send + 'static,
+    S: Service<Request, Response = Request::Response, Error = BoxError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Request::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.limiter {
+            // the inner service is checked once a slot is acquired
+            Some(_) => Poll::Ready(Ok(())),
+            None => self.inner.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let limiter = match &self.limiter {
+            Some(limiter) => limiter.clone(),
+            None => return self.inner.call(req).boxed(),
+        };
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+
+        async move {
+            let mut permit = match limiter.acquire().await {
+                Ok(permit) => permit,
+                Err(rejection) => {
+                    limiter.rejected(rejection);
+                    return req.overloaded(&limiter);
+                }
+            };
+            let response = inner.oneshot(req).await?;
+            permit.sample(Request::is_success(&response));
+            Ok(Request::release(response, permit))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(config: serde_json::Value) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(
+            Some("products"),
+            &serde_json::from_value(config).unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn it_queues_then_rejects_requests() {
+        let limiter = limiter(serde_json::json!({
+            "max_concurrent_requests": 1,
+            "max_queued_requests": 1,
+            "queue_timeout": "50ms"
+        }));
+
+        let first = limiter.acquire().await.unwrap();
+        let queued = tokio::task::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            limiter.acquire().await.err(),
+            Some(Rejection::QueueFull),
+            "the queue is full"
+        );
+        drop(first);
+        assert!(queued.await.unwrap(), "the queued request got the slot");
+
+        let _second = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.acquire().await.err(), Some(Rejection::QueueTimeout));
+    }
+
+    #[test]
+    fn it_adapts_the_limit_to_the_latency() {
+        let mut gradient = Gradient {
+            limit: 10.0,
+            long_rtt: None,
+            min_limit: 1.0,
+            max_limit: 100.0,
+            smoothing: 0.2,
+            tolerance: 1.5,
+        };
+
+        // stable latency: the limit grows
+        let mut limit = 10.0;
+        for _ in 0..10 {
+            let new_limit = gradient.update(0.1, limit as usize);
+            assert!(new_limit > limit);
+            limit = new_limit;
+        }
+
+        // latency increasing a lot: the limit shrinks
+        let new_limit = gradient.update(1.0, limit as usize);
+        assert!(new_limit < limit);
+
+        // unused slots: the limit does not change
+        assert_eq!(gradient.update(0.1, 0), new_limit);
+    }
+}
@@ -7,6 +7,8 @@
 //! * Rate limiting
 //!
 
+mod circuit_breaker;
+mod concurrency;
 mod deduplication;
 mod rate;
 mod retry;
@@ -14,15 +16,19 @@ mod timeout;
 
 use std::collections::HashMap;
 use std::num::NonZeroU64;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
 use futures::future::BoxFuture;
+use futures::FutureExt;
 use http::header::CONTENT_ENCODING;
+use http::header::RETRY_AFTER;
 use http::HeaderValue;
+use http::StatusCode;
 use schemars::JsonSchema;
 use serde::Deserialize;
-use tower::retry::Retry;
 use tower::util::Either;
 use tower::util::Oneshot;
 use tower::BoxError;
@@ -30,20 +36,33 @@ use tower::Service;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use self::circuit_breaker::CircuitBreaker;
+use self::circuit_breaker::CircuitBreakerConfig;
+use self::circuit_breaker::CircuitBreakerLayer;
+use self::concurrency::ConcurrencyLimitConf;
+use self::concurrency::ConcurrencyLimitLayer;
+use self::concurrency::ConcurrencyLimiter;
 use self::deduplication::QueryDeduplicationLayer;
+use self::rate::ClientRateLimitConf;
+use self::rate::ClientRateLimiter;
+use self::rate::CostRateLimiter;
 use self::rate::RateLimitLayer;
 pub(crate) use self::rate::RateLimited;
 use self::retry::RetryPolicy;
 pub(crate) use self::timeout::Elapsed;
 use self::timeout::TimeoutLayer;
 use crate::error::ConfigurationError;
+use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
+use crate::services::execution;
 use crate::services::subgraph;
 use crate::services::subgraph_service::Compression;
+use crate::services::subgraph_service::HttpClientConfig;
 use crate::services::supergraph;
 use crate::services::SubgraphRequest;
+use crate::spec::query::cost::OPERATION_COST_CONTEXT_KEY;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 pub(crate) const APOLLO_TRAFFIC_SHAPING: &str = "apollo.traffic_shaping";
@@ -69,6 +88,12 @@ struct Shaping {
     /// Retry configuration
     //  *experimental feature*: Enables request retry
     experimental_retry: Option<RetryConfig>,
+    /// Configuration of the HTTP client: connection pool, timeouts and HTTP/2 usage
+    http_client: Option<HttpClientConfig>,
+    /// Circuit breaker: fails requests fast while the subgraph is unhealthy
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Limit the number of concurrent requests to the subgraph
+    concurrency_limit: Option<ConcurrencyLimitConf>,
 }
 
 impl Merge for Shaping {
@@ -89,6 +114,47 @@ impl Merge for Shaping {
                     .as_ref()
                     .or(fallback.experimental_retry.as_ref())
                     .cloned(),
+                http_client: Self::merge_option(&self.http_client, &fallback.http_client),
+                circuit_breaker: Self::merge_option(
+                    &self.circuit_breaker,
+                    &fallback.circuit_breaker,
+                ),
+                concurrency_limit: self
+                    .concurrency_limit
+                    .as_ref()
+                    .or(fallback.concurrency_limit.as_ref())
+                    .cloned(),
+            },
+        }
+    }
+}
+
+impl Shaping {
+    fn merge_option<T: Merge + Clone>(value: &Option<T>, fallback: &Option<T>) -> Option<T> {
+        match value {
+            Some(value) => Some(value.merge(fallback.as_ref())),
+            None => fallback.clone(),
+        }
+    }
+}
+
+impl Merge for HttpClientConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => HttpClientConfig {
+                http2: self.http2.or(fallback.http2),
+                pool_idle_timeout: self.pool_idle_timeout.or(fallback.pool_idle_timeout),
+                pool_max_idle_per_host: self
+                    .pool_max_idle_per_host
+                    .or(fallback.pool_max_idle_per_host),
+                connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+                http2_keep_alive_interval: self
+                    .http2_keep_alive_interval
+                    .or(fallback.http2_keep_alive_interval),
+                http2_keep_alive_timeout: self
+                    .http2_keep_alive_timeout
+                    .or(fallback.http2_keep_alive_timeout),
             },
         }
     }
@@ -114,6 +180,25 @@ struct RetryConfig {
     /// allows request retries on mutations. This should only be activated if mutations
     /// are idempotent. Disabled by default
     retry_mutations: Option<bool>,
+    /// maximum number of attempts of a request, including the first one. The default
+    /// value is 3
+    max_attempts: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// delay before the first retry, doubled on each following retry. The default value
+    /// is 100ms
+    min_backoff: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// maximum delay between retries. Requests are not retried if the subgraph asks to
+    /// wait longer with the Retry-After header. The default value is 5s
+    max_backoff: Option<Duration>,
+    /// randomizes each delay between half and all of its value, to spread retries.
+    /// Enabled by default
+    jitter: Option<bool>,
+    /// HTTP status codes of subgraph responses that are retried, in addition to
+    /// connection errors. The default value is [502, 503, 504]
+    status_codes: Option<Vec<u16>>,
 }
 
 impl Merge for RetryConfig {
@@ -125,6 +210,15 @@ impl Merge for RetryConfig {
                 min_per_sec: self.min_per_sec.or(fallback.min_per_sec),
                 retry_percent: self.retry_percent.or(fallback.retry_percent),
                 retry_mutations: self.retry_mutations.or(fallback.retry_mutations),
+                max_attempts: self.max_attempts.or(fallback.max_attempts),
+                min_backoff: self.min_backoff.or(fallback.min_backoff),
+                max_backoff: self.max_backoff.or(fallback.max_backoff),
+                jitter: self.jitter.or(fallback.jitter),
+                status_codes: self
+                    .status_codes
+                    .as_ref()
+                    .or(fallback.status_codes.as_ref())
+                    .cloned(),
             },
         }
     }
@@ -135,6 +229,13 @@ impl Merge for RetryConfig {
 struct RouterShaping {
     /// Enable global rate limiting
     global_rate_limit: Option<RateLimitConf>,
+    /// Enable rate limiting by estimated operation cost, the capacity being the total cost
+    /// allowed per interval. Requires `limits.cost.enabled`
+    cost_rate_limit: Option<RateLimitConf>,
+    /// Enable rate limiting per client
+    client_rate_limit: Option<ClientRateLimitConf>,
+    /// Limit the number of concurrent requests
+    concurrency_limit: Option<ConcurrencyLimitConf>,
     #[serde(deserialize_with = "humantime_serde::deserialize", default)]
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
@@ -185,7 +286,12 @@ impl Merge for RateLimitConf {
 pub(crate) struct TrafficShaping {
     config: Config,
     rate_limit_router: Option<RateLimitLayer>,
+    cost_rate_limit_router: Option<CostRateLimiter>,
+    client_rate_limit_router: Option<ClientRateLimiter>,
     rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+    circuit_breakers: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+    concurrency_limit_router: Option<Arc<ConcurrencyLimiter>>,
+    concurrency_limit_subgraphs: Mutex<HashMap<String, Arc<ConcurrencyLimiter>>>,
 }
 
 #[async_trait::async_trait]
@@ -215,13 +321,121 @@ impl Plugin for TrafficShaping {
                 }
             })
             .transpose()?;
+        let cost_rate_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.cost_rate_limit.as_ref())
+            .map(|cost_rate_limit_conf| {
+                CostRateLimiter::new(cost_rate_limit_conf.capacity, cost_rate_limit_conf.interval)
+            });
+        let client_rate_limit_router = match init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.client_rate_limit.as_ref())
+        {
+            Some(client_rate_limit_conf) => {
+                Some(ClientRateLimiter::new(client_rate_limit_conf).await)
+            }
+            None => None,
+        };
+        let concurrency_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.concurrency_limit.as_ref())
+            .map(|concurrency_limit_conf| {
+                Arc::new(ConcurrencyLimiter::new(None, concurrency_limit_conf))
+            });
 
         Ok(Self {
             config: init.config,
             rate_limit_router,
+            cost_rate_limit_router,
+            client_rate_limit_router,
             rate_limit_subgraphs: Mutex::new(HashMap::new()),
+            circuit_breakers: Mutex::new(HashMap::new()),
+            concurrency_limit_router,
+            concurrency_limit_subgraphs: Mutex::new(HashMap::new()),
         })
     }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let service = match self.concurrency_limit_router.clone() {
+            Some(concurrency_limit) => ServiceBuilder::new()
+                .layer(ConcurrencyLimitLayer::new(Some(concurrency_limit)))
+                .buffered()
+                .service(service)
+                .boxed(),
+            None => service,
+        };
+
+        match self.client_rate_limit_router.clone() {
+            Some(client_rate_limit) => ServiceBuilder::new()
+                .checkpoint_async(move |req: supergraph::Request| {
+                    let client_rate_limit = client_rate_limit.clone();
+                    async move {
+                        match client_rate_limit.check(&req).await {
+                            Ok(()) => Ok(ControlFlow::Continue(req)),
+                            Err(retry_after) => {
+                                let error = crate::error::Error::builder()
+                                    .message(RateLimited::new().to_string())
+                                    .extension_code("REQUEST_RATE_LIMITED")
+                                    .build();
+                                // Retry-After is expressed in whole seconds
+                                let retry_after = retry_after.as_secs()
+                                    + u64::from(retry_after.subsec_nanos() > 0);
+                                let res = supergraph::Response::builder()
+                                    .error(error)
+                                    .status_code(StatusCode::TOO_MANY_REQUESTS)
+                                    .header(RETRY_AFTER, retry_after.to_string())
+                                    .context(req.context)
+                                    .build()?;
+                                Ok(ControlFlow::Break(res))
+                            }
+                        }
+                    }
+                    .boxed()
+                })
+                .buffered()
+                .service(service)
+                .boxed(),
+            None => service,
+        }
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        match self.cost_rate_limit_router.clone() {
+            Some(cost_rate_limit) => ServiceBuilder::new()
+                .checkpoint(move |req: execution::Request| {
+                    // operations without an estimated cost count as one request
+                    let cost: u64 = req
+                        .context
+                        .get(OPERATION_COST_CONTEXT_KEY)
+                        .unwrap_or_default()
+                        .unwrap_or(1);
+                    match cost_rate_limit.acquire(cost) {
+                        Ok(()) => Ok(ControlFlow::Continue(req)),
+                        Err(rate_limited) => {
+                            let error = crate::error::Error::builder()
+                                .message(rate_limited.to_string())
+                                .extension_code("REQUEST_RATE_LIMITED")
+                                .build();
+                            let res = execution::Response::builder()
+                                .error(error)
+                                .status_code(StatusCode::TOO_MANY_REQUESTS)
+                                .context(req.context)
+                                .build()?;
+                            Ok(ControlFlow::Break(res))
+                        }
+                    }
+                })
+                .service(service)
+                .boxed(),
+            None => service,
+        }
+    }
 }
 
 impl TrafficShaping {
@@ -233,6 +447,13 @@ impl TrafficShaping {
         merged_subgraph_config.or_else(|| all_config.cloned())
     }
 
+    /// Configuration of the HTTP client sending requests to a subgraph
+    pub(crate) fn subgraph_client_config(&self, name: &str) -> HttpClientConfig {
+        Self::merge_config(self.config.all.as_ref(), self.config.subgraphs.get(name))
+            .and_then(|config| config.http_client)
+            .unwrap_or_default()
+    }
+
     pub(crate) fn supergraph_service_internal<S>(
         &self,
         service: S,
@@ -278,15 +499,7 @@ impl TrafficShaping {
         Future = tower::util::Either<
             tower::util::Either<
                 BoxFuture<'static, Result<subgraph::Response, BoxError>>,
-                timeout::future::ResponseFuture<
-                    Oneshot<
-                        tower::util::Either<
-                            Retry<RetryPolicy, tower::util::Either<rate::service::RateLimit<S>, S>>,
-                            tower::util::Either<rate::service::RateLimit<S>, S>,
-                        >,
-                        subgraph::Request,
-                    >,
-                >,
+                BoxFuture<'static, Result<subgraph::Response, BoxError>>,
             >,
             <S as Service<subgraph::Request>>::Future,
         >,
@@ -320,19 +533,44 @@ impl TrafficShaping {
             });
 
             let retry = config.experimental_retry.as_ref().map(|config| {
-                let retry_policy = RetryPolicy::new(
-                    config.ttl,
-                    config.min_per_sec,
-                    config.retry_percent,
-                    config.retry_mutations,
-                );
+                let retry_policy = RetryPolicy::new(name, config);
                 tower::retry::RetryLayer::new(retry_policy)
             });
 
+            // the circuit breaker is shared by all the services of the subgraph
+            let circuit_breaker = config.circuit_breaker.as_ref().map(|circuit_breaker_conf| {
+                self.circuit_breakers
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| Arc::new(CircuitBreaker::new(name, circuit_breaker_conf)))
+                    .clone()
+            });
+
+            let concurrency_limit =
+                config
+                    .concurrency_limit
+                    .as_ref()
+                    .map(|concurrency_limit_conf| {
+                        self.concurrency_limit_subgraphs
+                            .lock()
+                            .unwrap()
+                            .entry(name.to_string())
+                            .or_insert_with(|| {
+                                Arc::new(ConcurrencyLimiter::new(
+                                    Some(name),
+                                    concurrency_limit_conf,
+                                ))
+                            })
+                            .clone()
+                    });
+
             Either::A(ServiceBuilder::new()
                 .option_layer(config.deduplicate_query.unwrap_or_default().then(
                   QueryDeduplicationLayer::default
                 ))
+                    .layer(ConcurrencyLimitLayer::new(concurrency_limit))
+                    .layer(CircuitBreakerLayer::new(circuit_breaker))
                     .layer(TimeoutLayer::new(
                         config
                         .timeout
@@ -370,17 +608,21 @@ mod test {
 
     use super::*;
     use crate::json_ext::Object;
+    use crate::plugin::test::MockExecutionService;
     use crate::plugin::test::MockSubgraph;
     use crate::plugin::test::MockSupergraphService;
     use crate::plugin::DynPlugin;
     use crate::router_factory::create_plugins;
     use crate::services::router;
     use crate::services::router_service::RouterCreator;
+    use crate::services::ExecutionRequest;
+    use crate::services::ExecutionResponse;
     use crate::services::PluggableSupergraphServiceBuilder;
     use crate::services::SupergraphRequest;
     use crate::services::SupergraphResponse;
     use crate::spec::Schema;
     use crate::Configuration;
+    use crate::Context;
 
     static EXPECTED_RESPONSE: Lazy<Bytes> = Lazy::new(|| {
         Bytes::from_static(r#"{"data":{"topProducts":[{"upc":"1","name":"Table","reviews":[{"id":"1","product":{"name":"Table"},"author":{"id":"1","name":"Ada Lovelace"}},{"id":"4","product":{"name":"Table"},"author":{"id":"2","name":"Alan Turing"}}]},{"upc":"2","name":"Couch","reviews":[{"id":"2","product":{"name":"Couch"},"author":{"id":"1","name":"Ada Lovelace"}}]}]}}"#.as_bytes())
@@ -491,6 +733,7 @@ mod test {
             &Configuration::default(),
         )
         .await
+        .expect("should create the router")
         .make()
         .boxed()
     }
@@ -642,6 +885,118 @@ mod test {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn it_fails_fast_when_the_circuit_is_open() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                circuit_breaker:
+                    consecutive_failures: 2
+                    open_duration: 1h
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let test_service = {
+            let calls = calls.clone();
+            tower::service_fn(move |_req: SubgraphRequest| {
+                calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { Err::<subgraph::Response, BoxError>("connection refused".into()) }
+            })
+        };
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+
+        for _ in 0..2 {
+            shaping
+                .subgraph_service_internal("test", test_service.clone())
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await
+                .expect_err("the subgraph request should fail");
+        }
+
+        // the circuit is shared by all the services of the subgraph
+        let response = shaping
+            .subgraph_service_internal("test", test_service.clone())
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let error = &response.response.body().errors[0];
+        assert_eq!(
+            error.extensions.get("code"),
+            Some(&json!("SUBREQUEST_CIRCUIT_OPEN"))
+        );
+        assert_eq!(error.extensions.get("service"), Some(&json!("test")));
+
+        // other subgraphs are not affected
+        shaping
+            .subgraph_service_internal("another", test_service.clone())
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph request should be sent");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn it_sheds_subgraph_requests_over_the_concurrency_limit() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                concurrency_limit:
+                    max_concurrent_requests: 1
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let rx = Arc::new(tokio::sync::Mutex::new(Some(rx)));
+        let test_service = tower::service_fn(move |req: SubgraphRequest| {
+            let rx = rx.clone();
+            async move {
+                // the first request waits until the second one was rejected
+                if let Some(rx) = rx.lock().await.take() {
+                    let _ = rx.await;
+                }
+                Ok::<_, BoxError>(
+                    subgraph::Response::fake_builder()
+                        .context(req.context)
+                        .build(),
+                )
+            }
+        });
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+
+        let first = tokio::task::spawn(
+            shaping
+                .subgraph_service_internal("test", test_service.clone())
+                .oneshot(SubgraphRequest::fake_builder().build()),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let response = shaping
+            .subgraph_service_internal("test", test_service.clone())
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let error = &response.response.body().errors[0];
+        assert_eq!(
+            error.extensions.get("code"),
+            Some(&json!("SUBREQUEST_OVERLOADED"))
+        );
+        assert_eq!(error.extensions.get("service"), Some(&json!("test")));
+
+        tx.send(()).unwrap();
+        let response = first.await.unwrap().unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn it_rate_limit_router_requests() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -710,4 +1065,40 @@ mod test {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn it_rate_limit_router_requests_by_cost() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            cost_rate_limit:
+                capacity: 10
+                interval: 10s
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let execute = |cost: u64| {
+            let mut mock_service = MockExecutionService::new();
+            mock_service.expect_call().times(0..2).returning(|_| {
+                Ok(ExecutionResponse::fake_builder()
+                    .data(json!({ "test": 1234_u32 }))
+                    .build()
+                    .unwrap())
+            });
+            let context = Context::new();
+            context.insert(OPERATION_COST_CONTEXT_KEY, cost).unwrap();
+            plugin
+                .execution_service(mock_service.boxed())
+                .oneshot(ExecutionRequest::fake_builder().context(context).build())
+        };
+
+        let response = execute(6).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+        let response = execute(6).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = execute(4).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
 }
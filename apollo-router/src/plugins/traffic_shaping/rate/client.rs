@@ -0,0 +1,295 @@
+//! Rate limits applied per client.
+//!
+//! Clients are identified by a key read from the request: a header, a claim of the JWT validated
+//! by the authentication plugin, a context entry or the client IP. Each value of the key gets its
+//! own fixed window of requests, kept in memory or shared across router instances through Redis.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use axum::extract::ConnectInfo;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::cache::redis::RedisCacheStorage;
+use crate::configuration::RedisCache;
+use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::services::supergraph;
+
+/// Per client rate limiting configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClientRateLimitConf {
+    /// Rate limits, each one applied separately to each value of its key
+    limits: Vec<ClientRateLimit>,
+    /// Shares the rate limits across router instances through Redis, instead of keeping them in memory
+    redis: Option<RedisCache>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ClientRateLimit {
+    /// Key identifying clients. Requests without a value for the key are not limited
+    key: ClientKey,
+    /// Number of requests allowed per client
+    capacity: NonZeroU64,
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[schemars(with = "String")]
+    /// Per interval
+    interval: Duration,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+enum ClientKey {
+    /// Value of a request header
+    Header(String),
+    /// Claim of the JWT validated by the authentication plugin
+    Claim(String),
+    /// Value of a request context entry
+    Context(String),
+    /// IP address of the client connection
+    ClientIp,
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKey::Header(name) => write!(f, "header:{name}"),
+            ClientKey::Claim(name) => write!(f, "claim:{name}"),
+            ClientKey::Context(name) => write!(f, "context:{name}"),
+            ClientKey::ClientIp => write!(f, "client_ip"),
+        }
+    }
+}
+
+impl ClientKey {
+    fn value(&self, request: &supergraph::Request) -> Option<String> {
+        match self {
+            ClientKey::Header(name) => request
+                .supergraph_request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            ClientKey::Claim(name) => request
+                .context
+                .get::<_, serde_json::Value>(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                .ok()
+                .flatten()
+                .and_then(|claims| claims.get(name).map(json_to_key)),
+            ClientKey::Context(name) => request
+                .context
+                .get::<_, serde_json::Value>(name)
+                .ok()
+                .flatten()
+                .as_ref()
+                .map(json_to_key),
+            ClientKey::ClientIp => request
+                .supergraph_request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        }
+    }
+}
+
+fn json_to_key(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Enforces the per client rate limits.
+#[derive(Clone)]
+pub(crate) struct ClientRateLimiter {
+    limits: Arc<Vec<ClientRateLimit>>,
+    storage: Storage,
+}
+
+#[derive(Clone)]
+enum Storage {
+    Memory(Arc<Mutex<Windows>>),
+    Redis(RedisCacheStorage),
+}
+
+/// In memory windows, by limit key and client
+struct Windows {
+    windows: HashMap<String, Window>,
+    last_cleanup: Instant,
+}
+
+struct Window {
+    start: Instant,
+    interval: Duration,
+    count: u64,
+}
+
+impl Windows {
+    fn increment(&mut self, key: String, interval: Duration) -> (u64, Duration) {
+        let now = Instant::now();
+        if now.duration_since(self.last_cleanup) >= interval {
+            self.windows
+                .retain(|_, window| now.duration_since(window.start) < window.interval);
+            self.last_cleanup = now;
+        }
+
+        let window = self.windows.entry(key).or_insert(Window {
+            start: now,
+            interval,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= window.interval {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count += 1;
+        (
+            window.count,
+            window
+                .interval
+                .saturating_sub(now.duration_since(window.start)),
+        )
+    }
+}
+
+impl ClientRateLimiter {
+    pub(crate) async fn new(conf: &ClientRateLimitConf) -> Self {
+        let storage = match &conf.redis {
+            Some(redis) => match RedisCacheStorage::new(redis, "client rate limit").await {
+                Ok(storage) => Some(Storage::Redis(storage)),
+                Err(e) => {
+                    tracing::error!(
+                        "invalid Redis configuration for client rate limiting, rate limits will be kept in memory: {:?}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Self {
+            limits: Arc::new(conf.limits.clone()),
+            storage: storage.unwrap_or_else(|| {
+                Storage::Memory(Arc::new(Mutex::new(Windows {
+                    windows: HashMap::new(),
+                    last_cleanup: Instant::now(),
+                })))
+            }),
+        }
+    }
+
+    /// Counts a request against the rate limits of its client.
+    ///
+    /// If a rate limit is exceeded, returns how long the client should wait before retrying.
+    pub(crate) async fn check(&self, request: &supergraph::Request) -> Result<(), Duration> {
+        let mut retry_after = None;
+        for limit in self.limits.iter() {
+            let value = match limit.key.value(request) {
+                Some(value) => value,
+                None => continue,
+            };
+            let key = format!("client_rate_limit:{}:{value}", limit.key);
+
+            let (count, time_left) = match &self.storage {
+                Storage::Memory(windows) => {
+                    let mut windows = windows.lock().expect("lock poisoned");
+                    windows.increment(key, limit.interval)
+                }
+                Storage::Redis(redis) => match redis.increment(&key, limit.interval).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        // let requests through rather than rejecting all of them
+                        tracing::error!("could not check client rate limit in Redis: {:?}", e);
+                        continue;
+                    }
+                },
+            };
+
+            if count > limit.capacity.get() {
+                tracing::info!(
+                    monotonic_counter.apollo_router_client_rate_limited_count = 1u64,
+                    key = %limit.key,
+                );
+                retry_after = retry_after.max(Some(time_left));
+            }
+        }
+
+        match retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+
+    async fn limiter(config: serde_json::Value) -> ClientRateLimiter {
+        ClientRateLimiter::new(&serde_json::from_value(config).unwrap()).await
+    }
+
+    fn request(client_name: &str, context: Context) -> supergraph::Request {
+        supergraph::Request::fake_builder()
+            .header("apollographql-client-name", client_name)
+            .context(context)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_limits_each_client_separately() {
+        let limiter = limiter(serde_json::json!({
+            "limits": [{
+                "key": { "header": "apollographql-client-name" },
+                "capacity": 2,
+                "interval": "10s"
+            }]
+        }))
+        .await;
+
+        assert!(limiter.check(&request("a", Context::new())).await.is_ok());
+        assert!(limiter.check(&request("a", Context::new())).await.is_ok());
+        let retry_after = limiter
+            .check(&request("a", Context::new()))
+            .await
+            .unwrap_err();
+        assert!(retry_after > Duration::from_secs(9) && retry_after <= Duration::from_secs(10));
+        assert!(limiter.check(&request("b", Context::new())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_limits_on_jwt_claims() {
+        let limiter = limiter(serde_json::json!({
+            "limits": [{
+                "key": { "claim": "sub" },
+                "capacity": 1,
+                "interval": "10s"
+            }]
+        }))
+        .await;
+
+        let context = Context::new();
+        context
+            .insert(
+                APOLLO_AUTHENTICATION_JWT_CLAIMS,
+                serde_json::json!({ "sub": "user1" }),
+            )
+            .unwrap();
+        assert!(limiter.check(&request("a", context.clone())).await.is_ok());
+        assert!(limiter.check(&request("b", context)).await.is_err());
+        // requests without the claim are not limited
+        assert!(limiter.check(&request("a", Context::new())).await.is_ok());
+        assert!(limiter.check(&request("a", Context::new())).await.is_ok());
+    }
+}
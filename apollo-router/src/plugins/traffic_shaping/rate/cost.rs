@@ -0,0 +1,68 @@
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::Rate;
+use super::RateLimited;
+
+/// Enforces a rate limit on the total estimated cost of the operations
+/// executed over a period of time.
+#[derive(Debug, Clone)]
+pub(crate) struct CostRateLimiter {
+    rate: Rate,
+    window: Arc<Mutex<Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    /// Cost spent during the previous window
+    previous: u64,
+    /// Cost spent during the current window
+    current: u64,
+}
+
+impl CostRateLimiter {
+    /// Create a new cost rate limiter.
+    pub(crate) fn new(num: NonZeroU64, per: Duration) -> Self {
+        CostRateLimiter {
+            rate: Rate::new(num, per),
+            window: Arc::new(Mutex::new(Window {
+                start: Instant::now(),
+                previous: 0,
+                current: 0,
+            })),
+        }
+    }
+
+    /// Spends the cost of an operation, unless it would exceed the rate limit.
+    pub(crate) fn acquire(&self, cost: u64) -> Result<(), RateLimited> {
+        let per = self.rate.per();
+        let mut window = self.window.lock().expect("lock poisoned");
+
+        let elapsed = window.start.elapsed();
+        if elapsed >= per * 2 {
+            window.start = Instant::now();
+            window.previous = 0;
+            window.current = 0;
+        } else if elapsed >= per {
+            window.start += per;
+            window.previous = window.current;
+            window.current = 0;
+        }
+
+        // sliding window: the previous window is counted for the part of it
+        // that still overlaps the last `per` duration
+        let overlap = 1.0 - (window.start.elapsed().as_secs_f64() / per.as_secs_f64()).min(1.0);
+        let estimated = (window.previous as f64 * overlap) as u64 + window.current;
+
+        if estimated.saturating_add(cost) > self.rate.num() {
+            tracing::trace!("cost rate limit exceeded");
+            return Err(RateLimited::new());
+        }
+        window.current = window.current.saturating_add(cost);
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 //! Limit the rate at which requests are processed.
 
+mod client;
+mod cost;
 mod error;
 pub(crate) mod future;
 mod layer;
@@ -7,6 +9,9 @@ mod layer;
 mod rate;
 pub(crate) mod service;
 
+pub(crate) use self::client::ClientRateLimitConf;
+pub(crate) use self::client::ClientRateLimiter;
+pub(crate) use self::cost::CostRateLimiter;
 pub(crate) use self::error::RateLimited;
 pub(crate) use self::layer::RateLimitLayer;
 pub(crate) use self::rate::Rate;
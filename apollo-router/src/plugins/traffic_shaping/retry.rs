@@ -1,64 +1,286 @@
-use std::future;
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use rand::Rng;
 use tower::retry::budget::Budget;
 use tower::retry::Policy;
+use tower::BoxError;
 
+use super::RetryConfig;
+use crate::error::FetchError;
 use crate::query_planner::OperationKind;
 use crate::services::subgraph;
+use crate::services::subgraph_service::retry_after;
 
-#[derive(Clone, Default)]
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const DEFAULT_STATUS_CODES: [u16; 3] = [502, 503, 504];
+
+#[derive(Clone)]
 pub(crate) struct RetryPolicy {
     budget: Arc<Budget>,
     retry_mutations: bool,
+    max_attempts: u32,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    status_codes: Arc<Vec<u16>>,
+    subgraph: Arc<String>,
+    /// Number of attempts of the current request
+    attempts: u32,
 }
 
 impl RetryPolicy {
-    pub(crate) fn new(
-        duration: Option<Duration>,
-        min_per_sec: Option<u32>,
-        retry_percent: Option<f32>,
-        retry_mutations: Option<bool>,
-    ) -> Self {
+    pub(crate) fn new(subgraph: &str, config: &RetryConfig) -> Self {
+        let min_backoff = config.min_backoff.unwrap_or(DEFAULT_MIN_BACKOFF);
         Self {
             budget: Arc::new(Budget::new(
-                duration.unwrap_or_else(|| Duration::from_secs(10)),
-                min_per_sec.unwrap_or(10),
-                retry_percent.unwrap_or(0.2),
+                config.ttl.unwrap_or_else(|| Duration::from_secs(10)),
+                config.min_per_sec.unwrap_or(10),
+                config.retry_percent.unwrap_or(0.2),
             )),
-            retry_mutations: retry_mutations.unwrap_or(false),
+            retry_mutations: config.retry_mutations.unwrap_or(false),
+            max_attempts: config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            min_backoff,
+            max_backoff: config
+                .max_backoff
+                .unwrap_or(DEFAULT_MAX_BACKOFF)
+                .max(min_backoff),
+            jitter: config.jitter.unwrap_or(true),
+            status_codes: Arc::new(
+                config
+                    .status_codes
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_STATUS_CODES.to_vec()),
+            ),
+            subgraph: Arc::new(subgraph.to_string()),
+            attempts: 1,
         }
     }
-}
 
-impl<Res, E> Policy<subgraph::Request, Res, E> for RetryPolicy {
-    type Future = future::Ready<Self>;
+    /// Delay before the next attempt: exponential backoff, with jitter between half and all of it
+    fn backoff(&self) -> Duration {
+        let exponent = self.attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .min_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        if self.jitter {
+            let half = backoff / 2;
+            half + half.mul_f64(rand::thread_rng().gen::<f64>())
+        } else {
+            backoff
+        }
+    }
 
-    fn retry(&self, req: &subgraph::Request, result: Result<&Res, &E>) -> Option<Self::Future> {
+    /// Why the request should be retried, if it should
+    fn retry_reason(&self, result: Result<&subgraph::Response, &BoxError>) -> Option<String> {
         match result {
-            Ok(_) => {
-                // Treat all `Response`s as success,
-                // so deposit budget and don't retry...
-                self.budget.deposit();
-                None
+            Ok(response) => {
+                let status = response.response.status().as_u16();
+                self.status_codes
+                    .contains(&status)
+                    .then(|| status.to_string())
             }
-            Err(_e) => {
-                if req.operation_kind == OperationKind::Mutation && !self.retry_mutations {
-                    return None;
-                }
+            Err(error) => match error.downcast_ref::<FetchError>() {
+                Some(FetchError::SubrequestHttpError {
+                    status_code: Some(status),
+                    ..
+                }) => self
+                    .status_codes
+                    .contains(&status.as_u16())
+                    .then(|| status.as_u16().to_string()),
+                // the subgraph could not be reached
+                Some(FetchError::SubrequestHttpError {
+                    status_code: None, ..
+                }) => Some("connection_error".to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The delay requested by the subgraph with the Retry-After header of its response, which can
+/// also be carried by the error of a response that could not be parsed
+fn requested_delay(result: Result<&subgraph::Response, &BoxError>) -> Option<Duration> {
+    match result {
+        Ok(response) => retry_after(response.response.headers()),
+        Err(error) => match error.downcast_ref::<FetchError>() {
+            Some(FetchError::SubrequestHttpError { retry_after, .. }) => *retry_after,
+            _ => None,
+        },
+    }
+}
 
-                let withdrew = self.budget.withdraw();
-                if withdrew.is_err() {
-                    return None;
+impl Policy<subgraph::Request, subgraph::Response, BoxError> for RetryPolicy {
+    type Future = BoxFuture<'static, Self>;
+
+    fn retry(
+        &self,
+        req: &subgraph::Request,
+        result: Result<&subgraph::Response, &BoxError>,
+    ) -> Option<Self::Future> {
+        let reason = match self.retry_reason(result) {
+            Some(reason) => reason,
+            None => {
+                if result.is_ok() {
+                    self.budget.deposit();
                 }
+                return None;
+            }
+        };
+
+        if req.operation_kind == OperationKind::Mutation && !self.retry_mutations {
+            return None;
+        }
+        if self.attempts >= self.max_attempts {
+            return None;
+        }
 
-                Some(future::ready(self.clone()))
+        let mut delay = self.backoff();
+        if let Some(retry_after) = requested_delay(result) {
+            // waiting longer than the maximum backoff would delay the response too much
+            if retry_after > self.max_backoff {
+                return None;
             }
+            delay = delay.max(retry_after);
         }
+
+        let withdrew = self.budget.withdraw();
+        if withdrew.is_err() {
+            return None;
+        }
+
+        tracing::info!(
+            monotonic_counter.apollo_router_subgraph_retries_count = 1u64,
+            subgraph = %self.subgraph,
+            reason = %reason,
+        );
+        tracing::info!(
+            attempt = self.attempts + 1,
+            delay_ms = delay.as_millis() as u64,
+            reason = %reason,
+            "retrying the request to subgraph '{}'",
+            self.subgraph
+        );
+
+        let mut policy = self.clone();
+        policy.attempts += 1;
+        Some(tokio::time::sleep(delay).map(move |_| policy).boxed())
     }
 
     fn clone_request(&self, req: &subgraph::Request) -> Option<subgraph::Request> {
         Some(req.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::header::RETRY_AFTER;
+    use http::StatusCode;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        let config = serde_json::from_value(serde_json::json!({
+            "min_backoff": "100ms",
+            "max_backoff": "300ms",
+            "jitter": false
+        }))
+        .unwrap();
+        RetryPolicy::new("products", &config)
+    }
+
+    fn response(status: StatusCode) -> subgraph::Response {
+        subgraph::Response::fake_builder()
+            .status_code(status)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn it_retries_configured_status_codes_with_backoff() {
+        tokio::time::pause();
+        let policy = policy();
+        let req = subgraph::Request::fake_builder().build();
+
+        assert!(policy.retry(&req, Ok(&response(StatusCode::OK))).is_none());
+        assert!(policy
+            .retry(&req, Ok(&response(StatusCode::INTERNAL_SERVER_ERROR)))
+            .is_none());
+
+        let second = policy
+            .retry(&req, Ok(&response(StatusCode::SERVICE_UNAVAILABLE)))
+            .unwrap()
+            .await;
+        assert_eq!(second.attempts, 2);
+        assert_eq!(second.backoff(), Duration::from_millis(200));
+        let connection_error: BoxError = FetchError::SubrequestHttpError {
+            service: "products".to_string(),
+            reason: "connection refused".to_string(),
+            status_code: None,
+            retry_after: None,
+        }
+        .into();
+        let third = second.retry(&req, Err(&connection_error)).unwrap().await;
+        assert_eq!(third.backoff(), Duration::from_millis(300));
+
+        // maximum number of attempts reached
+        assert!(third.retry(&req, Err(&connection_error)).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_successful_responses_that_are_not_json() {
+        let policy = policy();
+        let req = subgraph::Request::fake_builder().build();
+        let not_json: BoxError = FetchError::SubrequestHttpError {
+            service: "products".to_string(),
+            reason: "subgraph didn't return JSON".to_string(),
+            status_code: Some(StatusCode::OK),
+            retry_after: None,
+        }
+        .into();
+
+        assert!(policy.retry(&req, Err(&not_json)).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_respects_retry_after() {
+        tokio::time::pause();
+        let policy = policy();
+        let req = subgraph::Request::fake_builder().build();
+        let mut response = response(StatusCode::SERVICE_UNAVAILABLE);
+        response
+            .response
+            .headers_mut()
+            .insert(RETRY_AFTER, "10".parse().unwrap());
+
+        // the subgraph asks to wait longer than the maximum backoff
+        assert!(policy.retry(&req, Ok(&response)).is_none());
+
+        // the header of a response that is not JSON is carried by the error
+        let unavailable = |retry_after| -> BoxError {
+            FetchError::SubrequestHttpError {
+                service: "products".to_string(),
+                reason: "503: Service Unavailable".to_string(),
+                status_code: Some(StatusCode::SERVICE_UNAVAILABLE),
+                retry_after,
+            }
+            .into()
+        };
+        assert!(policy
+            .retry(&req, Err(&unavailable(Some(Duration::from_secs(10)))))
+            .is_none());
+
+        // the requested delay is waited for when it is longer than the backoff
+        let start = tokio::time::Instant::now();
+        let second = policy
+            .retry(&req, Err(&unavailable(Some(Duration::from_millis(250)))))
+            .unwrap()
+            .await;
+        assert_eq!(second.attempts, 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,4 @@
+//! Protocols used to stream GraphQL responses: multipart HTTP responses and WebSockets.
+
+pub(crate) mod multipart;
+pub(crate) mod websocket;
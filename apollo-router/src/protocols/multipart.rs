@@ -0,0 +1,263 @@
+//! Multipart HTTP responses, used for `@defer` and for subscriptions.
+//!
+//! Both modes use `graphql` as the boundary, but they differ in how parts are delimited
+//! and in their payload:
+//! - with `@defer`, each part is a GraphQL response, and the last one has `"hasNext": false`
+//! - with subscriptions, each event is wrapped in a `payload` field, and heartbeat parts
+//!   containing an empty object are regularly sent to keep the connection alive
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::Stream;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::time::Interval;
+use tokio::time::MissedTickBehavior;
+use tower::BoxError;
+
+use crate::graphql;
+
+/// Interval between two heartbeat parts in subscription mode
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+const HEARTBEAT: &[u8] = b"{}";
+
+/// Which multipart format to produce
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProtocolMode {
+    /// `multipart/mixed;deferSpec=20220824`
+    Defer,
+    /// `multipart/mixed;subscriptionSpec=1.0`
+    Subscription,
+}
+
+/// A subscription event, as sent in a multipart part
+#[derive(Serialize)]
+struct SubscriptionPayload<'a> {
+    payload: &'a graphql::Response,
+}
+
+/// Encodes a stream of GraphQL responses into the chunks of a multipart HTTP body
+pub(crate) struct Multipart {
+    stream: Pin<Box<dyn Stream<Item = graphql::Response> + Send>>,
+    heartbeat: Option<Interval>,
+    mode: ProtocolMode,
+    is_first_chunk: bool,
+    is_terminated: bool,
+}
+
+impl Multipart {
+    pub(crate) fn new<S>(stream: S, mode: ProtocolMode) -> Self
+    where
+        S: Stream<Item = graphql::Response> + Send + 'static,
+    {
+        Self::with_heartbeat_interval(stream, mode, HEARTBEAT_INTERVAL)
+    }
+
+    fn with_heartbeat_interval<S>(
+        stream: S,
+        mode: ProtocolMode,
+        heartbeat_interval: Duration,
+    ) -> Self
+    where
+        S: Stream<Item = graphql::Response> + Send + 'static,
+    {
+        let heartbeat = (mode == ProtocolMode::Subscription).then(|| {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + heartbeat_interval,
+                heartbeat_interval,
+            );
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        Self {
+            stream: stream.boxed(),
+            heartbeat,
+            mode,
+            is_first_chunk: true,
+            is_terminated: false,
+        }
+    }
+
+    fn part(&mut self, body: &[u8], is_last: bool) -> Bytes {
+        let mut buf = Vec::with_capacity(body.len() + 64);
+        match self.mode {
+            // each chunk contains a response and the next delimiter, to let client parsers
+            // know that they can process the response right away
+            ProtocolMode::Defer => {
+                if self.is_first_chunk {
+                    buf.extend_from_slice(b"\r\n--graphql\r\n");
+                }
+                buf.extend_from_slice(b"content-type: application/json\r\n\r\n");
+                buf.extend_from_slice(body);
+                // the last chunk has a different end delimiter
+                if is_last {
+                    buf.extend_from_slice(b"\r\n--graphql--\r\n");
+                } else {
+                    buf.extend_from_slice(b"\r\n--graphql\r\n");
+                }
+            }
+            // the end of the stream is not known in advance, so each chunk starts after
+            // a delimiter and ends with the next one, without its line ending
+            ProtocolMode::Subscription => {
+                if self.is_first_chunk {
+                    buf.extend_from_slice(b"\r\n--graphql");
+                }
+                buf.extend_from_slice(b"\r\ncontent-type: application/json\r\n\r\n");
+                buf.extend_from_slice(body);
+                buf.extend_from_slice(b"\r\n--graphql");
+            }
+        }
+        self.is_first_chunk = false;
+        buf.into()
+    }
+}
+
+impl Stream for Multipart {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_terminated {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            return match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(response)) => match self.mode {
+                    ProtocolMode::Defer => {
+                        let is_last = !response.has_next.unwrap_or(false);
+                        let body = match serde_json::to_vec(&response) {
+                            Ok(body) => body,
+                            Err(err) => return Poll::Ready(Some(Err(err.into()))),
+                        };
+                        self.is_terminated = is_last;
+                        Poll::Ready(Some(Ok(self.part(&body, is_last))))
+                    }
+                    ProtocolMode::Subscription => {
+                        let mut response = response;
+                        // the end of the subscription is marked by the end of the multipart body,
+                        // so the empty response that only carries `"hasNext": false` is skipped
+                        response.has_next = None;
+                        if response.data.is_none()
+                            && response.errors.is_empty()
+                            && response.extensions.is_empty()
+                        {
+                            continue;
+                        }
+
+                        let body =
+                            match serde_json::to_vec(&SubscriptionPayload { payload: &response }) {
+                                Ok(body) => body,
+                                Err(err) => return Poll::Ready(Some(Err(err.into()))),
+                            };
+                        if let Some(heartbeat) = self.heartbeat.as_mut() {
+                            // an event was just sent, no need for a heartbeat until the next interval
+                            heartbeat.reset();
+                        }
+                        Poll::Ready(Some(Ok(self.part(&body, false))))
+                    }
+                },
+                Poll::Ready(None) => {
+                    self.is_terminated = true;
+                    match self.mode {
+                        ProtocolMode::Defer => Poll::Ready(None),
+                        ProtocolMode::Subscription if self.is_first_chunk => {
+                            Poll::Ready(Some(Ok(Bytes::from_static(b"\r\n--graphql--\r\n"))))
+                        }
+                        ProtocolMode::Subscription => {
+                            Poll::Ready(Some(Ok(Bytes::from_static(b"--\r\n"))))
+                        }
+                    }
+                }
+                Poll::Pending => match self.heartbeat.as_mut() {
+                    Some(heartbeat) if heartbeat.poll_tick(cx).is_ready() => {
+                        Poll::Ready(Some(Ok(self.part(HEARTBEAT, false))))
+                    }
+                    _ => Poll::Pending,
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_encodes_deferred_responses() {
+        let responses = stream::iter(vec![
+            graphql::Response::builder()
+                .data(json!({"test": "hello"}))
+                .has_next(true)
+                .build(),
+            graphql::Response::builder().has_next(false).build(),
+        ]);
+        let chunks = Multipart::new(responses, ProtocolMode::Defer)
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            chunks,
+            vec![
+                "\r\n--graphql\r\ncontent-type: application/json\r\n\r\n{\"data\":{\"test\":\"hello\"},\"hasNext\":true}\r\n--graphql\r\n",
+                "content-type: application/json\r\n\r\n{\"hasNext\":false}\r\n--graphql--\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_encodes_subscription_events_and_heartbeats() {
+        let heartbeat_interval = Duration::from_millis(100);
+        let (mut sender, receiver) = futures::channel::mpsc::channel(1);
+        let mut multipart = Multipart::with_heartbeat_interval(
+            receiver,
+            ProtocolMode::Subscription,
+            heartbeat_interval,
+        );
+
+        let events = tokio::task::spawn(async move {
+            use futures::SinkExt;
+
+            sender
+                .send(
+                    graphql::Response::builder()
+                        .data(json!({"userWasCreated": {"id": "1"}}))
+                        .has_next(true)
+                        .build(),
+                )
+                .await
+                .unwrap();
+            // a single heartbeat is sent while waiting for the next event
+            tokio::time::sleep(heartbeat_interval + heartbeat_interval / 2).await;
+            sender
+                .send(graphql::Response::builder().has_next(false).build())
+                .await
+                .unwrap();
+        });
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = multipart.next().await {
+            chunks.push(String::from_utf8(chunk.unwrap().to_vec()).unwrap());
+        }
+        events.await.unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                "\r\n--graphql\r\ncontent-type: application/json\r\n\r\n{\"payload\":{\"data\":{\"userWasCreated\":{\"id\":\"1\"}}}}\r\n--graphql",
+                "\r\ncontent-type: application/json\r\n\r\n{}\r\n--graphql",
+                "--\r\n",
+            ]
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! GraphQL over WebSocket protocols.
+//!
+//! Two protocols are in use in the ecosystem:
+//! - `graphql-ws`, from the legacy `subscriptions-transport-ws` library
+//! - `graphql-transport-ws`, from the `graphql-ws` library
+//!
+//! They share most of their messages, so they are represented by the same types here.
+
+use futures::SinkExt;
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json_bytes::Value;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tower::BoxError;
+
+use crate::graphql;
+
+const CONNECTION_ERROR_CODE: &str = "WEBSOCKET_CONNECTION_ERROR";
+const SUBSCRIPTION_ERROR_CODE: &str = "WEBSOCKET_SUBSCRIPTION_ERROR";
+
+/// GraphQL over WebSocket protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WebSocketProtocol {
+    /// The `graphql-ws` protocol, from the legacy subscriptions-transport-ws library
+    GraphqlWs,
+    /// The `graphql-transport-ws` protocol, from the graphql-ws library
+    GraphqlTransportWs,
+}
+
+/// Request extension marking the operations received over a client WebSocket connection
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WebSocketRequest;
+
+impl Default for WebSocketProtocol {
+    fn default() -> Self {
+        WebSocketProtocol::GraphqlWs
+    }
+}
+
+impl WebSocketProtocol {
+    /// The value of the `Sec-WebSocket-Protocol` header for this protocol
+    pub(crate) const fn subprotocol(&self) -> &'static str {
+        match self {
+            WebSocketProtocol::GraphqlWs => "graphql-ws",
+            WebSocketProtocol::GraphqlTransportWs => "graphql-transport-ws",
+        }
+    }
+
+    fn subscribe(&self, id: String, payload: graphql::Request) -> ClientMessage {
+        match self {
+            WebSocketProtocol::GraphqlWs => ClientMessage::Start { id, payload },
+            WebSocketProtocol::GraphqlTransportWs => ClientMessage::Subscribe { id, payload },
+        }
+    }
+
+    fn complete(&self, id: String) -> ClientMessage {
+        match self {
+            WebSocketProtocol::GraphqlWs => ClientMessage::Stop { id },
+            WebSocketProtocol::GraphqlTransportWs => ClientMessage::Complete { id },
+        }
+    }
+}
+
+/// Messages sent by the client
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClientMessage {
+    /// Initializes the connection
+    ConnectionInit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    /// Starts an operation (graphql-transport-ws)
+    Subscribe {
+        id: String,
+        payload: graphql::Request,
+    },
+    /// Starts an operation (graphql-ws)
+    Start {
+        id: String,
+        payload: graphql::Request,
+    },
+    /// Stops an operation (graphql-transport-ws)
+    Complete { id: String },
+    /// Stops an operation (graphql-ws)
+    Stop { id: String },
+    /// Closes the connection (graphql-ws)
+    ConnectionTerminate,
+    /// Checks that the other side is still alive (graphql-transport-ws)
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    /// Answers a ping (graphql-transport-ws)
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+}
+
+/// Messages sent by the server
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ServerMessage {
+    /// Acknowledges the connection initialization
+    ConnectionAck {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    /// Operation result (graphql-transport-ws)
+    Next {
+        id: String,
+        payload: graphql::Response,
+    },
+    /// Operation result (graphql-ws)
+    Data {
+        id: String,
+        payload: graphql::Response,
+    },
+    /// Operation execution error
+    Error { id: String, payload: ServerError },
+    /// The operation is finished
+    Complete { id: String },
+    /// Keep alive message (graphql-ws)
+    #[serde(rename = "ka")]
+    KeepAlive,
+    /// Checks that the other side is still alive (graphql-transport-ws)
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    /// Answers a ping (graphql-transport-ws)
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    /// The connection initialization was rejected (graphql-ws)
+    ConnectionError { payload: Value },
+}
+
+/// Error payload, which is a single error in graphql-ws and a list in graphql-transport-ws
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum ServerError {
+    Errors(Vec<graphql::Error>),
+    Error(graphql::Error),
+}
+
+impl ServerError {
+    fn into_errors(self) -> Vec<graphql::Error> {
+        match self {
+            ServerError::Errors(errors) => errors,
+            ServerError::Error(error) => vec![error],
+        }
+    }
+}
+
+impl From<ClientMessage> for Message {
+    fn from(message: ClientMessage) -> Self {
+        Message::Text(
+            serde_json::to_string(&message).expect("client messages are always serializable"),
+        )
+    }
+}
+
+/// Initializes the connection, then starts the subscription and returns the stream of its results.
+///
+/// The stream ends when the server completes the operation or closes the connection.
+pub(crate) async fn subscribe<S>(
+    mut connection: WebSocketStream<S>,
+    protocol: WebSocketProtocol,
+    request: graphql::Request,
+) -> Result<graphql::ResponseStream, BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    connection
+        .send(ClientMessage::ConnectionInit { payload: None }.into())
+        .await?;
+
+    loop {
+        match next_server_message(&mut connection).await? {
+            Some(ServerMessage::ConnectionAck { .. }) => break,
+            Some(ServerMessage::Ping { .. }) => {
+                connection
+                    .send(ClientMessage::Pong { payload: None }.into())
+                    .await?
+            }
+            Some(ServerMessage::ConnectionError { payload }) => {
+                return Err(format!("connection initialization was rejected: {payload}").into())
+            }
+            Some(_) => {}
+            None => return Err("connection closed before it was acknowledged".into()),
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    connection
+        .send(protocol.subscribe(id.clone(), request).into())
+        .await?;
+
+    let stream = futures::stream::unfold(Some((connection, id)), move |state| async move {
+        let (mut connection, id) = state?;
+        loop {
+            let message = match next_server_message(&mut connection).await {
+                Ok(Some(message)) => message,
+                Ok(None) => return None,
+                Err(err) => {
+                    let response = graphql::Response::builder()
+                        .error(
+                            graphql::Error::builder()
+                                .message(format!("websocket connection error: {err}"))
+                                .extension_code(CONNECTION_ERROR_CODE)
+                                .build(),
+                        )
+                        .build();
+                    return Some((response, None));
+                }
+            };
+
+            match message {
+                ServerMessage::Next {
+                    id: message_id,
+                    payload,
+                }
+                | ServerMessage::Data {
+                    id: message_id,
+                    payload,
+                } if message_id == id => return Some((payload, Some((connection, id)))),
+                ServerMessage::Error {
+                    id: message_id,
+                    payload,
+                } if message_id == id => {
+                    let response = graphql::Response::builder()
+                        .errors(payload.into_errors())
+                        .build();
+                    let _ = connection.send(protocol.complete(id).into()).await;
+                    let _ = connection.close(None).await;
+                    return Some((response, None));
+                }
+                ServerMessage::Complete { id: message_id } if message_id == id => {
+                    if protocol == WebSocketProtocol::GraphqlWs {
+                        let _ = connection
+                            .send(ClientMessage::ConnectionTerminate.into())
+                            .await;
+                    }
+                    let _ = connection.close(None).await;
+                    return None;
+                }
+                ServerMessage::Ping { .. } => {
+                    if connection
+                        .send(ClientMessage::Pong { payload: None }.into())
+                        .await
+                        .is_err()
+                    {
+                        return None;
+                    }
+                }
+                ServerMessage::ConnectionError { payload } => {
+                    let response = graphql::Response::builder()
+                        .error(
+                            graphql::Error::builder()
+                                .message(format!("subscription error: {payload}"))
+                                .extension_code(SUBSCRIPTION_ERROR_CODE)
+                                .build(),
+                        )
+                        .build();
+                    return Some((response, None));
+                }
+                _ => {}
+            }
+        }
+    });
+
+    Ok(Box::pin(stream))
+}
+
+/// Reads the next protocol message, skipping the WebSocket control frames.
+///
+/// Returns `None` when the connection is closed.
+async fn next_server_message<S>(
+    connection: &mut WebSocketStream<S>,
+) -> Result<Option<ServerMessage>, BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(message) = connection.next().await {
+        match message? {
+            Message::Text(text) => return Ok(Some(serde_json::from_str(&text)?)),
+            Message::Binary(bytes) => return Ok(Some(serde_json::from_slice(&bytes)?)),
+            Message::Close(_) => return Ok(None),
+            // ping frames are answered by tungstenite
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[test]
+    fn it_serializes_client_messages() {
+        let request = graphql::Request::builder()
+            .query("subscription { userWasCreated { id } }")
+            .build();
+
+        assert_eq!(
+            serde_json::to_value(ClientMessage::ConnectionInit { payload: None }).unwrap(),
+            serde_json::json!({ "type": "connection_init" })
+        );
+        assert_eq!(
+            serde_json::to_value(
+                WebSocketProtocol::GraphqlWs.subscribe("1".to_string(), request.clone())
+            )
+            .unwrap(),
+            serde_json::json!({
+                "type": "start",
+                "id": "1",
+                "payload": { "query": "subscription { userWasCreated { id } }" }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(
+                WebSocketProtocol::GraphqlTransportWs.subscribe("1".to_string(), request)
+            )
+            .unwrap(),
+            serde_json::json!({
+                "type": "subscribe",
+                "id": "1",
+                "payload": { "query": "subscription { userWasCreated { id } }" }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(WebSocketProtocol::GraphqlWs.complete("1".to_string())).unwrap(),
+            serde_json::json!({ "type": "stop", "id": "1" })
+        );
+    }
+
+    #[test]
+    fn it_deserializes_server_messages() {
+        assert_eq!(
+            serde_json::from_str::<ServerMessage>(r#"{"type":"ka"}"#).unwrap(),
+            ServerMessage::KeepAlive
+        );
+        assert_eq!(
+            serde_json::from_str::<ServerMessage>(r#"{"type":"connection_ack"}"#).unwrap(),
+            ServerMessage::ConnectionAck { payload: None }
+        );
+        assert_eq!(
+            serde_json::from_str::<ServerMessage>(
+                r#"{"type":"next","id":"1","payload":{"data":{"userWasCreated":{"id":"1"}}}}"#
+            )
+            .unwrap(),
+            ServerMessage::Next {
+                id: "1".to_string(),
+                payload: graphql::Response::builder()
+                    .data(json!({"userWasCreated": {"id": "1"}}))
+                    .build()
+            }
+        );
+
+        let single_error = serde_json::from_str::<ServerMessage>(
+            r#"{"type":"error","id":"1","payload":{"message":"oops"}}"#,
+        )
+        .unwrap();
+        let error_list = serde_json::from_str::<ServerMessage>(
+            r#"{"type":"error","id":"1","payload":[{"message":"oops"}]}"#,
+        )
+        .unwrap();
+        match (single_error, error_list) {
+            (
+                ServerMessage::Error {
+                    payload: single_error,
+                    ..
+                },
+                ServerMessage::Error {
+                    payload: error_list,
+                    ..
+                },
+            ) => assert_eq!(single_error.into_errors(), error_list.into_errors()),
+            _ => panic!("expected error messages"),
+        }
+    }
+}
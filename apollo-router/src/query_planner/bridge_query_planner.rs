@@ -119,6 +119,7 @@ impl BridgeQueryPlanner {
                     },
                 usage_reporting,
             } => {
+                let node = node.into_subscription_plan();
                 let subselections = node.parse_subselections(&self.schema)?;
                 selections.subselections = subselections;
                 Ok(QueryPlannerContent::Plan {
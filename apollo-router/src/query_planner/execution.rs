@@ -29,6 +29,7 @@ use crate::query_planner::FETCH_SPAN_NAME;
 use crate::query_planner::FLATTEN_SPAN_NAME;
 use crate::query_planner::PARALLEL_SPAN_NAME;
 use crate::query_planner::SEQUENCE_SPAN_NAME;
+use crate::query_planner::SUBSCRIBE_SPAN_NAME;
 use crate::services::SubgraphServiceFactory;
 use crate::spec::Query;
 use crate::spec::Schema;
@@ -88,7 +89,7 @@ pub(crate) struct ExecutionParameters<'a> {
 }
 
 impl PlanNode {
-    fn execute_recursively<'a>(
+    pub(crate) fn execute_recursively<'a>(
         &'a self,
         parameters: &'a ExecutionParameters<'a>,
         current_dir: &'a Path,
@@ -198,6 +199,17 @@ impl PlanNode {
                         }
                     }
                 }
+                PlanNode::Subscription { primary, rest } => {
+                    value = Value::default();
+                    errors = primary
+                        .execute_recursively(parameters, current_dir, rest, sender)
+                        .instrument(tracing::info_span!(
+                            SUBSCRIBE_SPAN_NAME,
+                            "otel.kind" = "INTERNAL",
+                            "apollo.subgraph.name" = primary.service_name.as_str()
+                        ))
+                        .await;
+                }
                 PlanNode::Defer {
                     primary:
                         Primary {
@@ -260,6 +260,8 @@ impl FetchNode {
             .map_err(|e| FetchError::SubrequestHttpError {
                 service: service_name.to_string(),
                 reason: e.to_string(),
+                status_code: None,
+                retry_after: None,
             })?
             .response
             .into_parts();
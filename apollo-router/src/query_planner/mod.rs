@@ -14,6 +14,7 @@ pub(crate) mod fetch;
 mod plan;
 pub(crate) mod rewrites;
 mod selection;
+mod subscription;
 pub use plan::*;
 
 pub(crate) const FETCH_SPAN_NAME: &str = "fetch";
@@ -26,6 +27,8 @@ pub(crate) const DEFER_DEFERRED_SPAN_NAME: &str = "defer_deferred";
 pub(crate) const CONDITION_SPAN_NAME: &str = "condition";
 pub(crate) const CONDITION_IF_SPAN_NAME: &str = "condition_if";
 pub(crate) const CONDITION_ELSE_SPAN_NAME: &str = "condition_else";
+pub(crate) const SUBSCRIBE_SPAN_NAME: &str = "subscribe";
+pub(crate) const SUBSCRIBE_EVENT_SPAN_NAME: &str = "subscription_event";
 
 // The code resides in a separate submodule to allow writing a log filter activating it
 // separately from the query planner logs, as follows:
@@ -8,6 +8,8 @@ use serde::Serialize;
 
 pub(crate) use self::fetch::OperationKind;
 use super::fetch;
+use super::subscription::SubscriptionNode;
+use crate::configuration::OperationCost;
 use crate::error::QueryPlannerError;
 use crate::json_ext;
 use crate::json_ext::Object;
@@ -57,6 +59,26 @@ impl QueryPlan {
     pub(crate) fn is_deferred(&self, operation: Option<&str>, variables: &Object) -> bool {
         self.root.is_deferred(operation, variables, &self.query)
     }
+
+    pub(crate) fn is_subscription(&self) -> bool {
+        matches!(self.root, PlanNode::Subscription { .. })
+    }
+
+    /// Estimates the cost of the fields selected by the operation, plus the cost of each subgraph fetch.
+    pub(crate) async fn estimate_cost(
+        &self,
+        schema: &Schema,
+        operation: Option<&str>,
+        variables: &Object,
+        config: &OperationCost,
+    ) -> u64 {
+        let fields = self
+            .query
+            .estimate_cost(schema, operation, variables, config)
+            .await;
+        let fetches = self.root.service_usage().count() as u64;
+        fields.saturating_add(fetches.saturating_mul(config.fetch_cost))
+    }
 }
 
 /// Query plans are composed of a set of nodes.
@@ -92,6 +114,12 @@ pub(crate) enum PlanNode {
         if_clause: Option<Box<PlanNode>>,
         else_clause: Option<Box<PlanNode>>,
     },
+
+    /// Open a stream of events from a subgraph, then execute the rest of the plan for each event.
+    Subscription {
+        primary: SubscriptionNode,
+        rest: Option<Box<PlanNode>>,
+    },
 }
 
 impl PlanNode {
@@ -106,6 +134,7 @@ impl PlanNode {
                 .map(|n| n.contains_mutations())
                 .unwrap_or(false),
             Self::Flatten(_) => false,
+            Self::Subscription { .. } => false,
             Self::Condition {
                 if_clause,
                 else_clause,
@@ -141,6 +170,7 @@ impl PlanNode {
                 .any(|n| n.is_deferred(operation, variables, query)),
             Self::Flatten(node) => node.node.is_deferred(operation, variables, query),
             Self::Fetch(..) => false,
+            Self::Subscription { .. } => false,
             Self::Defer { .. } => true,
             Self::Condition {
                 if_clause,
@@ -264,6 +294,10 @@ impl PlanNode {
                 Ok(())
             }
             Self::Fetch(..) => Ok(()),
+            Self::Subscription { rest, .. } => match rest {
+                Some(node) => node.collect_subselections(schema, initial_path, kind, subselections),
+                None => Ok(()),
+            },
             Self::Condition {
                 if_clause,
                 else_clause,
@@ -280,7 +314,43 @@ impl PlanNode {
         }
     }
 
-    #[cfg(test)]
+    /// Query planners without subscription support plan a subscription as a regular fetch,
+    /// or as a sequence starting with that fetch. This moves that fetch to a
+    /// [`PlanNode::Subscription`], so that it is executed as a stream of events.
+    pub(crate) fn into_subscription_plan(self) -> PlanNode {
+        match self {
+            Self::Fetch(fetch) if fetch.operation_kind == OperationKind::Subscription => {
+                Self::Subscription {
+                    primary: fetch.into(),
+                    rest: None,
+                }
+            }
+            Self::Sequence { nodes } => {
+                let mut nodes = nodes.into_iter();
+                match nodes.next() {
+                    Some(Self::Fetch(fetch))
+                        if fetch.operation_kind == OperationKind::Subscription =>
+                    {
+                        let mut rest: Vec<PlanNode> = nodes.collect();
+                        let rest = match rest.len() {
+                            0 => None,
+                            1 => rest.pop().map(Box::new),
+                            _ => Some(Box::new(Self::Sequence { nodes: rest })),
+                        };
+                        Self::Subscription {
+                            primary: fetch.into(),
+                            rest,
+                        }
+                    }
+                    first => Self::Sequence {
+                        nodes: first.into_iter().chain(nodes).collect(),
+                    },
+                }
+            }
+            node => node,
+        }
+    }
+
     /// Retrieves all the services used across all plan nodes.
     ///
     /// Note that duplicates are not filtered.
@@ -290,6 +360,11 @@ impl PlanNode {
                 Box::new(nodes.iter().flat_map(|x| x.service_usage()))
             }
             Self::Fetch(fetch) => Box::new(Some(fetch.service_name()).into_iter()),
+            Self::Subscription { primary, rest } => Box::new(
+                Some(primary.service_name.as_str())
+                    .into_iter()
+                    .chain(rest.iter().flat_map(|node| node.service_usage())),
+            ),
             Self::Flatten(flatten) => flatten.node.service_usage(),
             Self::Defer { primary, deferred } => primary
                 .node
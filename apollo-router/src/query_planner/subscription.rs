@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use futures::SinkExt;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tracing::Instrument;
+
+use super::execution::ExecutionParameters;
+use super::fetch::FetchNode;
+use super::fetch::OperationKind;
+use super::rewrites;
+use super::PlanNode;
+use super::SUBSCRIBE_EVENT_SPAN_NAME;
+use crate::error::Error;
+use crate::error::FetchError;
+use crate::graphql;
+use crate::graphql::Request;
+use crate::graphql::Response;
+use crate::http_ext;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::json_ext::ValueExt;
+use crate::services::SubgraphRequest;
+
+/// A subscription node: the root fetch of a subscription operation.
+///
+/// Instead of a single response, the subgraph sends back a stream of events.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SubscriptionNode {
+    /// The name of the service or subgraph that the subscription is querying.
+    pub(crate) service_name: String,
+
+    /// The variables that are used for the subgraph subscription.
+    pub(crate) variable_usages: Vec<String>,
+
+    /// The GraphQL subquery that is used for the subscription.
+    pub(crate) operation: String,
+
+    /// The GraphQL subquery operation name.
+    pub(crate) operation_name: Option<String>,
+
+    /// The GraphQL operation kind that is used for the subscription.
+    pub(crate) operation_kind: OperationKind,
+
+    // Optionally describes a number of "rewrites" that query plan executors should apply to the data that is sent as input of this subscription.
+    pub(crate) input_rewrites: Option<Vec<rewrites::DataRewrite>>,
+
+    // Optionally describes a number of "rewrites" to apply to the data received in each event (and before it is applied to the current in-memory results).
+    pub(crate) output_rewrites: Option<Vec<rewrites::DataRewrite>>,
+}
+
+impl From<FetchNode> for SubscriptionNode {
+    fn from(fetch: FetchNode) -> Self {
+        Self {
+            service_name: fetch.service_name,
+            variable_usages: fetch.variable_usages,
+            operation: fetch.operation,
+            operation_name: fetch.operation_name,
+            operation_kind: fetch.operation_kind,
+            input_rewrites: fetch.input_rewrites,
+            output_rewrites: fetch.output_rewrites,
+        }
+    }
+}
+
+impl SubscriptionNode {
+    /// Opens the subscription on the subgraph, then spawns a task executing the rest of the
+    /// plan for each event, and sending the result through `sender`.
+    ///
+    /// Returns the errors that prevented the subscription from starting.
+    pub(crate) async fn execute_recursively<'a>(
+        &'a self,
+        parameters: &'a ExecutionParameters<'a>,
+        current_dir: &'a Path,
+        rest: &'a Option<Box<PlanNode>>,
+        mut sender: futures::channel::mpsc::Sender<Response>,
+    ) -> Vec<Error> {
+        let mut stream = match self.subscribe(parameters, current_dir).await {
+            Ok(stream) => stream,
+            Err(errors) => return errors,
+        };
+
+        let rest = rest.clone();
+        let output_rewrites = self.output_rewrites.clone();
+        let current_dir = current_dir.clone();
+        let sc = parameters.schema.clone();
+        let orig = parameters.supergraph_request.clone();
+        let sf = parameters.service_factory.clone();
+        let ctx = parameters.context.clone();
+        let query = parameters.query.clone();
+
+        tokio::task::spawn(
+            async move {
+                let deferred_fetches = HashMap::new();
+                let parameters = ExecutionParameters {
+                    context: &ctx,
+                    service_factory: &sf,
+                    schema: &sc,
+                    supergraph_request: &orig,
+                    deferred_fetches: &deferred_fetches,
+                    query: &query,
+                };
+
+                while let Some(mut event) = stream.next().await {
+                    let mut value = event.data.take().unwrap_or_default();
+                    rewrites::apply_rewrites(&sc, &mut value, &output_rewrites);
+                    let mut errors = event.errors;
+
+                    if let Some(node) = &rest {
+                        let (v, _subselection, err) = node
+                            .execute_recursively(&parameters, &current_dir, &value, sender.clone())
+                            .instrument(tracing::info_span!(
+                                SUBSCRIBE_EVENT_SPAN_NAME,
+                                "otel.kind" = "INTERNAL"
+                            ))
+                            .await;
+                        value.deep_merge(v);
+                        errors.extend(err.into_iter());
+                    }
+
+                    let response = Response::builder()
+                        .data(value)
+                        .errors(errors)
+                        .extensions(event.extensions)
+                        .has_next(true)
+                        .build();
+                    if sender.send(response).await.is_err() {
+                        // the client went away, there is no need to keep listening to the subgraph
+                        tracing::debug!("subscription closed by the client");
+                        return;
+                    }
+                }
+
+                if let Err(e) = sender
+                    .send(Response::builder().has_next(false).build())
+                    .await
+                {
+                    tracing::error!("error sending the end of the subscription: {:?}", e);
+                }
+            }
+            .in_current_span(),
+        );
+
+        Vec::new()
+    }
+
+    async fn subscribe<'a>(
+        &'a self,
+        parameters: &'a ExecutionParameters<'a>,
+        current_dir: &'a Path,
+    ) -> Result<graphql::ResponseStream, Vec<Error>> {
+        let SubscriptionNode {
+            operation,
+            operation_kind,
+            operation_name,
+            service_name,
+            ..
+        } = self;
+
+        let variables = self
+            .variable_usages
+            .iter()
+            .filter_map(|key| {
+                parameters
+                    .supergraph_request
+                    .body()
+                    .variables
+                    .get_key_value(key.as_str())
+                    .map(|(variable_key, value)| (variable_key.clone(), value.clone()))
+            })
+            .collect::<Object>();
+
+        let mut subgraph_request = SubgraphRequest::builder()
+            .supergraph_request(parameters.supergraph_request.clone())
+            .subgraph_request(
+                http_ext::Request::builder()
+                    .method(http::Method::POST)
+                    .uri(
+                        parameters
+                            .schema
+                            .subgraphs()
+                            .find_map(|(name, url)| (name == service_name).then_some(url))
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "schema uri for subgraph '{service_name}' should already have been checked"
+                                )
+                            })
+                            .clone(),
+                    )
+                    .body(
+                        Request::builder()
+                            .query(operation)
+                            .and_operation_name(operation_name.clone())
+                            .variables(variables)
+                            .build(),
+                    )
+                    .build()
+                    .expect("it won't fail because the url is correct and already checked; qed"),
+            )
+            .operation_kind(*operation_kind)
+            .context(parameters.context.clone())
+            .build();
+        let (stream_sender, mut stream_receiver) = mpsc::channel(1);
+        subgraph_request.subscription_stream = Some(stream_sender);
+
+        let service = parameters
+            .service_factory
+            .create(service_name)
+            .expect("we already checked that the service exists during planning; qed");
+
+        let (_parts, response) = service
+            .oneshot(subgraph_request)
+            .instrument(tracing::trace_span!("subscription_stream"))
+            .await
+            .map_err(|e| {
+                let error = match e.downcast::<FetchError>() {
+                    Ok(error) => *error,
+                    Err(e) => FetchError::SubrequestWsError {
+                        service: service_name.to_string(),
+                        reason: e.to_string(),
+                    },
+                };
+                failfast_error!("Subscription error: {}", error);
+                vec![error.to_graphql_error(Some(current_dir.to_owned()))]
+            })?
+            .response
+            .into_parts();
+
+        // the subgraph service sends the stream before returning its response
+        match stream_receiver.try_recv() {
+            Ok(stream) => Ok(stream),
+            Err(_) if !response.errors.is_empty() => Err(response.errors),
+            Err(_) => Err(vec![FetchError::SubrequestWsError {
+                service: service_name.to_string(),
+                reason: "the subgraph service did not open a subscription stream".to_string(),
+            }
+            .to_graphql_error(Some(current_dir.to_owned()))]),
+        }
+    }
+}
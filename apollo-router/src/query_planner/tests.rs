@@ -15,6 +15,7 @@ use super::OperationKind;
 use super::PlanNode;
 use super::Primary;
 use super::QueryPlan;
+use crate::graphql;
 use crate::json_ext::Path;
 use crate::json_ext::PathElement;
 use crate::plugin;
@@ -615,3 +616,156 @@ async fn dependent_mutations() {
         )
         .await;
 }
+
+#[tokio::test]
+async fn subscription_executes_the_rest_of_the_plan_for_each_event() {
+    // a plan produced by a query planner without subscription support
+    let root = serde_json::from_str::<PlanNode>(
+        r#"{
+            "kind": "Sequence",
+            "nodes": [
+                {
+                    "kind": "Fetch",
+                    "serviceName": "product",
+                    "variableUsages": [],
+                    "operation": "subscription{bookAdded{__typename isbn}}",
+                    "operationKind": "subscription"
+                },
+                {
+                    "kind": "Flatten",
+                    "path": ["bookAdded"],
+                    "node": {
+                        "kind": "Fetch",
+                        "serviceName": "books",
+                        "requires": [
+                            {
+                                "kind": "InlineFragment",
+                                "typeCondition": "Book",
+                                "selections": [
+                                    { "kind": "Field", "name": "__typename" },
+                                    { "kind": "Field", "name": "isbn" }
+                                ]
+                            }
+                        ],
+                        "variableUsages": [],
+                        "operation": "query($representations:[_Any!]!){_entities(representations:$representations){...on Book{title}}}",
+                        "operationKind": "query"
+                    }
+                }
+            ]
+        }"#,
+    )
+    .unwrap()
+    .into_subscription_plan();
+    assert!(matches!(
+        &root,
+        PlanNode::Subscription {
+            rest: Some(rest),
+            ..
+        } if matches!(rest.as_ref(), PlanNode::Flatten(_))
+    ));
+
+    let query_plan: QueryPlan = QueryPlan {
+        root,
+        formatted_query_plan: Default::default(),
+        usage_reporting: UsageReporting {
+            stats_report_key: "this is a test report key".to_string(),
+            referenced_fields_by_type: Default::default(),
+        },
+        query: Arc::new(Query::default()),
+    };
+
+    let mut mock_product_service = plugin::test::MockSubgraphService::new();
+    mock_product_service.expect_clone().return_once(|| {
+        let mut mock_product_service = plugin::test::MockSubgraphService::new();
+        mock_product_service
+            .expect_call()
+            .times(1)
+            .returning(|request| {
+                assert_eq!(request.operation_kind, OperationKind::Subscription);
+                let events = futures::stream::iter(vec![
+                    graphql::Response::builder()
+                        .data(json!({"bookAdded": {"__typename": "Book", "isbn": "1"}}))
+                        .build(),
+                    graphql::Response::builder()
+                        .data(json!({"bookAdded": {"__typename": "Book", "isbn": "2"}}))
+                        .build(),
+                ]);
+                request
+                    .subscription_stream
+                    .expect("the subscription stream channel must be set")
+                    .try_send(Box::pin(events))
+                    .unwrap();
+                Ok(SubgraphResponse::fake_builder().build())
+            });
+        mock_product_service
+    });
+
+    let mut mock_books_service = plugin::test::MockSubgraphService::new();
+    mock_books_service.expect_clone().times(2).returning(|| {
+        let mut mock_books_service = plugin::test::MockSubgraphService::new();
+        mock_books_service
+            .expect_call()
+            .times(1)
+            .returning(|request| {
+                let representations = request
+                    .subgraph_request
+                    .body()
+                    .variables
+                    .get("representations")
+                    .unwrap();
+                let isbn = representations.as_array().unwrap()[0]
+                    .as_object()
+                    .unwrap()
+                    .get("isbn")
+                    .cloned()
+                    .unwrap();
+                Ok(SubgraphResponse::fake_builder()
+                    .data(json!({"_entities": [{"title": isbn}]}))
+                    .build())
+            });
+        mock_books_service
+    });
+
+    let sf = Arc::new(SubgraphServiceFactory {
+        services: Arc::new(HashMap::from([
+            (
+                "product".into(),
+                Arc::new(mock_product_service) as Arc<dyn MakeSubgraphService>,
+            ),
+            (
+                "books".into(),
+                Arc::new(mock_books_service) as Arc<dyn MakeSubgraphService>,
+            ),
+        ])),
+        plugins: Default::default(),
+    });
+
+    let (sender, receiver) = futures::channel::mpsc::channel(10);
+    let response = query_plan
+        .execute(
+            &Context::new(),
+            &sf,
+            &Default::default(),
+            &Arc::new(Schema::parse(test_schema!(), &Default::default()).unwrap()),
+            sender,
+        )
+        .await;
+    assert!(response.errors.is_empty());
+
+    let events = receiver.collect::<Vec<_>>().await;
+    assert_eq!(
+        events,
+        vec![
+            graphql::Response::builder()
+                .data(json!({"bookAdded": {"__typename": "Book", "isbn": "1", "title": "1"}}))
+                .has_next(true)
+                .build(),
+            graphql::Response::builder()
+                .data(json!({"bookAdded": {"__typename": "Book", "isbn": "2", "title": "2"}}))
+                .has_next(true)
+                .build(),
+            graphql::Response::builder().has_next(false).build(),
+        ]
+    );
+}
@@ -6,6 +6,8 @@ use axum::response::IntoResponse;
 use http::StatusCode;
 use multimap::MultiMap;
 use once_cell::sync::Lazy;
+use rustls::Certificate;
+use rustls::PrivateKey;
 use rustls::RootCertStore;
 use serde_json::Map;
 use serde_json::Value;
@@ -16,17 +18,23 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 use tower_service::Service;
 
+use crate::cache::registry::CacheRegistry;
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
+use crate::configuration::TlsClientAuth;
 use crate::configuration::TlsSubgraph;
 use crate::plugin::DynPlugin;
 use crate::plugin::Handler;
 use crate::plugin::PluginFactory;
+use crate::plugins::subscription::Subscription;
+use crate::plugins::subscription::APOLLO_SUBSCRIPTION_PLUGIN;
 use crate::plugins::traffic_shaping::TrafficShaping;
 use crate::plugins::traffic_shaping::APOLLO_TRAFFIC_SHAPING;
 use crate::services::new_service::ServiceFactory;
 use crate::services::router;
 use crate::services::router_service::RouterCreator;
+use crate::services::subgraph_service::generate_tls_client_config;
+use crate::services::subgraph_service::HttpClientConfig;
 use crate::services::transport;
 use crate::services::PluggableSupergraphServiceBuilder;
 use crate::services::SubgraphService;
@@ -105,6 +113,11 @@ pub(crate) trait RouterFactory:
     type Future: Send;
 
     fn web_endpoints(&self) -> MultiMap<ListenAddr, Endpoint>;
+
+    /// The caches of the router and their Redis connections, reported by its health check
+    fn caches(&self) -> CacheRegistry {
+        CacheRegistry::default()
+    }
 }
 
 /// Factory for creating a RouterFactory
@@ -139,6 +152,21 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
         previous_router: Option<&'a Self::RouterFactory>,
         extra_plugins: Option<Vec<(String, Box<dyn DynPlugin>)>>,
     ) -> Result<Self::RouterFactory, BoxError> {
+        // the caches created with the router are registered in its own registry
+        CacheRegistry::default()
+            .scope(self.create_router(configuration, schema, previous_router, extra_plugins))
+            .await
+    }
+}
+
+impl YamlRouterFactory {
+    async fn create_router<'a>(
+        &'a mut self,
+        configuration: Arc<Configuration>,
+        schema: Arc<Schema>,
+        previous_router: Option<&'a RouterCreator<SupergraphCreator>>,
+        extra_plugins: Option<Vec<(String, Box<dyn DynPlugin>)>>,
+    ) -> Result<RouterCreator<SupergraphCreator>, BoxError> {
         // Process the plugins.
         let plugins = create_plugins(&configuration, &schema, extra_plugins).await?;
 
@@ -148,6 +176,21 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
             .all
             .create_certificate_store()
             .transpose()?;
+        let tls_client_certificate = configuration
+            .tls
+            .subgraph
+            .all
+            .client_authentication
+            .as_ref()
+            .map(TlsClientAuth::load)
+            .transpose()?;
+
+        let subscription_plugin_conf = plugins
+            .iter()
+            .find(|i| i.0.as_str() == APOLLO_SUBSCRIPTION_PLUGIN)
+            .and_then(|plugin| (*plugin.1).as_any().downcast_ref::<Subscription>())
+            .map(|plugin| plugin.config.clone())
+            .filter(|config| config.enabled);
 
         let mut builder = PluggableSupergraphServiceBuilder::new(schema.clone());
         builder = builder.with_configuration(configuration.clone());
@@ -162,6 +205,23 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
                 .and_then(|subgraph| subgraph.create_certificate_store())
                 .transpose()?
                 .or_else(|| tls_root_store.clone());
+            let subgraph_client_certificate = configuration
+                .tls
+                .subgraph
+                .subgraphs
+                .get(name)
+                .and_then(|subgraph| subgraph.client_authentication.as_ref())
+                .map(TlsClientAuth::load)
+                .transpose()?
+                .or_else(|| tls_client_certificate.clone());
+            let tls_config =
+                generate_tls_client_config(subgraph_root_store, subgraph_client_certificate)
+                    .map_err(|e| ConfigurationError::ClientCertificate {
+                        error: format!("invalid client certificate for subgraph '{name}': {e}"),
+                    })?;
+            let subscription_config = subscription_plugin_conf
+                .as_ref()
+                .and_then(|config| config.mode.passthrough.subgraph(name));
 
             let subgraph_service = match plugins
                 .iter()
@@ -171,7 +231,7 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
                 Some(shaping) => Either::A(
                     shaping.subgraph_service_internal(
                         name,
-                        SubgraphService::new(
+                        SubgraphService::from_config(
                             name,
                             configuration
                                 .supergraph
@@ -181,11 +241,19 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
                                 .get(name)
                                 .map(|apq| apq.enabled)
                                 .unwrap_or(configuration.supergraph.apq.subgraph.all.enabled),
-                            subgraph_root_store,
+                            tls_config,
+                            &shaping.subgraph_client_config(name),
+                            subscription_config,
                         ),
                     ),
                 ),
-                None => Either::B(SubgraphService::new(name, false, subgraph_root_store)),
+                None => Either::B(SubgraphService::from_config(
+                    name,
+                    false,
+                    tls_config,
+                    &HttpClientConfig::default(),
+                    subscription_config,
+                )),
             };
             builder = builder.with_subgraph_service(name, subgraph_service);
         }
@@ -214,11 +282,9 @@ impl RouterSuperServiceFactory for YamlRouterFactory {
             }
         }
 
-        Ok(Self::RouterFactory::new(Arc::new(supergraph_creator), &configuration).await)
+        RouterCreator::new(Arc::new(supergraph_creator), &configuration).await
     }
-}
 
-impl YamlRouterFactory {
     pub(crate) async fn create_supergraph<'a>(
         &'a mut self,
         configuration: Arc<Configuration>,
@@ -235,6 +301,21 @@ impl YamlRouterFactory {
             .all
             .create_certificate_store()
             .transpose()?;
+        let tls_client_certificate = configuration
+            .tls
+            .subgraph
+            .all
+            .client_authentication
+            .as_ref()
+            .map(TlsClientAuth::load)
+            .transpose()?;
+
+        let subscription_plugin_conf = plugins
+            .iter()
+            .find(|i| i.0.as_str() == APOLLO_SUBSCRIPTION_PLUGIN)
+            .and_then(|plugin| (*plugin.1).as_any().downcast_ref::<Subscription>())
+            .map(|plugin| plugin.config.clone())
+            .filter(|config| config.enabled);
 
         let mut builder = PluggableSupergraphServiceBuilder::new(schema.clone());
         builder = builder.with_configuration(configuration.clone());
@@ -249,6 +330,23 @@ impl YamlRouterFactory {
                 .and_then(|subgraph| subgraph.create_certificate_store())
                 .transpose()?
                 .or_else(|| tls_root_store.clone());
+            let subgraph_client_certificate = configuration
+                .tls
+                .subgraph
+                .subgraphs
+                .get(name)
+                .and_then(|subgraph| subgraph.client_authentication.as_ref())
+                .map(TlsClientAuth::load)
+                .transpose()?
+                .or_else(|| tls_client_certificate.clone());
+            let tls_config =
+                generate_tls_client_config(subgraph_root_store, subgraph_client_certificate)
+                    .map_err(|e| ConfigurationError::ClientCertificate {
+                        error: format!("invalid client certificate for subgraph '{name}': {e}"),
+                    })?;
+            let subscription_config = subscription_plugin_conf
+                .as_ref()
+                .and_then(|config| config.mode.passthrough.subgraph(name));
 
             let subgraph_service = match plugins
                 .iter()
@@ -258,7 +356,7 @@ impl YamlRouterFactory {
                 Some(shaping) => Either::A(
                     shaping.subgraph_service_internal(
                         name,
-                        SubgraphService::new(
+                        SubgraphService::from_config(
                             name,
                             configuration
                                 .supergraph
@@ -268,11 +366,19 @@ impl YamlRouterFactory {
                                 .get(name)
                                 .map(|apq| apq.enabled)
                                 .unwrap_or(configuration.supergraph.apq.subgraph.all.enabled),
-                            subgraph_root_store,
+                            tls_config,
+                            &shaping.subgraph_client_config(name),
+                            subscription_config,
                         ),
                     ),
                 ),
-                None => Either::B(SubgraphService::new(name, false, subgraph_root_store)),
+                None => Either::B(SubgraphService::from_config(
+                    name,
+                    false,
+                    tls_config,
+                    &HttpClientConfig::default(),
+                    subscription_config,
+                )),
             };
             builder = builder.with_subgraph_service(name, subgraph_service);
         }
@@ -293,7 +399,26 @@ impl TlsSubgraph {
     }
 }
 
-fn create_certificate_store(
+impl TlsClientAuth {
+    fn load(&self) -> Result<(Vec<Certificate>, PrivateKey), ConfigurationError> {
+        let certificate_chain = load_certs(&self.certificate_chain).map_err(|e| {
+            ConfigurationError::ClientCertificate {
+                error: format!("could not parse the certificate chain: {e}"),
+            }
+        })?;
+        if certificate_chain.is_empty() {
+            return Err(ConfigurationError::ClientCertificate {
+                error: "the certificate chain is empty".to_string(),
+            });
+        }
+        let key = load_key(&self.key).map_err(|e| ConfigurationError::ClientCertificate {
+            error: format!("could not parse the key: {e}"),
+        })?;
+        Ok((certificate_chain, key))
+    }
+}
+
+pub(crate) fn create_certificate_store(
     certificate_authorities: &str,
 ) -> Result<RootCertStore, ConfigurationError> {
     let mut store = RootCertStore::empty();
@@ -318,7 +443,7 @@ fn create_certificate_store(
     }
 }
 
-fn load_certs(certificates: &str) -> io::Result<Vec<rustls::Certificate>> {
+pub(crate) fn load_certs(certificates: &str) -> io::Result<Vec<rustls::Certificate>> {
     tracing::debug!("loading root certificates");
 
     // Load and return certificate.
@@ -331,6 +456,24 @@ fn load_certs(certificates: &str) -> io::Result<Vec<rustls::Certificate>> {
     Ok(certs.into_iter().map(rustls::Certificate).collect())
 }
 
+pub(crate) fn load_key(data: &str) -> io::Result<PrivateKey> {
+    tracing::debug!("loading private key");
+
+    let mut reader = data.as_bytes();
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no private key found",
+    ))
+}
+
 /// test only helper method to create a router factory in integration tests
 ///
 /// not meant to be used directly
@@ -636,6 +779,41 @@ mod test {
         assert!(service.is_err())
     }
 
+    #[tokio::test]
+    async fn test_yaml_tls_client_certificate() {
+        let tls_config = |key: &str| {
+            serde_json::from_value(json!({
+                "subgraph": {
+                    "subgraphs": {
+                        "accounts": {
+                            "client_authentication": {
+                                "certificate_chain": include_str!("testdata/server.crt"),
+                                "key": key
+                            }
+                        }
+                    }
+                }
+            }))
+            .unwrap()
+        };
+
+        let config = Configuration::builder()
+            .tls(tls_config(include_str!("testdata/server.key")))
+            .build()
+            .unwrap();
+        assert!(create_service(config).await.is_ok());
+
+        let config = Configuration::builder()
+            .tls(tls_config(include_str!("testdata/server.crt")))
+            .build()
+            .unwrap();
+        let error = create_service(config).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not load client certificate: could not parse the key: no private key found"
+        );
+    }
+
     async fn create_service(config: Configuration) -> Result<(), BoxError> {
         let schema = include_str!("testdata/supergraph.graphql");
         let schema = Schema::parse(schema, &config).unwrap();
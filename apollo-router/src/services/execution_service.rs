@@ -71,6 +71,7 @@ impl Service<ExecutionRequest> for ExecutionService {
             let is_deferred = req
                 .query_plan
                 .is_deferred(operation_name.as_deref(), &variables);
+            let is_subscription = req.query_plan.is_subscription();
 
             let first = req
                 .query_plan
@@ -86,6 +87,16 @@ impl Service<ExecutionRequest> for ExecutionService {
             let query = req.query_plan.query.clone();
             let stream = if is_deferred {
                 filter_stream(first, receiver).boxed()
+            } else if is_subscription {
+                // subscription events are all sent through the receiver, the first response
+                // only contains errors if the subscription could not be started
+                if first.errors.is_empty() {
+                    receiver.boxed()
+                } else {
+                    let mut first = first;
+                    first.has_next = Some(false);
+                    once(ready(first)).boxed()
+                }
             } else {
                 once(ready(first)).chain(receiver).boxed()
             };
@@ -95,6 +106,15 @@ impl Service<ExecutionRequest> for ExecutionService {
 
             let stream = stream
                 .filter_map(move |mut response: Response| {
+                    // the last response of a subscription only marks the end of the stream
+                    if is_subscription
+                        && response.has_next == Some(false)
+                        && response.data.is_none()
+                        && response.errors.is_empty()
+                    {
+                        return ready(Some(response));
+                    }
+
                     // responses that would fall under a path that was previously nullified are not sent
                     if nullified_paths.iter().any(|path| match &response.path {
                         None => false,
@@ -122,7 +142,7 @@ impl Service<ExecutionRequest> for ExecutionService {
 
                     match (response.path.as_ref(), response.data.as_ref()) {
                         (None, _) | (_, None) => {
-                            if is_deferred {
+                            if is_deferred || is_subscription {
                                 response.has_next = Some(has_next);
                             }
 
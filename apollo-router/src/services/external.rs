@@ -81,6 +81,9 @@ pub(crate) struct Externalizable<T> {
     pub(crate) body: Option<T>,
     pub(crate) context: Option<Context>,
     pub(crate) sdl: Option<String>,
+    /// The name of the subgraph, at the subgraph stages
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) service_name: Option<String>,
 }
 
 impl<T> Externalizable<T>
@@ -93,6 +96,7 @@ where
         body: Option<T>,
         context: Option<Context>,
         sdl: Option<String>,
+        service_name: Option<String>,
     ) -> Self {
         Self {
             version: EXTERNALIZABLE_VERSION,
@@ -103,11 +107,21 @@ where
             body,
             context,
             sdl,
+            service_name,
         }
     }
 
     pub(crate) async fn call(self, url: &str, timeout: Option<Duration>) -> Result<Self, BoxError> {
         let my_client = CLIENT.as_ref().map_err(|e| e.to_string())?.clone();
+        self.call_with_client(my_client, url, timeout).await
+    }
+
+    pub(crate) async fn call_with_client(
+        self,
+        my_client: Client,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Self, BoxError> {
         let t = timeout.unwrap_or(DEFAULT_EXTERNALIZATION_TIMEOUT);
 
         tracing::debug!("forwarding json: {}", serde_json::to_string(&self)?);
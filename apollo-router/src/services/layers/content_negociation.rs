@@ -27,11 +27,18 @@ use crate::services::supergraph;
 use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
 use crate::services::MULTIPART_DEFER_SPEC_PARAMETER;
 use crate::services::MULTIPART_DEFER_SPEC_VALUE;
+use crate::services::MULTIPART_SUBSCRIPTION_CONTENT_TYPE;
+use crate::services::MULTIPART_SUBSCRIPTION_SPEC_PARAMETER;
+use crate::services::MULTIPART_SUBSCRIPTION_SPEC_VALUE;
 
 pub(crate) const GRAPHQL_JSON_RESPONSE_HEADER_VALUE: &str = "application/graphql-response+json";
 pub(crate) const ACCEPTS_WILDCARD_CONTEXT_KEY: &str = "content-negociation:accepts-wildcard";
 pub(crate) const ACCEPTS_MULTIPART_CONTEXT_KEY: &str = "content-negociation:accepts-multipart";
 pub(crate) const ACCEPTS_JSON_CONTEXT_KEY: &str = "content-negociation:accepts-json";
+pub(crate) const ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY: &str =
+    "content-negociation:accepts-multipart-subscription";
+/// Set by the supergraph service when the operation is a subscription
+pub(crate) const IS_SUBSCRIPTION_CONTEXT_KEY: &str = "content-negociation:is-subscription";
 
 /// [`Layer`] for Content-Type checks implementation.
 #[derive(Clone, Default)]
@@ -72,16 +79,28 @@ where
                     return Ok(ControlFlow::Break(response.into()));
                 }
                 let accepts_multipart = accepts_multipart(req.router_request.headers());
+                let accepts_multipart_subscription =
+                    accepts_multipart_subscription(req.router_request.headers());
                 let accepts_json = accepts_json(req.router_request.headers());
                 let accepts_wildcard = accepts_wildcard(req.router_request.headers());
 
-                if accepts_wildcard || accepts_multipart || accepts_json {
+                if accepts_wildcard
+                    || accepts_multipart
+                    || accepts_multipart_subscription
+                    || accepts_json
+                {
                     req.context
                         .insert(ACCEPTS_WILDCARD_CONTEXT_KEY, accepts_wildcard)
                         .unwrap();
                     req.context
                         .insert(ACCEPTS_MULTIPART_CONTEXT_KEY, accepts_multipart)
                         .unwrap();
+                    req.context
+                        .insert(
+                            ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY,
+                            accepts_multipart_subscription,
+                        )
+                        .unwrap();
                     req.context
                         .insert(ACCEPTS_JSON_CONTEXT_KEY, accepts_json)
                         .unwrap();
@@ -93,10 +112,11 @@ where
                                 serde_json::to_string(
                                     &graphql::Error::builder()
                                         .message(format!(
-                                            r#"'accept' header can't be different from \"*/*\", {:?}, {:?} or {:?}"#,
+                                            r#"'accept' header can't be different from \"*/*\", {:?}, {:?}, {:?} or {:?}"#,
                                             APPLICATION_JSON.essence_str(),
                                             GRAPHQL_JSON_RESPONSE_HEADER_VALUE,
-                                            MULTIPART_DEFER_CONTENT_TYPE
+                                            MULTIPART_DEFER_CONTENT_TYPE,
+                                            MULTIPART_SUBSCRIPTION_CONTENT_TYPE
                                         ))
                                         .extension_code("INVALID_ACCEPT_HEADER")
                                         .build(),
@@ -140,12 +160,25 @@ where
                     .get(ACCEPTS_MULTIPART_CONTEXT_KEY)
                     .unwrap_or_default()
                     .unwrap_or_default();
+                let accepts_multipart_subscription: bool = context
+                    .get(ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY)
+                    .unwrap_or_default()
+                    .unwrap_or_default();
+                let is_subscription: bool = context
+                    .get(IS_SUBSCRIPTION_CONTEXT_KEY)
+                    .unwrap_or_default()
+                    .unwrap_or_default();
 
                 if !res.has_next.unwrap_or_default() && (accepts_json || accepts_wildcard) {
                     parts.headers.insert(
                         CONTENT_TYPE,
                         HeaderValue::from_static(APPLICATION_JSON.essence_str()),
                     );
+                } else if is_subscription && accepts_multipart_subscription {
+                    parts.headers.insert(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static(MULTIPART_SUBSCRIPTION_CONTENT_TYPE),
+                    );
                 } else if accepts_multipart {
                     parts.headers.insert(
                         CONTENT_TYPE,
@@ -226,6 +259,24 @@ fn accepts_wildcard(headers: &HeaderMap) -> bool {
 
 /// Returns true if the headers contain accept header to enable defer
 fn accepts_multipart(headers: &HeaderMap) -> bool {
+    accepts_multipart_spec(
+        headers,
+        MULTIPART_DEFER_SPEC_PARAMETER,
+        MULTIPART_DEFER_SPEC_VALUE,
+    )
+}
+
+/// Returns true if the headers contain accept header to receive subscriptions as multipart responses
+fn accepts_multipart_subscription(headers: &HeaderMap) -> bool {
+    accepts_multipart_spec(
+        headers,
+        MULTIPART_SUBSCRIPTION_SPEC_PARAMETER,
+        MULTIPART_SUBSCRIPTION_SPEC_VALUE,
+    )
+}
+
+/// Returns true if the headers accept `multipart/mixed` with this specification parameter
+fn accepts_multipart_spec(headers: &HeaderMap, parameter: &str, spec: &str) -> bool {
     headers.get_all(ACCEPT).iter().any(|value| {
         value
             .to_str()
@@ -237,13 +288,9 @@ fn accepts_multipart(headers: &HeaderMap) -> bool {
                         .map(|mime| {
                             mime.ty == MULTIPART
                                 && mime.subty == MIXED
-                                && mime.get_param(
-                                    mediatype::Name::new(MULTIPART_DEFER_SPEC_PARAMETER)
-                                        .expect("valid name"),
-                                ) == Some(
-                                    mediatype::Value::new(MULTIPART_DEFER_SPEC_VALUE)
-                                        .expect("valid value"),
-                                )
+                                && mime
+                                    .get_param(mediatype::Name::new(parameter).expect("valid name"))
+                                    == Some(mediatype::Value::new(spec).expect("valid value"))
                         })
                         .unwrap_or(false)
                 })
@@ -294,5 +341,14 @@ mod tests {
             HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE),
         );
         assert!(accepts_multipart(&default_headers));
+        assert!(!accepts_multipart_subscription(&default_headers));
+
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("multipart/mixed;subscriptionSpec=1.0, application/json"),
+        );
+        assert!(accepts_multipart_subscription(&default_headers));
+        assert!(!accepts_multipart(&default_headers));
     }
 }
@@ -2,4 +2,5 @@
 pub(crate) mod allow_only_http_post_mutations;
 pub(crate) mod apq;
 pub(crate) mod content_negociation;
+pub(crate) mod persisted_queries;
 pub(crate) mod static_page;
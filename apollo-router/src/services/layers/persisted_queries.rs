@@ -0,0 +1,499 @@
+//! Persisted queries and safelisting.
+//!
+//! The persisted query manifest is a JSON file listing the operations that clients are expected
+//! to send, by ID. It is used to:
+//! - resolve requests that only contain the ID of an operation
+//! - log the operations that are not in the manifest
+//! - reject the operations that are not in the manifest (safelisting)
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use futures::StreamExt;
+use http::StatusCode;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+
+use crate::configuration::PersistedQueries;
+use crate::services::SupergraphRequest;
+use crate::services::SupergraphResponse;
+use crate::Configuration;
+
+/// Context key containing the ID of the operation, when it was resolved from the manifest
+pub(crate) const PERSISTED_QUERY_ID_CONTEXT_KEY: &str = "persisted_query_id";
+
+const PERSISTED_QUERY_EXTENSION: &str = "persistedQuery";
+const PERSISTED_QUERY_NOT_IN_LIST_CODE: &str = "PERSISTED_QUERY_NOT_IN_LIST";
+const QUERY_NOT_IN_SAFELIST_CODE: &str = "QUERY_NOT_IN_SAFELIST";
+
+/// The manifest file formats
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestFile {
+    /// The manifest format generated by Apollo tooling:
+    /// `{ "format": "apollo-persisted-query-manifest", "version": 1, "operations": [...] }`
+    Operations { operations: Vec<ManifestOperation> },
+    /// A map of operation IDs to operation bodies
+    Map(HashMap<String, String>),
+}
+
+#[derive(Deserialize)]
+struct ManifestOperation {
+    id: String,
+    body: String,
+}
+
+/// The operations of a persisted query manifest
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    /// operation bodies by ID
+    operations: HashMap<String, String>,
+    /// all the operation bodies, to check full requests against the manifest
+    bodies: HashSet<String>,
+}
+
+impl Manifest {
+    fn parse(content: &str) -> Result<Self, serde_json::Error> {
+        let operations = match serde_json::from_str(content)? {
+            ManifestFile::Operations { operations } => operations
+                .into_iter()
+                .map(|operation| (operation.id, operation.body))
+                .collect(),
+            ManifestFile::Map(operations) => operations,
+        };
+        let bodies = operations.values().cloned().collect();
+
+        Ok(Self { operations, bodies })
+    }
+
+    async fn load(path: &Path) -> Result<Self, BoxError> {
+        let content = tokio::fs::read_to_string(path).await.map_err(|err| {
+            format!(
+                "could not read the persisted query manifest at {}: {err}",
+                path.display()
+            )
+        })?;
+
+        Ok(Self::parse(&content).map_err(|err| {
+            format!(
+                "could not parse the persisted query manifest at {}: {err}",
+                path.display()
+            )
+        })?)
+    }
+
+    fn len(&self) -> usize {
+        self.operations.len()
+    }
+}
+
+/// [`Layer`] for persisted queries and safelisting.
+#[derive(Clone)]
+pub(crate) struct PersistedQueryLayer {
+    /// set to None if persisted queries are disabled
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    manifest: Arc<ArcSwap<Manifest>>,
+    config: PersistedQueries,
+    /// ID-only requests that are not in the manifest are handed over to APQ if it is enabled
+    apq_enabled: bool,
+    /// reloads the manifest when the file changes, if hot reloading is enabled
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.abort();
+        }
+    }
+}
+
+impl PersistedQueryLayer {
+    pub(crate) async fn new(configuration: &Configuration) -> Result<Self, BoxError> {
+        let config = &configuration.persisted_queries;
+        let path = match (&config.manifest, config.enabled) {
+            (Some(path), true) => path.clone(),
+            _ => return Ok(Self::disabled()),
+        };
+
+        let manifest = Manifest::load(&path).await?;
+        tracing::info!(
+            "loaded the persisted query manifest with {} operations",
+            manifest.len()
+        );
+        let manifest = Arc::new(ArcSwap::from_pointee(manifest));
+
+        let watcher = config.hot_reload.then(|| {
+            let manifest = manifest.clone();
+            tokio::task::spawn(async move {
+                let mut changes = crate::files::watch(&path).boxed();
+                while changes.next().await.is_some() {
+                    match Manifest::load(&path).await {
+                        Ok(new_manifest) => {
+                            tracing::info!(
+                                "reloaded the persisted query manifest with {} operations",
+                                new_manifest.len()
+                            );
+                            manifest.store(Arc::new(new_manifest));
+                        }
+                        Err(err) => tracing::error!("{err}"),
+                    }
+                }
+            })
+        });
+
+        Ok(Self {
+            inner: Some(Arc::new(Inner {
+                manifest,
+                config: config.clone(),
+                apq_enabled: configuration.supergraph.apq.enabled,
+                watcher,
+            })),
+        })
+    }
+
+    pub(crate) fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    /// Resolves the requests that only contain an operation ID from the manifest.
+    ///
+    /// A resolved request looks like a request containing the full operation, and the operation ID
+    /// is available in the context under [`PERSISTED_QUERY_ID_CONTEXT_KEY`].
+    pub(crate) fn supergraph_request(
+        &self,
+        mut request: SupergraphRequest,
+    ) -> Result<SupergraphRequest, SupergraphResponse> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => return Ok(request),
+        };
+
+        let body = request.supergraph_request.body();
+        if body.query.is_some() {
+            return Ok(request);
+        }
+        let id = match body
+            .extensions
+            .get(PERSISTED_QUERY_EXTENSION)
+            .and_then(|persisted_query| persisted_query.as_object())
+            .and_then(|persisted_query| persisted_query.get("sha256Hash"))
+            .and_then(|id| id.as_str())
+        {
+            Some(id) => id.to_string(),
+            None => return Ok(request),
+        };
+
+        let query = inner.manifest.load().operations.get(&id).cloned();
+        match query {
+            Some(query) => {
+                tracing::info!(
+                    monotonic_counter.apollo_router_persisted_queries_count = 1u64,
+                    outcome = "resolved",
+                );
+                let body = request.supergraph_request.body_mut();
+                body.query = Some(query);
+                // the operation is now complete, APQ must not register it
+                body.extensions.remove(PERSISTED_QUERY_EXTENSION);
+                let _ = request.context.insert(PERSISTED_QUERY_ID_CONTEXT_KEY, id);
+                Ok(request)
+            }
+            // let APQ look for the operation in its cache
+            None if inner.apq_enabled => Ok(request),
+            None => {
+                tracing::info!(
+                    monotonic_counter.apollo_router_persisted_queries_count = 1u64,
+                    outcome = "not_found",
+                );
+                Err(error_response(
+                    format!("Persisted query '{id}' not found in the persisted query list"),
+                    PERSISTED_QUERY_NOT_IN_LIST_CODE,
+                    request,
+                ))
+            }
+        }
+    }
+
+    /// Checks the operation of a request against the manifest, once it is known.
+    ///
+    /// Unknown operations are logged if `log_unknown` is enabled, and rejected if safelisting is
+    /// enabled.
+    pub(crate) fn check_operation(
+        &self,
+        request: SupergraphRequest,
+    ) -> Result<SupergraphRequest, SupergraphResponse> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => return Ok(request),
+        };
+        if !inner.config.log_unknown && !inner.config.safelist.enabled {
+            return Ok(request);
+        }
+        if request.context.contains_key(PERSISTED_QUERY_ID_CONTEXT_KEY) {
+            return Ok(request);
+        }
+
+        let body = request.supergraph_request.body();
+        let query = body.query.as_deref().unwrap_or_default();
+        if inner.manifest.load().bodies.contains(query) {
+            return Ok(request);
+        }
+
+        if inner.config.log_unknown {
+            tracing::warn!(
+                operation_name = body.operation_name.as_deref().unwrap_or_default(),
+                operation_body = query,
+                "unknown operation, it is not in the persisted query manifest"
+            );
+        }
+        if !inner.config.safelist.enabled {
+            return Ok(request);
+        }
+
+        tracing::info!(
+            monotonic_counter.apollo_router_persisted_queries_count = 1u64,
+            outcome = "rejected",
+        );
+        Err(error_response(
+            "The operation is not in the persisted query safelist".to_string(),
+            QUERY_NOT_IN_SAFELIST_CODE,
+            request,
+        ))
+    }
+}
+
+fn error_response(message: String, code: &str, request: SupergraphRequest) -> SupergraphResponse {
+    SupergraphResponse::builder()
+        .error(
+            crate::error::Error::builder()
+                .message(message)
+                .extension_code(code)
+                .build(),
+        )
+        .status_code(StatusCode::BAD_REQUEST)
+        .context(request.context)
+        .build()
+        .expect("response is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use serde_json_bytes::json;
+    use tower::Service;
+
+    use super::*;
+    use crate::configuration::Apq;
+    use crate::configuration::Safelist;
+    use crate::configuration::Supergraph;
+    use crate::files::tests::create_temp_file;
+    use crate::files::tests::write_and_flush;
+    use crate::graphql;
+    use crate::services::layers::content_negociation::ACCEPTS_JSON_CONTEXT_KEY;
+    use crate::services::router;
+    use crate::services::router_service::from_supergraph_mock_callback_and_configuration;
+    use crate::Context;
+
+    const MANIFEST: &str = r#"{
+        "format": "apollo-persisted-query-manifest",
+        "version": 1,
+        "operations": [
+            {
+                "id": "me-id",
+                "name": "Me",
+                "type": "query",
+                "body": "query Me { me { name } }"
+            }
+        ]
+    }"#;
+
+    async fn router_service(
+        path: &Path,
+        apq_enabled: bool,
+        log_unknown: bool,
+        safelist: bool,
+    ) -> impl Service<
+        router::Request,
+        Response = router::Response,
+        Error = BoxError,
+        Future = futures::future::BoxFuture<'static, router::ServiceResult>,
+    > + Send {
+        let configuration = Configuration::fake_builder()
+            .supergraph(
+                Supergraph::fake_builder()
+                    .apq(Apq {
+                        enabled: apq_enabled,
+                        ..Default::default()
+                    })
+                    .build(),
+            )
+            .persisted_queries(PersistedQueries {
+                enabled: true,
+                manifest: Some(path.to_path_buf()),
+                hot_reload: false,
+                log_unknown,
+                safelist: Safelist { enabled: safelist },
+            })
+            .build()
+            .unwrap();
+
+        from_supergraph_mock_callback_and_configuration(
+            |request| {
+                let query = request.supergraph_request.body().query.clone();
+                Ok(SupergraphResponse::fake_builder()
+                    .data(json!({ "query": query }))
+                    .context(request.context)
+                    .build()
+                    .unwrap())
+            },
+            Arc::new(configuration),
+        )
+        .await
+    }
+
+    fn request(query: Option<&str>, id: Option<&str>) -> router::Request {
+        let context = Context::new();
+        context.insert(ACCEPTS_JSON_CONTEXT_KEY, true).unwrap();
+        let mut request = SupergraphRequest::fake_builder()
+            .and_query(query.map(String::from))
+            .context(context)
+            .build()
+            .unwrap();
+        if let Some(id) = id {
+            request.supergraph_request.body_mut().extensions.insert(
+                PERSISTED_QUERY_EXTENSION,
+                json!({ "version": 1, "sha256Hash": id }),
+            );
+        }
+        request.try_into().unwrap()
+    }
+
+    async fn call(
+        service: &mut impl Service<router::Request, Response = router::Response, Error = BoxError>,
+        request: router::Request,
+    ) -> (StatusCode, graphql::Response) {
+        let response = service.call(request).await.unwrap();
+        let status = response.response.status();
+        let body = response
+            .into_graphql_response_stream()
+            .await
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn it_parses_manifests() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        assert_eq!(
+            manifest.operations.get("me-id").map(String::as_str),
+            Some("query Me { me { name } }")
+        );
+        assert!(manifest.bodies.contains("query Me { me { name } }"));
+
+        let manifest = Manifest::parse(r#"{ "me-id": "query Me { me { name } }" }"#).unwrap();
+        assert_eq!(
+            manifest.operations.get("me-id").map(String::as_str),
+            Some("query Me { me { name } }")
+        );
+
+        assert!(Manifest::parse(r#"{ "operations": [{ "id": "me-id" }] }"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_resolves_operations_from_the_manifest() {
+        let (path, mut file) = create_temp_file();
+        write_and_flush(&mut file, MANIFEST).await;
+        let mut service = router_service(&path, false, false, false).await;
+
+        let (status, response) = call(&mut service, request(None, Some("me-id"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response.data,
+            Some(json!({ "query": "query Me { me { name } }" }))
+        );
+
+        let (status, response) = call(&mut service, request(None, Some("unknown-id"))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&json!(PERSISTED_QUERY_NOT_IN_LIST_CODE))
+        );
+
+        // full operations are still accepted without safelisting
+        let (status, response) = call(&mut service, request(Some("{ me { id } }"), None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.data, Some(json!({ "query": "{ me { id } }" })));
+    }
+
+    #[tokio::test]
+    async fn it_hands_unknown_ids_over_to_apq() {
+        let (path, mut file) = create_temp_file();
+        write_and_flush(&mut file, MANIFEST).await;
+        let mut service = router_service(&path, true, false, false).await;
+
+        let (_, response) = call(&mut service, request(None, Some("unknown-id"))).await;
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&json!("PERSISTED_QUERY_NOT_FOUND"))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_only_allows_operations_in_the_safelist() {
+        let (path, mut file) = create_temp_file();
+        write_and_flush(&mut file, MANIFEST).await;
+        let mut service = router_service(&path, false, true, true).await;
+
+        let (status, _) = call(&mut service, request(None, Some("me-id"))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = call(
+            &mut service,
+            request(Some("query Me { me { name } }"), None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, response) = call(&mut service, request(Some("{ me { id } }"), None)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&json!(QUERY_NOT_IN_SAFELIST_CODE))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reloads_the_manifest() {
+        let (path, mut file) = create_temp_file();
+        write_and_flush(&mut file, MANIFEST).await;
+        let configuration = Configuration::fake_builder()
+            .persisted_queries(PersistedQueries {
+                enabled: true,
+                manifest: Some(path.clone()),
+                hot_reload: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let layer = PersistedQueryLayer::new(&configuration).await.unwrap();
+        let manifest = layer.inner.as_ref().unwrap().manifest.clone();
+        assert!(manifest.load().operations.contains_key("me-id"));
+
+        write_and_flush(&mut file, r#"{ "other-id": "{ me { id } }" }"#).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!manifest.load().operations.contains_key("me-id"));
+        assert!(manifest.load().operations.contains_key("other-id"));
+    }
+}
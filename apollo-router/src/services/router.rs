@@ -19,6 +19,7 @@ use tower::BoxError;
 
 use super::supergraph;
 use super::MULTIPART_DEFER_CONTENT_TYPE;
+use super::MULTIPART_SUBSCRIPTION_CONTENT_TYPE;
 use crate::graphql;
 use crate::json_ext::Path;
 use crate::services::TryIntoHeaderName;
@@ -108,6 +109,16 @@ impl TryFrom<supergraph::Request> for Request {
     }
 }
 
+/// A part of a multipart subscription response: an event, a heartbeat (`{}`),
+/// or an error that ends the subscription
+#[derive(serde::Deserialize)]
+struct SubscriptionPart {
+    #[serde(default)]
+    payload: Option<graphql::Response>,
+    #[serde(default)]
+    errors: Vec<graphql::Error>,
+}
+
 assert_impl_all!(Response: Send);
 #[non_exhaustive]
 #[derive(Debug)]
@@ -211,36 +222,59 @@ impl Response {
     pub async fn into_graphql_response_stream(
         self,
     ) -> impl Stream<Item = Result<crate::graphql::Response, serde_json::Error>> {
-        Box::pin(
-            if self
-                .response
-                .headers()
-                .get(CONTENT_TYPE)
-                .iter()
-                .any(|value| *value == HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE))
-            {
-                let multipart = Multipart::new(self.response.into_body(), "graphql");
-
-                Either::Left(futures::stream::unfold(multipart, |mut m| async {
-                    if let Ok(Some(response)) = m.next_field().await {
-                        if let Ok(bytes) = response.bytes().await {
+        let content_type = self.response.headers().get(CONTENT_TYPE).cloned();
+        let is_multipart_defer =
+            content_type == Some(HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE));
+        let is_multipart_subscription = content_type
+            == Some(HeaderValue::from_static(
+                MULTIPART_SUBSCRIPTION_CONTENT_TYPE,
+            ));
+
+        Box::pin(if is_multipart_defer || is_multipart_subscription {
+            let multipart = Multipart::new(self.response.into_body(), "graphql");
+
+            Either::Left(futures::stream::unfold(
+                multipart,
+                move |mut m| async move {
+                    while let Ok(Some(response)) = m.next_field().await {
+                        let bytes = response.bytes().await.ok()?;
+                        if !is_multipart_subscription {
                             return Some((
                                 serde_json::from_slice::<crate::graphql::Response>(&bytes),
                                 m,
                             ));
                         }
+
+                        match serde_json::from_slice::<SubscriptionPart>(&bytes) {
+                            Ok(SubscriptionPart {
+                                payload: Some(payload),
+                                ..
+                            }) => return Some((Ok(payload), m)),
+                            Ok(SubscriptionPart {
+                                payload: None,
+                                errors,
+                            }) if !errors.is_empty() => {
+                                return Some((
+                                    Ok(crate::graphql::Response::builder().errors(errors).build()),
+                                    m,
+                                ))
+                            }
+                            // heartbeat
+                            Ok(_) => {}
+                            Err(err) => return Some((Err(err), m)),
+                        }
                     }
                     None
-                }))
-            } else {
-                let mut body = self.response.into_body();
-                let res = body.next().await.and_then(|res| res.ok());
-
-                Either::Right(
-                    futures::stream::iter(res.into_iter())
-                        .map(|bytes| serde_json::from_slice::<crate::graphql::Response>(&bytes)),
-                )
-            },
-        )
+                },
+            ))
+        } else {
+            let mut body = self.response.into_body();
+            let res = body.next().await.and_then(|res| res.ok());
+
+            Either::Right(
+                futures::stream::iter(res.into_iter())
+                    .map(|bytes| serde_json::from_slice::<crate::graphql::Response>(&bytes)),
+            )
+        })
     }
 }
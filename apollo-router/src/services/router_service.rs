@@ -1,12 +1,16 @@
 //! Implements the router phase of the request lifecycle.
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::task::Poll;
 
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
 use axum::body::StreamBody;
+use axum::extract::ConnectInfo;
 use axum::response::*;
 use bytes::Buf;
-use bytes::Bytes;
+use futures::future;
 use futures::future::ready;
 use futures::future::BoxFuture;
 use futures::stream;
@@ -32,7 +36,10 @@ use super::layers::apq::APQLayer;
 use super::layers::content_negociation;
 use super::layers::content_negociation::ACCEPTS_JSON_CONTEXT_KEY;
 use super::layers::content_negociation::ACCEPTS_MULTIPART_CONTEXT_KEY;
+use super::layers::content_negociation::ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY;
 use super::layers::content_negociation::ACCEPTS_WILDCARD_CONTEXT_KEY;
+use super::layers::content_negociation::IS_SUBSCRIPTION_CONTEXT_KEY;
+use super::layers::persisted_queries::PersistedQueryLayer;
 use super::layers::static_page::StaticPageLayer;
 use super::new_service::ServiceFactory;
 use super::router;
@@ -41,10 +48,15 @@ use super::HasPlugins;
 #[cfg(test)]
 use super::SupergraphCreator;
 use super::MULTIPART_DEFER_CONTENT_TYPE;
+use super::MULTIPART_SUBSCRIPTION_CONTENT_TYPE;
+use crate::cache::registry::CacheRegistry;
 use crate::cache::DeduplicatingCache;
+use crate::configuration::Batching;
 use crate::graphql;
 #[cfg(test)]
 use crate::plugin::test::MockSupergraphService;
+use crate::protocols::multipart::Multipart;
+use crate::protocols::multipart::ProtocolMode;
 use crate::router_factory::RouterFactory;
 use crate::services::layers::content_negociation::GRAPHQL_JSON_RESPONSE_HEADER_VALUE;
 use crate::services::RouterRequest;
@@ -52,6 +64,7 @@ use crate::services::RouterResponse;
 use crate::services::SupergraphRequest;
 use crate::services::SupergraphResponse;
 use crate::Configuration;
+use crate::Context;
 use crate::Endpoint;
 use crate::ListenAddr;
 
@@ -63,20 +76,35 @@ where
 {
     supergraph_creator: Arc<SF>,
     apq_layer: APQLayer,
+    persisted_query_layer: PersistedQueryLayer,
+    batching: Batching,
 }
 
 impl<SF> RouterService<SF>
 where
     SF: ServiceFactory<supergraph::Request> + Clone + Send + Sync + 'static,
 {
-    pub(crate) fn new(supergraph_creator: Arc<SF>, apq_layer: APQLayer) -> Self {
+    pub(crate) fn new(
+        supergraph_creator: Arc<SF>,
+        apq_layer: APQLayer,
+        persisted_query_layer: PersistedQueryLayer,
+        batching: Batching,
+    ) -> Self {
         RouterService {
             supergraph_creator,
             apq_layer,
+            persisted_query_layer,
+            batching,
         }
     }
 }
 
+/// The operations sent in an HTTP request
+enum GraphQLRequests {
+    Single(graphql::Request),
+    Batch(Vec<graphql::Request>),
+}
+
 #[cfg(test)]
 pub(crate) async fn from_supergraph_mock_callback_and_configuration(
     supergraph_callback: impl FnMut(supergraph::Request) -> supergraph::ServiceResult
@@ -105,6 +133,7 @@ pub(crate) async fn from_supergraph_mock_callback_and_configuration(
         &configuration,
     )
     .await
+    .expect("should create the router")
     .make()
 }
 
@@ -145,6 +174,7 @@ pub(crate) async fn empty() -> impl Service<
         &Configuration::default(),
     )
     .await
+    .expect("should create the router")
     .make()
 }
 
@@ -174,21 +204,27 @@ where
 
         let supergraph_creator = self.supergraph_creator.clone();
         let apq = self.apq_layer.clone();
+        let persisted_queries = self.persisted_query_layer.clone();
+        let batching = self.batching.clone();
 
         let fut = async move {
-            let graphql_request: Result<graphql::Request, (&str, String)> = if parts.method
+            let graphql_request: Result<GraphQLRequests, (&str, String)> = if parts.method
                 == Method::GET
             {
                 parts
                     .uri
                     .query()
                     .map(|q| {
-                        graphql::Request::from_urlencoded_query(q.to_string()).map_err(|e| {
-                            (
-                                "failed to decode a valid GraphQL request from path",
-                                format!("failed to decode a valid GraphQL request from path {e}"),
-                            )
-                        })
+                        graphql::Request::from_urlencoded_query(q.to_string())
+                            .map(GraphQLRequests::Single)
+                            .map_err(|e| {
+                                (
+                                    "failed to decode a valid GraphQL request from path",
+                                    format!(
+                                        "failed to decode a valid GraphQL request from path {e}"
+                                    ),
+                                )
+                            })
                     })
                     .unwrap_or_else(|| {
                         Err(("missing query string", "missing query string".to_string()))
@@ -203,7 +239,12 @@ where
                         )
                     })
                     .and_then(|bytes| {
-                        serde_json::from_reader(bytes.reader()).map_err(|err| {
+                        if batching.enabled && is_batch(&bytes) {
+                            serde_json::from_reader(bytes.reader()).map(GraphQLRequests::Batch)
+                        } else {
+                            serde_json::from_reader(bytes.reader()).map(GraphQLRequests::Single)
+                        }
+                        .map_err(|err| {
                             (
                                 "failed to deserialize the request body into JSON",
                                 format!("failed to deserialize the request body into JSON: {err}"),
@@ -213,40 +254,26 @@ where
             };
 
             match graphql_request {
-                Ok(graphql_request) => {
+                Ok(GraphQLRequests::Batch(graphql_requests)) => {
+                    call_batch(
+                        supergraph_creator,
+                        &apq,
+                        &persisted_queries,
+                        &batching,
+                        parts,
+                        graphql_requests,
+                        context,
+                    )
+                    .await
+                }
+                Ok(GraphQLRequests::Single(graphql_request)) => {
                     let request = SupergraphRequest {
                         supergraph_request: http::Request::from_parts(parts, graphql_request),
                         context,
                     };
 
-                    let request_res = apq.supergraph_request(request).await;
-
                     let SupergraphResponse { response, context } =
-                        match request_res.and_then(|request| {
-                            let query = request.supergraph_request.body().query.as_ref();
-
-                            if query.is_none() || query.unwrap().trim().is_empty() {
-                                let errors = vec![crate::error::Error::builder()
-                                    .message("Must provide query string.".to_string())
-                                    .extension_code("MISSING_QUERY_STRING")
-                                    .build()];
-                                tracing::error!(
-                                    monotonic_counter.apollo_router_http_requests_total = 1u64,
-                                    status = %StatusCode::BAD_REQUEST.as_u16(),
-                                    error = "Must provide query string",
-                                    "Must provide query string"
-                                );
-
-                                Err(SupergraphResponse::builder()
-                                    .errors(errors)
-                                    .status_code(StatusCode::BAD_REQUEST)
-                                    .context(request.context)
-                                    .build()
-                                    .expect("response is valid"))
-                            } else {
-                                Ok(request)
-                            }
-                        }) {
+                        match prepare_request(&apq, &persisted_queries, request).await {
                             Err(response) => response,
                             Ok(request) => supergraph_creator.create().oneshot(request).await?,
                         };
@@ -263,6 +290,14 @@ where
                         .get(ACCEPTS_MULTIPART_CONTEXT_KEY)
                         .unwrap_or_default()
                         .unwrap_or_default();
+                    let accepts_multipart_subscription: bool = context
+                        .get(ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY)
+                        .unwrap_or_default()
+                        .unwrap_or_default();
+                    let is_subscription: bool = context
+                        .get(IS_SUBSCRIPTION_CONTEXT_KEY)
+                        .unwrap_or_default()
+                        .unwrap_or_default();
 
                     let (mut parts, mut body) = response.into_parts();
                     process_vary_header(&mut parts.headers);
@@ -298,41 +333,23 @@ where
                                         context,
                                     })
                                 })
-                            } else if accepts_multipart {
-                                parts.headers.insert(
-                                    CONTENT_TYPE,
-                                    HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE),
-                                );
+                            } else if accepts_multipart
+                                || (is_subscription && accepts_multipart_subscription)
+                            {
+                                let (content_type, mode) =
+                                    if is_subscription && accepts_multipart_subscription {
+                                        (
+                                            MULTIPART_SUBSCRIPTION_CONTENT_TYPE,
+                                            ProtocolMode::Subscription,
+                                        )
+                                    } else {
+                                        (MULTIPART_DEFER_CONTENT_TYPE, ProtocolMode::Defer)
+                                    };
+                                parts
+                                    .headers
+                                    .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
 
-                                // each chunk contains a response and the next delimiter, to let client parsers
-                                // know that they can process the response right away
-                                let mut first_buf = Vec::from(
-                                    &b"\r\n--graphql\r\ncontent-type: application/json\r\n\r\n"[..],
-                                );
-                                serde_json::to_writer(&mut first_buf, &response)?;
-                                if response.has_next.unwrap_or(false) {
-                                    first_buf.extend_from_slice(b"\r\n--graphql\r\n");
-                                } else {
-                                    first_buf.extend_from_slice(b"\r\n--graphql--\r\n");
-                                }
-
-                                let body = once(ready(Ok(Bytes::from(first_buf)))).chain(body.map(
-                                    |res| {
-                                        let mut buf = Vec::from(
-                                            &b"content-type: application/json\r\n\r\n"[..],
-                                        );
-                                        serde_json::to_writer(&mut buf, &res)?;
-
-                                        // the last chunk has a different end delimiter
-                                        if res.has_next.unwrap_or(false) {
-                                            buf.extend_from_slice(b"\r\n--graphql\r\n");
-                                        } else {
-                                            buf.extend_from_slice(b"\r\n--graphql--\r\n");
-                                        }
-
-                                        Ok::<_, BoxError>(buf.into())
-                                    },
-                                ));
+                                let body = Multipart::new(once(ready(response)).chain(body), mode);
 
                                 let response =
                                     (parts, StreamBody::new(body)).into_response().map(|body| {
@@ -365,10 +382,11 @@ where
                                         serde_json::to_string(
                                             &graphql::Error::builder()
                                                 .message(format!(
-                                                    r#"'accept' header can't be different from \"*/*\", {:?}, {:?} or {:?}"#,
+                                                    r#"'accept' header can't be different from \"*/*\", {:?}, {:?}, {:?} or {:?}"#,
                                                     APPLICATION_JSON.essence_str(),
                                                     GRAPHQL_JSON_RESPONSE_HEADER_VALUE,
-                                                    MULTIPART_DEFER_CONTENT_TYPE
+                                                    MULTIPART_DEFER_CONTENT_TYPE,
+                                                    MULTIPART_SUBSCRIPTION_CONTENT_TYPE
                                                 ))
                                                 .extension_code("INVALID_ACCEPT_HEADER")
                                                 .build(),
@@ -414,6 +432,183 @@ where
     }
 }
 
+/// Resolves the query of a request from persisted queries or APQ, then checks that it is present
+/// and allowed.
+async fn prepare_request(
+    apq: &APQLayer,
+    persisted_queries: &PersistedQueryLayer,
+    request: SupergraphRequest,
+) -> Result<SupergraphRequest, SupergraphResponse> {
+    let request = persisted_queries.supergraph_request(request)?;
+    let request = apq.supergraph_request(request).await?;
+
+    let query = request.supergraph_request.body().query.as_ref();
+    if query.is_none() || query.unwrap().trim().is_empty() {
+        let errors = vec![crate::error::Error::builder()
+            .message("Must provide query string.".to_string())
+            .extension_code("MISSING_QUERY_STRING")
+            .build()];
+        tracing::error!(
+            monotonic_counter.apollo_router_http_requests_total = 1u64,
+            status = %StatusCode::BAD_REQUEST.as_u16(),
+            error = "Must provide query string",
+            "Must provide query string"
+        );
+
+        Err(SupergraphResponse::builder()
+            .errors(errors)
+            .status_code(StatusCode::BAD_REQUEST)
+            .context(request.context)
+            .build()
+            .expect("response is valid"))
+    } else {
+        persisted_queries.check_operation(request)
+    }
+}
+
+/// Executes a batch of operations, each one with its own context, and returns a JSON array
+/// of their responses, in the same order.
+async fn call_batch<SF>(
+    supergraph_creator: Arc<SF>,
+    apq: &APQLayer,
+    persisted_queries: &PersistedQueryLayer,
+    batching: &Batching,
+    parts: http::request::Parts,
+    graphql_requests: Vec<graphql::Request>,
+    context: Context,
+) -> Result<router::Response, BoxError>
+where
+    SF: ServiceFactory<supergraph::Request> + Clone + Send + Sync + 'static,
+    <SF as ServiceFactory<supergraph::Request>>::Service:
+        Service<supergraph::Request, Response = supergraph::Response, Error = BoxError> + Send,
+    <<SF as ServiceFactory<supergraph::Request>>::Service as Service<supergraph::Request>>::Future:
+        Send,
+{
+    if let Some(max_size) = batching.max_size {
+        if graphql_requests.len() > max_size {
+            return batch_error(
+                format!(
+                    "the batch contains {} operations, more than the maximum batch size of {max_size}",
+                    graphql_requests.len()
+                ),
+                "BATCH_LIMIT_EXCEEDED",
+                context,
+            );
+        }
+    }
+
+    let mut requests = Vec::with_capacity(graphql_requests.len());
+    for graphql_request in graphql_requests {
+        let request_context = Context::new();
+        request_context.extend(&context);
+        // the responses are serialized together in a JSON array, they cannot be multipart
+        request_context.insert(ACCEPTS_MULTIPART_CONTEXT_KEY, false)?;
+        request_context.insert(ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY, false)?;
+
+        let request = SupergraphRequest {
+            supergraph_request: http::Request::from_parts(batch_parts(&parts), graphql_request),
+            context: request_context,
+        };
+        requests.push(prepare_request(apq, persisted_queries, request).await);
+    }
+
+    let contains_deferred_operation = requests.iter().any(|request| match request {
+        Ok(request) => request
+            .supergraph_request
+            .body()
+            .query
+            .as_deref()
+            .map(contains_defer)
+            .unwrap_or_default(),
+        Err(_) => false,
+    });
+    if contains_deferred_operation {
+        return batch_error(
+            "batches cannot contain operations using @defer".to_string(),
+            "BATCHING_DEFER_UNSUPPORTED",
+            context,
+        );
+    }
+
+    let responses = future::join_all(requests.into_iter().map(|request| {
+        let supergraph_creator = supergraph_creator.clone();
+        async move {
+            let SupergraphResponse { mut response, .. } = match request {
+                Err(response) => response,
+                Ok(request) => supergraph_creator.create().oneshot(request).await?,
+            };
+            Ok::<_, BoxError>(response.body_mut().next().await.unwrap_or_default())
+        }
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut response = http::Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+        .body(Body::from(serde_json::to_string(&responses)?))?;
+    process_vary_header(response.headers_mut());
+
+    Ok(router::Response { response, context })
+}
+
+fn batch_error(
+    message: String,
+    extension_code: &str,
+    context: Context,
+) -> Result<router::Response, BoxError> {
+    let response = graphql::Response::builder()
+        .error(
+            graphql::Error::builder()
+                .message(message)
+                .extension_code(extension_code)
+                .build(),
+        )
+        .build();
+
+    Ok(router::Response {
+        response: http::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+            .body(Body::from(serde_json::to_string(&response)?))?,
+        context,
+    })
+}
+
+/// Whether the body of an HTTP request is a JSON array
+fn is_batch(body: &[u8]) -> bool {
+    body.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'[')
+}
+
+/// Whether a query uses the `@defer` directive
+fn contains_defer(query: &str) -> bool {
+    let document = apollo_parser::Parser::new(query).parse().document();
+    document
+        .syntax()
+        .descendants()
+        .filter_map(ast::Directive::cast)
+        .any(|directive| {
+            directive
+                .name()
+                .map(|name| &name.text().to_string() == "defer")
+                .unwrap_or_default()
+        })
+}
+
+/// Copies the parts of a batched HTTP request for each of its operations
+fn batch_parts(parts: &http::request::Parts) -> http::request::Parts {
+    let mut request = http::Request::new(());
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    if let Some(connect_info) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        request.extensions_mut().insert(connect_info.clone());
+    }
+    request.into_parts().0
+}
+
 // Process the headers to make sure that `VARY` is set correctly
 fn process_vary_header(headers: &mut HeaderMap<HeaderValue>) {
     if headers.get(VARY).is_none() {
@@ -431,6 +626,9 @@ where
     supergraph_creator: Arc<SF>,
     static_page: StaticPageLayer,
     apq_layer: APQLayer,
+    persisted_query_layer: PersistedQueryLayer,
+    batching: Batching,
+    caches: CacheRegistry,
 }
 
 impl<SF> ServiceFactory<router::Request> for RouterCreator<SF>
@@ -469,6 +667,10 @@ where
             .for_each(|p| mm.extend(p.web_endpoints()));
         mm
     }
+
+    fn caches(&self) -> CacheRegistry {
+        self.caches.clone()
+    }
 }
 
 impl<SF> RouterCreator<SF>
@@ -479,7 +681,10 @@ where
     <<SF as ServiceFactory<supergraph::Request>>::Service as Service<supergraph::Request>>::Future:
         Send,
 {
-    pub(crate) async fn new(supergraph_creator: Arc<SF>, configuration: &Configuration) -> Self {
+    pub(crate) async fn new(
+        supergraph_creator: Arc<SF>,
+        configuration: &Configuration,
+    ) -> Result<Self, BoxError> {
         let static_page = StaticPageLayer::new(configuration);
         let apq_layer = if configuration.supergraph.apq.enabled {
             APQLayer::with_cache(
@@ -492,12 +697,16 @@ where
         } else {
             APQLayer::disabled()
         };
+        let persisted_query_layer = PersistedQueryLayer::new(configuration).await?;
 
-        Self {
+        Ok(Self {
             supergraph_creator,
             static_page,
             apq_layer,
-        }
+            persisted_query_layer,
+            batching: configuration.batching.clone(),
+            caches: CacheRegistry::current().unwrap_or_default(),
+        })
     }
 
     pub(crate) fn make(
@@ -511,6 +720,8 @@ where
         let router_service = content_negociation::RouterLayer::default().layer(RouterService::new(
             self.supergraph_creator.clone(),
             self.apq_layer.clone(),
+            self.persisted_query_layer.clone(),
+            self.batching.clone(),
         ));
 
         ServiceBuilder::new()
@@ -539,7 +750,6 @@ mod tests {
 
     use super::*;
     use crate::services::supergraph;
-    use crate::Context;
 
     // Test Vary processing
 
@@ -698,4 +908,99 @@ mod tests {
         assert_eq!(expected_error, actual_error);
         assert!(response.errors[0].extensions.contains_key("code"));
     }
+
+    async fn call_batch_router(body: &str, batching: Batching) -> (StatusCode, serde_json::Value) {
+        let configuration = Configuration::fake_builder()
+            .batching(batching)
+            .build()
+            .unwrap();
+        let router_service = from_supergraph_mock_callback_and_configuration(
+            move |req| {
+                let query = req.supergraph_request.body().query.clone().unwrap();
+                Ok(SupergraphResponse::new_from_graphql_response(
+                    graphql::Response::builder()
+                        .data(json!({ "query": query }))
+                        .build(),
+                    req.context,
+                ))
+            },
+            Arc::new(configuration),
+        )
+        .await;
+
+        let request = http::Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router_service
+            .oneshot(router::Request::from(request))
+            .await
+            .unwrap()
+            .response;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn it_executes_batches_in_order() {
+        let (status, body) = call_batch_router(
+            r#"[{"query":"{ a }"}, {"query":""}, {"query":"{ b }"}]"#,
+            Batching {
+                enabled: true,
+                max_size: None,
+            },
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["data"]["query"], "{ a }");
+        assert_eq!(
+            body[1]["errors"][0]["extensions"]["code"],
+            "MISSING_QUERY_STRING"
+        );
+        assert_eq!(body[2]["data"]["query"], "{ b }");
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_batches() {
+        let (status, body) = call_batch_router(
+            r#"[{"query":"{ a }"}, {"query":"{ b }"}]"#,
+            Batching {
+                enabled: true,
+                max_size: Some(1),
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["errors"][0]["extensions"]["code"],
+            "BATCH_LIMIT_EXCEEDED"
+        );
+
+        let (status, body) = call_batch_router(
+            r#"[{"query":"{ a }"}, {"query":"{ b ... @defer { c } }"}]"#,
+            Batching {
+                enabled: true,
+                max_size: None,
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["errors"][0]["extensions"]["code"],
+            "BATCHING_DEFER_UNSUPPORTED"
+        );
+
+        let (status, _) = call_batch_router(
+            r#"[{"query":"{ a }"}]"#,
+            Batching {
+                enabled: false,
+                max_size: None,
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
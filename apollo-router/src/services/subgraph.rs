@@ -7,6 +7,7 @@ use serde_json_bytes::ByteString;
 use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
 use static_assertions::assert_impl_all;
+use tokio::sync::mpsc;
 use tower::BoxError;
 
 use crate::error::Error;
@@ -31,6 +32,9 @@ pub struct Request {
     pub operation_kind: OperationKind,
 
     pub context: Context,
+
+    /// Channel used by the subgraph service to send back the stream of events of a subscription
+    pub(crate) subscription_stream: Option<mpsc::Sender<graphql::ResponseStream>>,
 }
 
 #[buildstructor::buildstructor]
@@ -50,6 +54,7 @@ impl Request {
             subgraph_request,
             operation_kind,
             context,
+            subscription_stream: None,
         }
     }
 
@@ -99,6 +104,7 @@ impl Clone for Request {
             subgraph_request,
             operation_kind: self.operation_kind,
             context: self.context.clone(),
+            subscription_stream: self.subscription_stream.clone(),
         }
     }
 }
@@ -6,6 +6,7 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 
 use ::serde::Deserialize;
 use async_compression::tokio::write::BrotliEncoder;
@@ -18,17 +19,28 @@ use http::header::ACCEPT_ENCODING;
 use http::header::CONTENT_ENCODING;
 use http::header::CONTENT_TYPE;
 use http::header::{self};
+use http::uri::PathAndQuery;
+use http::uri::Scheme;
 use http::HeaderMap;
 use http::HeaderValue;
+use http::Uri;
 use hyper::client::HttpConnector;
 use hyper::Client;
 use hyper_rustls::ConfigBuilderExt;
 use hyper_rustls::HttpsConnector;
 use mime::APPLICATION_JSON;
 use opentelemetry::global;
+use rustls::Certificate;
+use rustls::PrivateKey;
 use rustls::RootCertStore;
 use schemars::JsonSchema;
 use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_util::either::Either;
 use tower::util::BoxService;
 use tower::BoxError;
 use tower::Service;
@@ -43,8 +55,11 @@ use super::layers::content_negociation::GRAPHQL_JSON_RESPONSE_HEADER_VALUE;
 use super::Plugins;
 use crate::error::FetchError;
 use crate::graphql;
+use crate::plugins::subscription::WebSocketConfiguration;
 use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
 use crate::plugins::telemetry::LOGGING_DISPLAY_HEADERS;
+use crate::protocols::websocket;
+use crate::query_planner::OperationKind;
 use crate::services::layers::apq;
 use crate::services::SubgraphRequest;
 use crate::services::SubgraphResponse;
@@ -80,6 +95,45 @@ pub(crate) enum Compression {
     Br,
 }
 
+/// Use of HTTP/2 for the connections to a subgraph
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Http2Config {
+    /// HTTP/2 is used if the subgraph supports it, negotiated with ALPN on TLS connections
+    #[default]
+    Enable,
+    /// HTTP/1.1 is always used
+    Disable,
+    /// HTTP/2 is always used, with prior knowledge (h2c) on plaintext connections
+    Http2Only,
+}
+
+/// Configuration of the HTTP client sending requests to a subgraph
+#[derive(PartialEq, Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HttpClientConfig {
+    /// Use of HTTP/2: `enable` (default), `disable` or `http2only`
+    pub(crate) http2: Option<Http2Config>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long idle connections are kept in the pool (default: 90s)
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    /// Maximum number of idle connections kept in the pool per host (default: no limit)
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout for establishing connections (default: no timeout)
+    pub(crate) connect_timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Interval of the HTTP/2 keepalive pings, also sent on idle connections (default: disabled)
+    pub(crate) http2_keep_alive_interval: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long to wait for the acknowledgement of a keepalive ping before closing the connection (default: 20s)
+    pub(crate) http2_keep_alive_timeout: Option<Duration>,
+}
+
 impl Display for Compression {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -106,6 +160,13 @@ pub(crate) struct SubgraphService {
     /// If a subgraph sends the error message PERSISTED_QUERY_NOT_SUPPORTED,
     /// apq is set to false
     apq: Arc<AtomicBool>,
+
+    /// WebSocket configuration used to execute subscriptions, set if subscriptions
+    /// are enabled for this subgraph
+    subscription_config: Option<WebSocketConfiguration>,
+
+    /// TLS configuration reused for WebSocket connections
+    tls_config: Arc<rustls::ClientConfig>,
 }
 
 impl SubgraphService {
@@ -113,38 +174,89 @@ impl SubgraphService {
         service: impl Into<String>,
         apq_enabled: bool,
         tls_cert_store: Option<RootCertStore>,
+        subscription_config: Option<WebSocketConfiguration>,
+    ) -> Self {
+        let tls_config = generate_tls_client_config(tls_cert_store, None)
+            .expect("a TLS configuration without client authentication is always valid");
+        Self::from_config(
+            service,
+            apq_enabled,
+            tls_config,
+            &HttpClientConfig::default(),
+            subscription_config,
+        )
+    }
+
+    pub(crate) fn from_config(
+        service: impl Into<String>,
+        apq_enabled: bool,
+        tls_config: rustls::ClientConfig,
+        client_config: &HttpClientConfig,
+        subscription_config: Option<WebSocketConfiguration>,
     ) -> Self {
         let mut http_connector = HttpConnector::new();
         http_connector.set_nodelay(true);
         http_connector.set_keepalive(Some(std::time::Duration::from_secs(60)));
+        http_connector.set_connect_timeout(client_config.connect_timeout);
         http_connector.enforce_http(false);
-        let tls_config = match tls_cert_store {
-            None => rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_native_roots()
-                .with_no_client_auth(),
-            Some(store) => rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(store)
-                .with_no_client_auth(),
+        let builder = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config.clone())
+            .https_or_http();
+        let http2 = client_config.http2.unwrap_or_default();
+        let connector = match http2 {
+            Http2Config::Enable => builder
+                .enable_http1()
+                .enable_http2()
+                .wrap_connector(http_connector),
+            Http2Config::Disable => builder.enable_http1().wrap_connector(http_connector),
+            Http2Config::Http2Only => builder.enable_http2().wrap_connector(http_connector),
         };
-        let connector = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .wrap_connector(http_connector);
+
+        let mut client_builder = hyper::Client::builder();
+        client_builder
+            .http2_only(http2 == Http2Config::Http2Only)
+            .http2_keep_alive_interval(client_config.http2_keep_alive_interval)
+            .http2_keep_alive_while_idle(client_config.http2_keep_alive_interval.is_some());
+        if let Some(pool_idle_timeout) = client_config.pool_idle_timeout {
+            client_builder.pool_idle_timeout(pool_idle_timeout);
+        }
+        if let Some(pool_max_idle_per_host) = client_config.pool_max_idle_per_host {
+            client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        if let Some(http2_keep_alive_timeout) = client_config.http2_keep_alive_timeout {
+            client_builder.http2_keep_alive_timeout(http2_keep_alive_timeout);
+        }
 
         Self {
             client: ServiceBuilder::new()
                 .layer(DecompressionLayer::new())
-                .service(hyper::Client::builder().build(connector)),
+                .service(client_builder.build(connector)),
             service: Arc::new(service.into()),
             apq: Arc::new(<AtomicBool>::new(apq_enabled)),
+            subscription_config,
+            tls_config: Arc::new(tls_config),
         }
     }
 }
 
+/// Creates the TLS configuration of the connections to a subgraph, verified with the certificate
+/// authorities of the system if no certificate store is provided, and authenticated with the
+/// client certificate chain and key if they are provided
+pub(crate) fn generate_tls_client_config(
+    tls_cert_store: Option<RootCertStore>,
+    client_certificate: Option<(Vec<Certificate>, PrivateKey)>,
+) -> Result<rustls::ClientConfig, rustls::Error> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let builder = match tls_cert_store {
+        None => builder.with_native_roots(),
+        Some(store) => builder.with_root_certificates(store),
+    };
+    match client_certificate {
+        Some((certificate_chain, key)) => builder.with_single_cert(certificate_chain, key),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
 impl tower::Service<SubgraphRequest> for SubgraphService {
     type Response = SubgraphResponse;
     type Error = BoxError;
@@ -171,6 +283,27 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
 
         let arc_apq_enabled = self.apq.clone();
 
+        if request.operation_kind == OperationKind::Subscription {
+            let subscription_config = self.subscription_config.clone();
+            let tls_config = self.tls_config.clone();
+            return Box::pin(async move {
+                let subscription_config =
+                    subscription_config.ok_or_else(|| FetchError::SubrequestWsError {
+                        service: service_name.clone(),
+                        reason: "subscriptions are not enabled for this subgraph".to_string(),
+                    })?;
+                call_websocket(
+                    request,
+                    body,
+                    context,
+                    service_name,
+                    subscription_config,
+                    tls_config,
+                )
+                .await
+            });
+        }
+
         let make_calls = async move {
             // If APQ is not enabled, simply make the graphql call
             // with the same request body.
@@ -237,6 +370,162 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
     }
 }
 
+/// call_websocket opens a WebSocket connection to the subgraph to execute a subscription,
+/// and sends the stream of its events back through the request's subscription channel
+async fn call_websocket(
+    request: SubgraphRequest,
+    body: graphql::Request,
+    context: Context,
+    service_name: String,
+    subscription_config: WebSocketConfiguration,
+    tls_config: Arc<rustls::ClientConfig>,
+) -> Result<SubgraphResponse, BoxError> {
+    let SubgraphRequest {
+        subgraph_request,
+        subscription_stream,
+        ..
+    } = request;
+    let ws_error = |reason: String| FetchError::SubrequestWsError {
+        service: service_name.clone(),
+        reason,
+    };
+
+    let subscription_stream = subscription_stream
+        .ok_or_else(|| ws_error("cannot get the subscription stream channel".to_string()))?;
+    let (parts, _) = subgraph_request.into_parts();
+
+    let mut uri_parts = parts.uri.into_parts();
+    uri_parts.scheme = Some(
+        match uri_parts.scheme.as_ref().map(Scheme::as_str) {
+            Some("https") | Some("wss") => Scheme::try_from("wss"),
+            _ => Scheme::try_from("ws"),
+        }
+        .expect("ws and wss are valid schemes; qed"),
+    );
+    if let Some(path) = &subscription_config.path {
+        uri_parts.path_and_query = Some(
+            PathAndQuery::try_from(path.as_str())
+                .map_err(|err| ws_error(format!("invalid websocket path: {err}")))?,
+        );
+    }
+    let uri = Uri::from_parts(uri_parts)
+        .map_err(|err| ws_error(format!("invalid websocket url: {err}")))?;
+
+    let mut request = (&uri)
+        .into_client_request()
+        .map_err(|err| ws_error(format!("cannot create the websocket request: {err}")))?;
+    for (name, value) in parts.headers.iter() {
+        if !is_websocket_reserved_header(name) {
+            request.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(subscription_config.protocol.subprotocol()),
+    );
+
+    let secure = uri.scheme_str() == Some("wss");
+    let host = uri.host().map(String::from).unwrap_or_default();
+    let port = uri
+        .port_u16()
+        .unwrap_or_else(|| if secure { 443 } else { 80 });
+    let subgraph_req_span = tracing::info_span!("subgraph_request",
+        "otel.kind" = "CLIENT",
+        "net.peer.name" = &display(&host),
+        "net.peer.port" = &display(port),
+        "http.route" = &display(uri.path()),
+        "http.url" = &display(&uri),
+        "net.transport" = "ip_tcp",
+        "apollo.subgraph.name" = %service_name
+    );
+    get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &subgraph_req_span.context(),
+            &mut opentelemetry_http::HeaderInjector(request.headers_mut()),
+        );
+    });
+    if context.contains_key(LOGGING_DISPLAY_HEADERS) {
+        tracing::info!(http.request.headers = ?request.headers(), apollo.subgraph.name = %service_name, "Request headers to subgraph {service_name:?}");
+    }
+    if context.contains_key(LOGGING_DISPLAY_BODY) {
+        tracing::info!(http.request.body = ?body, apollo.subgraph.name = %service_name, "Request body to subgraph {service_name:?}");
+    }
+
+    let connection = async {
+        let stream = connect_websocket_stream(&host, port, secure, tls_config).await?;
+        let (connection, _response) = client_async(request, stream).await?;
+        Ok::<_, BoxError>(connection)
+    }
+    .instrument(subgraph_req_span.clone())
+    .await
+    .map_err(|err| {
+        tracing::error!(fetch_error = format!("{err:?}").as_str());
+        ws_error(format!("cannot connect to the subgraph: {err}"))
+    })?;
+
+    let stream = websocket::subscribe(connection, subscription_config.protocol, body)
+        .instrument(subgraph_req_span)
+        .await
+        .map_err(|err| ws_error(format!("cannot start the subscription: {err}")))?;
+
+    subscription_stream
+        .send(stream)
+        .await
+        .map_err(|_| ws_error("cannot send the subscription stream".to_string()))?;
+
+    Ok(SubgraphResponse::new_from_response(
+        http::Response::new(graphql::Response::default()),
+        context,
+    ))
+}
+
+/// Opens the connection of a WebSocket to the subgraph, using the TLS configuration of its HTTP
+/// client for `wss` URLs
+async fn connect_websocket_stream(
+    host: &str,
+    port: u16,
+    secure: bool,
+    tls_config: Arc<rustls::ClientConfig>,
+) -> Result<Either<TcpStream, TlsStream<TcpStream>>, BoxError> {
+    // IPv6 addresses are enclosed in brackets in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+    if !secure {
+        return Ok(Either::Left(stream));
+    }
+
+    let server_name = rustls::ServerName::try_from(host)?;
+    let stream = TlsConnector::from(tls_config)
+        .connect(server_name, stream)
+        .await?;
+    Ok(Either::Right(stream))
+}
+
+/// Headers that must not be forwarded on a WebSocket handshake, because they are either
+/// set by the WebSocket client or only relevant to the original HTTP request
+fn is_websocket_reserved_header(name: &header::HeaderName) -> bool {
+    [
+        header::HOST,
+        header::CONNECTION,
+        header::UPGRADE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_TYPE,
+        header::CONTENT_ENCODING,
+        header::ACCEPT,
+        header::ACCEPT_ENCODING,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_VERSION,
+        header::SEC_WEBSOCKET_PROTOCOL,
+        header::SEC_WEBSOCKET_EXTENSIONS,
+    ]
+    .contains(name)
+        || name.as_str() == "keep-alive"
+}
+
 /// call_http makes http calls with modified graphql::Request (body)
 async fn call_http(
     request: SubgraphRequest,
@@ -327,6 +616,8 @@ async fn call_http(
                     return Err(FetchError::SubrequestHttpError {
                         service: service_name.clone(),
                         reason: err.to_string(),
+                        status_code: None,
+                        retry_after: None,
                     }.into());
                 }
                 Ok(response) => response,
@@ -356,11 +647,15 @@ async fn call_http(
                                 parts.status.as_str(),
                                 parts.status.canonical_reason().unwrap_or("Unknown")
                             ),
+                            status_code: Some(parts.status),
+                            retry_after: retry_after(&parts.headers),
                         }))
                     } else {
                         Err(BoxError::from(FetchError::SubrequestHttpError {
                             service: service_name.clone(),
                             reason: format!("subgraph didn't return JSON (expected content-type: {} or content-type: {GRAPHQL_JSON_RESPONSE_HEADER_VALUE}; found content-type: {content_type:?})", APPLICATION_JSON.essence_str()),
+                            status_code: Some(parts.status),
+                            retry_after: None,
                         }))
                     };
                 }
@@ -378,6 +673,8 @@ async fn call_http(
                 return Err(FetchError::SubrequestHttpError {
                     service: service_name.clone(),
                     reason: err.to_string(),
+                    status_code: None,
+                    retry_after: None,
                 }.into())
 
                 }, Ok(body) => body,
@@ -409,6 +706,18 @@ async fn call_http(
     Ok(SubgraphResponse::new_from_response(resp, context))
 }
 
+/// The delay requested by the subgraph with the Retry-After header, in seconds
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
 fn get_apq_error(gql_response: &graphql::Response) -> APQError {
     for error in &gql_response.errors {
         // Check if error message is an APQ error
@@ -966,11 +1275,79 @@ mod tests {
         server.await.unwrap();
     }
 
+    // starts a local server emulating a subgraph only accepting HTTP/2 with prior knowledge
+    async fn emulate_h2c_subgraph(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            assert_eq!(request.version(), http::Version::HTTP_2);
+            Ok(http::Response::builder()
+                .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+                .status(StatusCode::OK)
+                .body(
+                    serde_json::to_string(&Response {
+                        data: Some(Value::String(ByteString::from("test"))),
+                        ..Response::default()
+                    })
+                    .expect("always valid")
+                    .into(),
+                )
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).http2_only(true).serve(make_svc);
+        server.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_h2c_prior_knowledge() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3535").unwrap();
+        tokio::task::spawn(emulate_h2c_subgraph(socket_addr));
+        let subgraph_service = SubgraphService::from_config(
+            "test",
+            false,
+            generate_tls_client_config(None, None).unwrap(),
+            &HttpClientConfig {
+                http2: Some(Http2Config::Http2Only),
+                pool_max_idle_per_host: Some(1),
+                http2_keep_alive_interval: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+            None,
+        );
+
+        let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
+        let response = subgraph_service
+            .oneshot(SubgraphRequest {
+                supergraph_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+                subscription_stream: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(Value::String(ByteString::from("test")))
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_bad_status_code_should_not_fail() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2626").unwrap();
         tokio::task::spawn(emulate_subgraph_bad_request(socket_addr));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let response = subgraph_service
@@ -990,6 +1367,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1004,7 +1382,7 @@ mod tests {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2525").unwrap();
         tokio::task::spawn(emulate_subgraph_bad_response_format(socket_addr));
 
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let err = subgraph_service
@@ -1024,6 +1402,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap_err();
@@ -1037,7 +1416,7 @@ mod tests {
     async fn test_compressed_request_response_body() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2727").unwrap();
         tokio::task::spawn(emulate_subgraph_compressed_response(socket_addr));
-        let subgraph_service = SubgraphService::new("test", false, None);
+        let subgraph_service = SubgraphService::new("test", false, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let resp = subgraph_service
@@ -1058,6 +1437,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1074,7 +1454,7 @@ mod tests {
     async fn test_unauthorized() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2828").unwrap();
         tokio::task::spawn(emulate_subgraph_unauthorized(socket_addr));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let err = subgraph_service
@@ -1094,6 +1474,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap_err();
@@ -1107,7 +1488,7 @@ mod tests {
     async fn test_persisted_query_not_supported_message() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2929").unwrap();
         tokio::task::spawn(emulate_persisted_query_not_supported_message(socket_addr));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         assert!(subgraph_service.clone().apq.as_ref().load(Relaxed));
 
@@ -1130,6 +1511,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1149,7 +1531,7 @@ mod tests {
         tokio::task::spawn(emulate_persisted_query_not_supported_extension_code(
             socket_addr,
         ));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         assert!(subgraph_service.clone().apq.as_ref().load(Relaxed));
 
@@ -1172,6 +1554,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1189,7 +1572,7 @@ mod tests {
     async fn test_persisted_query_not_found_message() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:3131").unwrap();
         tokio::task::spawn(emulate_persisted_query_not_found_message(socket_addr));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let resp = subgraph_service
@@ -1210,6 +1593,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1228,7 +1612,7 @@ mod tests {
         tokio::task::spawn(emulate_persisted_query_not_found_extension_code(
             socket_addr,
         ));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let resp = subgraph_service
@@ -1249,6 +1633,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1265,7 +1650,7 @@ mod tests {
     async fn test_apq_enabled_subgraph_configuration() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:3333").unwrap();
         tokio::task::spawn(emulate_expected_apq_enabled_configuration(socket_addr));
-        let subgraph_service = SubgraphService::new("test", true, None);
+        let subgraph_service = SubgraphService::new("test", true, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let resp = subgraph_service
@@ -1286,6 +1671,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
@@ -1302,7 +1688,7 @@ mod tests {
     async fn test_apq_disabled_subgraph_configuration() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:3434").unwrap();
         tokio::task::spawn(emulate_expected_apq_disabled_configuration(socket_addr));
-        let subgraph_service = SubgraphService::new("test", false, None);
+        let subgraph_service = SubgraphService::new("test", false, None, None);
 
         let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
         let resp = subgraph_service
@@ -1323,6 +1709,7 @@ mod tests {
                     .expect("expecting valid request"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
+                subscription_stream: None,
             })
             .await
             .unwrap();
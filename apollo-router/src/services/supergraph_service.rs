@@ -18,12 +18,15 @@ use tracing_futures::Instrument;
 
 use super::layers::content_negociation;
 use super::layers::content_negociation::ACCEPTS_MULTIPART_CONTEXT_KEY;
+use super::layers::content_negociation::ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY;
+use super::layers::content_negociation::IS_SUBSCRIPTION_CONTEXT_KEY;
 use super::new_service::ServiceFactory;
 use super::subgraph_service::MakeSubgraphService;
 use super::subgraph_service::SubgraphServiceFactory;
 use super::ExecutionServiceFactory;
 use super::QueryPlannerContent;
 use crate::error::CacheResolverError;
+use crate::error::QueryPlannerError;
 use crate::error::ServiceBuildError;
 use crate::graphql;
 use crate::graphql::IntoGraphQLErrors;
@@ -42,7 +45,11 @@ use crate::services::QueryPlannerRequest;
 use crate::services::QueryPlannerResponse;
 use crate::services::SupergraphRequest;
 use crate::services::SupergraphResponse;
+use crate::spec::authorization;
+use crate::spec::authorization::RequestAccess;
+use crate::spec::query::cost::OPERATION_COST_CONTEXT_KEY;
 use crate::spec::Schema;
+use crate::spec::SpecError;
 use crate::Configuration;
 use crate::Context;
 use crate::Endpoint;
@@ -59,6 +66,7 @@ pub(crate) struct SupergraphService {
     execution_service_factory: ExecutionServiceFactory,
     query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
     schema: Arc<Schema>,
+    configuration: Arc<Configuration>,
 }
 
 #[buildstructor::buildstructor]
@@ -68,11 +76,13 @@ impl SupergraphService {
         query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
         execution_service_factory: ExecutionServiceFactory,
         schema: Arc<Schema>,
+        configuration: Arc<Configuration>,
     ) -> Self {
         SupergraphService {
             query_planner_service,
             execution_service_factory,
             schema,
+            configuration,
         }
     }
 }
@@ -96,10 +106,11 @@ impl Service<SupergraphRequest> for SupergraphService {
         let execution = self.execution_service_factory.create();
 
         let schema = self.schema.clone();
+        let configuration = self.configuration.clone();
 
         let context_cloned = req.context.clone();
-        let fut =
-            service_call(planning, execution, schema, req).or_else(|error: BoxError| async move {
+        let fut = service_call(planning, execution, schema, configuration, req).or_else(
+            |error: BoxError| async move {
                 let errors = vec![crate::error::Error {
                     message: error.to_string(),
                     extensions: serde_json_bytes::json!({
@@ -117,7 +128,8 @@ impl Service<SupergraphRequest> for SupergraphService {
                     .context(context_cloned)
                     .build()
                     .expect("building a response like this should not fail"))
-            });
+            },
+        );
 
         Box::pin(fut)
     }
@@ -127,12 +139,57 @@ async fn service_call<ExecutionService>(
     planning: CachingQueryPlanner<BridgeQueryPlanner>,
     execution: ExecutionService,
     schema: Arc<Schema>,
-    req: SupergraphRequest,
+    configuration: Arc<Configuration>,
+    mut req: SupergraphRequest,
 ) -> Result<SupergraphResponse, BoxError>
 where
     ExecutionService:
         Service<ExecutionRequest, Response = ExecutionResponse, Error = BoxError> + Send,
 {
+    let mut unauthorized_errors = Vec::new();
+    if configuration.authorization.enabled {
+        let body = req.supergraph_request.body();
+        let filtered = body.query.as_deref().and_then(|query| {
+            authorization::filter_query(
+                query,
+                body.operation_name.as_deref(),
+                &schema,
+                &RequestAccess::from_context(&req.context),
+            )
+        });
+        if let Some(filtered) = filtered {
+            unauthorized_errors = filtered
+                .unauthorized_paths
+                .into_iter()
+                .map(|path| {
+                    graphql::Error::builder()
+                        .message("Unauthorized field or type")
+                        .path(path)
+                        .extension_code("UNAUTHORIZED_FIELD_OR_TYPE")
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            tracing::info!(
+                monotonic_counter.apollo_router_unauthorized_fields_count =
+                    unauthorized_errors.len() as u64,
+            );
+
+            match filtered.query {
+                Some(query) if !configuration.authorization.reject_unauthorized => {
+                    req.supergraph_request.body_mut().query = Some(query);
+                }
+                _ => {
+                    return Ok(SupergraphResponse::builder()
+                        .context(req.context)
+                        .errors(unauthorized_errors)
+                        .status_code(StatusCode::FORBIDDEN)
+                        .build()
+                        .expect("this response build must not fail"));
+                }
+            }
+        }
+    }
+
     let context = req.context;
     let body = req.supergraph_request.body();
     let variables = body.variables.clone();
@@ -190,6 +247,10 @@ where
                 .get(ACCEPTS_MULTIPART_CONTEXT_KEY)
                 .unwrap_or_default()
                 .unwrap_or_default();
+            let accepts_multipart_subscription: bool = context
+                .get(ACCEPTS_MULTIPART_SUBSCRIPTION_CONTEXT_KEY)
+                .unwrap_or_default()
+                .unwrap_or_default();
 
             if is_deferred && !accepts_multipart {
                 let mut response = SupergraphResponse::new_from_graphql_response(graphql::Response::builder()
@@ -200,11 +261,61 @@ where
                     .build(), context);
                 *response.response.status_mut() = StatusCode::NOT_ACCEPTABLE;
                 Ok(response)
+            } else if plan.is_subscription()
+                && !accepts_multipart
+                && !accepts_multipart_subscription
+            {
+                let mut response = SupergraphResponse::new_from_graphql_response(graphql::Response::builder()
+                    .errors(vec![crate::error::Error::builder()
+                        .message(String::from("the router received a subscription but the client does not accept multipart/mixed HTTP responses. To enable subscription support, add the HTTP header 'Accept: multipart/mixed; subscriptionSpec=1.0'"))
+                        .extension_code("SUBSCRIPTION_BAD_HEADER")
+                        .build()])
+                    .build(), context);
+                *response.response.status_mut() = StatusCode::NOT_ACCEPTABLE;
+                Ok(response)
             } else if let Some(err) = plan.query.validate_variables(body, &schema).err() {
                 let mut res = SupergraphResponse::new_from_graphql_response(err, context);
                 *res.response.status_mut() = StatusCode::BAD_REQUEST;
                 Ok(res)
             } else {
+                let cost = &configuration.limits.cost;
+                if cost.enabled {
+                    let estimated_cost = plan
+                        .estimate_cost(&schema, operation_name.as_deref(), &variables, cost)
+                        .await;
+                    context.insert(OPERATION_COST_CONTEXT_KEY, estimated_cost)?;
+
+                    match cost.max_cost {
+                        Some(max_cost) if estimated_cost > max_cost => {
+                            if !configuration.limits.warn_only {
+                                let errors =
+                                    QueryPlannerError::from(SpecError::CostLimitExceeded {
+                                        cost: estimated_cost,
+                                        max_cost,
+                                    })
+                                    .into_graphql_errors()
+                                    .unwrap_or_default();
+                                return Ok(SupergraphResponse::builder()
+                                    .context(context)
+                                    .errors(errors)
+                                    .status_code(StatusCode::BAD_REQUEST)
+                                    .build()
+                                    .expect("this response build must not fail"));
+                            }
+                            tracing::warn!(
+                                operation_name = operation_name.as_deref().unwrap_or_default(),
+                                "operation exceeds the maximum cost ({estimated_cost} > {max_cost}), it would be rejected if warn_only was disabled"
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+
+                if plan.is_subscription() {
+                    // lets the router service pick the multipart format of subscriptions
+                    context.insert(IS_SUBSCRIPTION_CONTEXT_KEY, true)?;
+                }
+
                 let execution_response = execution
                     .oneshot(
                         ExecutionRequest::internal_builder()
@@ -220,6 +331,14 @@ where
                 let ExecutionResponse { response, context } = execution_response;
 
                 let (parts, response_stream) = response.into_parts();
+                // the errors of the removed fields are reported in the first response
+                let mut unauthorized_errors = Some(unauthorized_errors);
+                let response_stream = response_stream.map(move |mut response| {
+                    if let Some(errors) = unauthorized_errors.take() {
+                        response.errors.extend(errors);
+                    }
+                    response
+                });
 
                 Ok(SupergraphResponse {
                     context,
@@ -355,6 +474,7 @@ impl PluggableSupergraphServiceBuilder {
             subgraph_service_factory,
             schema: self.schema,
             plugins,
+            configuration,
         })
     }
 }
@@ -388,6 +508,7 @@ pub(crate) struct SupergraphCreator {
     subgraph_service_factory: Arc<SubgraphServiceFactory>,
     schema: Arc<Schema>,
     plugins: Arc<Plugins>,
+    configuration: Arc<Configuration>,
 }
 
 pub(crate) trait HasPlugins {
@@ -424,6 +545,7 @@ impl SupergraphCreator {
                 subgraph_service_factory: self.subgraph_service_factory.clone(),
             })
             .schema(self.schema.clone())
+            .configuration(self.configuration.clone())
             .build();
 
         let supergraph_service = match self
@@ -2083,4 +2205,145 @@ mod tests {
             serde_json::json!({"t": {"us": [{"f": "fA"}, {"f": "fB"}]}}),
         );
     }
+
+    const AUTHORIZATION_SCHEMA: &str = r#"schema
+        @core(feature: "https://specs.apollo.dev/core/v0.1")
+        @core(feature: "https://specs.apollo.dev/join/v0.1")
+         {
+        query: Query
+   }
+   directive @core(feature: String!) repeatable on SCHEMA
+   directive @join__field(graph: join__Graph, requires: join__FieldSet, provides: join__FieldSet) on FIELD_DEFINITION
+   directive @join__type(graph: join__Graph!, key: join__FieldSet) repeatable on OBJECT | INTERFACE
+   directive @join__owner(graph: join__Graph!) on OBJECT | INTERFACE
+   directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+   directive @authenticated on OBJECT | FIELD_DEFINITION | INTERFACE
+   scalar join__FieldSet
+   enum join__Graph {
+       USER @join__graph(name: "user", url: "http://localhost:4001/graphql")
+   }
+   type Query {
+       currentUser: User @join__field(graph: USER)
+   }
+   type User
+   @join__owner(graph: USER)
+   @join__type(graph: USER, key: "id"){
+       id: ID!
+       name: String @authenticated
+   }"#;
+
+    async fn authorization_service(reject_unauthorized: bool) -> supergraph::BoxCloneService {
+        let subgraphs = MockedSubgraphs(
+            [(
+                "user",
+                MockSubgraph::builder()
+                    .with_json(
+                        serde_json::json! {{"query":"{currentUser{id}}"}},
+                        serde_json::json! {{"data": {"currentUser": { "id": "1" }}}},
+                    )
+                    .with_json(
+                        serde_json::json! {{"query":"{currentUser{id name}}"}},
+                        serde_json::json! {{"data": {"currentUser": { "id": "1", "name": "Ada" }}}},
+                    )
+                    .build(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        TestHarness::builder()
+            .configuration_json(serde_json::json!({
+                "authorization": {
+                    "enabled": true,
+                    "reject_unauthorized": reject_unauthorized
+                }
+            }))
+            .unwrap()
+            .schema(AUTHORIZATION_SCHEMA)
+            .extra_plugin(subgraphs)
+            .build_supergraph()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unauthorized_operations() {
+        let service = authorization_service(true).await;
+
+        let request = supergraph::Request::fake_builder()
+            .query("{ currentUser { id name } }")
+            .build()
+            .unwrap();
+        let mut response = service.oneshot(request).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::FORBIDDEN);
+        let response = response.next_response().await.unwrap();
+        assert_eq!(response.data, None);
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&serde_json_bytes::json!("UNAUTHORIZED_FIELD_OR_TYPE"))
+        );
+        assert_eq!(
+            serde_json::to_value(&response.errors[0].path).unwrap(),
+            serde_json::json!(["currentUser", "name"])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_removes_unauthorized_fields() {
+        let mock_writer =
+            tracing_test::internal::MockWriter::new(&tracing_test::internal::GLOBAL_BUF);
+        let subscriber = tracing_test::internal::get_subscriber(mock_writer, "apollo_router=info");
+        let _guard = tracing::dispatcher::set_default(&subscriber);
+
+        let service = authorization_service(false).await;
+
+        let request = supergraph::Request::fake_builder()
+            .query("{ currentUser { id name } }")
+            .build()
+            .unwrap();
+        let mut response = service.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+        let response = response.next_response().await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&response.data).unwrap(),
+            serde_json::json!({"currentUser": {"id": "1"}}),
+        );
+        // the errors of the removed fields are appended to the first response
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&response.errors[0].path).unwrap(),
+            serde_json::json!(["currentUser", "name"])
+        );
+        assert!(tracing_test::internal::logs_with_scope_contain(
+            "apollo_router",
+            "monotonic_counter.apollo_router_unauthorized_fields_count=1"
+        ));
+
+        // authenticated requests access the entire query
+        let context = Context::new();
+        context
+            .insert(
+                crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS,
+                serde_json::json!({"sub": "1"}),
+            )
+            .unwrap();
+        let request = supergraph::Request::fake_builder()
+            .query("{ currentUser { id name } }")
+            .context(context)
+            .build()
+            .unwrap();
+        let response = service
+            .oneshot(request)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&response.data).unwrap(),
+            serde_json::json!({"currentUser": {"id": "1", "name": "Ada"}}),
+        );
+        assert!(response.errors.is_empty());
+    }
 }
@@ -0,0 +1,748 @@
+//! Authorization of fields and types with the `@authenticated` and `@requiresScopes` directives.
+//!
+//! Types and fields of the supergraph can require an authenticated request, or a set of scopes
+//! from the JWT claims. Before planning, the fields and types the request is not authorized to
+//! access are removed from the query, and reported as errors at their paths in the response.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use apollo_compiler::hir;
+use apollo_compiler::ApolloCompiler;
+use apollo_compiler::HirDatabase;
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
+
+use crate::json_ext::Path;
+use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::query_planner::OperationKind;
+use crate::spec::Schema;
+use crate::Context;
+
+const AUTHENTICATED_DIRECTIVE_NAME: &str = "authenticated";
+const REQUIRES_SCOPES_DIRECTIVE_NAME: &str = "requiresScopes";
+
+/// Claim holding the scopes of the request, as a space separated string or a list of strings
+const SCOPE_CLAIM: &str = "scope";
+
+/// Authorization requirements of the types and fields of a schema
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct AuthorizationRequirements {
+    /// types and fields (as `Type.field`) requiring an authenticated request
+    authenticated: BTreeSet<String>,
+    /// types and fields requiring scopes: the request must have all the scopes of one of the sets
+    scopes: BTreeMap<String, Vec<BTreeSet<String>>>,
+}
+
+impl AuthorizationRequirements {
+    pub(crate) fn new(compiler: &ApolloCompiler) -> Self {
+        let mut requirements = Self::default();
+        for (name, def) in compiler.db.object_types().iter() {
+            requirements.add(name, def.directives());
+            for ext in def.extensions() {
+                requirements.add(name, ext.directives());
+            }
+            for field in def.fields_definition().iter().chain(
+                def.extensions()
+                    .iter()
+                    .flat_map(|ext| ext.fields_definition()),
+            ) {
+                requirements.add(&format!("{name}.{}", field.name()), field.directives());
+            }
+        }
+        for (name, def) in compiler.db.interfaces().iter() {
+            requirements.add(name, def.directives());
+            for ext in def.extensions() {
+                requirements.add(name, ext.directives());
+            }
+            for field in def.fields_definition().iter().chain(
+                def.extensions()
+                    .iter()
+                    .flat_map(|ext| ext.fields_definition()),
+            ) {
+                requirements.add(&format!("{name}.{}", field.name()), field.directives());
+            }
+        }
+        // an interface field can be resolved on any implementing object type, so it requires
+        // what this object type and its field require
+        let subtype_map = compiler.db.subtype_map();
+        let object_types = compiler.db.object_types();
+        for (name, def) in compiler.db.interfaces().iter() {
+            let implementers = match subtype_map.get(name) {
+                Some(implementers) => implementers,
+                None => continue,
+            };
+            for field in def.fields_definition().iter().chain(
+                def.extensions()
+                    .iter()
+                    .flat_map(|ext| ext.fields_definition()),
+            ) {
+                let key = format!("{name}.{}", field.name());
+                for implementer in implementers
+                    .iter()
+                    .filter(|implementer| object_types.contains_key(*implementer))
+                {
+                    requirements.inherit(&key, implementer);
+                    requirements.inherit(&key, &format!("{implementer}.{}", field.name()));
+                }
+            }
+        }
+        requirements
+    }
+
+    fn add(&mut self, key: &str, directives: &[hir::Directive]) {
+        for directive in directives {
+            if directive.name() == AUTHENTICATED_DIRECTIVE_NAME {
+                self.authenticated.insert(key.to_string());
+            } else if directive.name() == REQUIRES_SCOPES_DIRECTIVE_NAME {
+                if let Some(hir::Value::List(sets)) = directive.argument_by_name("scopes") {
+                    let sets = sets.iter().filter_map(|set| match set {
+                        hir::Value::List(scopes) => Some(
+                            scopes
+                                .iter()
+                                .filter_map(|scope| match scope {
+                                    hir::Value::String(scope) => Some(scope.clone()),
+                                    _ => None,
+                                })
+                                .collect(),
+                        ),
+                        _ => None,
+                    });
+                    self.scopes.entry(key.to_string()).or_default().extend(sets);
+                }
+            }
+        }
+    }
+
+    /// Adds the requirements of a type or field to those of another one
+    fn inherit(&mut self, key: &str, from: &str) {
+        if self.authenticated.contains(from) {
+            self.authenticated.insert(key.to_string());
+        }
+        if let Some(from_sets) = self.scopes.get(from).cloned() {
+            let sets = match self.scopes.get(key) {
+                // the request must satisfy both requirements
+                Some(sets) => sets
+                    .iter()
+                    .flat_map(|set| {
+                        from_sets
+                            .iter()
+                            .map(move |from_set| set.union(from_set).cloned().collect())
+                    })
+                    .collect(),
+                None => from_sets,
+            };
+            self.scopes.insert(key.to_string(), sets);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.authenticated.is_empty() && self.scopes.is_empty()
+    }
+
+    /// Whether a request can access a type or a field (as `Type.field`)
+    fn allows(&self, key: &str, access: &RequestAccess) -> bool {
+        (access.authenticated || !self.authenticated.contains(key))
+            && self.scopes.get(key).map_or(true, |sets| {
+                sets.iter()
+                    .any(|set| set.iter().all(|scope| access.scopes.contains(scope)))
+            })
+    }
+}
+
+/// What a request can access, from the JWT claims validated by the authentication plugin
+#[derive(Debug, Default)]
+pub(crate) struct RequestAccess {
+    authenticated: bool,
+    scopes: HashSet<String>,
+}
+
+impl RequestAccess {
+    pub(crate) fn from_context(context: &Context) -> Self {
+        let claims = match context
+            .get::<_, serde_json::Value>(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+            .ok()
+            .flatten()
+        {
+            Some(claims) => claims,
+            None => return Self::default(),
+        };
+        let scopes = match claims.get(SCOPE_CLAIM) {
+            Some(serde_json::Value::String(scopes)) => {
+                scopes.split_whitespace().map(str::to_string).collect()
+            }
+            Some(serde_json::Value::Array(scopes)) => scopes
+                .iter()
+                .filter_map(|scope| scope.as_str().map(str::to_string))
+                .collect(),
+            _ => HashSet::new(),
+        };
+        Self {
+            authenticated: true,
+            scopes,
+        }
+    }
+
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Identifies what the request can access, for the caches of responses to filtered queries
+    pub(crate) fn cache_key(&self) -> String {
+        if !self.authenticated {
+            return String::new();
+        }
+        let mut scopes: Vec<&str> = self.scopes.iter().map(String::as_str).collect();
+        scopes.sort_unstable();
+        format!("authenticated:{}", scopes.join(" "))
+    }
+}
+
+/// A query without the fields and types the request cannot access
+#[derive(Debug)]
+pub(crate) struct FilteredQuery {
+    /// The filtered query, `None` if nothing is left to execute in the operation
+    pub(crate) query: Option<String>,
+    /// Paths of the removed fields and fragments in the response
+    pub(crate) unauthorized_paths: Vec<Path>,
+}
+
+/// Removes the fields and types a request cannot access from a query.
+///
+/// Returns `None` if the request can access the entire query.
+pub(crate) fn filter_query(
+    query: &str,
+    operation_name: Option<&str>,
+    schema: &Schema,
+    access: &RequestAccess,
+) -> Option<FilteredQuery> {
+    if schema.authorization.is_empty() {
+        return None;
+    }
+    let document = apollo_parser::Parser::new(query).parse().document();
+
+    let mut filter = Filter {
+        schema,
+        access,
+        fragments: document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    Some((fragment_name(&fragment)?, fragment))
+                }
+                _ => None,
+            })
+            .collect(),
+        removed: BTreeSet::new(),
+        walked_fragments: HashSet::new(),
+        used_fragments: HashSet::new(),
+        visiting: HashSet::new(),
+        unauthorized_paths: Vec::new(),
+    };
+
+    let mut executed_operation_is_empty = false;
+    let operations = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for (index, operation) in operations.iter().enumerate() {
+        let name = operation.name().map(|name| name.text().to_string());
+        let is_executed = match operation_name {
+            Some(operation_name) => name.as_deref() == Some(operation_name),
+            None => index == 0,
+        };
+        let kind = operation
+            .operation_type()
+            .map(OperationKind::from)
+            .unwrap_or(OperationKind::Query);
+        let root_type = schema.root_operation_name(kind);
+
+        let keep = if !filter.allows(root_type) {
+            filter.unauthorized_paths.push(Path::default());
+            false
+        } else {
+            match operation.selection_set() {
+                Some(selection_set) => {
+                    filter.selection_set(&selection_set, root_type, &mut Vec::new())
+                }
+                None => true,
+            }
+        };
+        if !keep {
+            filter.remove(operation.syntax());
+            executed_operation_is_empty |= is_executed;
+        }
+    }
+
+    if filter.removed.is_empty() {
+        return None;
+    }
+
+    // fragments are only left if they are still used
+    for (name, fragment) in &filter.fragments {
+        if filter.walked_fragments.contains(name) && !filter.used_fragments.contains(name) {
+            filter.remove(fragment.syntax());
+        }
+    }
+
+    let unauthorized_paths = filter.unauthorized_paths;
+    if executed_operation_is_empty {
+        return Some(FilteredQuery {
+            query: None,
+            unauthorized_paths,
+        });
+    }
+
+    let query = remove_ranges(query, &filter.removed);
+    let unused_variables = unused_variables(&query);
+    Some(FilteredQuery {
+        query: Some(remove_ranges(&query, &unused_variables)),
+        unauthorized_paths,
+    })
+}
+
+struct Filter<'a> {
+    schema: &'a Schema,
+    access: &'a RequestAccess,
+    fragments: HashMap<String, ast::FragmentDefinition>,
+    /// text ranges removed from the query
+    removed: BTreeSet<(usize, usize)>,
+    /// fragments spread in the operations
+    walked_fragments: HashSet<String>,
+    /// fragments still spread in the operations after filtering
+    used_fragments: HashSet<String>,
+    /// fragments being walked, to stop on fragment cycles
+    visiting: HashSet<String>,
+    unauthorized_paths: Vec<Path>,
+}
+
+impl<'a> Filter<'a> {
+    fn allows(&self, key: &str) -> bool {
+        self.schema.authorization.allows(key, self.access)
+    }
+
+    fn remove(&mut self, node: &ast::SyntaxNode) {
+        let range = node.text_range();
+        self.removed
+            .insert((range.start().into(), range.end().into()));
+    }
+
+    fn unauthorized(&mut self, path: &[String]) {
+        self.unauthorized_paths.push(Path::from_slice(path));
+    }
+
+    /// Filters a selection set, returning whether any selection is left in it
+    fn selection_set(
+        &mut self,
+        selection_set: &ast::SelectionSet,
+        type_name: &str,
+        path: &mut Vec<String>,
+    ) -> bool {
+        let mut keep_any = false;
+        for selection in selection_set.selections() {
+            let keep = match &selection {
+                ast::Selection::Field(field) => self.field(field, type_name, path),
+                ast::Selection::InlineFragment(inline_fragment) => {
+                    let type_name = inline_fragment
+                        .type_condition()
+                        .and_then(|type_condition| type_condition_name(&type_condition))
+                        .unwrap_or_else(|| type_name.to_string());
+                    self.fragment_selection_set(inline_fragment.selection_set(), &type_name, path)
+                }
+                ast::Selection::FragmentSpread(fragment_spread) => {
+                    self.fragment_spread(fragment_spread, path)
+                }
+            };
+            if keep {
+                keep_any = true;
+            } else {
+                self.remove(selection.syntax());
+            }
+        }
+        keep_any
+    }
+
+    fn field(&mut self, field: &ast::Field, type_name: &str, path: &mut Vec<String>) -> bool {
+        let name = match field.name() {
+            Some(name) => name.text().to_string(),
+            None => return true,
+        };
+        // __typename and introspection fields
+        if name.starts_with("__") {
+            return true;
+        }
+
+        let schema = self.schema;
+        let field_type_name = match schema
+            .object_types
+            .get(type_name)
+            .and_then(|ty| ty.field(&name))
+            .or_else(|| {
+                schema
+                    .interfaces
+                    .get(type_name)
+                    .and_then(|ty| ty.field(&name))
+            })
+            .and_then(|field_type| field_type.inner_type_name())
+        {
+            Some(field_type_name) => field_type_name,
+            // invalid fields are reported by validation
+            None => return true,
+        };
+
+        path.push(
+            field
+                .alias()
+                .and_then(|alias| alias.name())
+                .map(|alias| alias.text().to_string())
+                .unwrap_or_else(|| name.clone()),
+        );
+        let keep = if !self.allows(&format!("{type_name}.{name}")) || !self.allows(field_type_name)
+        {
+            self.unauthorized(path);
+            false
+        } else {
+            match field.selection_set() {
+                Some(selection_set) => self.selection_set(&selection_set, field_type_name, path),
+                None => true,
+            }
+        };
+        path.pop();
+        keep
+    }
+
+    fn fragment_spread(
+        &mut self,
+        fragment_spread: &ast::FragmentSpread,
+        path: &mut Vec<String>,
+    ) -> bool {
+        let name = match fragment_spread.fragment_name().and_then(|name| name.name()) {
+            Some(name) => name.text().to_string(),
+            None => return true,
+        };
+        let fragment = match self.fragments.get(&name) {
+            Some(fragment) => fragment.clone(),
+            // unknown fragments are reported by validation
+            None => return true,
+        };
+        let type_name = match fragment
+            .type_condition()
+            .and_then(|type_condition| type_condition_name(&type_condition))
+        {
+            Some(type_name) => type_name,
+            None => return true,
+        };
+        if !self.visiting.insert(name.clone()) {
+            return true;
+        }
+
+        self.walked_fragments.insert(name.clone());
+        let keep = self.fragment_selection_set(fragment.selection_set(), &type_name, path);
+        self.visiting.remove(&name);
+        if keep {
+            self.used_fragments.insert(name);
+        }
+        keep
+    }
+
+    fn fragment_selection_set(
+        &mut self,
+        selection_set: Option<ast::SelectionSet>,
+        type_name: &str,
+        path: &mut Vec<String>,
+    ) -> bool {
+        if !self.allows(type_name) {
+            self.unauthorized(path);
+            return false;
+        }
+        match selection_set {
+            Some(selection_set) => self.selection_set(&selection_set, type_name, path),
+            None => true,
+        }
+    }
+}
+
+fn fragment_name(fragment: &ast::FragmentDefinition) -> Option<String> {
+    Some(fragment.fragment_name()?.name()?.text().to_string())
+}
+
+fn type_condition_name(type_condition: &ast::TypeCondition) -> Option<String> {
+    Some(type_condition.named_type()?.name()?.text().to_string())
+}
+
+/// Text ranges of the variable definitions which are not used anymore in their operation
+fn unused_variables(query: &str) -> BTreeSet<(usize, usize)> {
+    let document = apollo_parser::Parser::new(query).parse().document();
+    let fragments: HashMap<String, ast::FragmentDefinition> = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::FragmentDefinition(fragment) => {
+                Some((fragment_name(&fragment)?, fragment))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut unused = BTreeSet::new();
+    for definition in document.definitions() {
+        let operation = match definition {
+            ast::Definition::OperationDefinition(operation) => operation,
+            _ => continue,
+        };
+        let variable_definitions = match operation.variable_definitions() {
+            Some(variable_definitions) => variable_definitions,
+            None => continue,
+        };
+
+        // variables used in the operation and the fragments it spreads
+        let mut used_variables = HashSet::new();
+        let mut nodes = operation
+            .selection_set()
+            .map(|selection_set| selection_set.syntax().clone())
+            .into_iter()
+            .chain(
+                operation
+                    .directives()
+                    .map(|directives| directives.syntax().clone()),
+            )
+            .collect::<Vec<_>>();
+        let mut spread_fragments = HashSet::new();
+        while let Some(node) = nodes.pop() {
+            for descendant in node.descendants() {
+                if let Some(variable) = ast::Variable::cast(descendant.clone()) {
+                    if let Some(name) = variable.name() {
+                        used_variables.insert(name.text().to_string());
+                    }
+                } else if let Some(fragment_spread) = ast::FragmentSpread::cast(descendant) {
+                    if let Some(fragment) = fragment_spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .and_then(|name| fragments.get(&name.text().to_string()))
+                    {
+                        if spread_fragments.insert(fragment.syntax().text_range()) {
+                            nodes.push(fragment.syntax().clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        let (used, unused_definitions): (Vec<_>, Vec<_>) = variable_definitions
+            .variable_definitions()
+            .partition(|variable_definition| {
+                variable_definition
+                    .variable()
+                    .and_then(|variable| variable.name())
+                    .map_or(true, |name| {
+                        used_variables.contains(&name.text().to_string())
+                    })
+            });
+        if used.is_empty() {
+            // `()` is not a valid list of variable definitions
+            let range = variable_definitions.syntax().text_range();
+            unused.insert((range.start().into(), range.end().into()));
+        } else {
+            for variable_definition in unused_definitions {
+                let range = variable_definition.syntax().text_range();
+                unused.insert((range.start().into(), range.end().into()));
+            }
+        }
+    }
+    unused
+}
+
+/// Removes text ranges from a string, overlapping ranges being merged
+fn remove_ranges(text: &str, ranges: &BTreeSet<(usize, usize)>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+    for (start, end) in ranges {
+        if *start < position {
+            position = position.max(*end);
+            continue;
+        }
+        result.push_str(&text[position..*start]);
+        position = *end;
+    }
+    result.push_str(&text[position..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Configuration;
+
+    const SCHEMA: &str = r#"
+        schema
+            @core(feature: "https://specs.apollo.dev/core/v0.1")
+            @core(feature: "https://specs.apollo.dev/join/v0.1")
+        {
+            query: Query
+        }
+        directive @core(feature: String!) repeatable on SCHEMA
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        directive @authenticated on OBJECT | FIELD_DEFINITION | INTERFACE
+        directive @requiresScopes(scopes: [[String!]!]!) on OBJECT | FIELD_DEFINITION | INTERFACE
+        enum join__Graph {
+            TEST @join__graph(name: "test", url: "http://localhost:4001/graphql")
+        }
+
+        type Query {
+            products: [Product]
+            me: User @authenticated
+            product(id: ID!): Product
+            posts: [Post]
+        }
+
+        type Product {
+            id: ID!
+            name: String
+            reviews: [Review]
+            inventory: Int @requiresScopes(scopes: [["read:inventory"], ["admin"]])
+        }
+
+        type Review @authenticated {
+            body: String
+        }
+
+        type User {
+            id: ID!
+            email: String @requiresScopes(scopes: [["read:email", "read:user"]])
+        }
+
+        interface Post {
+            title: String
+            body: String
+        }
+
+        type Article implements Post {
+            title: String
+            body: String @authenticated
+        }
+
+        type Announcement implements Post {
+            title: String @requiresScopes(scopes: [["read:announcement"]])
+            body: String
+        }
+    "#;
+
+    fn filter(query: &str, access: RequestAccess) -> Option<(Option<String>, Vec<String>)> {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        filter_query(query, None, &schema, &access).map(|filtered| {
+            (
+                filtered.query,
+                filtered
+                    .unauthorized_paths
+                    .iter()
+                    .map(|path| path.to_string())
+                    .collect(),
+            )
+        })
+    }
+
+    fn access(scopes: &[&str]) -> RequestAccess {
+        RequestAccess {
+            authenticated: true,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn it_reads_the_directives_of_the_schema() {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        let requirements = &schema.authorization;
+        assert!(requirements.authenticated.contains("Query.me"));
+        assert!(requirements.authenticated.contains("Review"));
+        assert_eq!(
+            requirements.scopes.get("User.email"),
+            Some(&vec![["read:email", "read:user"]
+                .into_iter()
+                .map(str::to_string)
+                .collect()])
+        );
+
+        assert!(!requirements.allows("Product.inventory", &access(&["read:email"])));
+        assert!(requirements.allows("Product.inventory", &access(&["admin"])));
+        assert!(!requirements.allows("User.email", &access(&["read:email"])));
+        assert!(requirements.allows("User.email", &access(&["read:user", "read:email"])));
+    }
+
+    #[test]
+    fn it_removes_unauthorized_fields() {
+        assert!(filter("{ products { id name } }", RequestAccess::default()).is_none());
+
+        let (query, paths) = filter(
+            "query($id: ID!) {
+                products { id inventory reviews { body } }
+                me { id email }
+                product(id: $id) { ...ProductFields }
+            }
+            fragment ProductFields on Product { name inventory }",
+            access(&["read:email"]),
+        )
+        .unwrap();
+        let query = query.unwrap();
+        assert!(!query.contains("inventory"));
+        assert!(!query.contains("email"));
+        assert!(query.contains("reviews { body }"));
+        assert!(query.contains("me { id"));
+        assert!(query.contains("$id: ID!"));
+        assert_eq!(
+            paths,
+            vec!["/products/inventory", "/me/email", "/product/inventory"]
+        );
+    }
+
+    #[test]
+    fn it_removes_emptied_fields_fragments_and_variables() {
+        let (query, paths) = filter(
+            "query($id: ID!) {
+                products { name }
+                me { ...UserFields }
+                user: me { id }
+                product(id: $id) { reviews { body } }
+            }
+            fragment UserFields on User { id }",
+            RequestAccess::default(),
+        )
+        .unwrap();
+        let query = query.unwrap();
+        assert!(!query.contains("me"));
+        assert!(!query.contains("UserFields"));
+        assert!(!query.contains("$id"));
+        assert!(query.contains("products { name }"));
+        assert_eq!(paths, vec!["/me", "/user", "/product/reviews"]);
+
+        // nothing is left to execute
+        let (query, paths) = filter("{ me { id } }", RequestAccess::default()).unwrap();
+        assert_eq!(query, None);
+        assert_eq!(paths, vec!["/me"]);
+    }
+
+    #[test]
+    fn it_checks_the_implementations_of_interface_fields() {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        let requirements = &schema.authorization;
+        assert!(requirements.authenticated.contains("Post.body"));
+        assert!(!requirements.authenticated.contains("Post.title"));
+        assert!(!requirements.allows("Post.title", &access(&[])));
+        assert!(requirements.allows("Post.title", &access(&["read:announcement"])));
+
+        let (query, paths) = filter("{ posts { title body } }", RequestAccess::default()).unwrap();
+        assert_eq!(query, None);
+        assert_eq!(paths, vec!["/posts/title", "/posts/body"]);
+
+        let (query, paths) = filter("{ posts { title body } }", access(&[])).unwrap();
+        let query = query.unwrap();
+        assert!(!query.contains("title"));
+        assert!(query.contains("body"));
+        assert_eq!(paths, vec!["/posts/title"]);
+
+        assert!(filter("{ posts { title body } }", access(&["read:announcement"])).is_none());
+    }
+}
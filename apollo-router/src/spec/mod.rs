@@ -2,6 +2,7 @@
 #![cfg_attr(not(test), deny(clippy::expect_used))]
 #![cfg_attr(not(test), deny(clippy::panic))]
 
+pub(crate) mod authorization;
 mod field_type;
 mod fragments;
 pub(crate) mod query;
@@ -19,6 +20,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
 
+use self::query::limits::OperationLimit;
 use crate::graphql::ErrorExtension;
 use crate::json_ext::Object;
 
@@ -36,6 +38,10 @@ pub(crate) enum SpecError {
     ParsingError(String),
     /// subscription operation is not supported
     SubscriptionNotSupported,
+    /// maximum {0} limit exceeded in this operation
+    OperationLimitExceeded(OperationLimit),
+    /// estimated operation cost {cost} exceeds the maximum cost {max_cost}
+    CostLimitExceeded { cost: u64, max_cost: u64 },
 }
 
 impl SpecError {
@@ -55,6 +61,8 @@ impl ErrorExtension for SpecError {
             SpecError::InvalidField(_, _) => "INVALID_FIELD",
             SpecError::ParsingError(_) => "PARSING_ERROR",
             SpecError::SubscriptionNotSupported => "SUBSCRIPTION_NOT_SUPPORTED",
+            SpecError::OperationLimitExceeded(limit) => limit.extension_code(),
+            SpecError::CostLimitExceeded { .. } => "MAX_COST_LIMIT",
         }
         .to_string()
     }
@@ -69,6 +77,10 @@ impl ErrorExtension for SpecError {
                 obj.insert("type", ty.clone().into());
                 obj.insert("field", field.clone().into());
             }
+            SpecError::CostLimitExceeded { cost, max_cost } => {
+                obj.insert("cost", (*cost).into());
+                obj.insert("maxCost", (*max_cost).into());
+            }
             _ => (),
         }
 
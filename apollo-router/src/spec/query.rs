@@ -38,6 +38,9 @@ use crate::spec::Selection;
 use crate::spec::SpecError;
 use crate::Configuration;
 
+pub(crate) mod cost;
+pub(crate) mod limits;
+
 pub(crate) const TYPENAME: &str = "__typename";
 
 /// A GraphQL query.
@@ -336,6 +339,8 @@ impl Query {
             .iter()
             .map(|operation| Operation::from_hir(operation, schema))
             .collect::<Result<Vec<_>, SpecError>>()?;
+        Self::check_subscriptions(&operations, configuration)?;
+        limits::check(&operations, &fragments, &configuration.limits)?;
 
         Ok(Query {
             string: query,
@@ -385,6 +390,8 @@ impl Query {
             })
             .map(|operation| Operation::from_ast(operation, schema))
             .collect::<Result<Vec<_>, SpecError>>()?;
+        Self::check_subscriptions(&operations, configuration)?;
+        limits::check(&operations, &fragments, &configuration.limits)?;
 
         Ok(Query {
             compiler: OnceCell::new(),
@@ -395,6 +402,21 @@ impl Query {
         })
     }
 
+    /// Subscriptions can only be executed if they were enabled in the configuration
+    fn check_subscriptions(
+        operations: &[Operation],
+        configuration: &Configuration,
+    ) -> Result<(), SpecError> {
+        if !configuration.subscription_enabled()
+            && operations
+                .iter()
+                .any(|operation| operation.kind == OperationKind::Subscription)
+        {
+            return Err(SpecError::SubscriptionNotSupported);
+        }
+        Ok(())
+    }
+
     pub(crate) async fn compiler(&self, schema: Option<&Schema>) -> MutexGuard<'_, ApolloCompiler> {
         self.compiler
             .get_or_init(|| async { Mutex::new(self.uncached_compiler(schema)) })
@@ -1172,9 +1194,6 @@ impl Operation {
     fn from_hir(operation: &hir::OperationDefinition, schema: &Schema) -> Result<Self, SpecError> {
         let name = operation.name().map(|s| s.to_owned());
         let kind = operation.operation_ty().into();
-        if kind == OperationKind::Subscription {
-            return Err(SpecError::SubscriptionNotSupported);
-        }
         let current_field_type = FieldType::Named(schema.root_operation_name(kind).to_owned());
         let selection_set = operation
             .selection_set()
@@ -1221,9 +1240,6 @@ impl Operation {
             })
             .unwrap_or(OperationKind::Query);
 
-        if kind == OperationKind::Subscription {
-            return Err(SpecError::SubscriptionNotSupported);
-        }
         let current_field_type = FieldType::Named(schema.root_operation_name(kind).to_owned());
 
         let selection_set = operation
@@ -0,0 +1,277 @@
+//! Static cost estimation of operations.
+//!
+//! Each field costs its configured weight, and the cost of the selection set of a list field is
+//! multiplied by the size of the list: the value of its `first` or `limit` argument, or the
+//! configured default list size. Fields skipped with `@skip` or `@include` are still counted, so
+//! the estimated cost is an upper bound.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use apollo_compiler::hir;
+use apollo_compiler::HirDatabase;
+
+use super::Query;
+use crate::configuration::OperationCost;
+use crate::json_ext::Object;
+use crate::spec::FieldType;
+use crate::spec::Schema;
+use crate::spec::TYPENAME;
+
+/// Context key holding the estimated cost of the operation
+pub(crate) const OPERATION_COST_CONTEXT_KEY: &str = "operation_cost";
+
+/// Arguments giving the size of a list field
+const LIST_SIZE_ARGUMENTS: [&str; 2] = ["first", "limit"];
+
+impl Query {
+    /// Estimates the cost of the fields selected by an operation of this query.
+    pub(crate) async fn estimate_cost(
+        &self,
+        schema: &Schema,
+        operation_name: Option<&str>,
+        variables: &Object,
+        config: &OperationCost,
+    ) -> u64 {
+        let compiler = self.compiler(Some(schema)).await;
+        let operations = compiler.db.all_operations();
+        let operation = match operation_name {
+            Some(name) => operations.iter().find(|op| op.name() == Some(name)),
+            None => operations.first(),
+        };
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return 0,
+        };
+        let fragments = compiler.db.all_fragments();
+
+        let mut estimator = Estimator {
+            query: self,
+            schema,
+            operation_name,
+            variables,
+            config,
+            fragments: fragments
+                .iter()
+                .map(|(name, fragment)| (name.as_str(), fragment.as_ref()))
+                .collect(),
+            estimated_fragments: HashMap::new(),
+            visiting: HashSet::new(),
+        };
+        let root_type = schema.root_operation_name(operation.operation_ty().into());
+        estimator.selection_set(operation.selection_set().selection(), root_type)
+    }
+}
+
+struct Estimator<'a> {
+    query: &'a Query,
+    schema: &'a Schema,
+    operation_name: Option<&'a str>,
+    variables: &'a Object,
+    config: &'a OperationCost,
+    fragments: HashMap<&'a str, &'a hir::FragmentDefinition>,
+    estimated_fragments: HashMap<&'a str, u64>,
+    /// fragments being estimated, to stop on fragment cycles
+    visiting: HashSet<&'a str>,
+}
+
+impl<'a> Estimator<'a> {
+    fn selection_set(&mut self, selection_set: &'a [hir::Selection], type_name: &str) -> u64 {
+        selection_set
+            .iter()
+            .map(|selection| self.selection(selection, type_name))
+            .fold(0, u64::saturating_add)
+    }
+
+    fn selection(&mut self, selection: &'a hir::Selection, type_name: &str) -> u64 {
+        match selection {
+            hir::Selection::Field(field) => self.field(field, type_name),
+            hir::Selection::InlineFragment(inline_fragment) => {
+                let type_name = inline_fragment.type_condition().unwrap_or(type_name);
+                self.selection_set(inline_fragment.selection_set().selection(), type_name)
+            }
+            hir::Selection::FragmentSpread(fragment_spread) => {
+                self.fragment(fragment_spread.name())
+            }
+        }
+    }
+
+    fn field(&mut self, field: &'a hir::Field, type_name: &str) -> u64 {
+        let name = field.name();
+        let weight = self
+            .config
+            .field_weights
+            .get(&format!("{type_name}.{name}"))
+            .copied()
+            .unwrap_or(self.config.default_field_weight);
+        if name == TYPENAME {
+            return weight;
+        }
+
+        let schema = self.schema;
+        let field_type = match schema
+            .object_types
+            .get(type_name)
+            .and_then(|ty| ty.field(name))
+            .or_else(|| {
+                schema
+                    .interfaces
+                    .get(type_name)
+                    .and_then(|ty| ty.field(name))
+            }) {
+            Some(field_type) => field_type,
+            None => return weight,
+        };
+        let field_type_name = match field_type.inner_type_name() {
+            Some(field_type_name) => field_type_name,
+            None => return weight,
+        };
+
+        let nested = self.selection_set(field.selection_set().selection(), field_type_name);
+        let multiplier = if is_list(field_type) {
+            self.list_size(field)
+        } else {
+            1
+        };
+        weight.saturating_add(nested.saturating_mul(multiplier))
+    }
+
+    fn list_size(&self, field: &hir::Field) -> u64 {
+        field
+            .arguments()
+            .iter()
+            .find(|argument| LIST_SIZE_ARGUMENTS.contains(&argument.name()))
+            .and_then(|argument| match argument.value() {
+                hir::Value::Int(value) => Some(value.get() as u64),
+                hir::Value::Variable(variable) => self
+                    .query
+                    .variable_value(self.operation_name, variable.name(), self.variables)
+                    .and_then(|value| value.as_u64()),
+                _ => None,
+            })
+            .unwrap_or(self.config.default_list_size)
+    }
+
+    fn fragment(&mut self, name: &'a str) -> u64 {
+        if let Some(cost) = self.estimated_fragments.get(name) {
+            return *cost;
+        }
+        let fragment = match self.fragments.get(name) {
+            Some(fragment) => *fragment,
+            None => return 0,
+        };
+        if !self.visiting.insert(name) {
+            return 0;
+        }
+
+        let cost = self.selection_set(
+            fragment.selection_set().selection(),
+            fragment.type_condition(),
+        );
+        self.visiting.remove(name);
+        self.estimated_fragments.insert(name, cost);
+        cost
+    }
+}
+
+fn is_list(field_type: &FieldType) -> bool {
+    match field_type {
+        FieldType::List(_) => true,
+        FieldType::NonNull(inner) => is_list(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::Configuration;
+
+    const SCHEMA: &str = r#"
+        schema
+            @core(feature: "https://specs.apollo.dev/core/v0.1")
+            @core(feature: "https://specs.apollo.dev/join/v0.1")
+        {
+            query: Query
+        }
+        directive @core(feature: String!) repeatable on SCHEMA
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        enum join__Graph {
+            TEST @join__graph(name: "test", url: "http://localhost:4001/graphql")
+        }
+
+        type Query {
+            me: User
+            users(first: Int, limit: Int): [User!]!
+        }
+
+        type User {
+            id: ID!
+            name: String
+            friends(first: Int): [User]
+        }
+    "#;
+
+    async fn estimate(query: &str, variables: Object, config: &OperationCost) -> u64 {
+        let configuration = Configuration::default();
+        let schema = Schema::parse(SCHEMA, &configuration).unwrap();
+        let query = Query::parse(query, &schema, &configuration).unwrap();
+        query.estimate_cost(&schema, None, &variables, config).await
+    }
+
+    #[tokio::test]
+    async fn it_weights_fields() {
+        let config = OperationCost {
+            field_weights: [("Query.me".to_string(), 5)].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            estimate("{ me { id name } }", Object::new(), &config).await,
+            7
+        );
+        assert_eq!(
+            estimate(
+                "{ me { ...UserFields } } fragment UserFields on User { id ... on User { name } }",
+                Object::new(),
+                &config
+            )
+            .await,
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn it_multiplies_list_fields() {
+        let config = OperationCost {
+            default_list_size: 10,
+            ..Default::default()
+        };
+        // 1 + 10 * (1 + 1)
+        assert_eq!(
+            estimate("{ users { id name } }", Object::new(), &config).await,
+            21
+        );
+        // 1 + 3 * (1 + (1 + 2 * 1))
+        assert_eq!(
+            estimate(
+                "{ users(first: 3) { id friends(first: 2) { id } } }",
+                Object::new(),
+                &config
+            )
+            .await,
+            13
+        );
+        // 1 + 4 * 1
+        assert_eq!(
+            estimate(
+                "query($limit: Int) { users(limit: $limit) { id } }",
+                json!({ "limit": 4 }).as_object().unwrap().clone(),
+                &config
+            )
+            .await,
+            5
+        );
+    }
+}
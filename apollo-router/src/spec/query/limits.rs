@@ -0,0 +1,324 @@
+//! Limits on the shape of operations: depth, height, aliases and root fields.
+//!
+//! Operations are measured after fragment expansion. Each fragment is measured once, so
+//! operations that reuse fragments many times are measured without expanding them again.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use displaydoc::Display;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Operation;
+use crate::configuration::OperationLimits;
+use crate::spec::Fragments;
+use crate::spec::Selection;
+use crate::spec::SpecError;
+
+/// A limit on the shape of operations
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum OperationLimit {
+    /// depth
+    Depth,
+    /// height
+    Height,
+    /// aliases
+    Aliases,
+    /// root fields
+    RootFields,
+}
+
+impl OperationLimit {
+    pub(crate) const fn extension_code(&self) -> &'static str {
+        match self {
+            OperationLimit::Depth => "MAX_DEPTH_LIMIT",
+            OperationLimit::Height => "MAX_HEIGHT_LIMIT",
+            OperationLimit::Aliases => "MAX_ALIASES_LIMIT",
+            OperationLimit::RootFields => "MAX_ROOT_FIELDS_LIMIT",
+        }
+    }
+}
+
+/// The measures of a selection set, compared to the limits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Measures {
+    /// depth of the most nested field
+    depth: u32,
+    /// number of fields, including nested ones
+    height: u32,
+    /// number of aliased fields, including nested ones
+    aliases: u32,
+    /// number of fields at the top level of the selection set
+    fields: u32,
+}
+
+impl Measures {
+    fn add(&mut self, other: Measures) {
+        self.depth = self.depth.max(other.depth);
+        self.height = self.height.saturating_add(other.height);
+        self.aliases = self.aliases.saturating_add(other.aliases);
+        self.fields = self.fields.saturating_add(other.fields);
+    }
+}
+
+/// Checks the operations of a query against the configured limits.
+///
+/// In `warn_only` mode, the exceeded limits are logged and the operations are accepted.
+pub(crate) fn check(
+    operations: &[Operation],
+    fragments: &Fragments,
+    limits: &OperationLimits,
+) -> Result<(), SpecError> {
+    if limits.max_depth.is_none()
+        && limits.max_height.is_none()
+        && limits.max_aliases.is_none()
+        && limits.max_root_fields.is_none()
+    {
+        return Ok(());
+    }
+
+    let mut measurer = Measurer {
+        fragments,
+        measured_fragments: HashMap::new(),
+        visiting: HashSet::new(),
+    };
+    for operation in operations {
+        let measures = measurer.selection_set(&operation.selection_set);
+        let exceeded = [
+            (OperationLimit::Depth, measures.depth, limits.max_depth),
+            (OperationLimit::Height, measures.height, limits.max_height),
+            (
+                OperationLimit::Aliases,
+                measures.aliases,
+                limits.max_aliases,
+            ),
+            (
+                OperationLimit::RootFields,
+                measures.fields,
+                limits.max_root_fields,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(limit, measured, max)| match max {
+            Some(max) if measured > max => Some((limit, measured, max)),
+            _ => None,
+        });
+
+        for (limit, measured, max) in exceeded {
+            if !limits.warn_only {
+                return Err(SpecError::OperationLimitExceeded(limit));
+            }
+            tracing::warn!(
+                operation_name = operation.name.as_deref().unwrap_or_default(),
+                "operation exceeds the maximum {limit} limit ({measured} > {max}), it would be rejected if warn_only was disabled"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+struct Measurer<'a> {
+    fragments: &'a Fragments,
+    measured_fragments: HashMap<&'a str, Measures>,
+    /// fragments being measured, to stop on fragment cycles
+    visiting: HashSet<&'a str>,
+}
+
+impl<'a> Measurer<'a> {
+    fn selection_set(&mut self, selection_set: &'a [Selection]) -> Measures {
+        let mut measures = Measures::default();
+        for selection in selection_set {
+            measures.add(self.selection(selection));
+        }
+        measures
+    }
+
+    fn selection(&mut self, selection: &'a Selection) -> Measures {
+        match selection {
+            Selection::Field {
+                alias,
+                selection_set,
+                ..
+            } => {
+                let nested = selection_set
+                    .as_deref()
+                    .map(|selection_set| self.selection_set(selection_set))
+                    .unwrap_or_default();
+                Measures {
+                    depth: nested.depth.saturating_add(1),
+                    height: nested.height.saturating_add(1),
+                    aliases: nested.aliases.saturating_add(u32::from(alias.is_some())),
+                    fields: 1,
+                }
+            }
+            Selection::InlineFragment { selection_set, .. } => self.selection_set(selection_set),
+            Selection::FragmentSpread { name, .. } => self.fragment(name),
+        }
+    }
+
+    fn fragment(&mut self, name: &'a str) -> Measures {
+        if let Some(measures) = self.measured_fragments.get(name) {
+            return *measures;
+        }
+        let fragment = match self.fragments.get(name) {
+            Some(fragment) => fragment,
+            None => return Measures::default(),
+        };
+        if !self.visiting.insert(name) {
+            return Measures::default();
+        }
+
+        let measures = self.selection_set(&fragment.selection_set);
+        self.visiting.remove(name);
+        self.measured_fragments.insert(name, measures);
+        measures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::query::Query;
+    use crate::spec::Schema;
+    use crate::Configuration;
+
+    const SCHEMA: &str = r#"
+        schema
+            @core(feature: "https://specs.apollo.dev/core/v0.1")
+            @core(feature: "https://specs.apollo.dev/join/v0.1")
+        {
+            query: Query
+        }
+        directive @core(feature: String!) repeatable on SCHEMA
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        enum join__Graph {
+            TEST @join__graph(name: "test", url: "http://localhost:4001/graphql")
+        }
+
+        type Query {
+            me: User
+            user(id: ID!): User
+        }
+
+        type User {
+            id: ID!
+            name: String
+            friends: [User]
+        }
+    "#;
+
+    fn parse(query: &str, limits: OperationLimits) -> Result<Query, SpecError> {
+        let configuration = Configuration::fake_builder()
+            .limits(limits)
+            .build()
+            .unwrap();
+        let schema = Schema::parse(SCHEMA, &configuration).unwrap();
+        Query::parse(query, &schema, &configuration)
+    }
+
+    fn measure(query: &str) -> Measures {
+        let configuration = Configuration::default();
+        let schema = Schema::parse(SCHEMA, &configuration).unwrap();
+        let query = Query::parse(query, &schema, &configuration).unwrap();
+        let mut measurer = Measurer {
+            fragments: &query.fragments,
+            measured_fragments: HashMap::new(),
+            visiting: HashSet::new(),
+        };
+        measurer.selection_set(&query.operations[0].selection_set)
+    }
+
+    #[test]
+    fn it_measures_operations_after_fragment_expansion() {
+        assert_eq!(
+            measure(
+                "query {
+                    me { ...UserFields }
+                    other: user(id: \"1\") { ...UserFields ... on User { id } }
+                }
+                fragment UserFields on User { id name friends { alias: id } }"
+            ),
+            Measures {
+                depth: 3,
+                height: 11,
+                aliases: 3,
+                fields: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_operations_exceeding_a_limit() {
+        let query = "{ me { friends { friends { id } } } a: me { id } b: me { id } }";
+        assert!(parse(query, OperationLimits::default()).is_ok());
+
+        let error = parse(
+            query,
+            OperationLimits {
+                max_depth: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            SpecError::OperationLimitExceeded(OperationLimit::Depth)
+        ));
+
+        let error = parse(
+            query,
+            OperationLimits {
+                max_depth: Some(4),
+                max_aliases: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            SpecError::OperationLimitExceeded(OperationLimit::Aliases)
+        ));
+
+        let error = parse(
+            query,
+            OperationLimits {
+                max_root_fields: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            SpecError::OperationLimitExceeded(OperationLimit::RootFields)
+        ));
+
+        let error = parse(
+            query,
+            OperationLimits {
+                max_height: Some(7),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            SpecError::OperationLimitExceeded(OperationLimit::Height)
+        ));
+    }
+
+    #[test]
+    fn it_accepts_operations_exceeding_a_limit_in_warn_only_mode() {
+        let query = "{ a: me { id } b: me { id } }";
+        assert!(parse(
+            query,
+            OperationLimits {
+                max_aliases: Some(1),
+                warn_only: true,
+                ..Default::default()
+            },
+        )
+        .is_ok());
+    }
+}
@@ -5274,3 +5274,38 @@ fn test_query_not_named_query() {
         "unexpected selection {selection:?}"
     );
 }
+
+#[test]
+fn subscriptions_are_rejected_unless_enabled() {
+    let schema = with_supergraph_boilerplate(
+        "type Query {
+        name: String
+    }
+
+    type Subscription {
+        userWasCreated: User
+    }
+
+    type User {
+        id: ID!
+        name: String
+    }",
+    );
+    let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+    let query = "subscription { userWasCreated { id name } }";
+
+    assert!(matches!(
+        Query::parse(query, &schema, &Default::default()),
+        Err(SpecError::SubscriptionNotSupported)
+    ));
+    assert!(matches!(
+        Query::parse_with_ast(query, &schema, &Default::default()),
+        Err(SpecError::SubscriptionNotSupported)
+    ));
+
+    let configuration: Configuration = "subscription:\n  enabled: true"
+        .parse()
+        .expect("could not parse configuration");
+    let query = Query::parse(query, &schema, &configuration).expect("could not parse query");
+    assert_eq!(query.operations[0].kind(), &OperationKind::Subscription);
+}
@@ -21,6 +21,7 @@ use crate::error::SchemaError;
 use crate::json_ext::Object;
 use crate::json_ext::Value;
 use crate::query_planner::OperationKind;
+use crate::spec::authorization::AuthorizationRequirements;
 use crate::spec::query::parse_hir_value;
 use crate::spec::query::parse_value;
 use crate::spec::FieldType;
@@ -41,6 +42,7 @@ pub(crate) struct Schema {
     api_schema: Option<Box<Schema>>,
     pub(crate) schema_id: Option<String>,
     root_operations: HashMap<OperationKind, String>,
+    pub(crate) authorization: AuthorizationRequirements,
 }
 
 pub(crate) fn sorted_map<K, V>(
@@ -92,6 +94,7 @@ impl std::fmt::Debug for Schema {
             api_schema,
             schema_id,
             root_operations,
+            authorization,
         } = self;
         writeln!(f, "Schema:")?;
         writeln!(f, "  raw_sdl: {raw_sdl:?}")?;
@@ -133,6 +136,7 @@ impl std::fmt::Debug for Schema {
         sorted_map_of_sets(f, "enums", enums)?;
         sorted_map_of_sets(f, "subtype_map", subtype_map)?;
         sorted_map(f, "  ", "subgraphs", subgraphs)?;
+        writeln!(f, "  authorization: {authorization:?}")?;
         writeln!(f, "  schema_id: {schema_id:?}")?;
         writeln!(f, "  api_schema: {api_schema:?}")?;
         Ok(())
@@ -302,6 +306,7 @@ impl Schema {
                 api_schema: None,
                 schema_id,
                 root_operations,
+                authorization: AuthorizationRequirements::new(&compiler),
             })
         }
     }
@@ -732,6 +737,7 @@ impl Schema {
                 api_schema: None,
                 schema_id,
                 root_operations,
+                authorization: AuthorizationRequirements::new(&compiler),
             })
         }
     }